@@ -0,0 +1,888 @@
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::certificates::Certificate;
+use crate::config::Config;
+use crate::profile::Packages;
+use crate::state::{self, Change};
+use crate::system::System;
+use crate::{error, linux, release, system, unix};
+
+#[derive(Debug)]
+pub(crate) struct Alpine<'s> {
+    config: &'s Config,
+}
+
+static JAVA_HOME: &str = "/usr/lib/jvm/default-jvm";
+
+static GROOVY_VERSION: &str = "4.0.24";
+
+impl<'s> Alpine<'s> {
+    pub(crate) fn new(config: &'s Config) -> Self {
+        Alpine { config }
+    }
+
+    fn enable_repository(&self, repository: &str) -> Result<(), Box<dyn Error>> {
+        let repositories = "/etc/apk/repositories";
+        let contents = fs::read_to_string(repositories)?;
+        let suffix = format!("/{repository}");
+        let new_contents = contents
+            .lines()
+            .map(|line| {
+                if line.starts_with('#') && line.ends_with(&suffix) && !line.contains("/edge/") {
+                    line.trim_start_matches('#').trim().to_string()
+                } else {
+                    line.to_string()
+                }
+            })
+            .collect::<Vec<String>>();
+        let mut file = system::create_file(self, repositories)?;
+        writeln!(file, "{}", new_contents.join("\n"))?;
+        Ok(())
+    }
+
+    fn enable_service(&self, service: &str) -> Result<String, Box<dyn Error>> {
+        let output = self.execute(&format!("rc-update add {service} default"), true)?;
+        state::record(Change::Service {
+            name: service.to_string(),
+        });
+        Ok(output)
+    }
+
+    fn is_container(&self) -> bool {
+        Path::new("/.dockerenv").exists() || Path::new("/run/.containerenv").exists()
+    }
+
+    fn is_installed(&self, app: &str) -> Result<bool, Box<dyn Error>> {
+        let output = unix::execute(&format!("apk info -e {app}"), false, false, false)?;
+        if !output.trim().is_empty() {
+            debug!("{} is already installed.", app);
+            return Ok(true);
+        }
+        debug!("{} is not installed.", app);
+        Ok(false)
+    }
+
+    fn not_supported(&self, application: &str) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(error::Error::new(&format!(
+            "{application} is not supported on Alpine"
+        ))))
+    }
+}
+
+#[async_trait]
+impl<'s> System for Alpine<'s> {
+    fn create_snapshot(&self, run: u32) -> Result<(), Box<dyn Error>> {
+        linux::snapshot::create(self, run)
+    }
+
+    fn execute(&self, command: &str, super_user: bool) -> Result<String, Box<dyn Error>> {
+        unix::execute(command, super_user, true, self.config.dry_run)
+    }
+
+    fn get_home_dir(&self) -> String {
+        linux::get_home_dir()
+    }
+
+    fn query(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        unix::execute(command, true, false, false)
+    }
+
+    fn run(
+        &self,
+        program: &str,
+        args: Vec<&str>,
+        super_user: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        unix::run(program, &args, super_user, self.config.dry_run)
+    }
+
+    fn install_applications(&self, application: Vec<&str>) -> Result<String, Box<dyn Error>> {
+        let output = self.execute(&format!("apk add {}", application.join(" ")), true)?;
+        state::record_packages(&application);
+        Ok(output)
+    }
+
+    fn install_affinity_suite(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Affinity Suite")
+    }
+
+    fn install_android_studio(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Android Studio")
+    }
+
+    fn install_archiver(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Archiver")
+    }
+
+    fn install_audacity(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Audacity")
+    }
+
+    fn install_bambu_studio(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Bambu Studio")
+    }
+
+    fn install_bash(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("bash")? {
+            self.install_application("bash")?;
+        }
+        if !self.is_installed("bash-completion")? {
+            self.install_application("bash-completion")?;
+        }
+        unix::setup_bash(self, self.config)?;
+        Ok(())
+    }
+
+    fn install_blender(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Blender")
+    }
+
+    fn install_bluetooth(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("bluez")? {
+            self.install_application("bluez")?;
+        }
+        self.enable_service("bluetooth")?;
+        Ok(())
+    }
+
+    fn install_calibre(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Calibre")
+    }
+
+    fn install_certificate(&self, certificate: &Certificate) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("ca-certificates")? {
+            self.install_application("ca-certificates")?;
+        }
+        if !self.is_installed("nss-tools")? {
+            self.install_application("nss-tools")?;
+        }
+        self.execute(
+            &format!(
+                "cp {} /usr/local/share/ca-certificates/{}.crt",
+                certificate.file, certificate.name
+            ),
+            true,
+        )?;
+        self.execute("update-ca-certificates", true)?;
+        linux::add_certificate_to_nss(self, certificate)?;
+        Ok(())
+    }
+
+    async fn install_codecs(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Codecs")
+    }
+
+    fn install_cplusplus(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("gcc")? {
+            self.install_application("gcc")?;
+        }
+        if !self.is_installed("g++")? {
+            self.install_application("g++")?;
+        }
+        if !self.is_installed("make")? {
+            self.install_application("make")?;
+        }
+        if !self.is_installed("cmake")? {
+            self.install_application("cmake")?;
+        }
+        Ok(())
+    }
+
+    async fn install_cryptomator(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Cryptomator")
+    }
+
+    fn install_curl(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("curl")? {
+            self.install_application("curl")?;
+        }
+        Ok(())
+    }
+
+    async fn install_davinci_resolve(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("DaVinci Resolve")
+    }
+
+    fn install_discord(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Discord")
+    }
+
+    fn install_disk_usage_analyser(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Disk Usage Analyser")
+    }
+
+    fn install_development_extras(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("build-base")? {
+            self.install_application("build-base")?;
+        }
+        if !self.is_installed("openssl-dev")? {
+            self.install_application("openssl-dev")?;
+        }
+        if !self.is_installed("pkgconf")? {
+            self.install_application("pkgconf")?;
+        }
+        Ok(())
+    }
+
+    fn install_docker(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("docker-cli")? {
+            self.install_application("docker-cli")?;
+        }
+        if !self.is_installed("docker-cli-compose")? {
+            self.install_application("docker-cli-compose")?;
+        }
+        // Containers are expected to talk to the host's daemon, so only install the engine on a
+        // real machine
+        if !self.is_container() {
+            if !self.is_installed("docker")? {
+                self.install_application("docker")?;
+            }
+            self.enable_service("docker")?;
+        }
+        linux::setup_docker(self.config.dry_run)?;
+        Ok(())
+    }
+
+    async fn install_eclipse(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Eclipse")
+    }
+
+    async fn install_epic_games(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Epic Games")
+    }
+
+    fn install_exact_audio_copy(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Exact Audio Copy")
+    }
+
+    fn install_extras(&self, extras: &Packages) -> Result<(), Box<dyn Error>> {
+        if !extras.packages.is_empty() {
+            self.install_applications(extras.packages.iter().map(String::as_str).collect())?;
+        }
+        if !extras.aur.is_empty()
+            || !extras.snaps.is_empty()
+            || !extras.classic_snaps.is_empty()
+            || !extras.flatpaks.is_empty()
+        {
+            warn!(
+                "Not installing {}, as AUR packages, snaps and flatpaks aren't supported on Alpine",
+                [
+                    extras.aur.as_slice(),
+                    extras.snaps.as_slice(),
+                    extras.classic_snaps.as_slice(),
+                    extras.flatpaks.as_slice(),
+                ]
+                .concat()
+                .join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    async fn install_exercism(&self) -> Result<(), Box<dyn Error>> {
+        let exercism_path = format!("{}/bin/exercism", self.get_home_dir());
+        if !Path::new(&format!("{exercism_path}/exercism")).exists() {
+            let exercism = release::resolve(self.config, &release::EXERCISM).await?;
+            system::download_file(self.config, &exercism.url, "exercism.tar.gz").await?;
+            linux::untar_rename_root("exercism.tar.gz", &exercism_path)?;
+            let user_id = unix::get_user_id();
+            let group_id = unix::get_group_id();
+            unix::recursively_chown(&exercism_path, &user_id, &group_id)?;
+            unix::recursively_chmod(&format!("{exercism_path}/exercism"), &0o755, &0o755)?;
+            unix::add_to_path(self, &exercism_path)?;
+            fs::remove_file("exercism.tar.gz")?;
+        }
+        Ok(())
+    }
+
+    fn install_firefox(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Firefox")
+    }
+
+    fn install_firmware_updater(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("fwupd")? {
+            self.install_application("fwupd")?;
+        }
+        Ok(())
+    }
+
+    fn install_fish(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("fish")? {
+            self.install_application("fish")?;
+        }
+        unix::setup_fish(self, self.config)
+    }
+
+    fn install_git(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("git")? {
+            self.install_application("git")?;
+        }
+        system::setup_git_config(self)?;
+        Ok(())
+    }
+
+    fn install_gimp(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("GIMP")
+    }
+
+    async fn install_godot(&self) -> Result<(), Box<dyn Error>> {
+        if self.config.cli_only {
+            info!("Skipping Godot as it needs a window manager");
+            return Ok(());
+        }
+        if !self.is_installed("godot")? {
+            self.install_application("godot")?;
+        }
+        Ok(())
+    }
+
+    async fn install_gog_galaxy(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("GOG Galaxy")
+    }
+
+    async fn install_google_chrome(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Google Chrome")
+    }
+
+    async fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
+        let google_cloud_sdk = "/opt/google-cloud-sdk";
+        if !Path::new(google_cloud_sdk).exists() {
+            if !self.is_installed("python3")? {
+                self.install_application("python3")?;
+            }
+            system::download_file(
+                self.config,
+                "https://dl.google.com/dl/cloudsdk/channels/rapid/google-cloud-cli-linux-x86_64.tar.gz",
+                "google-cloud-cli.tar.gz",
+            )
+            .await?;
+            linux::untar_rename_root("google-cloud-cli.tar.gz", google_cloud_sdk)?;
+            fs::remove_file("google-cloud-cli.tar.gz")?;
+        }
+        let google_cloud_sdk_bin = format!("{google_cloud_sdk}/bin");
+        unix::add_to_path(self, &google_cloud_sdk_bin)?;
+        Ok(())
+    }
+
+    fn install_google_drive(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Google Drive")
+    }
+
+    fn install_gpg(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("gnupg")? {
+            self.install_application("gnupg")?;
+        }
+        Ok(())
+    }
+
+    fn install_gradle(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("gradle")? {
+            self.install_application("gradle")?;
+        }
+        Ok(())
+    }
+
+    fn install_gramps(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Gramps")
+    }
+
+    async fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
+        let groovy_path = format!("/opt/groovy-{GROOVY_VERSION}");
+        if !Path::new(&groovy_path).exists() {
+            system::download_file(
+                self.config,
+                &format!("https://groovy.jfrog.io/artifactory/dist-release-local/groovy-zips/apache-groovy-binary-{GROOVY_VERSION}.zip"),
+                "groovy.zip",
+            )
+            .await?;
+            system::extract_zip(Path::new("groovy.zip"), Path::new("/opt"), false)?;
+            fs::remove_file("groovy.zip")?;
+        }
+        let groovy_bin = format!("{groovy_path}/bin");
+        unix::add_to_path(self, &groovy_bin)?;
+        Ok(())
+    }
+
+    fn install_handbrake(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Handbrake")
+    }
+
+    fn install_inkscape(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Inkscape")
+    }
+
+    fn install_insync(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Insync")
+    }
+
+    fn install_intel_gpu_laptop_tools(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Intel GPU laptop tools")
+    }
+
+    fn install_intellij(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("IntelliJ")
+    }
+
+    fn install_jdk(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("openjdk21-jdk")? {
+            self.install_application("openjdk21-jdk")?;
+        }
+        unix::set_java_home(self, JAVA_HOME)?;
+        unix::add_to_path(self, "$JAVA_HOME/bin")?;
+        Ok(())
+    }
+
+    fn install_keepassxc(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("KeePassXC")
+    }
+
+    async fn install_kubectl(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("kubectl")? {
+            self.install_application("kubectl")?;
+        }
+        Ok(())
+    }
+
+    async fn install_helm(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("helm")? {
+            self.install_application("helm")?;
+        }
+        Ok(())
+    }
+
+    fn install_latex(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("LaTeX")
+    }
+
+    fn install_office(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Office")
+    }
+
+    async fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("OpenSCAD")
+    }
+
+    fn install_lutris(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Lutris")
+    }
+
+    fn install_maven(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("maven")? {
+            self.install_application("maven")?;
+        }
+        Ok(())
+    }
+
+    fn install_makemkv(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("MakeMKV")
+    }
+
+    fn install_microcode(&self) -> Result<(), Box<dyn Error>> {
+        let cpu_name = linux::get_cpu_name();
+
+        match cpu_name.as_deref() {
+            Some("GenuineIntel") if !self.is_installed("intel-ucode")? => {
+                self.install_application("intel-ucode")?;
+            }
+            Some("AuthenticAMD") if !self.is_installed("amd-ucode")? => {
+                self.install_application("amd-ucode")?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Microsoft Edge")
+    }
+
+    fn install_mkvtoolnix(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("MKVToolNix")
+    }
+
+    fn install_networking_tools(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("bind-tools")? {
+            self.install_application("bind-tools")?;
+        }
+        if !self.is_installed("iputils")? {
+            self.install_application("iputils")?;
+        }
+        if !self.is_installed("nmap")? {
+            self.install_application("nmap")?;
+        }
+        Ok(())
+    }
+
+    fn install_nextcloud_client(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Nextcloud Client")
+    }
+
+    async fn install_nodejs(&self) -> Result<(), Box<dyn Error>> {
+        // nvm only provides glibc builds, so use the musl builds from the repositories instead
+        if !self.is_installed("nodejs")? {
+            self.install_application("nodejs")?;
+        }
+        if !self.is_installed("npm")? {
+            self.install_application("npm")?;
+        }
+        self.execute("npm install --global yarn", true)?;
+        Ok(())
+    }
+
+    async fn install_nordvpn(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("NordVPN")
+    }
+
+    fn install_nvidia_tools(&self, _gpu: &str) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Nvidia tools")
+    }
+
+    fn install_nvidia_laptop_tools(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Nvidia laptop tools")
+    }
+
+    fn install_obs_studio(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("OBS Studio")
+    }
+
+    fn install_onedrive(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("OneDrive")
+    }
+
+    fn install_origin(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Origin")
+    }
+
+    fn install_powertop(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("powertop")? {
+            self.install_application("powertop")?;
+        }
+        Ok(())
+    }
+
+    fn install_printer_drivers(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("cups")? {
+            self.install_application("cups")?;
+        }
+        self.enable_service("cupsd")?;
+        if !self.is_installed("avahi")? {
+            self.install_application("avahi")?;
+        }
+        self.enable_service("avahi-daemon")?;
+        Ok(())
+    }
+
+    fn install_python(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("python3")? {
+            self.install_application("python3")?;
+        }
+        if !self.is_installed("py3-pip")? {
+            self.install_application("py3-pip")?;
+        }
+        Ok(())
+    }
+
+    fn install_quicklook(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("QuickLook")
+    }
+
+    fn install_retroarch(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("RetroArch")
+    }
+
+    async fn install_rust(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("rustup")? {
+            self.install_application("rustup")?;
+            self.execute("rustup-init -y", false)?;
+        }
+        unix::add_to_path(self, &format!("{}/.cargo/bin", self.get_home_dir()))?;
+        self.execute("rustup default stable", false)?;
+        Ok(())
+    }
+
+    fn install_rust_rover(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Rust Rover")
+    }
+
+    fn install_slack(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Slack")
+    }
+
+    async fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Spotify")
+    }
+
+    fn install_steam(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Steam")
+    }
+
+    fn install_sweet_home_3d(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Sweet Home 3D")
+    }
+
+    fn install_syncthing(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("syncthing")? {
+            self.install_application("syncthing")?;
+        }
+        Ok(())
+    }
+
+    async fn install_system_extras(&self) -> Result<(), Box<dyn Error>> {
+        self.enable_repository("community")?;
+        self.update_os_repo()?;
+        // Provides chsh, groupadd and usermod, which the shared unix setup relies on
+        if !self.is_installed("shadow")? {
+            self.install_application("shadow")?;
+        }
+        if !self.is_installed("coreutils")? {
+            self.install_application("coreutils")?;
+        }
+        if !self.is_installed("man-db")? {
+            self.install_application("man-db")?;
+        }
+        if !self.is_installed("openrc")? {
+            self.install_application("openrc")?;
+        }
+        Ok(())
+    }
+
+    async fn install_tauon_music_box(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Tauon Music Box")
+    }
+
+    async fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("terraform")? && !Path::new("/usr/local/bin/terraform").exists() {
+            // Downloaded like the zip itself, so a bundle keeps the version it was created with.
+            system::download_file(
+                self.config,
+                "https://checkpoint-api.hashicorp.com/v1/check/terraform",
+                "terraform-checkpoint.json",
+            )
+            .await?;
+            let version = terraform_version(&fs::read_to_string("terraform-checkpoint.json")?)?;
+            fs::remove_file("terraform-checkpoint.json")?;
+            let arch = match std::env::consts::ARCH {
+                "aarch64" => "arm64",
+                _ => "amd64",
+            };
+            system::download_file(
+                self.config,
+                &format!("https://releases.hashicorp.com/terraform/{version}/terraform_{version}_linux_{arch}.zip"),
+                "terraform.zip",
+            )
+            .await?;
+            system::extract_zip(
+                Path::new("terraform.zip"),
+                Path::new("/usr/local/bin"),
+                false,
+            )?;
+            unix::recursively_chmod("/usr/local/bin/terraform", &0o755, &0o755)?;
+            fs::remove_file("terraform.zip")?;
+        }
+        Ok(())
+    }
+
+    async fn install_themes(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Themes")
+    }
+
+    fn install_tlp(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("tlp")? {
+            self.install_application("tlp")?;
+        }
+        self.enable_service("tlp")?;
+        Ok(())
+    }
+
+    fn install_tmux(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("tmux")? {
+            self.install_application("tmux")?;
+        }
+        unix::setup_tmux(self, self.config)?;
+        Ok(())
+    }
+
+    fn install_vim(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("vim")? {
+            self.install_application("vim")?;
+        }
+        Ok(())
+    }
+
+    async fn install_vlc(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("VLC")
+    }
+
+    fn install_vm_tools(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("VM tools")
+    }
+
+    async fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("VSCode")
+    }
+
+    async fn install_wifi(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("iwd")? {
+            self.install_application("iwd")?;
+        }
+        self.enable_service("iwd")?;
+        Ok(())
+    }
+
+    fn install_window_manager(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Window manager")
+    }
+
+    fn install_wget(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("wget")? {
+            self.install_application("wget")?;
+        }
+        Ok(())
+    }
+
+    fn install_whatsapp(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("WhatsApp")
+    }
+
+    fn install_whipper(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Whipper")
+    }
+
+    fn install_wine(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Wine")
+    }
+
+    async fn install_xbox_streaming(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Xbox streaming")
+    }
+
+    fn install_xcode(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    async fn install_zsh(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("zsh")? {
+            self.install_application("zsh")?;
+        }
+        if !self.is_installed("zsh-vcs")? {
+            self.install_application("zsh-vcs")?;
+        }
+        // oh-my-zsh clones itself with git
+        if !self.is_installed("git")? {
+            self.install_application("git")?;
+        }
+        unix::setup_zsh(self, self.config, Some("/bin/zsh")).await?;
+        Ok(())
+    }
+
+    async fn rank_mirrors(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Mirror ranking")
+    }
+
+    fn is_applied(&self, change: &Change) -> Result<bool, Box<dyn Error>> {
+        match change {
+            Change::Package { name } => self.is_installed(name),
+            Change::Service { name } => {
+                Ok(unix::execute("rc-update show default", true, false, false)?
+                    .lines()
+                    .any(|line| line.split_whitespace().next() == Some(name)))
+            }
+            _ => linux::is_applied(self, change),
+        }
+    }
+
+    fn list_installed(&self) -> Result<Packages, Box<dyn Error>> {
+        Ok(Packages {
+            aur: Vec::new(),
+            classic_snaps: Vec::new(),
+            flatpaks: linux::flatpak::list_all_applications(self)?,
+            packages: fs::read_to_string("/etc/apk/world")?
+                .split_whitespace()
+                .map(|package| package.to_string())
+                .collect(),
+            snaps: Vec::new(),
+        })
+    }
+
+    fn revert(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        match change {
+            Change::Package { name } => {
+                self.execute(&format!("apk del {name}"), true)?;
+            }
+            Change::Service { name } => {
+                self.execute(&format!("rc-update del {name} default"), true)?;
+            }
+            _ => linux::revert(self, change)?,
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, run: Option<u32>) -> Result<(), Box<dyn Error>> {
+        linux::snapshot::rollback(self, run)
+    }
+
+    fn set_development_shortcuts(&self) -> Result<(), Box<dyn Error>> {
+        self.not_supported("Development shortcuts")
+    }
+
+    fn set_development_environment_settings(&self) -> Result<(), Box<dyn Error>> {
+        linux::set_development_environment_settings()?;
+        Ok(())
+    }
+
+    fn setup_nas(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("cifs-utils")? {
+            self.install_application("cifs-utils")?;
+        }
+        linux::setup_nas(self, self.config.dry_run)?;
+        Ok(())
+    }
+
+    fn setup_power_saving_tweaks(&self) -> Result<(), Box<dyn Error>> {
+        linux::setup_power_saving_tweaks()?;
+        Ok(())
+    }
+
+    fn setup_user_bin(&self) -> Result<(), Box<dyn Error>> {
+        unix::setup_user_bin(self)?;
+        Ok(())
+    }
+
+    fn update_os(&self) -> Result<(), Box<dyn Error>> {
+        self.update_os_repo()?;
+        self.execute("apk upgrade --available", true)?;
+        Ok(())
+    }
+
+    fn update_os_repo(&self) -> Result<(), Box<dyn Error>> {
+        self.execute("apk update", true)?;
+        Ok(())
+    }
+}
+
+/// Returns the latest Terraform version from HashiCorp's checkpoint response.
+fn terraform_version(checkpoint: &str) -> Result<String, Box<dyn Error>> {
+    let checkpoint: serde_json::Value = serde_json::from_str(checkpoint)?;
+    checkpoint["current_version"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| error::Error::new("Unable to determine the Terraform version").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terraform_version_reads_the_current_version() {
+        let checkpoint = r#"{"product":"terraform","current_version":"1.9.8","alerts":[]}"#;
+
+        assert_eq!(terraform_version(checkpoint).unwrap(), "1.9.8");
+        assert!(terraform_version(r#"{"product":"terraform"}"#).is_err());
+    }
+}
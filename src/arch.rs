@@ -1,17 +1,29 @@
 use async_trait::async_trait;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
 use std::error::Error;
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::path::PathBuf;
 use tokio::io::{stdin, stdout, AsyncWriteExt};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, LinesCodec};
 
+use crate::bundle::{self, Bundle};
+use crate::certificates::Certificate;
 use crate::config::Config;
-use crate::system::{download_file, System};
-use crate::{linux, system, unix};
+use crate::linux::flatpak::{self, Installation, Override};
+use crate::profile::Packages;
+use crate::state::{self, Change};
+use crate::system::System;
+use crate::{backup, linux, managed_block, system, unix};
+use pacman_conf::{PacmanConf, PACMAN_CONF};
+
+mod aur;
+mod local_repository;
+mod mirrorlist;
+mod pacman_conf;
+mod review;
 
 #[derive(Debug)]
 pub(crate) struct Arch<'s> {
@@ -20,6 +32,17 @@ pub(crate) struct Arch<'s> {
 
 static JAVA_HOME: &str = "/usr/lib/jvm/default";
 
+/// Creates a package database with nothing installed but the same sync databases as the system,
+/// so downloading packages into a bundle also fetches the dependencies this machine already has.
+fn empty_database() -> Result<tempfile::TempDir, Box<dyn Error>> {
+    let database = tempfile::Builder::new()
+        .prefix("dotfiles-pacman-")
+        .tempdir()?;
+    fs::create_dir(database.path().join("local"))?;
+    std::os::unix::fs::symlink("/var/lib/pacman/sync", database.path().join("sync"))?;
+    Ok(database)
+}
+
 impl<'s> Arch<'s> {
     pub(crate) fn new(config: &'s Config) -> Self {
         Arch { config }
@@ -30,20 +53,28 @@ impl<'s> Arch<'s> {
     }
 
     fn aur_install_applications(&self, applications: Vec<&str>) -> Result<String, Box<dyn Error>> {
-        self.execute(
-            &format!("yay -S --noconfirm --needed {}", applications.join(" ")),
-            false,
-        )
+        let before = self.installed_packages()?;
+        let output = aur::install_applications(self, self.config, applications)?;
+        self.record_new_packages(&before)?;
+        Ok(output)
     }
 
     fn enable_kernel_module(&self, module: &str) -> Result<(), Box<dyn Error>> {
-        linux::add_kernel_module(module)?;
+        linux::add_kernel_module(self, module)?;
         self.execute("mkinitcpio -p linux", true)?;
         Ok(())
     }
 
     fn enable_service(&self, service: &str) -> Result<String, Box<dyn Error>> {
-        self.execute(&format!("systemctl enable {service}"), true)
+        if linux::is_service_enabled(service)? {
+            debug!("{} is already enabled.", service);
+            return Ok(String::new());
+        }
+        let output = self.execute(&format!("systemctl enable {service}"), true)?;
+        state::record(Change::Service {
+            name: service.to_string(),
+        });
+        Ok(output)
     }
 
     fn install_hunspell(&self) -> Result<(), Box<dyn Error>> {
@@ -56,7 +87,21 @@ impl<'s> Arch<'s> {
         Ok(())
     }
 
+    /// Lists the names of every installed package, explicitly or as a dependency.
+    fn installed_packages(&self) -> Result<HashSet<String>, Box<dyn Error>> {
+        Ok(self
+            .query("pacman -Qq")?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect())
+    }
+
     fn is_installed(&self, app: &str) -> Result<bool, Box<dyn Error>> {
+        if let Some(Bundle::Creating(_)) = bundle::of(self.config) {
+            // Everything gets fetched into the bundle, whatever this machine already has.
+            return Ok(false);
+        }
         let output = unix::execute(&format!("pacman -Qi {app}"), false, false, false);
         if !output?.ends_with("was not found") {
             debug!("{} is already installed.", app);
@@ -66,9 +111,22 @@ impl<'s> Arch<'s> {
         Ok(false)
     }
 
+    /// Records the packages installed since `before`, so those the system already had, or that
+    /// pacman skipped as up to date, aren't removed by an uninstall.
+    fn record_new_packages(&self, before: &HashSet<String>) -> Result<(), Box<dyn Error>> {
+        let after = self.installed_packages()?;
+        let mut installed = after
+            .difference(before)
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        installed.sort();
+        state::record_packages(&installed);
+        Ok(())
+    }
+
     fn remote_install(&self, url: &str) -> Result<bool, Box<dyn Error>> {
         debug!("Downloading and installing {}", url);
-        match unix::execute(&format!("pacman -U {url}"), false, false, false) {
+        match self.run("pacman", vec!["-U", "--noconfirm", url], true) {
             Ok(_) => {
                 debug!("Installed {} successfully.", url);
                 Ok(true)
@@ -80,6 +138,10 @@ impl<'s> Arch<'s> {
 
 #[async_trait]
 impl<'s> System for Arch<'s> {
+    fn create_snapshot(&self, run: u32) -> Result<(), Box<dyn Error>> {
+        linux::snapshot::create(self, run)
+    }
+
     fn execute(&self, command: &str, super_user: bool) -> Result<String, Box<dyn Error>> {
         unix::execute(command, super_user, true, self.config.dry_run)
     }
@@ -88,11 +150,47 @@ impl<'s> System for Arch<'s> {
         linux::get_home_dir()
     }
 
+    fn query(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        unix::execute(command, true, false, false)
+    }
+
+    fn run(
+        &self,
+        program: &str,
+        args: Vec<&str>,
+        super_user: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        unix::run(program, &args, super_user, self.config.dry_run)
+    }
+
     fn install_applications(&self, application: Vec<&str>) -> Result<String, Box<dyn Error>> {
-        self.execute(
-            &format!("pacman -S --noconfirm --needed {}", application.join(" ")),
-            true,
-        )
+        let packages = application.join(" ");
+        let before = self.installed_packages()?;
+        let output = match bundle::of(self.config) {
+            Some(bundle @ Bundle::Creating(_)) => {
+                let database = empty_database()?;
+                unix::execute(
+                    &format!(
+                        "pacman -Sw --noconfirm --cachedir {} --dbpath {} {packages}",
+                        bundle.packages(),
+                        database.path().display()
+                    ),
+                    true,
+                    true,
+                    false,
+                )?
+            }
+            Some(bundle @ Bundle::InstallingFrom(_)) => self.execute(
+                &format!(
+                    "pacman -S --noconfirm --needed --cachedir {} {packages}",
+                    bundle.packages()
+                ),
+                true,
+            )?,
+            None => self.execute(&format!("pacman -S --noconfirm --needed {packages}"), true)?,
+        };
+        self.record_new_packages(&before)?;
+        Ok(output)
     }
 
     fn install_affinity_suite(&self) -> Result<(), Box<dyn Error>> {
@@ -117,6 +215,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_audacity(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.audacityteam.Audacity")? {
+            return Ok(());
+        }
         if !self.is_installed("audacity")? {
             self.install_application("audacity")?;
         }
@@ -132,6 +233,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_bambu_studio(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.bambulab.BambuStudio")? {
+            return Ok(());
+        }
         if !self.is_installed("bambustudio-bin")? {
             self.aur_install_application("bambustudio-bin")?;
         }
@@ -139,11 +243,14 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_bash(&self) -> Result<(), Box<dyn Error>> {
-        unix::setup_bash(self)?;
+        unix::setup_bash(self, self.config)?;
         Ok(())
     }
 
     fn install_blender(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.blender.Blender")? {
+            return Ok(());
+        }
         if !self.is_installed("blender")? {
             self.install_application("blender")?;
         }
@@ -166,12 +273,24 @@ impl<'s> System for Arch<'s> {
 
     // TODO: Create `CALIBRE_OVERRIDE_DATABASE_PATH=~/calibre` setup, so books can be added from a network share
     fn install_calibre(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.calibre_ebook.calibre")? {
+            return Ok(());
+        }
         if !self.is_installed("calibre")? {
             self.install_application("calibre")?;
         }
         Ok(())
     }
 
+    fn install_certificate(&self, certificate: &Certificate) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("nss")? {
+            self.install_application("nss")?;
+        }
+        self.execute(&format!("trust anchor --store {}", certificate.file), true)?;
+        linux::add_certificate_to_nss(self, certificate)?;
+        Ok(())
+    }
+
     async fn install_codecs(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("libdvdread")? {
             debug!("Installing libdvdread");
@@ -233,7 +352,7 @@ impl<'s> System for Arch<'s> {
             debug!("Installing flac");
             self.install_application("flac")?;
         }
-        system::setup_codecs(self).await?;
+        system::setup_codecs(self, self.config).await?;
         let user_id = unix::get_user_id();
         let group_id = unix::get_group_id();
         unix::recursively_chown(
@@ -258,6 +377,9 @@ impl<'s> System for Arch<'s> {
     }
 
     async fn install_cryptomator(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.cryptomator.Cryptomator")? {
+            return Ok(());
+        }
         // Required as a dependency for cryptomator
         self.install_jdk()?;
         if !self.is_installed("cryptomator")? {
@@ -285,16 +407,23 @@ impl<'s> System for Arch<'s> {
             self.remote_install("https://archive.archlinux.org/packages/q/qt5-webengine/qt5-webengine-5.15.9-3-x86_64.pkg.tar.zst")?;
             // self.aur_install_application("qt5-webengine")?;
         }
+        if !self.is_installed("davinci-resolve-studio")?
+            && local_repository::contains(self.config, "davinci-resolve-studio")?
+        {
+            self.install_application("davinci-resolve-studio")?;
+        }
         if !self.is_installed("davinci-resolve-studio")? {
             let aur_dir = format!("{}/Downloads", self.get_home_dir());
             debug!("Creating {}", aur_dir);
-            fs::create_dir_all(&aur_dir)?;
-            download_file(
-                "https://aur.archlinux.org/cgit/aur.git/snapshot/davinci-resolve-studio.tar.gz",
-                "davinci-resolve-studio.tar.gz",
-            )
-            .await?;
-            linux::untar_rename_root("davinci-resolve-studio.tar.gz", "davinci-resolve-studio")?;
+            system::create_dir(&aur_dir)?;
+            let build_dir = aur::fetch(self, self.config, "davinci-resolve-studio")?;
+            if self.config.dry_run {
+                info!(
+                    "Would ask for the studio zip file in {} to build it",
+                    aur_dir
+                );
+                return linux::setup_davinci_resolve(self, self.config);
+            }
 
             info!("Download the studio zip file and put into {}", aur_dir);
             open::that("https://www.blackmagicdesign.com/uk/products/davinciresolve/studio")?;
@@ -343,7 +472,8 @@ impl<'s> System for Arch<'s> {
                 }
             }
             let to = format!(
-                "davinci-resolve-studio/{}",
+                "{}/{}",
+                build_dir,
                 expected_zip.file_name().unwrap().to_str().unwrap()
             );
             debug!("Copying {} to {}", expected_zip.to_str().unwrap(), to);
@@ -357,28 +487,17 @@ impl<'s> System for Arch<'s> {
             }
             let user_id = unix::get_user_id();
             let group_id = unix::get_group_id();
-            unix::recursively_chown("davinci-resolve-studio", &user_id, &group_id)?;
-            unix::execute_path(
-                "makepkg -si --noconfirm",
-                false,
-                &format!(
-                    "{}/davinci-resolve-studio",
-                    std::env::current_dir()
-                        .unwrap()
-                        .into_os_string()
-                        .into_string()
-                        .unwrap()
-                ),
-                true,
-                self.config.dry_run,
-            )?;
-            fs::remove_dir_all(format!("{}/davinci-resolve-studio", aur_dir))?;
+            unix::recursively_chown(&build_dir, &user_id, &group_id)?;
+            aur::build(self, self.config, "davinci-resolve-studio")?;
         }
-        linux::setup_davinci_resolve(self)?;
+        linux::setup_davinci_resolve(self, self.config)?;
         Ok(())
     }
 
     fn install_discord(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.discordapp.Discord")? {
+            return Ok(());
+        }
         if !self.is_installed("discord")? {
             self.install_application("discord")?;
         }
@@ -417,17 +536,19 @@ impl<'s> System for Arch<'s> {
         if !self.is_installed("eclipse-jee")? {
             self.aur_install_application("eclipse-jee")?;
         }
-        if Path::new("/opt/eclipse").exists() {
-            fs::create_dir_all("/opt/eclipse")?;
-        }
+        system::create_dir("/opt/eclipse")?;
 
         system::download_file(
+            self.config,
             "https://projectlombok.org/downloads/lombok.jar",
             "/opt/eclipse/lombok.jar",
         )
         .await?;
 
-        system::add_to_file(
+        // Every line of eclipse.ini is an argument, so the markers earlier versions wrote around
+        // the agent are taken off.
+        managed_block::remove("/opt/eclipse/eclipse.ini", "lombok")?;
+        system::add_line_to_file(
             "/opt/eclipse/eclipse.ini",
             "-javaagent:/opt/eclipse/lombok.jar",
         )?;
@@ -435,6 +556,9 @@ impl<'s> System for Arch<'s> {
     }
 
     async fn install_epic_games(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.heroicgameslauncher.hgl")? {
+            return Ok(());
+        }
         if !self.is_installed("heroic-games-launcher-bin")? {
             self.aur_install_application("heroic-games-launcher-bin")?;
         }
@@ -445,6 +569,25 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
+    fn install_extras(&self, extras: &Packages) -> Result<(), Box<dyn Error>> {
+        if !extras.packages.is_empty() {
+            self.install_applications(extras.packages.iter().map(String::as_str).collect())?;
+        }
+        if !extras.aur.is_empty() {
+            self.aur_install_applications(extras.aur.iter().map(String::as_str).collect())?;
+        }
+        linux::install_flatpak_extras(self, self.config, extras)?;
+        if !extras.snaps.is_empty() || !extras.classic_snaps.is_empty() {
+            warn!(
+                "Not installing the snaps {}, as snaps aren't supported on Arch",
+                [extras.snaps.as_slice(), extras.classic_snaps.as_slice()]
+                    .concat()
+                    .join(", ")
+            );
+        }
+        Ok(())
+    }
+
     async fn install_exercism(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("exercism-bin")? {
             self.aur_install_application("exercism-bin")?;
@@ -453,10 +596,23 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_firefox(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.mozilla.firefox")? {
+            flatpak::override_application(
+                self,
+                Installation::from(self.config),
+                "org.mozilla.firefox",
+                vec![
+                    Override::Socket("wayland".to_string()),
+                    Override::Environment("MOZ_ENABLE_WAYLAND".to_string(), "1".to_string()),
+                ],
+            )?;
+            return Ok(());
+        }
         if !self.is_installed("firefox")? {
             self.install_application("firefox")?;
             system::add_to_file(
                 &format!("{}/.config/environment.d/envvars.conf", self.get_home_dir()),
+                "firefox-wayland",
                 "MOZ_ENABLE_WAYLAND=1",
             )?;
         }
@@ -471,6 +627,13 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
+    fn install_fish(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("fish")? {
+            self.install_application("fish")?;
+        }
+        unix::setup_fish(self, self.config)
+    }
+
     fn install_git(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("git")? {
             self.install_application("git")?;
@@ -480,6 +643,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_gimp(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.gimp.GIMP")? {
+            return Ok(());
+        }
         if !self.is_installed("gimp")? {
             self.install_application("gimp")?;
         }
@@ -499,6 +665,9 @@ impl<'s> System for Arch<'s> {
     }
 
     async fn install_google_chrome(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.google.Chrome")? {
+            return Ok(());
+        }
         if !self.is_installed("google-chrome")? {
             self.aur_install_application("google-chrome")?;
             info!("To enable screen sharing, you will need to enable `enable-webrtc-pipewire-catpturer` chrome://flags/#enable-webrtc-pipewire-capturer")
@@ -506,7 +675,7 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
-    fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("google-cloud-sdk")? {
             self.aur_install_application("google-cloud-sdk")?;
         }
@@ -535,13 +704,16 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_gramps(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.gramps_project.Gramps")? {
+            return Ok(());
+        }
         if !self.is_installed("gramps")? {
             self.install_application("gramps")?;
         }
         Ok(())
     }
 
-    fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("groovy")? {
             self.install_application("groovy")?;
         }
@@ -549,6 +721,15 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_handbrake(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "fr.handbrake.ghb")? {
+            flatpak::override_application(
+                self,
+                Installation::from(self.config),
+                "fr.handbrake.ghb",
+                vec![Override::Filesystem("xdg-videos".to_string())],
+            )?;
+            return Ok(());
+        }
         if !self.is_installed("handbrake")? {
             self.install_application("handbrake")?;
         }
@@ -556,6 +737,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_inkscape(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.inkscape.Inkscape")? {
+            return Ok(());
+        }
         if !self.is_installed("inkscape")? {
             self.install_application("inkscape")?;
         }
@@ -596,14 +780,15 @@ impl<'s> System for Arch<'s> {
         if !self.is_installed("jdk-openjdk")? {
             self.install_application("jdk-openjdk")?;
         }
-        unix::set_java_home(self, ".zshrc", JAVA_HOME)?;
-        unix::set_java_home(self, ".bashrc", JAVA_HOME)?;
-        unix::add_to_path(self, ".zshrc", "$JAVA_HOME/bin")?;
-        unix::add_to_path(self, ".bashrc", "$JAVA_HOME/bin")?;
+        unix::set_java_home(self, JAVA_HOME)?;
+        unix::add_to_path(self, "$JAVA_HOME/bin")?;
         Ok(())
     }
 
     fn install_keepassxc(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.keepassxc.KeePassXC")? {
+            return Ok(());
+        }
         if !self.is_installed("keepassxc")? {
             self.install_application("keepassxc")?;
         }
@@ -639,6 +824,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_office(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.libreoffice.LibreOffice")? {
+            return Ok(());
+        }
         if !self.is_installed("libreoffice-fresh")? {
             self.install_application("libreoffice-fresh")?;
         }
@@ -652,7 +840,10 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
-    fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.openscad.OpenSCAD")? {
+            return Ok(());
+        }
         if !self.is_installed("openscad-git")? {
             self.aur_install_application("openscad-git")?;
         }
@@ -660,6 +851,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_lutris(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "net.lutris.Lutris")? {
+            return Ok(());
+        }
         if !self.is_installed("lutris")? {
             self.install_application("lutris")?;
         }
@@ -699,7 +893,10 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
-    fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.microsoft.Edge")? {
+            return Ok(());
+        }
         if !self.is_installed("microsoft-edge-stable-bin")? {
             self.aur_install_application("microsoft-edge-stable-bin")?;
         }
@@ -707,6 +904,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_mkvtoolnix(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.bunkus.mkvtoolnix-gui")? {
+            return Ok(());
+        }
         if !self.is_installed("mkvtoolnix-gui")? {
             self.install_application("mkvtoolnix-gui")?;
         }
@@ -817,6 +1017,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_obs_studio(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.obsproject.Studio")? {
+            return Ok(());
+        }
         if !self.is_installed("obs-studio")? {
             self.install_application("obs-studio")?;
         }
@@ -879,6 +1082,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_retroarch(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.libretro.RetroArch")? {
+            return Ok(());
+        }
         if !self.is_installed("retroarch")? {
             self.install_application("retroarch")?;
         }
@@ -928,13 +1134,19 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_slack(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.slack.Slack")? {
+            return Ok(());
+        }
         if !self.is_installed("slack-desktop")? {
             self.aur_install_application("slack-desktop")?;
         }
         Ok(())
     }
 
-    fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.spotify.Client")? {
+            return Ok(());
+        }
         if !self.is_installed("spotify")? {
             self.aur_install_application("spotify")?;
         }
@@ -942,6 +1154,9 @@ impl<'s> System for Arch<'s> {
     }
 
     fn install_steam(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.valvesoftware.Steam")? {
+            return Ok(());
+        }
         if !self.is_installed("steam")? {
             self.install_application("steam")?;
         }
@@ -954,11 +1169,7 @@ impl<'s> System for Arch<'s> {
         }
 
         let sweet_home_3d_desktop = "/usr/share/applications/sweethome3d.desktop";
-        let mut sweet_home_3d_desktop_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(sweet_home_3d_desktop)?;
+        let mut sweet_home_3d_desktop_file = system::create_file(self, sweet_home_3d_desktop)?;
 
         let content = "[Desktop Entry]\n\
             Version=1.0\n\
@@ -972,6 +1183,7 @@ impl<'s> System for Arch<'s> {
             StartupWMClass=com-eteks-sweethome3d-SweetHome3D\n\
             MimeType=application/x-sweethome3d\n";
         write!(sweet_home_3d_desktop_file, "{content}")?;
+        state::record_file(sweet_home_3d_desktop);
 
         Ok(())
     }
@@ -991,62 +1203,26 @@ impl<'s> System for Arch<'s> {
             self.install_application("base-devel")?;
         }
 
-        let original_pacman_file = File::open("/etc/pacman.conf")?;
-        let original_lines = BufReader::new(original_pacman_file).lines();
-        let mut enable_multilib = false;
-        let mut new_lines = original_lines
-            .map(|line| {
-                let unwrapped_line = line.unwrap();
-                if unwrapped_line.starts_with("#[multilib]") {
-                    // Crude way to signify that we are under the multilib section
-                    enable_multilib = true;
-                    unwrapped_line.replacen('#', "", 1)
-                } else if enable_multilib
-                    && unwrapped_line.starts_with("#Include = /etc/pacman.d/mirrorlist")
-                {
-                    enable_multilib = false;
-                    unwrapped_line.replacen('#', "", 1)
-                } else {
-                    unwrapped_line
-                }
-            })
-            .collect::<Vec<String>>();
-
-        if !new_lines.contains(&"[multilib]".to_string()) {
-            new_lines.push("[multilib]".to_string());
-            new_lines.push("Include = /etc/pacman.d/mirrorlist".to_string());
+        let mut pacman_conf = PacmanConf::load(PACMAN_CONF)?;
+        if !pacman_conf.enable_repository("multilib") {
+            pacman_conf.add_repository("multilib", vec![("Include", "/etc/pacman.d/mirrorlist")]);
         }
-
-        let mut new_pacman_file = OpenOptions::new().write(true).open("/etc/pacman.conf")?;
-        new_pacman_file.write_all(new_lines.join("\n").as_bytes())?;
+        if let Some(local_repo) = &self.config.local_repo {
+            local_repository::create(self, local_repo)?;
+            local_repository::verify(self.config, local_repo)?;
+            local_repository::register(&mut pacman_conf, local_repo);
+        }
+        if let Some(profile) = &self.config.profile {
+            pacman_conf.apply(&profile.pacman);
+        }
+        if pacman_conf != PacmanConf::load(PACMAN_CONF)? {
+            backup::back_up(self, PACMAN_CONF)?;
+        }
+        pacman_conf.update(PACMAN_CONF, self.config.dry_run)?;
 
         self.update_os_repo()?;
 
-        if !self.is_installed("yay")? {
-            system::download_file(
-                "https://aur.archlinux.org/cgit/aur.git/snapshot/yay.tar.gz",
-                "yay.tar.gz",
-            )
-            .await?;
-            linux::untar_rename_root("yay.tar.gz", "yay")?;
-            let user_id = unix::get_user_id();
-            let group_id = unix::get_group_id();
-            unix::recursively_chown("yay", &user_id, &group_id)?;
-            unix::execute_path(
-                "makepkg -si --noconfirm",
-                false,
-                &format!(
-                    "{}/yay",
-                    std::env::current_dir()
-                        .unwrap()
-                        .into_os_string()
-                        .into_string()
-                        .unwrap()
-                ),
-                true,
-                self.config.dry_run,
-            )?;
-        }
+        aur::bootstrap(self, self.config)?;
         if !self.is_installed("wget")? {
             self.install_application("wget")?;
         }
@@ -1105,15 +1281,14 @@ impl<'s> System for Arch<'s> {
             }
             s
         }).collect::<String>();
-        let mut file = OpenOptions::new()
-            .create(false)
-            .write(true)
-            .truncate(true)
-            .open("/etc/nsswitch.conf")?;
+        let mut file = system::create_file(self, "/etc/nsswitch.conf")?;
         file.write_all(new_contents.as_bytes())?;
         if !self.is_installed("speech-dispatcher")? {
             self.install_application("speech-dispatcher")?;
         }
+        if self.config.prefer_flatpak && !self.is_installed("flatpak")? {
+            self.install_application("flatpak")?;
+        }
         unix::add_user_to_group("optical", self.config.dry_run)?;
         Ok(())
     }
@@ -1130,7 +1305,7 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
-    fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("terraform")? {
             self.install_application("terraform")?;
         }
@@ -1138,7 +1313,7 @@ impl<'s> System for Arch<'s> {
     }
 
     async fn install_themes(&self) -> Result<(), Box<dyn Error>> {
-        fs::create_dir_all(format!("{}/.themes", self.get_home_dir()))?;
+        system::create_dir(&format!("{}/.themes", self.get_home_dir()))?;
         let user_id = unix::get_user_id();
         let group_id = unix::get_group_id();
         unix::recursively_chown(
@@ -1147,7 +1322,7 @@ impl<'s> System for Arch<'s> {
             &group_id,
         )?;
         if self.config.gnome {
-            linux::gtk_theme(self)?;
+            linux::gtk_theme(self, self.config)?;
         }
         Ok(())
     }
@@ -1170,7 +1345,7 @@ impl<'s> System for Arch<'s> {
         if !self.is_installed("tmux-bash-completion")? {
             self.aur_install_application("tmux-bash-completion")?;
         }
-        linux::setup_tmux(self)?;
+        linux::setup_tmux(self, self.config)?;
         Ok(())
     }
 
@@ -1182,6 +1357,9 @@ impl<'s> System for Arch<'s> {
     }
 
     async fn install_vlc(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.videolan.VLC")? {
+            return Ok(());
+        }
         if !self.is_installed("vlc")? {
             self.install_application("vlc")?;
         }
@@ -1198,8 +1376,10 @@ impl<'s> System for Arch<'s> {
         Ok(())
     }
 
-    fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
-        if !self.is_installed("visual-studio-code-bin")? {
+    async fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
+        if !flatpak::install_if_preferred(self, self.config, "com.visualstudio.code")?
+            && !self.is_installed("visual-studio-code-bin")?
+        {
             self.aur_install_application("visual-studio-code-bin")?;
         }
         self.install_hunspell()?;
@@ -1219,7 +1399,7 @@ impl<'s> System for Arch<'s> {
                 self.install_application("gnome-tweaks")?;
             }
             if !self.is_installed("xdg-desktop-portal-gnome")? {
-                self.install_application("xdg-desktop-portal-gnome ")?;
+                self.install_application("xdg-desktop-portal-gnome")?;
             }
             if !self.is_installed("libcanbera")? {
                 self.install_application("libcanberra")?;
@@ -1293,12 +1473,8 @@ impl<'s> System for Arch<'s> {
                 self.install_application("xdg-desktop-portal-kde")?;
             }
             let parent_dir = format!("{}/.config/plasma-workspace/env", self.get_home_dir());
-            fs::create_dir_all(&parent_dir)?;
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(format!("{}/gtk.sh", parent_dir))?;
+            system::create_dir(&parent_dir)?;
+            let mut file = system::create_file(self, &format!("{}/gtk.sh", parent_dir))?;
             writeln!(file, "export GTK_USE_PORTAL=1")?;
         }
         self.enable_service("NetworkManager")?;
@@ -1323,14 +1499,10 @@ impl<'s> System for Arch<'s> {
         if !self.is_installed("python-pillow")? {
             self.install_application("python-pillow")?;
         }
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(format!(
-                "{}/.config/whipper/whipper.conf",
-                self.get_home_dir()
-            ))?;
+        let mut file = system::create_file(
+            self,
+            &format!("{}/.config/whipper/whipper.conf", self.get_home_dir()),
+        )?;
         writeln!(file, "[whipper.cd.rip]")?;
         writeln!(file, "output_directory = ~/Music")?;
         writeln!(file, "track_template = %%A/%%d/%%t %%n")?;
@@ -1347,6 +1519,9 @@ impl<'s> System for Arch<'s> {
     }
 
     async fn install_xbox_streaming(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "io.github.unknownskl.greenlight")? {
+            return Ok(());
+        }
         if !self.is_installed("greenlight-bin")? {
             self.aur_install_application("greenlight-bin")?;
         }
@@ -1364,10 +1539,56 @@ impl<'s> System for Arch<'s> {
         if !self.is_installed("zsh-completion")? {
             self.install_application("zsh-completions")?;
         }
-        unix::setup_zsh(self, None).await?;
+        unix::setup_zsh(self, self.config, None).await?;
+        Ok(())
+    }
+
+    async fn rank_mirrors(&self) -> Result<(), Box<dyn Error>> {
+        mirrorlist::rank(self, self.config).await
+    }
+
+    fn is_applied(&self, change: &Change) -> Result<bool, Box<dyn Error>> {
+        match change {
+            Change::Package { name } => self.is_installed(name),
+            Change::Service { name } => linux::is_service_enabled(name),
+            _ => linux::is_applied(self, change),
+        }
+    }
+
+    fn list_installed(&self) -> Result<Packages, Box<dyn Error>> {
+        let query = |command: &str| -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(unix::execute(command, true, false, false)?
+                .lines()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect())
+        };
+        Ok(Packages {
+            aur: query("pacman -Qqem")?,
+            classic_snaps: Vec::new(),
+            flatpaks: flatpak::list_all_applications(self)?,
+            packages: query("pacman -Qqen")?,
+            snaps: Vec::new(),
+        })
+    }
+
+    fn revert(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        match change {
+            Change::Package { name } => {
+                self.execute(&format!("pacman -Rns --noconfirm {name}"), true)?;
+            }
+            Change::Service { name } => {
+                self.execute(&format!("systemctl disable --now {name}"), true)?;
+            }
+            _ => linux::revert(self, change)?,
+        }
         Ok(())
     }
 
+    fn rollback(&self, run: Option<u32>) -> Result<(), Box<dyn Error>> {
+        linux::snapshot::rollback(self, run)
+    }
+
     fn set_development_shortcuts(&self) -> Result<(), Box<dyn Error>> {
         if self.config.gnome {
             linux::gnome_development_shortcuts(self)?;
@@ -1397,12 +1618,38 @@ impl<'s> System for Arch<'s> {
 
     fn update_os(&self) -> Result<(), Box<dyn Error>> {
         self.update_os_repo()?;
-        self.execute("pacman -Syu --noconfirm", true)?;
+        match bundle::of(self.config) {
+            Some(bundle @ Bundle::Creating(_)) => unix::execute(
+                &format!("pacman -Suw --noconfirm --cachedir {}", bundle.packages()),
+                true,
+                true,
+                false,
+            )?,
+            Some(bundle @ Bundle::InstallingFrom(_)) => self.execute(
+                &format!("pacman -Su --noconfirm --cachedir {}", bundle.packages()),
+                true,
+            )?,
+            None => self.execute("pacman -Syu --noconfirm", true)?,
+        };
+        if self.config.prefer_flatpak {
+            flatpak::update_applications(self, Installation::from(self.config))?;
+        }
         Ok(())
     }
 
     fn update_os_repo(&self) -> Result<(), Box<dyn Error>> {
-        self.execute("pacman -Sy", true)?;
+        match bundle::of(self.config) {
+            // The packages are fetched against the synced databases, so they're synced for real.
+            Some(Bundle::Creating(_)) => {
+                unix::execute("pacman -Sy", true, true, false)?;
+            }
+            Some(bundle @ Bundle::InstallingFrom(_)) => {
+                bundle.restore_package_lists("/var/lib/pacman/sync")?;
+            }
+            None => {
+                self.execute("pacman -Sy", true)?;
+            }
+        }
         Ok(())
     }
 }
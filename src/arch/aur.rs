@@ -0,0 +1,683 @@
+use log::{debug, info};
+use std::error::Error;
+use std::fs;
+
+use super::{local_repository, review};
+use crate::bundle::{self, Bundle};
+use crate::config::{AurHelper, Config};
+use crate::system::System;
+use crate::{error, unix};
+
+static AUR_URL: &str = "https://aur.archlinux.org";
+
+type Dependencies<'d> = &'d mut dyn FnMut(&str) -> Result<Vec<String>, Box<dyn Error>>;
+
+type IsAur<'a> = &'a dyn Fn(&str) -> Result<bool, Box<dyn Error>>;
+
+/// Installs the configured AUR helper, if it isn't already installed.
+///
+/// Nothing needs installing when building directly with `makepkg`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::aur;
+///
+/// aur::bootstrap(&system, &config)?;
+/// ```
+pub(crate) fn bootstrap(system: &dyn System, config: &Config) -> Result<(), Box<dyn Error>> {
+    let package = match config.aur_helper {
+        AurHelper::Makepkg => return Ok(()),
+        AurHelper::Paru => "paru-bin",
+        AurHelper::Yay => "yay-bin",
+    };
+    if is_satisfied(system, config, helper_command(config.aur_helper))? {
+        debug!("{} is already installed.", package);
+        return Ok(());
+    }
+    if local_repository::contains(config, package)? {
+        system.install_application(package)?;
+        return Ok(());
+    }
+    info!("Installing {} from the AUR", package);
+    fetch(system, config, package)?;
+    // The helper isn't installed yet, so it's built with makepkg.
+    build_with(system, config, package, AurHelper::Makepkg)
+}
+
+/// Builds the package found in its build directory as the user, with the configured helper or
+/// `makepkg`, installing it and any dependencies from the official repositories, then removes the
+/// build directory.
+///
+/// The package needs to have been fetched first, and its PKGBUILD gets reviewed before building,
+/// so what's built is exactly what was reviewed. When there is a local repository configured, the
+/// built package is added to it.
+///
+/// With a bundle, the package is always built with `makepkg`, as the helpers look packages up in
+/// the AUR. Its sources and dependencies from the official repositories are fetched into the
+/// bundle when creating one, and installed from there when installing from one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::aur;
+///
+/// aur::fetch(&system, &config, "yay-bin")?;
+/// aur::build(&system, &config, "yay-bin")?;
+/// ```
+pub(crate) fn build(
+    system: &dyn System,
+    config: &Config,
+    package: &str,
+) -> Result<(), Box<dyn Error>> {
+    build_with(system, config, package, config.aur_helper)
+}
+
+fn build_with(
+    system: &dyn System,
+    config: &Config,
+    package: &str,
+    helper: AurHelper,
+) -> Result<(), Box<dyn Error>> {
+    let directory = build_directory(system, package);
+    review::approve(system, config, package, &directory)?;
+    match (bundle::of(config), helper) {
+        (Some(bundle), _) => {
+            let srcdest = format!("SRCDEST={}", bundle.sources());
+            if let Bundle::Creating(_) = bundle {
+                // The sources are kept in the bundle even though the rest of the run is a dry run.
+                unix::run(
+                    "env",
+                    &[
+                        &srcdest,
+                        "makepkg",
+                        "-D",
+                        &directory,
+                        "--verifysource",
+                        "--nodeps",
+                    ],
+                    false,
+                    false,
+                )?;
+            }
+            install_repository_dependencies(system, &directory)?;
+            // Holding the version stops makepkg updating VCS sources from the network.
+            system.run(
+                "env",
+                vec![
+                    &srcdest,
+                    "makepkg",
+                    "-D",
+                    &directory,
+                    "-si",
+                    "--noconfirm",
+                    "--needed",
+                    "--holdver",
+                ],
+                false,
+            )?
+        }
+        (None, AurHelper::Makepkg) => system.run(
+            "makepkg",
+            vec!["-D", &directory, "-si", "--noconfirm", "--needed"],
+            false,
+        )?,
+        // Building the reviewed directory stops the helper fetching the package again itself.
+        (None, helper) => system.run(
+            helper_command(helper),
+            vec!["-Bi", "--noconfirm", &directory],
+            false,
+        )?,
+    };
+    local_repository::add(system, config, &directory)?;
+    system.run("rm", vec!["-rf", &directory], false)?;
+    Ok(())
+}
+
+/// Installs the dependencies of the package in the build directory that come from the official
+/// repositories, so with a bundle they come from it instead of `makepkg` downloading them.
+fn install_repository_dependencies(
+    system: &dyn System,
+    directory: &str,
+) -> Result<(), Box<dyn Error>> {
+    let srcinfo = fs::read_to_string(format!("{directory}/.SRCINFO")).unwrap_or_default();
+    let mut dependencies = Vec::new();
+    for dependency in parse_srcinfo(&srcinfo) {
+        if is_in_repositories(system, &dependency)? {
+            dependencies.push(dependency);
+        }
+    }
+    if !dependencies.is_empty() {
+        system.install_applications(dependencies.iter().map(String::as_str).collect())?;
+    }
+    Ok(())
+}
+
+fn build_directory(system: &dyn System, package: &str) -> String {
+    format!("{}/{}", build_root(system), package)
+}
+
+fn build_root(system: &dyn System) -> String {
+    format!("{}/.cache/dotfiles/aur", system.get_home_dir())
+}
+
+/// Returns the names of the packages the package depends on, based on its `.SRCINFO`.
+fn dependencies(
+    system: &dyn System,
+    config: &Config,
+    package: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let directory = fetch(system, config, package)?;
+    match fs::read_to_string(format!("{directory}/.SRCINFO")) {
+        Ok(srcinfo) => Ok(parse_srcinfo(&srcinfo)),
+        Err(e) => {
+            debug!("Unable to read the .SRCINFO for {}: {}", package, e);
+            Ok(vec![])
+        }
+    }
+}
+
+/// Downloads and extracts the AUR snapshot of the package as the user, replacing any previous
+/// copy, returning the directory it was extracted to.
+///
+/// When creating a bundle the snapshot is kept in it, and when installing from one the snapshot
+/// is extracted from there instead of being downloaded.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::aur;
+///
+/// let directory = aur::fetch(&system, &config, "davinci-resolve-studio")?;
+/// ```
+pub(crate) fn fetch(
+    system: &dyn System,
+    config: &Config,
+    package: &str,
+) -> Result<String, Box<dyn Error>> {
+    let root = build_root(system);
+    let directory = build_directory(system, package);
+    // A bundle is created in a dry run, but the package still needs extracting to be reviewed
+    // and have its dependencies and sources fetched.
+    let run = |program: &str, args: Vec<&str>| match bundle::of(config) {
+        Some(Bundle::Creating(_)) => unix::run(program, &args, false, false),
+        _ => system.run(program, args, false),
+    };
+    run("mkdir", vec!["-p", &root])?;
+    run("rm", vec!["-rf", &directory])?;
+    let snapshot = match bundle::of(config) {
+        Some(bundle @ Bundle::Creating(_)) => {
+            let snapshot = format!("{}/{package}.tar.gz", bundle.aur());
+            // The snapshot is kept in the bundle even though the rest of the run is a dry run.
+            unix::run(
+                "curl",
+                &["-fsSL", "-o", &snapshot, &snapshot_url(system, package)?],
+                true,
+                false,
+            )?;
+            snapshot
+        }
+        Some(bundle @ Bundle::InstallingFrom(_)) => format!("{}/{package}.tar.gz", bundle.aur()),
+        None => {
+            let snapshot = format!("{root}/{package}.tar.gz");
+            system.run(
+                "curl",
+                vec!["-fsSL", "-o", &snapshot, &snapshot_url(system, package)?],
+                false,
+            )?;
+            snapshot
+        }
+    };
+    // Split packages share the snapshot of their pkgbase, so it's extracted into a directory
+    // named after the package instead of the one it contains.
+    run("mkdir", vec!["-p", &directory])?;
+    run(
+        "tar",
+        vec!["-xzf", &snapshot, "-C", &directory, "--strip-components=1"],
+    )?;
+    if bundle::of(config).is_none() {
+        system.run("rm", vec!["-f", &snapshot], false)?;
+    }
+    Ok(directory)
+}
+
+/// Returns the URL of the package's snapshot, looking up its pkgbase in the AUR.
+fn snapshot_url(system: &dyn System, package: &str) -> Result<String, Box<dyn Error>> {
+    let response = system.query(&format!(
+        "curl -fsSL --globoff {AUR_URL}/rpc/v5/info?arg[]={package}"
+    ))?;
+    let package_base = package_base(&response)
+        .ok_or_else(|| error::Error::new(&format!("There is no {package} package in the AUR")))?;
+    Ok(format!(
+        "{AUR_URL}/cgit/aur.git/snapshot/{package_base}.tar.gz"
+    ))
+}
+
+fn helper_command(helper: AurHelper) -> &'static str {
+    match helper {
+        AurHelper::Makepkg => "makepkg",
+        AurHelper::Paru => "paru",
+        AurHelper::Yay => "yay",
+    }
+}
+
+/// Installs the packages from the AUR with the configured helper.
+///
+/// Any dependencies that are only available from the AUR are built and installed first, so the
+/// PKGBUILD of every package built gets reviewed, and any packages already in the local repository
+/// are installed from there instead.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::aur;
+///
+/// aur::install_applications(&system, &config, vec!["google-chrome", "slack-desktop"])?;
+/// ```
+pub(crate) fn install_applications(
+    system: &dyn System,
+    config: &Config,
+    applications: Vec<&str>,
+) -> Result<String, Box<dyn Error>> {
+    let mut cached: Vec<&str> = Vec::new();
+    let mut uncached: Vec<&str> = Vec::new();
+    for application in applications {
+        if local_repository::contains(config, application)? {
+            cached.push(application);
+        } else {
+            uncached.push(application);
+        }
+    }
+    if !cached.is_empty() {
+        system.install_applications(cached)?;
+    }
+    let packages = resolve(
+        &uncached,
+        &mut |package| dependencies(system, config, package),
+        &|dependency| is_aur_dependency(system, config, dependency),
+    )?;
+    debug!("Building {} from the AUR", packages.join(", "));
+    for package in &packages {
+        build(system, config, package)?;
+    }
+    Ok(String::new())
+}
+
+/// Returns whether the dependency is neither installed nor available from the official
+/// repositories, so needs building from the AUR.
+fn is_aur_dependency(
+    system: &dyn System,
+    config: &Config,
+    dependency: &str,
+) -> Result<bool, Box<dyn Error>> {
+    Ok(!is_satisfied(system, config, dependency)? && !is_in_repositories(system, dependency)?)
+}
+
+fn is_in_repositories(system: &dyn System, dependency: &str) -> Result<bool, Box<dyn Error>> {
+    let output = system.query(&format!("pacman -Sp --print-format %n {dependency}"))?;
+    Ok(!output.contains("target not found"))
+}
+
+/// Returns whether the dependency is installed, which it never is when creating a bundle, as the
+/// bundle needs everything the machines installing from it could be missing.
+fn is_satisfied(
+    system: &dyn System,
+    config: &Config,
+    dependency: &str,
+) -> Result<bool, Box<dyn Error>> {
+    if let Some(Bundle::Creating(_)) = bundle::of(config) {
+        return Ok(false);
+    }
+    let output = system.query(&format!("pacman -T {dependency}"))?;
+    Ok(output.trim().is_empty())
+}
+
+/// Returns the pkgbase of the package from the AUR's RPC response, which is what names the
+/// snapshot of a split package.
+fn package_base(response: &str) -> Option<String> {
+    let response: serde_json::Value = serde_json::from_str(response).ok()?;
+    response["results"][0]["PackageBase"]
+        .as_str()
+        .map(String::from)
+}
+
+/// Returns the dependencies listed in the `.SRCINFO`, without any version constraints.
+fn parse_srcinfo(srcinfo: &str) -> Vec<String> {
+    let mut dependencies: Vec<String> = Vec::new();
+    for line in srcinfo.lines() {
+        let Some((key, value)) = line.trim().split_once(" = ") else {
+            continue;
+        };
+        let key = key.split('_').next().unwrap_or(key);
+        if !["depends", "makedepends", "checkdepends"].contains(&key) {
+            continue;
+        }
+        let name = value
+            .split(['<', '>', '='])
+            .next()
+            .unwrap_or(value)
+            .to_string();
+        if !dependencies.contains(&name) {
+            dependencies.push(name);
+        }
+    }
+    dependencies
+}
+
+/// Orders the packages so that any AUR dependencies are built before the packages that need them.
+fn resolve(
+    packages: &[&str],
+    dependencies: Dependencies,
+    is_aur: IsAur,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut order: Vec<String> = Vec::new();
+    let mut visiting: Vec<String> = Vec::new();
+    for package in packages {
+        visit(package, dependencies, is_aur, &mut visiting, &mut order)?;
+    }
+    Ok(order)
+}
+
+fn visit(
+    package: &str,
+    dependencies: Dependencies,
+    is_aur: IsAur,
+    visiting: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    if order.iter().any(|ordered| ordered == package) {
+        return Ok(());
+    }
+    if visiting.iter().any(|visited| visited == package) {
+        return Err(Box::from(error::Error::new(&format!(
+            "Circular AUR dependency on {package}"
+        ))));
+    }
+    visiting.push(package.to_string());
+    for dependency in dependencies(package)? {
+        if is_aur(&dependency)? {
+            visit(&dependency, dependencies, is_aur, visiting, order)?;
+        }
+    }
+    visiting.pop();
+    order.push(package.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::config::parse;
+    use crate::system::MockSystem;
+
+    fn graph() -> HashMap<&'static str, Vec<String>> {
+        HashMap::from([
+            ("app", vec!["lib-a".to_string(), "glibc".to_string()]),
+            ("other", vec!["lib-b".to_string()]),
+            ("lib-a", vec!["lib-b".to_string()]),
+            ("lib-b", vec!["gcc".to_string()]),
+        ])
+    }
+
+    fn is_aur(dependency: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(dependency.starts_with("lib-"))
+    }
+
+    #[test]
+    fn test_parse_srcinfo_strips_versions_and_duplicates() {
+        let srcinfo = "pkgbase = google-chrome\n\
+            \tpkgver = 131.0\n\
+            \tmakedepends = git\n\
+            \tdepends = alsa-lib>=1.2\n\
+            \tdepends = gtk3\n\
+            \tdepends_x86_64 = nss\n\
+            \toptdepends = pipewire\n\
+            \n\
+            pkgname = google-chrome\n\
+            \tdepends = gtk3\n";
+
+        assert_eq!(
+            parse_srcinfo(srcinfo),
+            vec!["git", "alsa-lib", "gtk3", "nss"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_builds_aur_dependencies_first() {
+        let graph = graph();
+        let order = resolve(
+            &["app", "other"],
+            &mut |package| Ok(graph.get(package).cloned().unwrap_or_default()),
+            &is_aur,
+        )
+        .unwrap();
+
+        assert_eq!(order, vec!["lib-b", "lib-a", "app", "other"]);
+    }
+
+    #[test]
+    fn test_resolve_errors_on_circular_dependencies() {
+        let graph = HashMap::from([
+            ("lib-a", vec!["lib-b".to_string()]),
+            ("lib-b", vec!["lib-a".to_string()]),
+        ]);
+        let result = resolve(
+            &["lib-a"],
+            &mut |package| Ok(graph.get(package).cloned().unwrap_or_default()),
+            &is_aur,
+        );
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Circular AUR dependency on lib-a"
+        );
+    }
+
+    /// Expects the package's snapshot to be looked up, downloaded and extracted as the user.
+    fn expect_fetch(mock_system: &mut MockSystem, package: &'static str, package_base: &str) {
+        let response =
+            format!("{{\"resultcount\":1,\"results\":[{{\"PackageBase\":\"{package_base}\"}}]}}");
+        mock_system
+            .expect_query()
+            .with(eq(format!(
+                "curl -fsSL --globoff https://aur.archlinux.org/rpc/v5/info?arg[]={package}"
+            )))
+            .times(1)
+            .returning(move |_| Ok(response.clone()));
+        for command in [
+            "mkdir -p /home/user/.cache/dotfiles/aur".to_string(),
+            format!("rm -rf /home/user/.cache/dotfiles/aur/{package}"),
+            format!("curl -fsSL -o /home/user/.cache/dotfiles/aur/{package}.tar.gz https://aur.archlinux.org/cgit/aur.git/snapshot/{package_base}.tar.gz"),
+            format!("mkdir -p /home/user/.cache/dotfiles/aur/{package}"),
+            format!("tar -xzf /home/user/.cache/dotfiles/aur/{package}.tar.gz -C /home/user/.cache/dotfiles/aur/{package} --strip-components=1"),
+            format!("rm -f /home/user/.cache/dotfiles/aur/{package}.tar.gz"),
+        ] {
+            expect_run(mock_system, command);
+        }
+    }
+
+    /// Expects the command to be run once as the user, with its arguments separated by spaces.
+    fn expect_run(mock_system: &mut MockSystem, command: String) {
+        mock_system
+            .expect_run()
+            .withf(move |program, args, super_user| {
+                format!("{program} {}", args.join(" ")) == command && !super_user
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(String::new()));
+    }
+
+    fn mock_system() -> MockSystem {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_get_home_dir()
+            .returning(|| "/home/user".to_string());
+        mock_system
+    }
+
+    #[test]
+    fn test_package_base_reads_the_rpc_response() {
+        assert_eq!(
+            package_base("{\"resultcount\":1,\"results\":[{\"Name\":\"python-foo-docs\",\"PackageBase\":\"python-foo\"}]}"),
+            Some("python-foo".to_string())
+        );
+        assert_eq!(package_base("{\"resultcount\":0,\"results\":[]}"), None);
+        assert_eq!(package_base("<html>"), None);
+    }
+
+    #[test]
+    fn test_fetch_extracts_snapshot_as_user() {
+        let mut mock_system = mock_system();
+        expect_fetch(&mut mock_system, "yay-bin", "yay-bin");
+
+        let directory = fetch(&mock_system, &parse(vec![]), "yay-bin").unwrap();
+        assert_eq!(directory, "/home/user/.cache/dotfiles/aur/yay-bin");
+    }
+
+    #[test]
+    fn test_fetch_uses_the_pkgbase_snapshot_of_split_packages() {
+        let mut mock_system = mock_system();
+        expect_fetch(&mut mock_system, "python-foo-docs", "python-foo");
+
+        let directory = fetch(&mock_system, &parse(vec![]), "python-foo-docs").unwrap();
+        assert_eq!(directory, "/home/user/.cache/dotfiles/aur/python-foo-docs");
+    }
+
+    #[test]
+    fn test_is_aur_dependency_queries_pacman() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .with(eq("pacman -T glibc"))
+            .returning(|_| Ok(String::new()));
+        mock_system
+            .expect_query()
+            .with(eq("pacman -T gtk3"))
+            .returning(|_| Ok("gtk3".to_string()));
+        mock_system
+            .expect_query()
+            .with(eq("pacman -Sp --print-format %n gtk3"))
+            .returning(|_| Ok("gtk3".to_string()));
+        mock_system
+            .expect_query()
+            .with(eq("pacman -T yay-bin"))
+            .returning(|_| Ok("yay-bin".to_string()));
+        mock_system
+            .expect_query()
+            .with(eq("pacman -Sp --print-format %n yay-bin"))
+            .returning(|_| Ok("error: target not found: yay-bin".to_string()));
+
+        let config = parse(vec![]);
+        assert!(!is_aur_dependency(&mock_system, &config, "glibc").unwrap());
+        assert!(!is_aur_dependency(&mock_system, &config, "gtk3").unwrap());
+        assert!(is_aur_dependency(&mock_system, &config, "yay-bin").unwrap());
+    }
+
+    #[test]
+    fn test_is_aur_dependency_ignores_installed_packages_when_creating_a_bundle() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .with(eq("pacman -T yay-bin"))
+            .never();
+        mock_system
+            .expect_query()
+            .with(eq("pacman -Sp --print-format %n yay-bin"))
+            .returning(|_| Ok("error: target not found: yay-bin".to_string()));
+
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "bundle".to_string(),
+            "--out".to_string(),
+            "/mnt/usb/bundle".to_string(),
+        ]);
+        assert!(is_aur_dependency(&mock_system, &config, "yay-bin").unwrap());
+    }
+
+    #[test]
+    fn test_install_applications_from_a_bundle_builds_offline_with_makepkg() {
+        let config = parse(vec![
+            "--dry-run".to_string(),
+            "--aur-helper".to_string(),
+            "paru".to_string(),
+            "--from-bundle".to_string(),
+            "/nonexistent/bundle".to_string(),
+        ]);
+        let mut mock_system = mock_system();
+        for command in [
+            "mkdir -p /home/user/.cache/dotfiles/aur",
+            "rm -rf /home/user/.cache/dotfiles/aur/google-chrome",
+            "mkdir -p /home/user/.cache/dotfiles/aur/google-chrome",
+            "tar -xzf /nonexistent/bundle/aur/google-chrome.tar.gz -C /home/user/.cache/dotfiles/aur/google-chrome --strip-components=1",
+            "env SRCDEST=/nonexistent/bundle/sources makepkg -D /home/user/.cache/dotfiles/aur/google-chrome -si --noconfirm --needed --holdver",
+            "rm -rf /home/user/.cache/dotfiles/aur/google-chrome",
+        ] {
+            expect_run(&mut mock_system, command.to_string());
+        }
+
+        let result = install_applications(&mock_system, &config, vec!["google-chrome"]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_install_applications_with_paru_builds_the_reviewed_snapshots() {
+        let config = parse(vec![
+            "--dry-run".to_string(),
+            "--aur-helper".to_string(),
+            "paru".to_string(),
+        ]);
+        let mut mock_system = mock_system();
+        for package in ["google-chrome", "slack-desktop"] {
+            expect_fetch(&mut mock_system, package, package);
+            expect_run(
+                &mut mock_system,
+                format!("paru -Bi --noconfirm /home/user/.cache/dotfiles/aur/{package}"),
+            );
+            expect_run(
+                &mut mock_system,
+                format!("rm -rf /home/user/.cache/dotfiles/aur/{package}"),
+            );
+        }
+
+        let result = install_applications(
+            &mock_system,
+            &config,
+            vec!["google-chrome", "slack-desktop"],
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_install_applications_with_makepkg() {
+        let config = parse(vec![
+            "--dry-run".to_string(),
+            "--aur-helper".to_string(),
+            "makepkg".to_string(),
+        ]);
+        let mut mock_system = mock_system();
+        expect_fetch(&mut mock_system, "google-chrome", "google-chrome");
+        expect_run(
+            &mut mock_system,
+            "makepkg -D /home/user/.cache/dotfiles/aur/google-chrome -si --noconfirm --needed"
+                .to_string(),
+        );
+        expect_run(
+            &mut mock_system,
+            "rm -rf /home/user/.cache/dotfiles/aur/google-chrome".to_string(),
+        );
+
+        let result = install_applications(&mock_system, &config, vec!["google-chrome"]);
+        assert!(result.is_ok());
+    }
+}
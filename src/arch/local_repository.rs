@@ -0,0 +1,359 @@
+use log::{debug, info};
+use nix::sys::statfs::{statfs, NFS_SUPER_MAGIC};
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use super::pacman_conf::PacmanConf;
+use crate::config::Config;
+use crate::error;
+use crate::system::System;
+use crate::unix;
+
+/// The name of the pacman repository holding the packages built by `makepkg`.
+pub(crate) static NAME: &str = "dotfiles-local";
+
+/// Copies the packages built in the build directory into the local repository and adds them to
+/// its database, replacing any older versions.
+///
+/// Does nothing when there is no local repository configured.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::local_repository;
+///
+/// local_repository::add(&system, &config, "/home/user/.cache/dotfiles/aur/yay-bin")?;
+/// ```
+pub(crate) fn add(
+    system: &dyn System,
+    config: &Config,
+    build_directory: &str,
+) -> Result<(), Box<dyn Error>> {
+    let Some(repository) = &config.local_repo else {
+        return Ok(());
+    };
+    let package_list = unix::execute_path(
+        "makepkg --packagelist",
+        false,
+        build_directory,
+        false,
+        config.dry_run,
+    )?;
+    for package in parse_package_list(&package_list) {
+        if !Path::new(&package).exists() {
+            debug!("{} wasn't built, so not adding it to {}.", package, NAME);
+            continue;
+        }
+        let file_name = Path::new(&package)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&package)
+            .to_string();
+        info!("Adding {} to {}", file_name, NAME);
+        system.run("cp", vec![&package, &format!("{repository}/")], true)?;
+        system.run(
+            "repo-add",
+            vec![
+                "-R",
+                &database(repository),
+                &format!("{repository}/{file_name}"),
+            ],
+            true,
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns whether the package is available from the local repository, so doesn't need building.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::local_repository;
+///
+/// if local_repository::contains(&config, "yay-bin")? {
+///     system.install_application("yay-bin")?;
+/// }
+/// ```
+pub(crate) fn contains(config: &Config, package: &str) -> Result<bool, Box<dyn Error>> {
+    if config.local_repo.is_none() {
+        return Ok(false);
+    }
+    let output = unix::execute(&format!("pacman -Sl {NAME}"), true, false, false)?;
+    Ok(parse_sync_list(&output).iter().any(|name| name == package))
+}
+
+/// Creates the local repository directory, owned by root and only writable by it, along with an
+/// empty database, if they don't exist.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::local_repository;
+///
+/// local_repository::create(&system, "/mnt/nas/pacman")?;
+/// ```
+pub(crate) fn create(system: &dyn System, repository: &str) -> Result<(), Box<dyn Error>> {
+    system
+        .run(
+            "install",
+            vec!["-d", "-m", "755", "-o", "root", "-g", "root", repository],
+            true,
+        )
+        .map_err(|e| {
+            error::Error::new(&format!(
+                "Could not create {repository} owned by root: {e}{}",
+                root_squash_hint(repository)
+            ))
+        })?;
+    if !Path::new(&database(repository)).exists() {
+        system.run("repo-add", vec![&database(repository)], true)?;
+    }
+    Ok(())
+}
+
+fn database(repository: &str) -> String {
+    format!("{repository}/{NAME}.db.tar.zst")
+}
+
+fn parse_package_list(package_list: &str) -> Vec<String> {
+    package_list
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| line.contains(".pkg.tar"))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+fn parse_sync_list(sync_list: &str) -> Vec<String> {
+    sync_list
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            match (columns.next(), columns.next()) {
+                (Some(repository), Some(name)) if repository == NAME => Some(name.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Checks the local repository can only be changed by root, since pacman installs its packages
+/// without checking signatures.
+///
+/// The directory and everything in it must be owned by root and not writable by the group or
+/// others, so no other user can swap in a package that pacman then installs as root. On NFS, the
+/// error explains how root squashing gets in the way.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::local_repository;
+///
+/// local_repository::verify(&config, "/mnt/nas/pacman")?;
+/// ```
+pub(crate) fn verify(config: &Config, repository: &str) -> Result<(), Box<dyn Error>> {
+    if config.dry_run && !Path::new(repository).exists() {
+        return Ok(());
+    }
+    let mut paths = vec![Path::new(repository).to_path_buf()];
+    for entry in fs::read_dir(repository)? {
+        paths.push(entry?.path());
+    }
+    for path in paths {
+        let metadata = fs::symlink_metadata(&path)?;
+        if metadata.uid() != 0 || metadata.mode() & 0o022 != 0 {
+            return Err(Box::new(error::Error::new(&format!(
+                "{} must be owned by root and only writable by it, since {} isn't signed{}",
+                path.display(),
+                NAME,
+                root_squash_hint(repository)
+            ))));
+        }
+    }
+    Ok(())
+}
+
+/// Explains why root can't own the repository when it's on NFS, which maps root to `nobody`
+/// unless the share is exported with `no_root_squash`.
+fn root_squash_hint(repository: &str) -> String {
+    let is_nfs = Path::new(repository)
+        .ancestors()
+        .find(|path| path.exists())
+        .and_then(|path| statfs(path).ok())
+        .is_some_and(|statfs| statfs.filesystem_type() == NFS_SUPER_MAGIC);
+    if !is_nfs {
+        return String::new();
+    }
+    format!(
+        ". {repository} is on NFS, which maps root to nobody unless the share is exported with \
+        no_root_squash, so export it that way or use a local directory"
+    )
+}
+
+/// Registers the local repository in the pacman configuration.
+///
+/// The packages are built locally, so are trusted without needing to be signed. This is only safe
+/// once [`verify`] has checked no one but root can change the repository.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::local_repository;
+///
+/// local_repository::register(&mut pacman_conf, "/mnt/nas/pacman");
+/// ```
+pub(crate) fn register(pacman_conf: &mut PacmanConf, repository: &str) {
+    pacman_conf.add_repository(
+        NAME,
+        vec![
+            ("SigLevel", "Optional TrustAll"),
+            ("Server", &format!("file://{repository}")),
+        ],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::config::parse;
+    use crate::system::MockSystem;
+
+    #[test]
+    fn test_register_adds_file_server() {
+        let mut pacman_conf = PacmanConf::parse("[options]\nColor\n");
+
+        register(&mut pacman_conf, "/mnt/nas/pacman");
+
+        assert_eq!(
+            pacman_conf.to_string(),
+            "[options]\n\
+            Color\n\
+            \n\
+            [dotfiles-local]\n\
+            SigLevel = Optional TrustAll\n\
+            Server = file:///mnt/nas/pacman\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_package_list_only_includes_packages() {
+        let package_list = "==> WARNING: Skipping verification\n\
+            /home/user/.cache/dotfiles/aur/yay-bin/yay-bin-12.4.2-1-x86_64.pkg.tar.zst\n\
+            /home/user/.cache/dotfiles/aur/yay-bin/yay-bin-debug-12.4.2-1-x86_64.pkg.tar.zst\n";
+
+        assert_eq!(
+            parse_package_list(package_list),
+            vec![
+                "/home/user/.cache/dotfiles/aur/yay-bin/yay-bin-12.4.2-1-x86_64.pkg.tar.zst",
+                "/home/user/.cache/dotfiles/aur/yay-bin/yay-bin-debug-12.4.2-1-x86_64.pkg.tar.zst"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_sync_list_only_includes_local_packages() {
+        let sync_list = "dotfiles-local yay-bin 12.4.2-1 [installed]\n\
+            dotfiles-local davinci-resolve-studio 19.1.4-1\n\
+            extra yay 12.4.2-1\n\
+            error: repository \"dotfiles-local\" was not found\n";
+
+        assert_eq!(
+            parse_sync_list(sync_list),
+            vec!["yay-bin", "davinci-resolve-studio"]
+        );
+    }
+
+    #[test]
+    fn test_contains_is_false_without_local_repo() {
+        let config = parse(vec![]);
+
+        assert!(!contains(&config, "yay-bin").unwrap());
+    }
+
+    #[test]
+    fn test_add_does_nothing_without_local_repo() {
+        let config = parse(vec![]);
+        let mock_system = MockSystem::new();
+
+        let result = add(&mock_system, &config, "/nonexistent");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_create_initialises_database() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_run()
+            .withf(|program, args, super_user| {
+                format!("{program} {}", args.join(" "))
+                    == "install -d -m 755 -o root -g root /nonexistent/pacman"
+                    && *super_user
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(String::new()));
+        mock_system
+            .expect_run()
+            .withf(|program, args, super_user| {
+                format!("{program} {}", args.join(" "))
+                    == "repo-add /nonexistent/pacman/dotfiles-local.db.tar.zst"
+                    && *super_user
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(String::new()));
+
+        let result = create(&mock_system, "/nonexistent/pacman");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_verify_refuses_a_repository_others_can_write_to() {
+        let config = parse(vec![]);
+        let repository = env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir(&repository).unwrap();
+        fs::set_permissions(&repository, fs::Permissions::from_mode(0o777)).unwrap();
+
+        let result = verify(&config, &repository.to_string_lossy());
+
+        assert!(result.is_err());
+        fs::remove_dir_all(&repository).unwrap();
+    }
+
+    #[test]
+    fn test_verify_skips_a_missing_repository_on_a_dry_run() {
+        let config = parse(vec!["--dry-run".to_string()]);
+
+        assert!(verify(&config, "/nonexistent/pacman").is_ok());
+    }
+
+    #[test]
+    fn test_create_fails_when_root_cannot_own_the_repository() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_run()
+            .returning(|_, _, _| Err(Box::new(error::Error::new("Operation not permitted"))));
+
+        let result = create(&mock_system, "/nonexistent/pacman");
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Could not create /nonexistent/pacman owned by root: Operation not permitted"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::linux::mirrors::{self, Mirror};
+use crate::system::System;
+use crate::{backup, error, network};
+
+static MIRRORLIST: &str = "/etc/pacman.d/mirrorlist";
+
+/// How many of the fastest mirrors to keep in the mirrorlist.
+static MIRRORS_TO_KEEP: usize = 10;
+
+/// Ranks the mirrors in the configured countries and writes the fastest to the mirrorlist, after
+/// backing up the existing one.
+///
+/// Uses reflector when it is installed, otherwise benchmarks the mirrors directly. A dry run ranks
+/// the mirrors without backing up or writing anything.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::mirrorlist;
+///
+/// mirrorlist::rank(&system, &config).await?;
+/// ```
+pub(crate) async fn rank(system: &dyn System, config: &Config) -> Result<(), Box<dyn Error>> {
+    backup::back_up(system, MIRRORLIST)?;
+    if Path::new("/usr/bin/reflector").exists() {
+        system.execute(
+            &format!(
+                "reflector --verbose --country {} --protocol https --sort rate --number {MIRRORS_TO_KEEP} --save {MIRRORLIST}",
+                config.mirror_countries.join(",")
+            ),
+            true,
+        )?;
+        return Ok(());
+    }
+    let countries = config
+        .mirror_countries
+        .iter()
+        .map(|country| format!("country={country}"))
+        .collect::<Vec<String>>()
+        .join("&");
+    let client = network::client()?;
+    let mirrorlist = client
+        .get(format!(
+            "https://archlinux.org/mirrorlist/?{countries}&protocol=https&use_mirror_status=on"
+        ))
+        .send()
+        .await?
+        .text()
+        .await?;
+    let ranked = mirrors::rank(mirrors::measure(parse(&mirrorlist)).await?);
+    if ranked.is_empty() {
+        return Err(Box::from(error::Error::new(&format!(
+            "None of the mirrors in {} responded",
+            config.mirror_countries.join(", ")
+        ))));
+    }
+    if config.dry_run {
+        info!(
+            "Would write the {} fastest mirrors to {}",
+            MIRRORS_TO_KEEP, MIRRORLIST
+        );
+        return Ok(());
+    }
+    info!(
+        "Writing the {} fastest mirrors to {}",
+        MIRRORS_TO_KEEP, MIRRORLIST
+    );
+    fs::write(
+        MIRRORLIST,
+        render(&ranked[..ranked.len().min(MIRRORS_TO_KEEP)]),
+    )?;
+    Ok(())
+}
+
+/// Returns the servers listed in the mirrorlist, including commented out ones, probing each
+/// with its core database.
+fn parse(mirrorlist: &str) -> Vec<Mirror> {
+    mirrorlist
+        .lines()
+        .filter_map(|line| {
+            line.trim_start_matches('#')
+                .trim()
+                .strip_prefix("Server")
+                .and_then(|server| server.trim_start().strip_prefix('='))
+                .map(|server| server.trim().to_string())
+        })
+        .map(|url| Mirror {
+            probe: format!(
+                "{}/core.db",
+                url.replace("$repo", "core").replace("$arch", "x86_64")
+            ),
+            url,
+        })
+        .collect()
+}
+
+fn render(mirrors: &[Mirror]) -> String {
+    let mut mirrorlist = String::from("## Ranked by dotfiles, fastest first\n");
+    for mirror in mirrors {
+        mirrorlist.push_str(&format!("Server = {}\n", mirror.url));
+    }
+    mirrorlist
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_includes_commented_servers() {
+        let mirrorlist = "##\n\
+            ## Arch Linux repository mirrorlist\n\
+            ##\n\
+            \n\
+            ## United Kingdom\n\
+            #Server = https://mirror.example.co.uk/archlinux/$repo/os/$arch\n\
+            Server = https://uk.example.org/arch/$repo/os/$arch\n";
+
+        assert_eq!(
+            parse(mirrorlist),
+            vec![
+                Mirror {
+                    url: "https://mirror.example.co.uk/archlinux/$repo/os/$arch".to_string(),
+                    probe: "https://mirror.example.co.uk/archlinux/core/os/x86_64/core.db"
+                        .to_string(),
+                },
+                Mirror {
+                    url: "https://uk.example.org/arch/$repo/os/$arch".to_string(),
+                    probe: "https://uk.example.org/arch/core/os/x86_64/core.db".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_lists_servers_in_order() {
+        let mirrors = parse(
+            "Server = https://fast.example/$repo/os/$arch\n\
+            Server = https://slow.example/$repo/os/$arch\n",
+        );
+
+        assert_eq!(
+            render(&mirrors),
+            "## Ranked by dotfiles, fastest first\n\
+            Server = https://fast.example/$repo/os/$arch\n\
+            Server = https://slow.example/$repo/os/$arch\n"
+        );
+    }
+}
@@ -0,0 +1,588 @@
+use log::{debug, info};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::Write;
+
+use crate::profile::Pacman;
+use crate::unix;
+
+/// The location of the pacman configuration.
+pub(crate) static PACMAN_CONF: &str = "/etc/pacman.conf";
+
+static OPTIONS: &str = "options";
+
+/// The options that belong to a repository section, rather than being general options.
+static REPOSITORY_OPTIONS: [&str; 4] = ["Include", "Server", "SigLevel", "Usage"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Kind {
+    Section {
+        name: String,
+        enabled: bool,
+    },
+    Option {
+        key: String,
+        value: Option<String>,
+        enabled: bool,
+    },
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Line {
+    raw: String,
+    kind: Kind,
+}
+
+impl Line {
+    fn parse(raw: &str) -> Self {
+        Line {
+            raw: raw.to_string(),
+            kind: parse_kind(raw),
+        }
+    }
+
+    fn new(kind: Kind) -> Self {
+        let mut line = Line {
+            raw: String::new(),
+            kind,
+        };
+        line.render();
+        line
+    }
+
+    fn render(&mut self) {
+        self.raw = match &self.kind {
+            Kind::Section { name, enabled } => format!("{}[{name}]", prefix(*enabled)),
+            Kind::Option {
+                key,
+                value: Some(value),
+                enabled,
+            } => format!("{}{key} = {value}", prefix(*enabled)),
+            Kind::Option {
+                key,
+                value: None,
+                enabled,
+            } => format!("{}{key}", prefix(*enabled)),
+            Kind::Other => return,
+        };
+    }
+
+    fn set_enabled(&mut self, enable: bool) {
+        match &mut self.kind {
+            Kind::Section { enabled, .. } | Kind::Option { enabled, .. } if *enabled != enable => {
+                *enabled = enable;
+            }
+            _ => return,
+        }
+        self.render();
+    }
+}
+
+fn prefix(enabled: bool) -> &'static str {
+    if enabled {
+        ""
+    } else {
+        "#"
+    }
+}
+
+/// Works out whether the line is a section, an option or anything else, such as a comment.
+///
+/// Commented out sections and options, e.g. `#[multilib]` or `#Color`, are kept as disabled
+/// entries so that they can be enabled again in place, whereas prose comments, e.g.
+/// `# Misc options`, have a space after the `#`.
+fn parse_kind(raw: &str) -> Kind {
+    let trimmed = raw.trim();
+    let (enabled, body) = match trimmed.strip_prefix('#') {
+        Some(body) => (false, body),
+        None => (true, trimmed),
+    };
+    if !body.starts_with(|c: char| c.is_ascii_alphabetic() || c == '[') {
+        return Kind::Other;
+    }
+    if let Some(name) = body
+        .strip_prefix('[')
+        .and_then(|body| body.strip_suffix(']'))
+    {
+        return Kind::Section {
+            name: name.trim().to_string(),
+            enabled,
+        };
+    }
+    let (key, value) = match body.split_once('=') {
+        Some((key, value)) => (key.trim(), Some(value.trim().to_string())),
+        None => (body, None),
+    };
+    if !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Kind::Other;
+    }
+    Kind::Option {
+        key: key.to_string(),
+        value,
+        enabled,
+    }
+}
+
+/// A structured view of `pacman.conf`, that keeps any lines it doesn't change exactly as they were,
+/// including comments.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::pacman_conf::{PacmanConf, PACMAN_CONF};
+///
+/// let mut pacman_conf = PacmanConf::load(PACMAN_CONF)?;
+/// pacman_conf.enable_repository("multilib");
+/// pacman_conf.set_option("ParallelDownloads", Some("5"));
+/// pacman_conf.update(PACMAN_CONF, config.dry_run)?;
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PacmanConf {
+    lines: Vec<Line>,
+}
+
+impl PacmanConf {
+    pub(crate) fn parse(contents: &str) -> Self {
+        PacmanConf {
+            lines: contents.lines().map(Line::parse).collect(),
+        }
+    }
+
+    pub(crate) fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Writes the configuration to a temporary file alongside the original, then renames it over
+    /// the original, so pacman never sees a partially written file.
+    pub(crate) fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let temporary_path = format!("{path}.dotfiles");
+        let mut file = fs::File::create(&temporary_path)?;
+        file.write_all(self.to_string().as_bytes())?;
+        file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(path) {
+            fs::set_permissions(&temporary_path, metadata.permissions())?;
+        }
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    }
+
+    /// Saves the configuration over the file when they differ, or on a dry run, shows the
+    /// difference instead, returning whether they differ.
+    pub(crate) fn update(&self, path: &str, dry_run: bool) -> Result<bool, Box<dyn Error>> {
+        if Self::load(path)? == *self {
+            debug!("{} is unchanged", path);
+            return Ok(false);
+        }
+        if !dry_run {
+            self.save(path)?;
+            info!("Updated {}", path);
+            return Ok(true);
+        }
+        info!("Would change {}:", path);
+        let changed = tempfile::NamedTempFile::new()?;
+        let changed_path = changed.path().to_string_lossy().to_string();
+        self.save(&changed_path)?;
+        unix::execute(&format!("diff -u {path} {changed_path}"), true, true, false)?;
+        Ok(true)
+    }
+
+    /// Adds the repository with the given options, or enables it and updates its options if it is
+    /// already there.
+    pub(crate) fn add_repository(&mut self, name: &str, options: Vec<(&str, &str)>) {
+        if !self.enable_repository(name) {
+            if self
+                .lines
+                .last()
+                .is_some_and(|line| !line.raw.trim().is_empty())
+            {
+                self.lines.push(Line::parse(""));
+            }
+            self.lines.push(Line::new(Kind::Section {
+                name: name.to_string(),
+                enabled: true,
+            }));
+        }
+        for (key, value) in options {
+            self.set_section_option(name, key, Some(value));
+        }
+    }
+
+    /// Applies the options and repositories from the profile.
+    pub(crate) fn apply(&mut self, pacman: &Pacman) {
+        for (key, enabled) in [("Color", pacman.color), ("ILoveCandy", pacman.i_love_candy)] {
+            match enabled {
+                Some(true) => self.set_option(key, None),
+                Some(false) => self.disable_option(key),
+                None => {}
+            }
+        }
+        if let Some(parallel_downloads) = pacman.parallel_downloads {
+            self.set_option("ParallelDownloads", Some(&parallel_downloads.to_string()));
+        }
+        self.ignore_packages(&pacman.ignore);
+        for (name, repository) in &pacman.repositories {
+            let mut options: Vec<(&str, &str)> = Vec::new();
+            if let Some(sig_level) = &repository.sig_level {
+                options.push(("SigLevel", sig_level));
+            }
+            if let Some(include) = &repository.include {
+                options.push(("Include", include));
+            }
+            if let Some(server) = &repository.server {
+                options.push(("Server", server));
+            }
+            self.add_repository(name, options);
+        }
+        for name in &pacman.disabled_repositories {
+            self.disable_repository(name);
+        }
+    }
+
+    /// Comments out the repository, returning whether it was found.
+    pub(crate) fn disable_repository(&mut self, name: &str) -> bool {
+        self.set_repository_enabled(name, false)
+    }
+
+    /// Comments out the option in the `[options]` section.
+    pub(crate) fn disable_option(&mut self, key: &str) {
+        if let Some(index) = self.find_option(OPTIONS, key, true) {
+            self.lines[index].set_enabled(false);
+        }
+    }
+
+    /// Uncomments the repository and its options, returning whether it was found.
+    pub(crate) fn enable_repository(&mut self, name: &str) -> bool {
+        self.set_repository_enabled(name, true)
+    }
+
+    /// Returns the value of the enabled option in the `[options]` section, with flags such as
+    /// `Color` having an empty value.
+    pub(crate) fn get_option(&self, key: &str) -> Option<String> {
+        let index = self.find_option(OPTIONS, key, true)?;
+        match &self.lines[index].kind {
+            Kind::Option { value, .. } => Some(value.clone().unwrap_or_default()),
+            _ => None,
+        }
+    }
+
+    /// Adds the packages to `IgnorePkg`, keeping any that are already ignored.
+    pub(crate) fn ignore_packages(&mut self, packages: &[String]) {
+        let mut ignored: Vec<String> = self
+            .get_option("IgnorePkg")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|package| package.to_string())
+            .collect();
+        for package in packages {
+            if !ignored.contains(package) {
+                ignored.push(package.clone());
+            }
+        }
+        if !ignored.is_empty() {
+            self.set_option("IgnorePkg", Some(&ignored.join(" ")));
+        }
+    }
+
+    #[cfg_attr(not(test), expect(unused))]
+    pub(crate) fn is_repository_enabled(&self, name: &str) -> bool {
+        self.lines.iter().any(|line| {
+            line.kind
+                == Kind::Section {
+                    name: name.to_string(),
+                    enabled: true,
+                }
+        })
+    }
+
+    /// Sets the option in the `[options]` section, enabling it if it is commented out. Options
+    /// without a value, such as `Color` and `ILoveCandy`, are set with `None`.
+    pub(crate) fn set_option(&mut self, key: &str, value: Option<&str>) {
+        self.set_section_option(OPTIONS, key, value);
+    }
+
+    fn find_option(&self, section: &str, key: &str, enabled_only: bool) -> Option<usize> {
+        let (start, end) = self.section_range(section)?;
+        let matching = |enabled_wanted: bool| {
+            (start + 1..end).find(|index| {
+                matches!(&self.lines[*index].kind,
+                    Kind::Option { key: found, enabled, .. } if found == key && *enabled == enabled_wanted)
+            })
+        };
+        matching(true).or_else(|| if enabled_only { None } else { matching(false) })
+    }
+
+    /// Returns the index of the section's header and the index after its last line.
+    fn section_range(&self, name: &str) -> Option<(usize, usize)> {
+        let start = self.lines.iter().position(
+            |line| matches!(&line.kind, Kind::Section { name: found, .. } if found == name),
+        )?;
+        let end = (start + 1..self.lines.len())
+            .find(|index| matches!(self.lines[*index].kind, Kind::Section { .. }))
+            .unwrap_or(self.lines.len());
+        Some((start, end))
+    }
+
+    fn set_repository_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let Some((start, end)) = self.section_range(name) else {
+            return false;
+        };
+        self.lines[start].set_enabled(enabled);
+        for line in &mut self.lines[start + 1..end] {
+            if matches!(&line.kind, Kind::Option { key, .. } if REPOSITORY_OPTIONS.contains(&key.as_str()))
+            {
+                line.set_enabled(enabled);
+            }
+        }
+        true
+    }
+
+    fn set_section_option(&mut self, section: &str, key: &str, value: Option<&str>) {
+        let line = Line::new(Kind::Option {
+            key: key.to_string(),
+            value: value.map(|value| value.to_string()),
+            enabled: true,
+        });
+        if let Some(index) = self.find_option(section, key, false) {
+            self.lines[index] = line;
+            return;
+        }
+        let Some((start, end)) = self.section_range(section) else {
+            self.lines.push(Line::new(Kind::Section {
+                name: section.to_string(),
+                enabled: true,
+            }));
+            self.lines.push(line);
+            return;
+        };
+        let last_option = (start..end)
+            .rev()
+            .find(|index| !matches!(self.lines[*index].kind, Kind::Other))
+            .unwrap_or(start);
+        self.lines.insert(last_option + 1, line);
+    }
+}
+
+impl Display for PacmanConf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line.raw)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::profile::PacmanRepository;
+
+    static DEFAULT: &str = "#
+# /etc/pacman.conf
+#
+[options]
+HoldPkg     = pacman glibc
+Architecture = auto
+
+#IgnorePkg   =
+
+# Misc options
+#UseSyslog
+#Color
+#NoProgressBar
+CheckSpace
+#VerbosePkgLists
+#ParallelDownloads = 5
+DownloadUser = alpm
+
+SigLevel    = Required DatabaseOptional
+
+#[core-testing]
+#Include = /etc/pacman.d/mirrorlist
+
+[core]
+Include = /etc/pacman.d/mirrorlist
+
+[extra]
+Include = /etc/pacman.d/mirrorlist
+
+# If you want to run 32 bit applications on your x86_64 system,
+# enable the multilib repositories as required here.
+
+#[multilib]
+#Include = /etc/pacman.d/mirrorlist
+";
+
+    #[test]
+    fn test_unchanged_round_trips() {
+        assert_eq!(PacmanConf::parse(DEFAULT).to_string(), DEFAULT);
+    }
+
+    #[test]
+    fn test_enable_repository_uncomments_section() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        assert!(pacman_conf.enable_repository("multilib"));
+        assert!(pacman_conf.is_repository_enabled("multilib"));
+        assert!(pacman_conf
+            .to_string()
+            .ends_with("# enable the multilib repositories as required here.\n\n[multilib]\nInclude = /etc/pacman.d/mirrorlist\n"));
+    }
+
+    #[test]
+    fn test_enable_repository_is_false_when_missing() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        assert!(!pacman_conf.enable_repository("chaotic-aur"));
+    }
+
+    #[test]
+    fn test_disable_repository_comments_section() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        assert!(pacman_conf.disable_repository("extra"));
+        assert!(!pacman_conf.is_repository_enabled("extra"));
+        assert!(pacman_conf
+            .to_string()
+            .contains("#[extra]\n#Include = /etc/pacman.d/mirrorlist\n"));
+    }
+
+    #[test]
+    fn test_set_option_uncomments_in_place() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        pacman_conf.set_option("ParallelDownloads", Some("10"));
+        pacman_conf.set_option("Color", None);
+
+        let contents = pacman_conf.to_string();
+        assert!(contents.contains("#UseSyslog\nColor\n#NoProgressBar\n"));
+        assert!(contents.contains("#VerbosePkgLists\nParallelDownloads = 10\nDownloadUser"));
+        assert_eq!(
+            pacman_conf.get_option("ParallelDownloads"),
+            Some("10".to_string())
+        );
+    }
+
+    #[test]
+    fn test_set_option_adds_missing_option_to_options_section() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        pacman_conf.set_option("ILoveCandy", None);
+
+        assert!(pacman_conf
+            .to_string()
+            .contains("SigLevel    = Required DatabaseOptional\nILoveCandy\n\n#[core-testing]"));
+    }
+
+    #[test]
+    fn test_disable_option_comments_option() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        pacman_conf.disable_option("CheckSpace");
+
+        assert_eq!(pacman_conf.get_option("CheckSpace"), None);
+        assert!(pacman_conf.to_string().contains("#CheckSpace\n"));
+    }
+
+    #[test]
+    fn test_ignore_packages_merges_with_existing() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        pacman_conf.ignore_packages(&["linux".to_string()]);
+        pacman_conf.ignore_packages(&["linux".to_string(), "linux-headers".to_string()]);
+
+        assert_eq!(
+            pacman_conf.get_option("IgnorePkg"),
+            Some("linux linux-headers".to_string())
+        );
+        assert!(pacman_conf
+            .to_string()
+            .contains("IgnorePkg = linux linux-headers\n\n# Misc options"));
+    }
+
+    #[test]
+    fn test_add_repository_appends_custom_repository() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        pacman_conf.add_repository(
+            "dotfiles-local",
+            vec![
+                ("SigLevel", "Optional TrustAll"),
+                ("Server", "file:///mnt/nas/pacman"),
+            ],
+        );
+
+        assert!(pacman_conf.to_string().ends_with(
+            "#Include = /etc/pacman.d/mirrorlist\n\n[dotfiles-local]\nSigLevel = Optional TrustAll\nServer = file:///mnt/nas/pacman\n"
+        ));
+    }
+
+    #[test]
+    fn test_apply_profile() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+        let pacman = Pacman {
+            color: Some(true),
+            disabled_repositories: vec!["extra".to_string()],
+            i_love_candy: Some(true),
+            ignore: vec!["linux".to_string()],
+            parallel_downloads: Some(5),
+            repositories: BTreeMap::from([(
+                "chaotic-aur".to_string(),
+                PacmanRepository {
+                    include: Some("/etc/pacman.d/chaotic-mirrorlist".to_string()),
+                    server: None,
+                    sig_level: None,
+                },
+            )]),
+        };
+
+        pacman_conf.apply(&pacman);
+
+        assert_eq!(pacman_conf.get_option("Color"), Some(String::new()));
+        assert_eq!(pacman_conf.get_option("ILoveCandy"), Some(String::new()));
+        assert_eq!(
+            pacman_conf.get_option("ParallelDownloads"),
+            Some("5".to_string())
+        );
+        assert_eq!(
+            pacman_conf.get_option("IgnorePkg"),
+            Some("linux".to_string())
+        );
+        assert!(!pacman_conf.is_repository_enabled("extra"));
+        assert!(pacman_conf
+            .to_string()
+            .ends_with("[chaotic-aur]\nInclude = /etc/pacman.d/chaotic-mirrorlist\n"));
+    }
+
+    #[test]
+    fn test_add_repository_updates_existing_repository() {
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        pacman_conf.add_repository("core-testing", vec![("Server", "https://example.com")]);
+        pacman_conf.add_repository("core-testing", vec![("Server", "https://example.org")]);
+
+        assert!(pacman_conf.to_string().contains(
+            "[core-testing]\nInclude = /etc/pacman.d/mirrorlist\nServer = https://example.org\n\n[core]"
+        ));
+    }
+
+    #[test]
+    fn test_update_only_writes_changes_outside_dry_runs() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_string_lossy().to_string();
+        fs::write(&path, DEFAULT).unwrap();
+        let mut pacman_conf = PacmanConf::parse(DEFAULT);
+
+        assert!(!pacman_conf.update(&path, false).unwrap());
+        pacman_conf.enable_repository("multilib");
+        assert!(pacman_conf.update(&path, true).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), DEFAULT);
+        assert!(pacman_conf.update(&path, false).unwrap());
+        assert_eq!(fs::read_to_string(&path).unwrap(), pacman_conf.to_string());
+    }
+}
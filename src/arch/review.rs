@@ -0,0 +1,280 @@
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::io::{stdin, stdout, Write};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::profile::Profile;
+use crate::system::System;
+use crate::{error, unix};
+
+/// Requires the PKGBUILD and `.install` files of the fetched package to be approved before it
+/// gets built, storing the approved copies so only changes need reviewing next time.
+///
+/// Packages the profile pins, or lists in its extras, are approved when their hash matches the pin,
+/// after showing any changes since the last approval as a diff. Otherwise, those changes need
+/// confirming, so packages only a step installs, like the AUR helper, don't need pins.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use arch::review;
+///
+/// let directory = aur::fetch(&system, &config, "google-chrome")?;
+/// review::approve(&system, &config, "google-chrome", &directory)?;
+/// ```
+pub(crate) fn approve(
+    system: &dyn System,
+    config: &Config,
+    package: &str,
+    directory: &str,
+) -> Result<(), Box<dyn Error>> {
+    if !Path::new(directory).join("PKGBUILD").exists() {
+        // A dry run doesn't extract the snapshot, so there's nothing to review, or build.
+        if config.dry_run {
+            debug!("There is no PKGBUILD to review for {}.", package);
+            return Ok(());
+        }
+        return Err(Box::from(error::Error::new(&format!(
+            "There is no PKGBUILD to review for {package} in {directory}"
+        ))));
+    }
+    let files = reviewed_files(directory)?;
+    let hash = hash(&files);
+    let approved_directory = approved_directory(system, package);
+    let approved_files = reviewed_files(&approved_directory).unwrap_or_default();
+    match config
+        .profile
+        .as_ref()
+        .filter(|profile| requires_pin(profile, package))
+    {
+        Some(profile) => {
+            if approved_files != files {
+                show_diff(&approved_directory, directory, &approved_files, &files)?;
+            }
+            check_pin(&profile.aur.pins, package, &hash)?
+        }
+        None => {
+            if approved_files == files {
+                debug!("The PKGBUILD for {} is unchanged since approval.", package);
+                return Ok(());
+            }
+            show_diff(&approved_directory, directory, &approved_files, &files)?;
+            if !confirm(&format!(
+                "Approve the PKGBUILD for {package} (sha256 {hash})? (y/N): "
+            ))? {
+                return Err(Box::from(error::Error::new(&format!(
+                    "The PKGBUILD for {package} was not approved"
+                ))));
+            }
+        }
+    }
+    info!("Approved the PKGBUILD for {} with sha256 {}", package, hash);
+    store(&approved_directory, &files)
+}
+
+fn approved_directory(system: &dyn System, package: &str) -> String {
+    format!(
+        "{}/.local/state/dotfiles/aur/{}",
+        system.get_home_dir(),
+        package
+    )
+}
+
+/// Returns whether the profile pins the package or lists it among its AUR extras.
+fn requires_pin(profile: &Profile, package: &str) -> bool {
+    profile.aur.pins.contains_key(package) || profile.extras.aur.iter().any(|aur| aur == package)
+}
+
+fn check_pin(
+    pins: &BTreeMap<String, String>,
+    package: &str,
+    hash: &str,
+) -> Result<(), Box<dyn Error>> {
+    match pins.get(package) {
+        Some(pin) if pin.eq_ignore_ascii_case(hash) => Ok(()),
+        Some(pin) => Err(Box::from(error::Error::new(&format!(
+            "The PKGBUILD for {package} has sha256 {hash}, but {pin} is pinned in the profile"
+        )))),
+        None => Err(Box::from(error::Error::new(&format!(
+            "There is no pin for {package} in the profile, the PKGBUILD has sha256 {hash}"
+        )))),
+    }
+}
+
+fn confirm(question: &str) -> Result<bool, Box<dyn Error>> {
+    let mut stdout = stdout();
+    stdout.write_all(question.as_bytes())?;
+    stdout.flush()?;
+    let mut answer = String::new();
+    stdin().read_line(&mut answer)?;
+    Ok(answer.trim().eq_ignore_ascii_case("y"))
+}
+
+/// Returns the SHA-256 of the files, covering both their names and contents.
+fn hash(files: &BTreeMap<String, String>) -> String {
+    let mut hasher = Sha256::new();
+    for (name, contents) in files {
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(contents.as_bytes());
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Returns the PKGBUILD and any `.install` files in the directory, keyed by their file name.
+fn reviewed_files(directory: &str) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let mut files = BTreeMap::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if path.is_file() && (name == "PKGBUILD" || name.ends_with(".install")) {
+            files.insert(name.to_string(), fs::read_to_string(&path)?);
+        }
+    }
+    Ok(files)
+}
+
+fn show_diff(
+    approved_directory: &str,
+    directory: &str,
+    approved_files: &BTreeMap<String, String>,
+    files: &BTreeMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let names: BTreeSet<&String> = approved_files.keys().chain(files.keys()).collect();
+    for name in names {
+        if approved_files.get(name) == files.get(name) {
+            continue;
+        }
+        unix::execute(
+            &format!("diff -u --new-file {approved_directory}/{name} {directory}/{name}"),
+            true,
+            true,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+fn store(approved_directory: &str, files: &BTreeMap<String, String>) -> Result<(), Box<dyn Error>> {
+    if Path::new(approved_directory).exists() {
+        fs::remove_dir_all(approved_directory)?;
+    }
+    fs::create_dir_all(approved_directory)?;
+    for (name, contents) in files {
+        fs::write(format!("{approved_directory}/{name}"), contents)?;
+    }
+    unix::recursively_chown(
+        approved_directory,
+        &unix::get_user_id(),
+        &unix::get_group_id(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::profile::{Aur, Packages};
+    use crate::system::MockSystem;
+    use std::env;
+    use uuid::Uuid;
+
+    fn files(pkgbuild: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("PKGBUILD".to_string(), pkgbuild.to_string()),
+            (
+                "google-chrome.install".to_string(),
+                "post_install() {}".to_string(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_approve_refuses_a_missing_pkgbuild() {
+        let directory = env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&directory).unwrap();
+        let directory = directory.to_string_lossy().to_string();
+        let mock_system = MockSystem::new();
+
+        let result = approve(&mock_system, &parse(vec![]), "google-chrome", &directory);
+        let dry_run = approve(
+            &mock_system,
+            &parse(vec!["--dry-run".to_string()]),
+            "google-chrome",
+            &directory,
+        );
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("There is no PKGBUILD to review for google-chrome"));
+        assert!(dry_run.is_ok());
+    }
+
+    #[test]
+    fn test_hash_changes_with_contents() {
+        assert_eq!(hash(&files("pkgver=1")), hash(&files("pkgver=1")));
+        assert_ne!(hash(&files("pkgver=1")), hash(&files("pkgver=2")));
+    }
+
+    #[test]
+    fn test_hash_is_sha256() {
+        assert_eq!(hash(&BTreeMap::new()).len(), 64);
+    }
+
+    #[test]
+    fn test_check_pin_matches_hash() {
+        let hash = hash(&files("pkgver=1"));
+        let pins = BTreeMap::from([("google-chrome".to_string(), hash.to_uppercase())]);
+
+        assert!(check_pin(&pins, "google-chrome", &hash).is_ok());
+    }
+
+    #[test]
+    fn test_check_pin_rejects_changed_hash() {
+        let pins = BTreeMap::from([("google-chrome".to_string(), hash(&files("pkgver=1")))]);
+
+        let result = check_pin(&pins, "google-chrome", &hash(&files("pkgver=2")));
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("is pinned in the profile"));
+    }
+
+    #[test]
+    fn test_check_pin_rejects_missing_pin() {
+        let result = check_pin(&BTreeMap::new(), "google-chrome", "abc123");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "There is no pin for google-chrome in the profile, the PKGBUILD has sha256 abc123"
+        );
+    }
+
+    #[test]
+    fn test_requires_pin_only_for_packages_the_profile_lists() {
+        let profile = Profile {
+            aur: Aur {
+                pins: BTreeMap::from([("google-chrome".to_string(), "abc123".to_string())]),
+            },
+            extras: Packages {
+                aur: vec!["slack-desktop".to_string()],
+                ..Packages::default()
+            },
+            ..Profile::default()
+        };
+
+        assert!(requires_pin(&profile, "google-chrome"));
+        assert!(requires_pin(&profile, "slack-desktop"));
+        assert!(!requires_pin(&profile, "yay"));
+    }
+}
@@ -0,0 +1,347 @@
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error;
+use crate::state;
+use crate::system::{self, System};
+
+/// When this invocation took its first backup, as `YYYYMMDDTHHMMSSZ`, so each invocation keeps
+/// its backups in a directory of its own.
+static TAKEN: OnceLock<String> = OnceLock::new();
+
+/// A copy of a file taken before a run overwrote it.
+#[derive(Debug, PartialEq)]
+struct Backup {
+    run: u32,
+    /// When the invocation took its first backup, as `YYYYMMDDTHHMMSSZ`.
+    taken: String,
+    /// The absolute path of the file that was overwritten.
+    file: String,
+    /// Where the copy is kept.
+    copy: PathBuf,
+}
+
+/// The directory an invocation keeps its backups in.
+struct RunDirectory {
+    run: u32,
+    /// When the invocation took its first backup, as `YYYYMMDDTHHMMSSZ`.
+    taken: String,
+    path: PathBuf,
+}
+
+/// Copies the file into `~/.local/state/dotfiles/backups` before it's overwritten, so hand edits
+/// can be restored with `dotfiles backups restore <file>`.
+///
+/// Backups are kept in a directory per invocation, named after the run, or 0 outside runs, and
+/// when it took its first backup, e.g. `42-20240131T120000Z/home/user/.zshrc`. Only the first
+/// overwrite in an invocation is backed up, as later ones would only copy what it wrote, and
+/// files that don't exist yet are skipped.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use backup;
+///
+/// backup::back_up(&system, &format!("{}/.zshrc", system.get_home_dir()))?;
+/// ```
+pub(crate) fn back_up(system: &dyn System, file: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(file).is_file() {
+        return Ok(());
+    }
+    if system::is_dry_run() {
+        info!("Would back up {}", file);
+        return Ok(());
+    }
+    let backups = directory(system)?;
+    // Files overwritten outside a run, which only installs start, are kept under run 0.
+    let run = state::current_run().unwrap_or_default();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let taken = TAKEN.get_or_init(|| timestamp(now));
+    if let Some(copy) = copy_into(&backups, run, taken, file)? {
+        state::give_to_user(&backups.to_string_lossy())?;
+        info!("Backed up {} to {}", file, copy.display());
+    }
+    Ok(())
+}
+
+/// Lists the files backed up by each run.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use backup;
+///
+/// backup::list(&system)?;
+/// ```
+pub(crate) fn list(system: &dyn System) -> Result<(), Box<dyn Error>> {
+    let backups = find(&directory(system)?)?;
+    if backups.is_empty() {
+        info!("There are no backups from previous runs");
+    }
+    for backup in backups {
+        info!("{:<6} {:<18} {}", backup.run, backup.taken, backup.file);
+    }
+    Ok(())
+}
+
+/// Restores the file, given as an absolute path, from the backup taken by the run, or the latest
+/// backup of it, backing up what it replaces first.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use backup;
+///
+/// backup::restore(&system, "/home/user/.zshrc", Some(42))?;
+/// ```
+pub(crate) fn restore(
+    system: &dyn System,
+    file: &str,
+    run: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let backups = find(&directory(system)?)?;
+    let backup = select(&backups, file, run).ok_or_else(|| match run {
+        Some(run) => error::Error::new(&format!("Run {run} didn't back up {file}")),
+        None => error::Error::new(&format!("There are no backups of {file}")),
+    })?;
+    back_up(system, file)?;
+    if let Some(parent) = Path::new(file).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(&backup.copy, file)?;
+    state::give_to_user(file)?;
+    info!(
+        "Restored {} from the backup taken by run {} at {}",
+        file, backup.run, backup.taken
+    );
+    Ok(())
+}
+
+fn directory(system: &dyn System) -> Result<PathBuf, Box<dyn Error>> {
+    let directory = Path::new(&state::directory(system)?).join("backups");
+    fs::create_dir_all(&directory)?;
+    Ok(directory)
+}
+
+/// Copies the file into the invocation's directory, unless it already has, returning the copy.
+fn copy_into(
+    backups: &Path,
+    run: u32,
+    taken: &str,
+    file: &str,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let copy = backups.join(format!("{run}-{taken}")).join(relative(file));
+    if copy.exists() {
+        return Ok(None);
+    }
+    if let Some(parent) = copy.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::copy(file, &copy)?;
+    Ok(Some(copy))
+}
+
+/// Returns every backup, ordered by run, when it was taken and then file.
+fn find(backups: &Path) -> Result<Vec<Backup>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    for run_directory in run_directories(backups)? {
+        for copy in files(&run_directory.path)? {
+            let file = Path::new("/").join(copy.strip_prefix(&run_directory.path)?);
+            found.push(Backup {
+                run: run_directory.run,
+                taken: run_directory.taken.clone(),
+                file: file.to_string_lossy().to_string(),
+                copy,
+            });
+        }
+    }
+    found.sort_by(|a, b| (a.run, &a.taken, &a.file).cmp(&(b.run, &b.taken, &b.file)));
+    Ok(found)
+}
+
+/// Returns the latest backup of the file, of those taken by the run when it's given.
+fn select<'b>(backups: &'b [Backup], file: &str, run: Option<u32>) -> Option<&'b Backup> {
+    backups
+        .iter()
+        .filter(|backup| backup.file == file)
+        .filter(|backup| run.is_none_or(|run| backup.run == run))
+        .max_by_key(|backup| &backup.taken)
+}
+
+fn run_directories(backups: &Path) -> Result<Vec<RunDirectory>, Box<dyn Error>> {
+    if !backups.exists() {
+        return Ok(Vec::new());
+    }
+    let mut run_directories = Vec::new();
+    for entry in fs::read_dir(backups)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some((run, taken)) = name.split_once('-') {
+            if let Ok(run) = run.parse() {
+                run_directories.push(RunDirectory {
+                    run,
+                    taken: taken.to_string(),
+                    path,
+                });
+            }
+        }
+    }
+    Ok(run_directories)
+}
+
+fn files(directory: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(files(&path)?);
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+/// Returns the path of the file without its root, so it can be kept under a run's directory.
+fn relative(file: &str) -> PathBuf {
+    Path::new(file)
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Formats the seconds since the epoch as a UTC timestamp, e.g. `20240131T120000Z`.
+fn timestamp(seconds: u64) -> String {
+    let days = (seconds / 86400) as i64;
+    let time = seconds % 86400;
+    // Converts the days since the epoch to a civil date, shifting the year to start in March so
+    // leap days fall at its end.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn backups(name: &str) -> PathBuf {
+        let backups = env::temp_dir().join(format!("dotfiles-backup-test-{name}"));
+        let _ = fs::remove_dir_all(&backups);
+        backups
+    }
+
+    #[test]
+    fn test_timestamp_is_utc() {
+        assert_eq!(timestamp(0), "19700101T000000Z");
+        assert_eq!(timestamp(951_782_400), "20000229T000000Z");
+        assert_eq!(timestamp(1_706_702_400), "20240131T120000Z");
+    }
+
+    #[test]
+    fn test_relative_drops_the_root() {
+        assert_eq!(
+            relative("/home/user/.zshrc"),
+            PathBuf::from("home/user/.zshrc")
+        );
+    }
+
+    #[test]
+    fn test_copy_into_keeps_the_first_copy_in_an_invocation() {
+        let backups = backups("first-copy");
+        let file = env::temp_dir().join("dotfiles-backup-test-first-copy.zshrc");
+        let file = file.to_string_lossy().to_string();
+        fs::write(&file, "hand edit").unwrap();
+
+        let copy = copy_into(&backups, 42, "20240131T120000Z", &file)
+            .unwrap()
+            .unwrap();
+        fs::write(&file, "written by the run").unwrap();
+
+        assert_eq!(
+            copy,
+            backups.join("42-20240131T120000Z").join(relative(&file))
+        );
+        assert_eq!(
+            copy_into(&backups, 42, "20240131T120000Z", &file).unwrap(),
+            None
+        );
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "hand edit");
+    }
+
+    #[test]
+    fn test_copy_into_keeps_each_invocation_outside_runs_apart() {
+        let backups = backups("outside-runs");
+        let file = env::temp_dir().join("dotfiles-backup-test-outside-runs.zshrc");
+        let file = file.to_string_lossy().to_string();
+        fs::write(&file, "hand edit").unwrap();
+        copy_into(&backups, 0, "20240131T120000Z", &file).unwrap();
+        fs::write(&file, "restored").unwrap();
+        copy_into(&backups, 0, "20240131T130000Z", &file).unwrap();
+
+        let found = find(&backups).unwrap();
+
+        assert_eq!(found.len(), 2);
+        let latest = select(&found, &file, Some(0)).unwrap();
+        assert_eq!(latest.taken, "20240131T130000Z");
+        assert_eq!(fs::read_to_string(&latest.copy).unwrap(), "restored");
+    }
+
+    #[test]
+    fn test_find_and_select_backups() {
+        let backups = backups("select");
+        let file = env::temp_dir().join("dotfiles-backup-test-select.zshrc");
+        let file = file.to_string_lossy().to_string();
+        fs::write(&file, "run 41").unwrap();
+        copy_into(&backups, 41, "20240130T120000Z", &file).unwrap();
+        fs::write(&file, "run 42").unwrap();
+        copy_into(&backups, 42, "20240131T120000Z", &file).unwrap();
+
+        let found = find(&backups).unwrap();
+
+        assert_eq!(
+            found.iter().map(|backup| backup.run).collect::<Vec<u32>>(),
+            vec![41, 42]
+        );
+        assert_eq!(found[0].file, file);
+        assert_eq!(found[0].taken, "20240130T120000Z");
+        assert_eq!(select(&found, &file, None), Some(&found[1]));
+        assert_eq!(select(&found, &file, Some(41)), Some(&found[0]));
+        assert_eq!(select(&found, &file, Some(40)), None);
+        assert_eq!(select(&found, "/home/user/.tmux.conf", None), None);
+    }
+}
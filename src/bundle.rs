@@ -0,0 +1,213 @@
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Command, Config};
+use crate::error;
+use crate::state;
+
+/// The package databases copied into bundles, so packages resolve the same way offline.
+static PACKAGE_LISTS: [&str; 2] = ["/var/lib/apt/lists", "/var/lib/pacman/sync"];
+
+/// A directory of everything an install downloads, so it can be run without a network.
+///
+/// Holds the direct downloads in `downloads/`, as a cache of the same shape as
+/// `~/.cache/dotfiles`, the distro packages in `packages/`, the AUR snapshots in `aur/`, the
+/// sources they build from in `sources/`, the package databases in `lists/` and the resolved
+/// release of each direct download in `releases.toml`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Bundle<'b> {
+    /// `dotfiles bundle` is fetching into the directory.
+    Creating(&'b str),
+    /// `dotfiles install --from-bundle` is installing from the directory.
+    InstallingFrom(&'b str),
+}
+
+impl Bundle<'_> {
+    fn directory(&self) -> &str {
+        match self {
+            Bundle::Creating(directory) | Bundle::InstallingFrom(directory) => directory,
+        }
+    }
+
+    pub(crate) fn downloads(&self) -> PathBuf {
+        Path::new(self.directory()).join("downloads")
+    }
+
+    pub(crate) fn packages(&self) -> String {
+        format!("{}/packages", self.directory())
+    }
+
+    pub(crate) fn aur(&self) -> String {
+        format!("{}/aur", self.directory())
+    }
+
+    /// Where `makepkg` keeps the sources of the AUR packages, as its `SRCDEST`.
+    pub(crate) fn sources(&self) -> String {
+        format!("{}/sources", self.directory())
+    }
+
+    /// The file recording the release resolved for each direct download, keyed by its asset.
+    pub(crate) fn releases_file(&self) -> PathBuf {
+        Path::new(self.directory()).join("releases.toml")
+    }
+
+    fn lists(&self, path: &str) -> PathBuf {
+        Path::new(self.directory())
+            .join("lists")
+            .join(path.trim_start_matches('/').replace('/', "-"))
+    }
+
+    /// Copies the package database from the bundle into place, instead of syncing it.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use bundle;
+    ///
+    /// bundle.restore_package_lists("/var/lib/pacman/sync")?;
+    /// ```
+    pub(crate) fn restore_package_lists(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let lists = self.lists(path);
+        if !lists.exists() {
+            return Err(Box::new(error::Error::new(&format!(
+                "The bundle has no copy of {path}"
+            ))));
+        }
+        info!("Restoring {} from the bundle", path);
+        copy_directory(&lists, Path::new(path))
+    }
+}
+
+/// Returns the bundle the run is creating or installing from, if any.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use bundle::{self, Bundle};
+///
+/// if let Some(Bundle::Creating(directory)) = bundle::of(&config) {
+///     info!("Fetching into {}", directory);
+/// }
+/// ```
+pub(crate) fn of(config: &Config) -> Option<Bundle<'_>> {
+    match (&config.command, &config.from_bundle) {
+        (Command::Bundle(directory), _) => Some(Bundle::Creating(directory)),
+        (_, Some(directory)) => Some(Bundle::InstallingFrom(directory)),
+        _ => None,
+    }
+}
+
+/// Creates the directories of the bundle, ready for the steps to fetch into.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use bundle;
+///
+/// bundle::prepare("/mnt/usb/bundle")?;
+/// ```
+pub(crate) fn prepare(directory: &str) -> Result<(), Box<dyn Error>> {
+    let bundle = Bundle::Creating(directory);
+    fs::create_dir_all(bundle.downloads())?;
+    // apt needs somewhere to download partial packages to.
+    fs::create_dir_all(format!("{}/partial", bundle.packages()))?;
+    fs::create_dir_all(bundle.aur())?;
+    // makepkg downloads the sources as the user.
+    fs::create_dir_all(bundle.sources())?;
+    state::give_to_user(&bundle.sources())?;
+    Ok(())
+}
+
+/// Finishes the bundle by copying in the package databases the packages were resolved against.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use bundle;
+///
+/// bundle::finish("/mnt/usb/bundle")?;
+/// ```
+pub(crate) fn finish(directory: &str) -> Result<(), Box<dyn Error>> {
+    let bundle = Bundle::Creating(directory);
+    for path in PACKAGE_LISTS {
+        if Path::new(path).is_dir() {
+            copy_directory(Path::new(path), &bundle.lists(path))?;
+        }
+    }
+    info!("Bundled everything into {}", directory);
+    Ok(())
+}
+
+/// Copies the directory's contents, leaving out lock files that belong to the running system.
+fn copy_directory(from: &Path, to: &Path) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_directory(&entry.path(), &target)?;
+        } else if entry.file_name() != "lock" {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> String {
+        let directory = std::env::temp_dir().join(format!("dotfiles-bundle-test-{name}"));
+        let _ = fs::remove_dir_all(&directory);
+        directory.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_of_bundle_command_is_creating() {
+        let config = crate::config::parse(vec![
+            "dotfiles".to_string(),
+            "bundle".to_string(),
+            "--out".to_string(),
+            "/mnt/usb/bundle".to_string(),
+        ]);
+
+        assert_eq!(of(&config), Some(Bundle::Creating("/mnt/usb/bundle")));
+    }
+
+    #[test]
+    fn test_of_from_bundle_is_installing() {
+        let config = crate::config::parse(vec![
+            "--from-bundle".to_string(),
+            "/mnt/usb/bundle".to_string(),
+        ]);
+
+        assert_eq!(of(&config), Some(Bundle::InstallingFrom("/mnt/usb/bundle")));
+        assert_eq!(of(&crate::config::parse(Vec::new())), None);
+    }
+
+    #[test]
+    fn test_copy_directory_skips_lock_files() {
+        let from = PathBuf::from(directory("copy-from"));
+        let to = PathBuf::from(directory("copy-to"));
+        fs::create_dir_all(from.join("partial")).unwrap();
+        fs::write(from.join("core.db"), "core").unwrap();
+        fs::write(from.join("lock"), "").unwrap();
+
+        copy_directory(&from, &to).unwrap();
+
+        assert_eq!(fs::read_to_string(to.join("core.db")).unwrap(), "core");
+        assert!(to.join("partial").is_dir());
+        assert!(!to.join("lock").exists());
+    }
+}
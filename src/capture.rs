@@ -0,0 +1,718 @@
+use log::info;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::config;
+use crate::error;
+use crate::install;
+use crate::profile::Packages;
+use crate::state::{self, Change, Ledger};
+use crate::system::System;
+
+/// The groups of steps, named after the flags that select them.
+static GROUPS: [&str; 17] = [
+    "browsers",
+    "development",
+    "docker",
+    "gaming",
+    "gcp",
+    "images",
+    "infrastructure",
+    "laptop",
+    "modelling",
+    "personal",
+    "printer",
+    "recording",
+    "ripping",
+    "video",
+    "video-editing",
+    "vm",
+    "vpn",
+];
+
+/// The packages, AUR packages and flatpaks each step installs across the backends, used to map
+/// packages back to steps on machines that weren't set up by dotfiles. The tests check it against
+/// the packages each Linux backend's steps install, so the two can't drift apart.
+static CATALOG: &[(&str, &[&str])] = &[
+    ("android-studio", &["android-studio"]),
+    ("archiver", &["ark", "file-roller"]),
+    (
+        "audacity",
+        &["audacity", "ffmpeg", "lame", "org.audacityteam.Audacity"],
+    ),
+    (
+        "bambu-studio",
+        &["bambustudio-bin", "com.bambulab.BambuStudio"],
+    ),
+    ("bash", &["bash", "bash-completion"]),
+    ("blender", &["blender", "org.blender.Blender"]),
+    (
+        "bluetooth",
+        &[
+            "bluez",
+            "bluez-utils",
+            "pulseaudio-bluetooth",
+            "pulseaudio-module-bluetooth",
+        ],
+    ),
+    ("calibre", &["calibre", "com.calibre_ebook.calibre"]),
+    (
+        "codecs",
+        &[
+            "flac",
+            "gst-libav",
+            "gst-plugins-ugly",
+            "libaacs",
+            "libaacs0",
+            "libbluray",
+            "libbluray-bdj",
+            "libbluray1",
+            "libdvd-pkg",
+            "libdvdcss",
+            "libdvdnav",
+            "libdvdread",
+            "libmpeg2",
+            "libtheora",
+            "libvpx",
+            "svt-av1",
+            "x264",
+            "x265",
+            "xvidcore",
+        ],
+    ),
+    ("cplusplus", &["cmake", "g++", "gcc", "make"]),
+    (
+        "cryptomator",
+        &["cryptomator", "org.cryptomator.Cryptomator"],
+    ),
+    ("curl", &["curl"]),
+    (
+        "davinci-resolve",
+        &["davinci-resolve-studio", "qt5-webengine"],
+    ),
+    (
+        "development-extras",
+        &[
+            "build-base",
+            "build-essential",
+            "libssl-dev",
+            "openssl-dev",
+            "pkg-config",
+            "pkgconf",
+        ],
+    ),
+    ("discord", &["com.discordapp.Discord", "discord"]),
+    ("disk-usage-analyser", &["baobab", "filelight"]),
+    (
+        "docker",
+        &[
+            "docker",
+            "docker-cli",
+            "docker-cli-compose",
+            "docker-compose",
+        ],
+    ),
+    (
+        "epic-games",
+        &["com.heroicgameslauncher.hgl", "heroic-games-launcher-bin"],
+    ),
+    ("exercism", &["exercism-bin"]),
+    ("firefox", &["firefox", "org.mozilla.firefox"]),
+    ("firmware-updater", &["fwupd"]),
+    ("gimp", &["gimp", "org.gimp.GIMP"]),
+    ("git", &["git"]),
+    ("godot", &["godot", "godot-mono"]),
+    ("google-chrome", &["com.google.Chrome", "google-chrome"]),
+    ("google-cloud-sdk", &["google-cloud-sdk"]),
+    ("gpg", &["gnupg", "seahorse", "seahorse-nautilus"]),
+    ("gradle", &["gradle"]),
+    (
+        "graphic-card-laptop-tools",
+        &["nvidia-prime", "xf86-video-intel"],
+    ),
+    (
+        "graphic-card-tools",
+        &[
+            "dkms",
+            "lib32-nvidia-580xx-utils",
+            "lib32-nvidia-utils",
+            "lib32-vulkan-icd-loader",
+            "linux-headers",
+            "nvidia-580xx-dkms",
+            "nvidia-580xx-settings",
+            "nvidia-580xx-utils",
+            "nvidia-open",
+            "nvidia-settings",
+            "nvidia-utils",
+            "opencl-nvidia",
+            "opencl-nvidia-580xx",
+            "ubuntu-drivers-common",
+            "vulkan-icd-loader",
+        ],
+    ),
+    ("gramps", &["gramps", "org.gramps_project.Gramps"]),
+    ("groovy", &["groovy"]),
+    ("handbrake", &["fr.handbrake.ghb", "handbrake"]),
+    ("helm", &["helm"]),
+    ("inkscape", &["inkscape", "org.inkscape.Inkscape"]),
+    (
+        "insync",
+        &[
+            "insync",
+            "insync-dolphin",
+            "insync-emblem-icons",
+            "insync-nautilus",
+        ],
+    ),
+    (
+        "intellij",
+        &["intellij-idea-ultimate", "intellij-idea-ultimate-edition"],
+    ),
+    ("jdk", &["jdk-openjdk", "openjdk-24-jdk", "openjdk21-jdk"]),
+    ("keepassxc", &["keepassxc", "org.keepassxc.KeePassXC"]),
+    ("kubectl", &["kubectl"]),
+    (
+        "latex",
+        &[
+            "perl-file-homedir",
+            "perl-yaml-tiny",
+            "texlive",
+            "texlive-extra-utils",
+        ],
+    ),
+    ("lutris", &["lutris", "net.lutris.Lutris"]),
+    (
+        "makemkv",
+        &["ccextractor", "makemkv", "makemkv-bin", "makemkv-oss"],
+    ),
+    ("maven", &["maven"]),
+    (
+        "microcode",
+        &[
+            "amd-microcode",
+            "amd-ucode",
+            "intel-microcode",
+            "intel-ucode",
+        ],
+    ),
+    (
+        "microsoft-edge",
+        &[
+            "com.microsoft.Edge",
+            "microsoft-edge-stable",
+            "microsoft-edge-stable-bin",
+        ],
+    ),
+    (
+        "mkvtoolnix",
+        &["mkvtoolnix-gui", "org.bunkus.mkvtoolnix-gui"],
+    ),
+    (
+        "networking-tools",
+        &["bind-tools", "inetutils", "iputils", "nmap"],
+    ),
+    ("nodejs", &["nodejs", "npm", "nvm"]),
+    (
+        "nordvpn",
+        &[
+            "gnome-shell-extension-nordvpn-connect-git",
+            "nordvpn",
+            "nordvpn-bin",
+            "ocs-url",
+            "plasma6-runners-nordvpn",
+        ],
+    ),
+    (
+        "obs-studio",
+        &["com.obsproject.Studio", "obs-studio", "qt6-wayland"],
+    ),
+    (
+        "office",
+        &[
+            "hunspell",
+            "hunspell-en-gb",
+            "hunspell-en_gb",
+            "hyphen",
+            "hyphen-en",
+            "hyphen-en-gb",
+            "libreoffice",
+            "libreoffice-fresh",
+            "org.libreoffice.LibreOffice",
+        ],
+    ),
+    ("openscad", &["openscad-git", "org.openscad.OpenSCAD"]),
+    ("powertop", &["powertop"]),
+    (
+        "printer-drivers",
+        &[
+            "avahi",
+            "avahi-daemon",
+            "cups",
+            "epson-inkjet-printer-escpr",
+            "ink",
+            "system-config-printer",
+        ],
+    ),
+    ("python", &["py3-pip", "python", "python3"]),
+    (
+        "retroarch",
+        &[
+            "libretro-beetle-psx-hw",
+            "libretro-desmume",
+            "libretro-gambatte",
+            "libretro-genesis-plus-gx",
+            "libretro-mgba",
+            "libretro-mupen64plus-next",
+            "libretro-ppsspp",
+            "libretro-snes9x",
+            "libretro-yabause",
+            "org.libretro.RetroArch",
+            "retroarch",
+        ],
+    ),
+    ("rust", &["rustup"]),
+    ("rust-rover", &["rustrover"]),
+    ("setup-nas", &["cifs-utils"]),
+    ("slack", &["com.slack.Slack", "slack", "slack-desktop"]),
+    (
+        "steam",
+        &["com.valvesoftware.Steam", "steam", "steam-installer"],
+    ),
+    ("sweet-home-3d", &["sweethome3d"]),
+    ("syncthing", &["syncthing"]),
+    (
+        "system-extras",
+        &[
+            "alsa-base",
+            "alsa-utils",
+            "base-devel",
+            "coreutils",
+            "exfat-utils",
+            "flatpak",
+            "fonts-dejavu",
+            "fonts-liberation",
+            "fonts-noto",
+            "fonts-noto-cjk",
+            "fonts-roboto",
+            "lib32-pipewire",
+            "man-db",
+            "network-manager",
+            "networkmanager",
+            "noto-fonts",
+            "noto-fonts-cjk",
+            "nss-mdns",
+            "ntfs-3g",
+            "openrc",
+            "pipewire",
+            "pipewire-alsa",
+            "pipewire-pulse",
+            "shadow",
+            "snapd",
+            "software-properties-common",
+            "speech-dispatcher",
+            "ttf-dejavu",
+            "ttf-liberation",
+            "ttf-ms-win11-auto",
+            "ttf-roboto",
+            "ubuntu-restricted-extras",
+            "wireplumber",
+        ],
+    ),
+    (
+        "tauon-music-box",
+        &["python-lynxpresence", "tauon-music-box"],
+    ),
+    ("terraform", &["terraform"]),
+    ("tlp", &["tlp"]),
+    ("tmux", &["tmux", "tmux-bash-completion", "xclip"]),
+    ("vim", &["vim"]),
+    ("vlc", &["org.videolan.VLC", "vlc", "vlc-plugins-extra"]),
+    ("vm-tools", &["open-vm-tools", "open-vm-tools-desktop"]),
+    (
+        "vscode",
+        &["code", "com.visualstudio.code", "visual-studio-code-bin"],
+    ),
+    ("wget", &["wget"]),
+    ("whipper", &["python-pillow", "whipper"]),
+    ("wifi", &["iwd"]),
+    (
+        "window-manager",
+        &[
+            "baloo",
+            "chrome-gnome-shell",
+            "dolphin",
+            "dolphin-plugins",
+            "ffmpegthumbnailer",
+            "ffmpegthumbs",
+            "gnome",
+            "gnome-browser-connector",
+            "gnome-shell-extension-appindicator",
+            "gnome-shell-extension-hidetopbar-git",
+            "gnome-shell-extension-sound-output-device-chooser",
+            "gnome-tweaks",
+            "gwenview",
+            "kde-config-sddm",
+            "kde-plasma-desktop",
+            "kdegraphics-thumbnailers",
+            "kleopatra",
+            "konsole",
+            "ktorrent",
+            "latte-dock",
+            "libappindicator",
+            "libappindicator-gtk3",
+            "libcanberra",
+            "libcanberra0",
+            "network-manager-gnome",
+            "okular",
+            "phonon-qt6-vlc",
+            "plasma",
+            "sddm",
+            "sddm-kcm",
+            "ubuntu-desktop-minimal",
+            "xdg-desktop-portal-gnome",
+            "xdg-desktop-portal-kde",
+        ],
+    ),
+    ("wine", &["wine"]),
+    (
+        "xbox-streaming",
+        &["greenlight-bin", "io.github.unknownskl.greenlight"],
+    ),
+    ("zsh", &["zsh", "zsh-completions", "zsh-vcs"]),
+];
+
+/// The profile written by a capture.
+#[derive(Debug, PartialEq, Serialize)]
+struct Captured {
+    groups: Vec<&'static str>,
+    extras: Packages,
+}
+
+/// Writes a profile of the machine to the path, selecting the groups of the steps that installed
+/// the explicitly installed packages, and listing the packages no step installs as extras.
+///
+/// Packages are matched to steps by what earlier runs recorded, then by what each step installs.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use capture;
+///
+/// capture::capture(&system, "workstation.toml")?;
+/// ```
+pub(crate) fn capture(system: &dyn System, path: &str) -> Result<(), Box<dyn Error>> {
+    if Path::new(path).exists() {
+        return Err(Box::new(error::Error::new(&format!(
+            "Not overwriting the existing {path}"
+        ))));
+    }
+    let installed = system.list_installed()?;
+    let recorded = recorded_steps(&Ledger::load(system)?);
+    let (steps, extras) = assign(&installed, &recorded);
+    let groups = groups(&steps);
+    info!(
+        "Matched the installed packages to {} steps in the groups {}",
+        steps.len(),
+        groups.join(", ")
+    );
+    info!(
+        "Adding {} packages, {} AUR packages, {} flatpaks and {} snaps that no step installs as extras",
+        extras.packages.len(),
+        extras.aur.len(),
+        extras.flatpaks.len(),
+        extras.snaps.len() + extras.classic_snaps.len()
+    );
+    fs::write(path, toml::to_string(&Captured { groups, extras })?)?;
+    state::give_to_user(path)?;
+    info!("Wrote the profile to {}", path);
+    Ok(())
+}
+
+/// Returns the packages and flatpaks the step installs across the backends, any one of which
+/// being installed shows the step has been.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use capture;
+///
+/// let packages = capture::catalogued("archiver");
+/// ```
+pub(crate) fn catalogued(step: &str) -> Option<&'static [&'static str]> {
+    CATALOG
+        .iter()
+        .find(|(catalogued, _)| *catalogued == step)
+        .map(|(_, packages)| *packages)
+}
+
+/// Returns the step that recorded installing each package, flatpak or snap.
+fn recorded_steps(ledger: &Ledger) -> BTreeMap<String, String> {
+    let mut recorded = BTreeMap::new();
+    for (step, changes) in &ledger.steps {
+        for change in &changes.changes {
+            if let Change::Package { name: package }
+            | Change::Flatpak {
+                application: package,
+                ..
+            }
+            | Change::Snap { name: package } = change
+            {
+                recorded.insert(package.clone(), step.clone());
+            }
+        }
+    }
+    recorded
+}
+
+/// Splits the installed packages into the steps that install them, and the extras that no step
+/// installs.
+fn assign(
+    installed: &Packages,
+    recorded: &BTreeMap<String, String>,
+) -> (BTreeSet<String>, Packages) {
+    let mut steps = BTreeSet::new();
+    let mut assign_each = |packages: &[String]| -> Vec<String> {
+        let mut extras = Vec::new();
+        for package in packages {
+            let step = recorded.get(package).cloned().or_else(|| {
+                CATALOG
+                    .iter()
+                    .find(|(_, packages)| packages.contains(&package.as_str()))
+                    .map(|(step, _)| step.to_string())
+            });
+            match step {
+                Some(step) => {
+                    steps.insert(step);
+                }
+                None => extras.push(package.clone()),
+            }
+        }
+        extras
+    };
+    let extras = Packages {
+        aur: assign_each(&installed.aur),
+        classic_snaps: assign_each(&installed.classic_snaps),
+        flatpaks: assign_each(&installed.flatpaks),
+        packages: assign_each(&installed.packages),
+        snaps: assign_each(&installed.snaps),
+    };
+    (steps, extras)
+}
+
+/// Returns the groups that select any of the steps, beyond the steps every install runs.
+fn groups(steps: &BTreeSet<String>) -> Vec<&'static str> {
+    let always = install::selected_steps(&config::parse(Vec::new()));
+    GROUPS
+        .into_iter()
+        .filter(|group| {
+            install::selected_steps(&config::parse(vec![format!("--{group}")]))
+                .iter()
+                .filter(|step| !always.contains(step))
+                .any(|step| steps.contains(*step))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn test_assign_uses_recorded_steps_then_catalog() {
+        let installed = Packages {
+            aur: strings(&["nordvpn-bin", "yay-bin"]),
+            classic_snaps: Vec::new(),
+            flatpaks: strings(&["com.valvesoftware.Steam"]),
+            packages: strings(&["htop", "my-font", "vim"]),
+            snaps: Vec::new(),
+        };
+        let recorded = BTreeMap::from([("my-font".to_string(), "themes".to_string())]);
+
+        let (steps, extras) = assign(&installed, &recorded);
+
+        assert_eq!(
+            steps,
+            BTreeSet::from(["nordvpn", "steam", "themes", "vim"].map(|step| step.to_string()))
+        );
+        assert_eq!(
+            extras,
+            Packages {
+                aur: strings(&["yay-bin"]),
+                classic_snaps: Vec::new(),
+                flatpaks: Vec::new(),
+                packages: strings(&["htop"]),
+                snaps: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_groups_skips_steps_every_install_runs() {
+        let steps = BTreeSet::from(["nordvpn", "steam", "vim"].map(|step| step.to_string()));
+
+        assert_eq!(groups(&steps), vec!["gaming", "vpn"]);
+    }
+
+    #[test]
+    fn test_catalog_lists_each_package_once() {
+        let mut seen = BTreeSet::new();
+
+        for (step, packages) in CATALOG {
+            for package in *packages {
+                assert!(seen.insert(package), "{package} is listed again by {step}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_catalog_only_has_known_steps() {
+        let every_step = install::selected_steps(&config::parse(
+            GROUPS.iter().map(|group| format!("--{group}")).collect(),
+        ));
+
+        for (step, _) in CATALOG {
+            assert!(every_step.contains(step), "{step} isn't a step");
+        }
+    }
+
+    /// The Linux backends, whose package managers the catalog covers.
+    static BACKENDS: [&str; 3] = [
+        include_str!("alpine.rs"),
+        include_str!("arch.rs"),
+        include_str!("ubuntu.rs"),
+    ];
+
+    /// Returns the body of each function in the source, before its tests, keyed by its name.
+    fn functions(source: &str) -> BTreeMap<&str, &str> {
+        let source = source.split("\nmod tests {").next().unwrap_or_default();
+        source
+            .split("fn ")
+            .skip(1)
+            .filter_map(|function| {
+                let name = function.split(['(', '<']).next()?;
+                Some((name, function))
+            })
+            .collect()
+    }
+
+    /// Returns the packages the function installs by name, along with those of the `install_`
+    /// functions it calls.
+    fn installed_by(functions: &BTreeMap<&str, &str>, name: &str) -> BTreeSet<String> {
+        let Some(body) = functions.get(name) else {
+            return BTreeSet::new();
+        };
+        let mut packages = BTreeSet::new();
+        for call in [
+            "install_application(",
+            "install_applications(",
+            "install_if_preferred(",
+        ] {
+            for arguments in body.split(call).skip(1) {
+                let arguments = arguments.split(')').next().unwrap_or_default();
+                packages.extend(
+                    arguments
+                        .split('"')
+                        .skip(1)
+                        .step_by(2)
+                        // Skips downloaded files and formatted names.
+                        .filter(|package| !package.contains(['/', '{']))
+                        .map(|package| package.to_string()),
+                );
+            }
+        }
+        for call in body.split("self.install_").skip(1) {
+            let helper = format!("install_{}", call.split('(').next().unwrap_or_default());
+            if helper != name && !helper.starts_with("install_application") {
+                packages.extend(installed_by(functions, &helper));
+            }
+        }
+        packages
+    }
+
+    /// Returns the packages the step installs on each of the Linux backends.
+    fn installed_by_step(step: &str) -> Vec<BTreeSet<String>> {
+        let function = step.replace('-', "_");
+        BACKENDS
+            .iter()
+            .map(|source| {
+                // Steps the backend doesn't implement itself use the trait's default.
+                let mut functions = functions(include_str!("system.rs"));
+                functions.extend(self::functions(source));
+                let mut packages = installed_by(&functions, &format!("install_{function}"));
+                packages.extend(installed_by(&functions, &function));
+                packages
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_catalog_lists_what_the_steps_install() {
+        let every_step = install::selected_steps(&config::parse(
+            GROUPS.iter().map(|group| format!("--{group}")).collect(),
+        ));
+
+        let mut uncatalogued = Vec::new();
+        for step in every_step {
+            for package in installed_by_step(step).into_iter().flatten() {
+                if !CATALOG
+                    .iter()
+                    .any(|(_, packages)| packages.contains(&package.as_str()))
+                {
+                    uncatalogued.push(format!("{step}: {package}"));
+                }
+            }
+        }
+
+        assert_eq!(uncatalogued, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_catalog_only_lists_what_the_steps_install() {
+        let mut not_installed = Vec::new();
+        for (step, packages) in CATALOG {
+            let installed = installed_by_step(step);
+            for package in *packages {
+                if !installed.iter().any(|backend| backend.contains(*package)) {
+                    not_installed.push(format!("{step}: {package}"));
+                }
+            }
+        }
+
+        assert_eq!(not_installed, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_recorded_steps_includes_packages_and_flatpaks() {
+        let mut ledger = Ledger::default();
+        ledger.steps.insert(
+            "nordvpn".to_string(),
+            state::Step {
+                run: 1,
+                changes: vec![
+                    Change::Package {
+                        name: "nordvpn-bin".to_string(),
+                    },
+                    Change::Service {
+                        name: "nordvpnd".to_string(),
+                    },
+                ],
+            },
+        );
+
+        assert_eq!(
+            recorded_steps(&ledger),
+            BTreeMap::from([("nordvpn-bin".to_string(), "nordvpn".to_string())])
+        );
+    }
+}
@@ -0,0 +1,315 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::error;
+use crate::system::{self, System};
+
+static BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+static END: &str = "-----END CERTIFICATE-----";
+
+static MAC_JAVA_HOME: &str = "/usr/libexec/java_home";
+static ARCHLINUX_JAVA: &str = "/usr/bin/archlinux-java";
+/// The links Arch, Alpine and Ubuntu keep to their default JDK.
+static DEFAULT_JDKS: [&str; 3] = [
+    "/usr/lib/jvm/default",
+    "/usr/lib/jvm/default-jvm",
+    "/usr/lib/jvm/default-java",
+];
+
+/// A CA certificate to trust, converted to a PEM file in the workspace.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Certificate {
+    /// The name it's stored under, e.g. the alias in Java's keystore, from its file name.
+    pub(crate) name: String,
+    /// The absolute path of the PEM file.
+    pub(crate) file: String,
+    /// The SHA-256 fingerprint, as colon separated hex, e.g. `AB:CD:...`.
+    pub(crate) fingerprint: String,
+}
+
+/// Installs the CA certificates, from files or URLs, into the system's store, the JDK and any
+/// other stores the system has, e.g. NSS databases on Linux, logging the fingerprint of each.
+///
+/// A PEM file with several certificates, like a chain, has each installed under its own name.
+///
+/// It runs before anything is downloaded, so the JDK only gets them when it's already installed;
+/// the certificates returned are added to it with `add_to_java` once it's been installed.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use certificates;
+///
+/// let installed = certificates::install(&config, &system, &profile.certificates).await?;
+/// ```
+pub(crate) async fn install(
+    config: &Config,
+    system: &dyn System,
+    sources: &[String],
+) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut installed = Vec::new();
+    for source in sources {
+        for certificate in fetch(config, source).await? {
+            system.install_certificate(&certificate)?;
+            info!(
+                "Installed the certificate {} with the SHA-256 fingerprint {}",
+                certificate.name, certificate.fingerprint
+            );
+            installed.push(certificate);
+        }
+    }
+    add_to_java(system, &installed)?;
+    Ok(installed)
+}
+
+/// Adds the certificates to the cacerts of the default JDK, if there is one, replacing any earlier
+/// copies, as `keytool` refuses to import an alias twice.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use certificates;
+///
+/// certificates::add_to_java(&system, &installed)?;
+/// ```
+pub(crate) fn add_to_java(
+    system: &dyn System,
+    certificates: &[Certificate],
+) -> Result<(), Box<dyn Error>> {
+    if certificates.is_empty() {
+        return Ok(());
+    }
+    let Some(java_home) = java_home(system) else {
+        debug!("There's no JDK to add the certificates to");
+        return Ok(());
+    };
+    let keytool = format!("{java_home}/bin/keytool");
+    let cacerts = format!("{java_home}/lib/security/cacerts");
+    for certificate in certificates {
+        let options = vec![
+            "-noprompt",
+            "-keystore",
+            &cacerts,
+            "-storepass",
+            "changeit",
+            "-alias",
+            &certificate.name,
+        ];
+        // Fails when there's no earlier copy, which is fine.
+        let _ = system.execute(&format!("{keytool} {} -delete", options.join(" ")), true);
+        let mut import = options.clone();
+        import.extend(["-importcert", "-trustcacerts", "-file", &certificate.file]);
+        system.run(&keytool, import, true)?;
+    }
+    Ok(())
+}
+
+/// Returns the home of the default JDK, as macOS' `java_home` or `archlinux-java` report it, or
+/// the link the distro keeps to it, as `JAVA_HOME` is only set in the shells started afterwards.
+fn java_home(system: &dyn System) -> Option<String> {
+    let mut candidates = Vec::new();
+    if Path::new(MAC_JAVA_HOME).exists() {
+        if let Ok(java_home) = system.query(MAC_JAVA_HOME) {
+            candidates.push(java_home.trim().to_string());
+        }
+    }
+    if Path::new(ARCHLINUX_JAVA).exists() {
+        if let Ok(name) = system.query(&format!("{ARCHLINUX_JAVA} get")) {
+            candidates.push(format!("/usr/lib/jvm/{}", name.trim()));
+        }
+    }
+    candidates.extend(DEFAULT_JDKS.iter().map(|jdk| jdk.to_string()));
+    first_jdk(candidates)
+}
+
+/// Returns the first of the directories that has `keytool`.
+fn first_jdk(candidates: Vec<String>) -> Option<String> {
+    candidates
+        .into_iter()
+        .find(|java_home| Path::new(java_home).join("bin").join("keytool").exists())
+}
+
+/// Reads the certificates from the file or URL, converting them to PEM when it's DER, and naming
+/// them after the file, numbered when there are several.
+async fn fetch(config: &Config, source: &str) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let file_name = source.rsplit('/').next().unwrap_or(source);
+    let contents = if source.starts_with("https://") || source.starts_with("http://") {
+        system::download_file(config, source, file_name).await?;
+        fs::read(file_name)?
+    } else {
+        fs::read(source).map_err(|e| error::Error::new(&format!("Unable to read {source}: {e}")))?
+    };
+    let ders = decode(&contents)
+        .map_err(|e| error::Error::new(&format!("Invalid certificate {source}: {e}")))?;
+    let mut certificates = Vec::new();
+    for (index, der) in ders.iter().enumerate() {
+        let name = match ders.len() {
+            1 => name(file_name),
+            _ => format!("{}-{}", name(file_name), index + 1),
+        };
+        let file = env::current_dir()?.join(format!("{name}.crt"));
+        fs::write(&file, encode(der))?;
+        certificates.push(Certificate {
+            name,
+            file: file.to_string_lossy().to_string(),
+            fingerprint: fingerprint(der),
+        });
+    }
+    Ok(certificates)
+}
+
+/// Returns the DER of each certificate in the PEM, or the contents when they're already DER.
+fn decode(contents: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let text = String::from_utf8_lossy(contents);
+    if !text.contains(BEGIN) {
+        // DER certificates are an ASN.1 sequence, so start with its tag.
+        if contents.first() == Some(&0x30) {
+            return Ok(vec![contents.to_vec()]);
+        }
+        return Err(Box::new(error::Error::new(
+            "It's neither a PEM nor a DER certificate",
+        )));
+    }
+    let mut ders = Vec::new();
+    let mut rest = text.as_ref();
+    while let Some(start) = rest.find(BEGIN) {
+        let body = &rest[start + BEGIN.len()..];
+        let end = body
+            .find(END)
+            .ok_or_else(|| error::Error::new(&format!("It's missing {END}")))?;
+        let base64 = body[..end]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+        ders.push(STANDARD.decode(base64)?);
+        rest = &body[end + END.len()..];
+    }
+    Ok(ders)
+}
+
+fn encode(der: &[u8]) -> String {
+    let base64 = STANDARD.encode(der);
+    let lines = base64
+        .as_bytes()
+        .chunks(64)
+        .map(|line| String::from_utf8_lossy(line).to_string())
+        .collect::<Vec<String>>();
+    format!("{BEGIN}\n{}\n{END}\n", lines.join("\n"))
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Returns the name for the certificate from its file name, leaving out the extension and
+/// anything that can't go in a command.
+fn name(file_name: &str) -> String {
+    let stem = Path::new(file_name)
+        .file_stem()
+        .map_or(file_name.to_string(), |stem| {
+            stem.to_string_lossy().to_string()
+        });
+    stem.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    /// The DER of a certificate is an ASN.1 sequence, which is all that's checked here.
+    static DER: [u8; 6] = [0x30, 0x04, 0x02, 0x02, 0x01, 0x00];
+
+    #[test]
+    fn test_decode_round_trips_pem() {
+        let pem = encode(&DER);
+
+        assert_eq!(
+            pem,
+            "-----BEGIN CERTIFICATE-----\nMAQCAgEA\n-----END CERTIFICATE-----\n"
+        );
+        assert_eq!(decode(pem.as_bytes()).unwrap(), vec![DER.to_vec()]);
+    }
+
+    #[test]
+    fn test_decode_takes_every_certificate() {
+        let pem = format!(
+            "Company Root CA\n{}Company Issuing CA\n{}",
+            encode(&DER),
+            encode(&[0x30, 0x00])
+        );
+
+        assert_eq!(
+            decode(pem.as_bytes()).unwrap(),
+            vec![DER.to_vec(), vec![0x30, 0x00]]
+        );
+    }
+
+    #[test]
+    fn test_decode_accepts_der_and_refuses_anything_else() {
+        assert_eq!(decode(&DER).unwrap(), vec![DER.to_vec()]);
+        assert!(decode(b"not a certificate").is_err());
+        assert!(decode(BEGIN.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_fingerprint_is_colon_separated_sha256() {
+        let fingerprint = fingerprint(&DER);
+
+        assert_eq!(fingerprint.len(), 32 * 3 - 1);
+        assert_eq!(
+            fingerprint.replace(':', "").to_lowercase(),
+            format!("{:x}", Sha256::digest(DER))
+        );
+    }
+
+    #[test]
+    fn test_name_strips_extension_and_unsafe_characters() {
+        assert_eq!(name("company-root.pem"), "company-root");
+        assert_eq!(name("Company Root CA.crt"), "Company-Root-CA");
+    }
+
+    #[test]
+    fn test_first_jdk_skips_directories_without_keytool() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_string());
+        let jre = root.join("jre");
+        let jdk = root.join("jdk");
+        fs::create_dir_all(jre.join("bin")).unwrap();
+        fs::create_dir_all(jdk.join("bin")).unwrap();
+        fs::write(jdk.join("bin").join("keytool"), "").unwrap();
+        let candidates = [root.join("missing"), jre, jdk.clone()]
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+
+        assert_eq!(
+            first_jdk(candidates),
+            Some(jdk.to_string_lossy().to_string())
+        );
+        assert_eq!(first_jdk(vec![]), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
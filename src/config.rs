@@ -1,24 +1,76 @@
+use crate::profile::{self, Profile};
+use crate::shell::Shell;
+
+/// The strategy used to build and install packages from the AUR.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum AurHelper {
+    /// Builds the packages directly from the AUR snapshots with `makepkg`.
+    Makepkg,
+    Paru,
+    Yay,
+}
+
+/// What to do, given by the first argument, installing when it isn't a known command.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Command {
+    /// Lists or restores the files backed up before runs overwrote them.
+    Backups(Backups),
+    /// Fetches everything the selected steps download into the directory, without installing.
+    Bundle(String),
+    /// Writes a profile of what is installed on the machine to the path.
+    Capture(String),
+    Install,
+    /// Lists the snapshots taken before each run, or restores the one taken before the run.
+    Rollback(Option<u32>),
+    /// Reports how the machine has drifted from the selected steps.
+    Status,
+    /// Reverses everything the step recorded, e.g. `nordvpn` or `sweet-home-3d`.
+    Uninstall(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Backups {
+    List,
+    /// Restores the file from the backup taken by the run, or the latest backup of it.
+    Restore {
+        file: String,
+        run: Option<u32>,
+    },
+}
+
 #[derive(Debug, PartialEq)]
 pub(crate) struct Config {
+    pub aur_helper: AurHelper,
     pub browsers: bool,
     pub cli_only: bool,
+    pub command: Command,
     pub debug: bool,
     pub development: bool,
     pub docker: bool,
     pub dry_run: bool,
+    pub flatpak_user: bool,
+    /// The bundle to install from instead of the network.
+    pub from_bundle: Option<String>,
     pub gaming: bool,
     pub gcp: bool,
     pub gnome: bool,
     pub infrastructure: bool,
     pub images: bool,
     pub kde: bool,
+    pub keep_workspace: bool,
     pub help: bool,
     pub laptop: bool,
+    pub local_repo: Option<String>,
+    pub mirror_countries: Vec<String>,
     pub modelling: bool,
     pub personal: bool,
+    pub prefer_flatpak: bool,
     pub printer: bool,
+    pub profile: Option<Profile>,
     pub recording: bool,
     pub ripping: bool,
+    /// The user's login shell, which is also set up.
+    pub shell: Shell,
     pub video: bool,
     pub video_editing: bool,
     pub vm: bool,
@@ -27,34 +79,125 @@ pub(crate) struct Config {
 }
 
 pub(crate) fn parse(args: Vec<String>) -> Config {
+    let profile = value(&args, "--profile").map(|path| {
+        profile::load(&path).unwrap_or_else(|e| panic!("Unable to load the profile {path}: {e}"))
+    });
+    let profile_groups = profile
+        .as_ref()
+        .map(|profile| profile.groups.clone())
+        .unwrap_or_default();
+    let flatpak = profile
+        .as_ref()
+        .map(|profile| (profile.flatpak.prefer, profile.flatpak.user))
+        .unwrap_or_default();
+    let group = |name: &str| {
+        args.contains(&format!("--{name}")) || profile_groups.iter().any(|group| group == name)
+    };
     Config {
-        browsers: args.contains(&"--browsers".to_string()),
+        aur_helper: match value(&args, "--aur-helper").as_deref() {
+            None | Some("yay") => AurHelper::Yay,
+            Some("paru") => AurHelper::Paru,
+            Some("makepkg") => AurHelper::Makepkg,
+            Some(helper) => panic!("Unknown AUR helper {helper}."),
+        },
+        browsers: group("browsers"),
         cli_only: args.contains(&"--cli-only".to_string()),
+        command: match args.get(1).map(String::as_str) {
+            Some("backups") => Command::Backups(match args.get(2).map(String::as_str) {
+                Some("restore") => Backups::Restore {
+                    file: absolute(
+                        args.get(3)
+                            .filter(|file| !file.starts_with("--"))
+                            .unwrap_or_else(|| panic!("Need the file to restore.")),
+                    ),
+                    run: value(&args, "--run").map(|run| {
+                        run.parse()
+                            .unwrap_or_else(|e| panic!("Invalid run {run}: {e}"))
+                    }),
+                },
+                _ => Backups::List,
+            }),
+            Some("bundle") => Command::Bundle(absolute(
+                &value(&args, "--out").unwrap_or_else(|| "bundle".to_string()),
+            )),
+            Some("capture") => Command::Capture(
+                value(&args, "--out").unwrap_or_else(|| "profile.toml".to_string()),
+            ),
+            Some("rollback") => {
+                Command::Rollback(args.get(2).filter(|run| !run.starts_with("--")).map(|run| {
+                    run.parse()
+                        .unwrap_or_else(|e| panic!("Invalid run {run}: {e}"))
+                }))
+            }
+            Some("status") => Command::Status,
+            Some("uninstall") => Command::Uninstall(
+                args.get(2)
+                    .filter(|step| !step.starts_with("--"))
+                    .unwrap_or_else(|| panic!("Need the application to uninstall."))
+                    .clone(),
+            ),
+            _ => Command::Install,
+        },
         debug: args.contains(&"--debug".to_string()),
-        development: args.contains(&"--development".to_string()),
-        docker: args.contains(&"--docker".to_string()),
-        dry_run: args.contains(&"--dry-run".to_string()),
-        gaming: args.contains(&"--gaming".to_string()),
-        gcp: args.contains(&"--gcp".to_string()),
+        development: group("development"),
+        docker: group("docker"),
+        dry_run: args.contains(&"--dry-run".to_string())
+            || args.get(1).is_some_and(|command| command == "bundle"),
+        flatpak_user: args.contains(&"--flatpak-user".to_string()) || flatpak.1,
+        from_bundle: value(&args, "--from-bundle").map(|path| absolute(&path)),
+        gaming: group("gaming"),
+        gcp: group("gcp"),
         gnome: args.contains(&"--gnome".to_string()),
         help: args.contains(&"--help".to_string()),
-        images: args.contains(&"--images".to_string()),
-        infrastructure: args.contains(&"--infrastructure".to_string()),
+        images: group("images"),
+        infrastructure: group("infrastructure"),
         kde: args.contains(&"--kde".to_string()),
-        laptop: args.contains(&"--laptop".to_string()),
-        modelling: args.contains(&"--modelling".to_string()),
-        personal: args.contains(&"--personal".to_string()),
-        printer: args.contains(&"--printer".to_string()),
-        recording: args.contains(&"--recording".to_string()),
-        ripping: args.contains(&"--ripping".to_string()),
-        video: args.contains(&"--video".to_string()),
-        video_editing: args.contains(&"--video-editing".to_string()),
-        vm: args.contains(&"--vm".to_string()),
-        vpn: args.contains(&"--vpn".to_string()),
+        keep_workspace: args.contains(&"--keep-workspace".to_string()),
+        laptop: group("laptop"),
+        local_repo: value(&args, "--local-repo").map(|path| absolute(&path)),
+        mirror_countries: value(&args, "--mirror-countries")
+            .map(|countries| {
+                countries
+                    .split(',')
+                    .map(|country| country.trim().to_uppercase())
+                    .filter(|country| !country.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        modelling: group("modelling"),
+        personal: group("personal"),
+        prefer_flatpak: args.contains(&"--prefer-flatpak".to_string()) || flatpak.0,
+        printer: group("printer"),
+        profile,
+        recording: group("recording"),
+        ripping: group("ripping"),
+        shell: value(&args, "--shell")
+            .map(|shell| shell.parse().unwrap_or_else(|e| panic!("{e}.")))
+            .unwrap_or_default(),
+        video: group("video"),
+        video_editing: group("video-editing"),
+        vm: group("vm"),
+        vpn: group("vpn"),
         wsl: !args.contains(&"--not-wsl".to_string()),
     }
 }
 
+/// Returns the value following the given argument, e.g. `paru` for `--aur-helper paru`.
+fn value(args: &[String], name: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .cloned()
+}
+
+/// Resolves the path against the current directory, as installs change into their workspace.
+fn absolute(path: &str) -> String {
+    std::path::absolute(path)
+        .unwrap_or_else(|e| panic!("Unable to resolve {path}: {e}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -62,11 +205,14 @@ mod tests {
     #[test]
     fn parse_sets_correctly_for_empty_args() {
         let config = parse(vec![]);
+        assert_eq!(config.aur_helper, AurHelper::Yay);
+        assert_eq!(config.command, Command::Install);
         assert!(!config.browsers);
         assert!(!config.cli_only);
         assert!(!config.development);
         assert!(!config.docker);
         assert!(!config.dry_run);
+        assert!(!config.flatpak_user);
         assert!(!config.gaming);
         assert!(!config.gcp);
         assert!(!config.gnome);
@@ -74,9 +220,14 @@ mod tests {
         assert!(!config.images);
         assert!(!config.infrastructure);
         assert!(!config.kde);
+        assert_eq!(config.from_bundle, None);
+        assert!(!config.keep_workspace);
         assert!(!config.laptop);
+        assert_eq!(config.local_repo, None);
         assert!(!config.modelling);
         assert!(!config.personal);
+        assert!(!config.prefer_flatpak);
+        assert_eq!(config.profile, None);
         assert!(!config.recording);
         assert!(!config.ripping);
         assert!(!config.video);
@@ -86,6 +237,30 @@ mod tests {
         assert!(config.wsl);
     }
 
+    #[test]
+    fn parse_sets_aur_helper_to_paru() {
+        let config = parse(vec!["--aur-helper".to_string(), "paru".to_string()]);
+        assert_eq!(config.aur_helper, AurHelper::Paru);
+    }
+
+    #[test]
+    fn parse_sets_aur_helper_to_makepkg() {
+        let config = parse(vec!["--aur-helper".to_string(), "makepkg".to_string()]);
+        assert_eq!(config.aur_helper, AurHelper::Makepkg);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown AUR helper pikaur.")]
+    fn parse_panics_for_unknown_aur_helper() {
+        parse(vec!["--aur-helper".to_string(), "pikaur".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unable to load the profile missing.toml")]
+    fn parse_panics_for_missing_profile() {
+        parse(vec!["--profile".to_string(), "missing.toml".to_string()]);
+    }
+
     #[test]
     fn parse_sets_browser_to_true() {
         let config = parse(vec!["--browsers".to_string()]);
@@ -116,6 +291,12 @@ mod tests {
         assert!(config.dry_run);
     }
 
+    #[test]
+    fn parse_sets_flatpak_user_to_true() {
+        let config = parse(vec!["--flatpak-user".to_string()]);
+        assert!(config.flatpak_user);
+    }
+
     #[test]
     fn parse_sets_gaming_to_true() {
         let config = parse(vec!["--gaming".to_string()]);
@@ -152,18 +333,139 @@ mod tests {
         assert!(config.infrastructure);
     }
 
+    #[test]
+    fn parse_sets_capture_command() {
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "capture".to_string(),
+            "--out".to_string(),
+            "workstation.toml".to_string(),
+        ]);
+        assert_eq!(
+            config.command,
+            Command::Capture("workstation.toml".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_sets_backups_command() {
+        let config = parse(vec!["dotfiles".to_string(), "backups".to_string()]);
+        assert_eq!(config.command, Command::Backups(Backups::List));
+    }
+
+    #[test]
+    fn parse_sets_backups_restore_command_with_run() {
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "backups".to_string(),
+            "restore".to_string(),
+            "/home/user/.zshrc".to_string(),
+            "--run".to_string(),
+            "42".to_string(),
+        ]);
+        assert_eq!(
+            config.command,
+            Command::Backups(Backups::Restore {
+                file: "/home/user/.zshrc".to_string(),
+                run: Some(42),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_sets_rollback_command() {
+        let config = parse(vec!["dotfiles".to_string(), "rollback".to_string()]);
+        assert_eq!(config.command, Command::Rollback(None));
+    }
+
+    #[test]
+    fn parse_sets_rollback_command_with_run() {
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "rollback".to_string(),
+            "42".to_string(),
+        ]);
+        assert_eq!(config.command, Command::Rollback(Some(42)));
+    }
+
+    #[test]
+    fn parse_sets_status_command() {
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "status".to_string(),
+            "--vpn".to_string(),
+        ]);
+        assert_eq!(config.command, Command::Status);
+        assert!(config.vpn);
+    }
+
+    #[test]
+    fn parse_sets_uninstall_command() {
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "uninstall".to_string(),
+            "nordvpn".to_string(),
+        ]);
+        assert_eq!(config.command, Command::Uninstall("nordvpn".to_string()));
+    }
+
+    #[test]
+    fn parse_sets_bundle_command_as_dry_run() {
+        let config = parse(vec![
+            "dotfiles".to_string(),
+            "bundle".to_string(),
+            "--out".to_string(),
+            "/mnt/usb/bundle".to_string(),
+        ]);
+        assert_eq!(
+            config.command,
+            Command::Bundle("/mnt/usb/bundle".to_string())
+        );
+        assert!(config.dry_run);
+    }
+
+    #[test]
+    fn parse_sets_from_bundle_to_absolute_path() {
+        let config = parse(vec![
+            "--from-bundle".to_string(),
+            "/mnt/usb/bundle".to_string(),
+        ]);
+        assert_eq!(config.from_bundle, Some("/mnt/usb/bundle".to_string()));
+    }
+
     #[test]
     fn parse_sets_kde_to_true() {
         let config = parse(vec!["--kde".to_string()]);
         assert!(config.kde);
     }
 
+    #[test]
+    fn parse_sets_keep_workspace_to_true() {
+        let config = parse(vec!["--keep-workspace".to_string()]);
+        assert!(config.keep_workspace);
+    }
+
     #[test]
     fn parse_sets_laptop_to_true() {
         let config = parse(vec!["--laptop".to_string()]);
         assert!(config.laptop);
     }
 
+    #[test]
+    fn parse_sets_local_repo_to_absolute_path() {
+        let config = parse(vec![
+            "--local-repo".to_string(),
+            "/mnt/nas/pacman".to_string(),
+        ]);
+        assert_eq!(config.local_repo, Some("/mnt/nas/pacman".to_string()));
+    }
+
+    #[test]
+    fn parse_sets_mirror_countries() {
+        let config = parse(vec!["--mirror-countries".to_string(), "gb, DE".to_string()]);
+        assert_eq!(config.mirror_countries, vec!["GB", "DE"]);
+    }
+
     #[test]
     fn parse_sets_modelling_to_true() {
         let config = parse(vec!["--modelling".to_string()]);
@@ -176,6 +478,12 @@ mod tests {
         assert!(config.personal);
     }
 
+    #[test]
+    fn parse_sets_prefer_flatpak_to_true() {
+        let config = parse(vec!["--prefer-flatpak".to_string()]);
+        assert!(config.prefer_flatpak);
+    }
+
     #[test]
     fn parse_sets_recording_to_true() {
         let config = parse(vec!["--recording".to_string()]);
@@ -188,6 +496,19 @@ mod tests {
         assert!(config.ripping);
     }
 
+    #[test]
+    fn parse_sets_shell() {
+        assert_eq!(parse(vec![]).shell, Shell::Zsh);
+        let config = parse(vec!["--shell".to_string(), "fish".to_string()]);
+        assert_eq!(config.shell, Shell::Fish);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown shell csh")]
+    fn parse_panics_for_unknown_shell() {
+        parse(vec!["--shell".to_string(), "csh".to_string()]);
+    }
+
     #[test]
     fn parse_sets_video_to_true() {
         let config = parse(vec!["--video".to_string()]);
@@ -226,6 +547,7 @@ mod tests {
             "--development".to_string(),
             "--docker".to_string(),
             "--dry-run".to_string(),
+            "--flatpak-user".to_string(),
             "--gaming".to_string(),
             "--gcp".to_string(),
             "--help".to_string(),
@@ -233,6 +555,7 @@ mod tests {
             "--laptop".to_string(),
             "--modelling".to_string(),
             "--personal".to_string(),
+            "--prefer-flatpak".to_string(),
             "--printer".to_string(),
             "--recording".to_string(),
             "--ripping".to_string(),
@@ -247,6 +570,7 @@ mod tests {
         assert!(config.development);
         assert!(config.docker);
         assert!(config.dry_run);
+        assert!(config.flatpak_user);
         assert!(config.gaming);
         assert!(config.gcp);
         assert!(config.help);
@@ -254,6 +578,7 @@ mod tests {
         assert!(config.laptop);
         assert!(config.modelling);
         assert!(config.personal);
+        assert!(config.prefer_flatpak);
         assert!(config.printer);
         assert!(config.recording);
         assert!(config.ripping);
@@ -0,0 +1,525 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info};
+use reqwest::header::{
+    HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::{network, state};
+
+/// A content-addressed cache of downloads, so re-runs, and machines sharing the cache, don't
+/// download the same file again.
+///
+/// Files are kept under `sha256/<hash of the contents>`, with `urls/<hash of the URL>` holding
+/// the hash of what the URL last returned along with its `ETag` and `Last-Modified`, so the URL
+/// can be checked for changes. `partial/<hash of the URL>` holds the part of an interrupted
+/// download, with the validators it was started with beside it, so it can be resumed.
+pub(crate) struct Cache {
+    directory: PathBuf,
+}
+
+impl Cache {
+    /// Opens the cache in `~/.cache/dotfiles`, creating it if needed.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use download::Cache;
+    ///
+    /// let cache = Cache::open()?;
+    /// ```
+    pub(crate) fn open() -> Result<Cache, Box<dyn Error>> {
+        let cache = Cache::open_in(Path::new(&home_dir()).join(".cache").join("dotfiles"))?;
+        cache.give_to_user()?;
+        Ok(cache)
+    }
+
+    /// Opens the cache in the directory, creating it if needed, e.g. for the downloads of a bundle.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use download::Cache;
+    ///
+    /// let cache = Cache::open_in(bundle.downloads())?;
+    /// ```
+    pub(crate) fn open_in(directory: PathBuf) -> Result<Cache, Box<dyn Error>> {
+        for subdirectory in ["partial", "sha256", "urls"] {
+            fs::create_dir_all(directory.join(subdirectory))?;
+        }
+        Ok(Cache { directory })
+    }
+
+    /// Hands the cache back to the user running sudo, so it can be shared and cleaned up.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use download::Cache;
+    ///
+    /// cache.give_to_user()?;
+    /// ```
+    pub(crate) fn give_to_user(&self) -> Result<(), Box<dyn Error>> {
+        state::give_to_user(&self.directory.to_string_lossy())
+    }
+
+    /// Returns the cached file for the URL, looking it up by the expected SHA-256 when there is
+    /// one, otherwise by what the URL returned last time, without checking the URL for changes.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use download::Cache;
+    ///
+    /// let cached = Cache::open()?.lookup("https://git.io/get_helm.sh", Some(&sha256));
+    /// ```
+    pub(crate) fn lookup(&self, url: &str, sha256: Option<&str>) -> Option<PathBuf> {
+        let sha256 = match sha256 {
+            Some(sha256) => sha256.trim().to_lowercase(),
+            None => self.entry(url)?.0,
+        };
+        let path = self.directory.join("sha256").join(sha256);
+        path.is_file().then_some(path)
+    }
+
+    /// Downloads the URL into the cache, streaming it to disk with a progress bar and resuming
+    /// any earlier partial download, returning the cached file.
+    ///
+    /// When the URL is already cached, the server is asked whether it has changed since, and the
+    /// cached file is returned when it hasn't.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use download::Cache;
+    ///
+    /// let cached = Cache::open()?.fetch("https://git.io/get_helm.sh").await?;
+    /// ```
+    pub(crate) async fn fetch(&self, url: &str) -> Result<PathBuf, Box<dyn Error>> {
+        let cached = self.entry(url).and_then(|(sha256, validators)| {
+            let path = self.directory.join("sha256").join(sha256);
+            path.is_file().then_some((path, validators))
+        });
+        let partial = self.directory.join("partial").join(hash(url));
+        let Some(validators) = stream(
+            url,
+            &partial,
+            cached.as_ref().map(|(_, validators)| validators),
+        )
+        .await?
+        else {
+            info!("{} hasn't changed, so using the cached copy", url);
+            return Ok(cached.expect("Only cached URLs can be unchanged").0);
+        };
+        let sha256 = state::hash_file(&partial.to_string_lossy())?;
+        let path = self.directory.join("sha256").join(&sha256);
+        fs::rename(&partial, &path)?;
+        remove_if_exists(&validators_file(&partial))?;
+        fs::write(
+            self.url_file(url),
+            format!("{sha256}\n{}", validators.render()),
+        )?;
+        debug!("Cached {} as {}", url, sha256);
+        Ok(path)
+    }
+
+    /// Removes the cached file, e.g. after it failed verification, so it isn't used again.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use download::Cache;
+    ///
+    /// cache.evict("https://git.io/get_helm.sh", &cached)?;
+    /// ```
+    pub(crate) fn evict(&self, url: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(path)?;
+        remove_if_exists(&self.url_file(url))
+    }
+
+    /// Returns the hash of what the URL last returned, along with its validators.
+    fn entry(&self, url: &str) -> Option<(String, Validators)> {
+        let contents = fs::read_to_string(self.url_file(url)).ok()?;
+        let (sha256, validators) = contents.split_once('\n').unwrap_or((&contents, ""));
+        Some((sha256.trim().to_string(), Validators::parse(validators)))
+    }
+
+    fn url_file(&self, url: &str) -> PathBuf {
+        self.directory.join("urls").join(hash(url))
+    }
+}
+
+/// The validators a server sent with a response, used to ask whether the URL has changed since.
+#[derive(Debug, Default, PartialEq)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &HeaderMap) -> Validators {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        Validators {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    fn parse(contents: &str) -> Validators {
+        let mut validators = Validators::default();
+        for line in contents.lines() {
+            if let Some(etag) = line.strip_prefix("etag: ") {
+                validators.etag = Some(etag.to_string());
+            } else if let Some(last_modified) = line.strip_prefix("last-modified: ") {
+                validators.last_modified = Some(last_modified.to_string());
+            }
+        }
+        validators
+    }
+
+    fn render(&self) -> String {
+        let mut contents = String::new();
+        if let Some(etag) = &self.etag {
+            contents.push_str(&format!("etag: {etag}\n"));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            contents.push_str(&format!("last-modified: {last_modified}\n"));
+        }
+        contents
+    }
+
+    /// Returns the validator for `If-Range`, which can't be a weak `ETag`.
+    fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+}
+
+fn validators_file(partial: &Path) -> PathBuf {
+    partial.with_extension("validators")
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Box<dyn Error>> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn hash(url: &str) -> String {
+    format!("{:x}", Sha256::digest(url.as_bytes()))
+}
+
+/// Streams the URL into the file, asking for only the rest of it when part was downloaded before,
+/// returning the validators of what was downloaded.
+///
+/// When the cached validators are given, returns nothing if the URL hasn't changed since. A
+/// partial download is only resumed while the URL still has the validator it was started with.
+async fn stream(
+    url: &str,
+    partial: &Path,
+    cached: Option<&Validators>,
+) -> Result<Option<Validators>, Box<dyn Error>> {
+    let client = network::client()?;
+    let partial_validators = fs::read_to_string(validators_file(partial))
+        .map(|contents| Validators::parse(&contents))
+        .unwrap_or_default();
+    let mut existing = match partial_validators.if_range() {
+        Some(_) => fs::metadata(partial).map_or(0, |metadata| metadata.len()),
+        None => 0,
+    };
+    let mut response = loop {
+        let mut request = client.get(url);
+        if let Some(cached) = cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        if let (true, Some(if_range)) = (existing > 0, partial_validators.if_range()) {
+            request = request
+                .header(RANGE, format!("bytes={existing}-"))
+                .header(IF_RANGE, if_range);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+            return Ok(None);
+        }
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE && existing > 0 {
+            debug!("Unable to resume {}, so starting again", url);
+            fs::remove_file(partial)?;
+            existing = 0;
+            continue;
+        }
+        break response.error_for_status()?;
+    };
+    let validators = Validators::from_headers(response.headers());
+    let resumed = response.status() == StatusCode::PARTIAL_CONTENT;
+    if resumed {
+        info!("Resuming {} from {} bytes", url, existing);
+    } else {
+        existing = 0;
+        fs::write(validators_file(partial), validators.render())?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resumed)
+        .truncate(!resumed)
+        .open(partial)?;
+    let progress = progress_bar(
+        url,
+        response.content_length().map(|length| length + existing),
+    );
+    progress.set_position(existing);
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk)?;
+        progress.inc(chunk.len() as u64);
+    }
+    progress.finish_and_clear();
+    Ok(Some(validators))
+}
+
+fn progress_bar(url: &str, length: Option<u64>) -> ProgressBar {
+    let name = url.rsplit('/').next().unwrap_or(url).to_string();
+    match length {
+        Some(length) => ProgressBar::new(length).with_style(
+            ProgressStyle::with_template(
+                "{msg} [{bar:40}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+            )
+            .expect("Invalid progress bar template")
+            .progress_chars("=> "),
+        ),
+        None => ProgressBar::new_spinner().with_style(
+            ProgressStyle::with_template("{spinner} {msg} {bytes} ({bytes_per_sec})")
+                .expect("Invalid progress bar template"),
+        ),
+    }
+    .with_message(name)
+}
+
+#[cfg(target_os = "linux")]
+fn home_dir() -> String {
+    crate::linux::get_home_dir()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn home_dir() -> String {
+    crate::system::get_home_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::thread;
+    use tiny_http::{Header, Response, Server};
+
+    use super::*;
+
+    static CONTENTS: &str = "#!/bin/sh\necho installing helm\n";
+
+    fn cache(name: &str) -> Cache {
+        let directory = std::env::temp_dir().join(format!("dotfiles-download-test-{name}"));
+        let _ = fs::remove_dir_all(&directory);
+        Cache::open_in(directory).unwrap()
+    }
+
+    static ETAG: &str = "\"v1\"";
+
+    fn serve() -> (String, thread::JoinHandle<BTreeMap<String, String>>) {
+        serve_on("127.0.0.1:0")
+    }
+
+    /// Serves the contents once with an `ETag`, honouring conditional and range requests,
+    /// returning the request's headers.
+    fn serve_on(address: &str) -> (String, thread::JoinHandle<BTreeMap<String, String>>) {
+        let server = Server::http(address).unwrap();
+        let url = format!(
+            "http://{}/get_helm.sh",
+            server.server_addr().to_ip().unwrap()
+        );
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let headers = request
+                .headers()
+                .iter()
+                .map(|header| {
+                    (
+                        header.field.to_string().to_lowercase(),
+                        header.value.to_string(),
+                    )
+                })
+                .collect::<BTreeMap<String, String>>();
+            let start = headers
+                .get("range")
+                .and_then(|range| range.strip_prefix("bytes="))
+                .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok())
+                .filter(|_| headers.get("if-range").map(String::as_str) == Some(ETAG));
+            let etag = Header::from_bytes("ETag", ETAG).unwrap();
+            let response = if headers.get("if-none-match").map(String::as_str) == Some(ETAG) {
+                Response::from_string("").with_status_code(304)
+            } else {
+                match start {
+                    Some(start) => Response::from_string(&CONTENTS[start..])
+                        .with_status_code(206)
+                        .with_header(
+                            Header::from_bytes(
+                                "Content-Range",
+                                format!("bytes {start}-{}/{}", CONTENTS.len() - 1, CONTENTS.len()),
+                            )
+                            .unwrap(),
+                        ),
+                    None => Response::from_string(CONTENTS),
+                }
+            };
+            request.respond(response.with_header(etag)).unwrap();
+            headers
+        });
+        (url, handle)
+    }
+
+    fn fetch(cache: &Cache, url: &str) -> PathBuf {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(cache.fetch(url))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_fetch_caches_by_contents() {
+        let cache = cache("fetch");
+        let (url, handle) = serve();
+
+        let path = fetch(&cache, &url);
+
+        assert_eq!(handle.join().unwrap().get("range"), None);
+        assert_eq!(fs::read_to_string(&path).unwrap(), CONTENTS);
+        let sha256 = format!("{:x}", Sha256::digest(CONTENTS.as_bytes()));
+        assert_eq!(path.file_name().unwrap().to_string_lossy(), sha256);
+        assert_eq!(cache.lookup(&url, None), Some(path.clone()));
+        assert_eq!(
+            cache.lookup("https://elsewhere.example.com/get_helm.sh", Some(&sha256)),
+            Some(path)
+        );
+    }
+
+    #[test]
+    fn test_fetch_resumes_partial_download_with_if_range() {
+        let cache = cache("resume");
+        let (url, handle) = serve();
+        let partial = cache.directory.join("partial").join(hash(&url));
+        fs::write(&partial, &CONTENTS[..10]).unwrap();
+        fs::write(validators_file(&partial), format!("etag: {ETAG}\n")).unwrap();
+
+        let path = fetch(&cache, &url);
+
+        let headers = handle.join().unwrap();
+        assert_eq!(headers.get("range"), Some(&"bytes=10-".to_string()));
+        assert_eq!(headers.get("if-range"), Some(&ETAG.to_string()));
+        assert_eq!(fs::read_to_string(path).unwrap(), CONTENTS);
+        assert!(!validators_file(&partial).exists());
+    }
+
+    #[test]
+    fn test_fetch_restarts_partial_download_that_changed() {
+        let cache = cache("changed");
+        let (url, handle) = serve();
+        let partial = cache.directory.join("partial").join(hash(&url));
+        fs::write(&partial, "#!/bin/bash\n").unwrap();
+        fs::write(validators_file(&partial), "etag: \"v0\"\n").unwrap();
+
+        let path = fetch(&cache, &url);
+
+        assert_eq!(
+            handle.join().unwrap().get("if-range"),
+            Some(&"\"v0\"".to_string())
+        );
+        assert_eq!(fs::read_to_string(path).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn test_fetch_restarts_partial_download_without_validators() {
+        let cache = cache("unvalidated");
+        let (url, handle) = serve();
+        fs::write(
+            cache.directory.join("partial").join(hash(&url)),
+            &CONTENTS[..10],
+        )
+        .unwrap();
+
+        let path = fetch(&cache, &url);
+
+        assert_eq!(handle.join().unwrap().get("range"), None);
+        assert_eq!(fs::read_to_string(path).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn test_fetch_revalidates_cached_url() {
+        let cache = cache("revalidate");
+        let (url, handle) = serve();
+        let path = fetch(&cache, &url);
+        handle.join().unwrap();
+        let address = url
+            .trim_start_matches("http://")
+            .trim_end_matches("/get_helm.sh");
+        let (_, handle) = serve_on(address);
+
+        let revalidated = fetch(&cache, &url);
+
+        assert_eq!(
+            handle.join().unwrap().get("if-none-match"),
+            Some(&ETAG.to_string())
+        );
+        assert_eq!(revalidated, path);
+    }
+
+    #[test]
+    fn test_lookup_misses_unknown_urls() {
+        let cache = cache("lookup");
+
+        assert_eq!(cache.lookup("https://example.com/get_helm.sh", None), None);
+        assert_eq!(
+            cache.lookup("https://example.com/get_helm.sh", Some("abc123")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_evict_removes_file_and_url() {
+        let cache = cache("evict");
+        let (url, handle) = serve();
+        let path = fetch(&cache, &url);
+        handle.join().unwrap();
+
+        cache.evict(&url, &path).unwrap();
+
+        assert!(!path.exists());
+        assert_eq!(cache.lookup(&url, None), None);
+    }
+}
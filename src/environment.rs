@@ -0,0 +1,579 @@
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::managed_block::{self, Outcome};
+use crate::shell::Shell;
+use crate::state::{self, Change};
+use crate::system::{self, System};
+
+/// Where the env files are kept, relative to the home directory.
+static DIRECTORY: &str = ".config/dotfiles";
+
+/// The blocks earlier versions wrote to each shell's config file, which the env file replaces.
+static REPLACED_BLOCKS: [&str; 2] = ["path", "JAVA_HOME"];
+
+/// The variables and PATH entries every shell gets, in the order they were added.
+#[derive(Debug, Default, PartialEq)]
+struct Environment {
+    /// Exported before the PATH entries, so they can use them, e.g. `$JAVA_HOME/bin`.
+    variables: Vec<(String, String)>,
+    path: Vec<String>,
+}
+
+impl Environment {
+    /// Reads the entries back from `env.sh`, ignoring any lines it didn't write.
+    fn parse(contents: &str) -> Environment {
+        let mut environment = Environment::default();
+        for line in contents.lines() {
+            if let Some(entry) = line
+                .strip_prefix("export PATH=\"$PATH:")
+                .and_then(|entry| entry.strip_suffix('"'))
+            {
+                environment.path.push(unquote(entry));
+            } else if let Some((key, value)) = line
+                .strip_prefix("export ")
+                .and_then(|variable| variable.split_once("=\""))
+                .and_then(|(key, value)| Some((key, value.strip_suffix('"')?)))
+            {
+                environment
+                    .variables
+                    .push((key.to_string(), unquote(value)));
+            }
+        }
+        environment
+    }
+
+    /// Adds the entry to the end of the PATH, returning whether it wasn't already there.
+    fn add_to_path(&mut self, entry: &str) -> bool {
+        if self.path.iter().any(|existing| existing == entry) {
+            return false;
+        }
+        self.path.push(entry.to_string());
+        true
+    }
+
+    /// Sets the variable, keeping its place when it's already set, returning whether it changed.
+    fn set_variable(&mut self, key: &str, value: &str) -> bool {
+        match self
+            .variables
+            .iter_mut()
+            .find(|(existing, _)| existing == key)
+        {
+            Some((_, existing)) if existing == value => false,
+            Some((_, existing)) => {
+                *existing = value.to_string();
+                true
+            }
+            None => {
+                self.variables.push((key.to_string(), value.to_string()));
+                true
+            }
+        }
+    }
+
+    /// Takes on the entries of the blocks earlier versions wrote, keeping the value of any variable
+    /// that's already set, returning whether it changed.
+    fn merge(&mut self, old: Environment) -> bool {
+        let mut changed = false;
+        for (key, value) in old.variables {
+            if !self.variables.iter().any(|(existing, _)| *existing == key) {
+                changed |= self.set_variable(&key, &value);
+            }
+        }
+        for entry in old.path {
+            changed |= self.add_to_path(&entry);
+        }
+        changed
+    }
+
+    fn render(&self, shell: Shell) -> String {
+        let mut lines = vec!["# Generated by dotfiles, which overwrites any changes.".to_string()];
+        lines.extend(
+            self.variables
+                .iter()
+                .map(|(key, value)| shell.variable(key, value)),
+        );
+        lines.extend(self.path.iter().map(|entry| shell.path_entry(entry)));
+        lines.join("\n") + "\n"
+    }
+}
+
+/// Adds the path to the end of the PATH every shell gets, through the env file, only if it's not
+/// already there.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// environment::add_to_path(&system, "$JAVA_HOME/bin")?; // Will add to the env file
+/// environment::add_to_path(&system, "$JAVA_HOME/bin")?; // Will not do anything
+/// ```
+pub(crate) fn add_to_path(system: &dyn System, path: &str) -> Result<Outcome, Box<dyn Error>> {
+    let outcome = update(&system.get_home_dir(), |environment| {
+        environment.add_to_path(path)
+    })?;
+    if !system::is_dry_run() {
+        state::give_to_user(&directory(&system.get_home_dir()))?;
+    }
+    // Recorded even when it was already there, as the env file is only ever written by steps, so
+    // the entry stays until every step needing it is uninstalled.
+    state::record(Change::PathEntry {
+        entry: path.to_string(),
+    });
+    Ok(outcome)
+}
+
+/// Sets the variable every shell exports, through the env file, replacing its old value.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// environment::set_variable(&system, "JAVA_HOME", "/usr/lib/jvm/default")?;
+/// ```
+pub(crate) fn set_variable(
+    system: &dyn System,
+    key: &str,
+    value: &str,
+) -> Result<Outcome, Box<dyn Error>> {
+    let outcome = update(&system.get_home_dir(), |environment| {
+        environment.set_variable(key, value)
+    })?;
+    if !system::is_dry_run() {
+        state::give_to_user(&directory(&system.get_home_dir()))?;
+    }
+    state::record(Change::Variable {
+        key: key.to_string(),
+    });
+    Ok(outcome)
+}
+
+/// Returns whether the env file has the entry on the PATH.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// let has_java = environment::contains_path(&system.get_home_dir(), "$JAVA_HOME/bin")?;
+/// ```
+pub(crate) fn contains_path(home: &str, entry: &str) -> Result<bool, io::Error> {
+    Ok(read(home)?.path.iter().any(|existing| existing == entry))
+}
+
+/// Returns whether the env file exports the variable.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// let has_java_home = environment::contains_variable(&system.get_home_dir(), "JAVA_HOME")?;
+/// ```
+pub(crate) fn contains_variable(home: &str, key: &str) -> Result<bool, io::Error> {
+    Ok(read(home)?
+        .variables
+        .iter()
+        .any(|(existing, _)| existing == key))
+}
+
+/// Takes the entry off the PATH in the env file, if it's there.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// environment::remove_from_path(&system.get_home_dir(), "/opt/groovy/bin")?;
+/// ```
+pub(crate) fn remove_from_path(home: &str, entry: &str) -> Result<Outcome, Box<dyn Error>> {
+    if !contains_path(home, entry)? {
+        return Ok(Outcome::Unchanged);
+    }
+    update(home, |environment| {
+        let length = environment.path.len();
+        environment.path.retain(|existing| existing != entry);
+        environment.path.len() != length
+    })
+}
+
+/// Stops the env file exporting the variable, if it does.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// environment::remove_variable(&system.get_home_dir(), "JAVA_HOME")?;
+/// ```
+pub(crate) fn remove_variable(home: &str, key: &str) -> Result<Outcome, Box<dyn Error>> {
+    if !contains_variable(home, key)? {
+        return Ok(Outcome::Unchanged);
+    }
+    update(home, |environment| {
+        let length = environment.variables.len();
+        environment
+            .variables
+            .retain(|(existing, _)| existing != key);
+        environment.variables.len() != length
+    })
+}
+
+/// Returns whether the file has the block with the id before the one sourcing the env file, where
+/// it can't use anything the env file sets.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// if environment::is_before_sources(".bashrc", "nvm")? {
+///     managed_block::remove(".bashrc", "nvm")?;
+/// }
+/// ```
+pub(crate) fn is_before_sources(file: &str, id: &str) -> Result<bool, io::Error> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let position = |id: &str| contents.find(&format!("# BEGIN dotfiles:{id}\n"));
+    Ok(match (position(id), position("env")) {
+        (Some(block), Some(sources)) => block < sources,
+        _ => false,
+    })
+}
+
+/// Sources the env file from the config file of each configured shell, once there is one, so
+/// config files rendered from their templates get it back.
+///
+/// The entries of the blocks earlier versions wrote are moved into the env file before the
+/// blocks are removed.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use environment;
+///
+/// environment::add_sources(&system.get_home_dir())?;
+/// ```
+pub(crate) fn add_sources(home: &str) -> Result<(), Box<dyn Error>> {
+    if !Path::new(&directory(home))
+        .join(file_name(Shell::Bash))
+        .exists()
+    {
+        return Ok(());
+    }
+    let mut environment = read(home)?;
+    if environment.merge(replaced_entries(home)?) {
+        write(home, &environment)?;
+    }
+    for shell in Shell::configured(home) {
+        let config_file = format!("{home}/{}", shell.config_file());
+        let env_file = format!("$HOME/{DIRECTORY}/{}", file_name(shell));
+        // Every step's entries share the env file, so sourcing it isn't recorded against the
+        // step, which would stop it being sourced once that step is uninstalled.
+        managed_block::write(&config_file, "env", &shell.source(&env_file))?;
+        for id in REPLACED_BLOCKS {
+            managed_block::remove(&config_file, id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the change to the env files, rewriting them when it, or taking on the entries of the
+/// blocks they replace, changes anything, returning whether the change did.
+fn update(
+    home: &str,
+    change: impl FnOnce(&mut Environment) -> bool,
+) -> Result<Outcome, Box<dyn Error>> {
+    let mut environment = read(home)?;
+    let merged = environment.merge(replaced_entries(home)?);
+    let changed = change(&mut environment);
+    let env_sh = Path::new(&directory(home)).join(file_name(Shell::Bash));
+    if changed || merged || !env_sh.exists() {
+        write(home, &environment)?;
+    }
+    add_sources(home)?;
+    Ok(if changed {
+        Outcome::Changed
+    } else {
+        Outcome::Unchanged
+    })
+}
+
+fn read(home: &str) -> Result<Environment, io::Error> {
+    match fs::read_to_string(Path::new(&directory(home)).join(file_name(Shell::Bash))) {
+        Ok(contents) => Ok(Environment::parse(&contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Environment::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write(home: &str, environment: &Environment) -> Result<(), Box<dyn Error>> {
+    let directory = directory(home);
+    if system::is_dry_run() {
+        info!("Would write the env files in {}", directory);
+        return Ok(());
+    }
+    fs::create_dir_all(&directory)?;
+    // fish can't source env.sh, so it gets the same entries in its own syntax.
+    for shell in [Shell::Bash, Shell::Fish] {
+        fs::write(
+            Path::new(&directory).join(file_name(shell)),
+            environment.render(shell),
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the entries of the blocks earlier versions wrote, which are in the same syntax as
+/// `env.sh` in the bash and zsh config files, and always had the same entries as fish's.
+fn replaced_entries(home: &str) -> Result<Environment, io::Error> {
+    let mut entries = Environment::default();
+    for shell in [Shell::Bash, Shell::Zsh] {
+        let config_file = format!("{home}/{}", shell.config_file());
+        for id in REPLACED_BLOCKS {
+            if let Some(block) = managed_block::read(&config_file, id)? {
+                entries.merge(Environment::parse(&block));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn directory(home: &str) -> String {
+    format!("{home}/{DIRECTORY}")
+}
+
+fn file_name(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash | Shell::Zsh => "env.sh",
+        Shell::Fish => "env.fish",
+    }
+}
+
+/// Reverses the escaping of a double quoted value.
+fn unquote(value: &str) -> String {
+    let mut unquoted = String::new();
+    let mut characters = value.chars();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => unquoted.extend(characters.next()),
+            _ => unquoted.push(character),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    fn home() -> String {
+        let home = env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&home).unwrap();
+        home.to_string_lossy().to_string()
+    }
+
+    fn read(home: &str, file: &str) -> String {
+        fs::read_to_string(format!("{home}/{file}")).unwrap()
+    }
+
+    #[test]
+    fn test_environment_keeps_ordered_deduplicated_entries() {
+        let mut environment = Environment::default();
+
+        assert!(environment.add_to_path("/opt/b"));
+        assert!(environment.add_to_path("/opt/a"));
+        assert!(!environment.add_to_path("/opt/b"));
+        assert!(environment.set_variable("JAVA_HOME", "/usr/lib/jvm/java-17"));
+        assert!(environment.set_variable("EDITOR", "vim"));
+        assert!(environment.set_variable("JAVA_HOME", "/usr/lib/jvm/java-21"));
+        assert!(!environment.set_variable("EDITOR", "vim"));
+
+        assert_eq!(environment.path, vec!["/opt/b", "/opt/a"]);
+        assert_eq!(
+            environment.variables,
+            vec![
+                ("JAVA_HOME".to_string(), "/usr/lib/jvm/java-21".to_string()),
+                ("EDITOR".to_string(), "vim".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_reads_back_what_render_writes() {
+        let mut environment = Environment::default();
+        environment.set_variable("GREETING", "say \"hi\" \\o/ = $USER");
+        environment.add_to_path("$HOME/My Programs/bin");
+
+        let rendered = environment.render(Shell::Bash);
+
+        assert_eq!(
+            rendered,
+            "# Generated by dotfiles, which overwrites any changes.\n\
+            export GREETING=\"say \\\"hi\\\" \\\\o/ = $USER\"\n\
+            export PATH=\"$PATH:$HOME/My Programs/bin\"\n"
+        );
+        assert_eq!(Environment::parse(&rendered), environment);
+        assert_eq!(
+            Environment::parse("export PATH=$PATH:/opt/bin\nalias ll='ls -l'\n"),
+            Environment::default()
+        );
+    }
+
+    #[test]
+    fn test_update_sources_the_env_file_in_place_of_the_old_blocks() {
+        let home = home();
+        fs::create_dir_all(format!("{home}/.config/fish")).unwrap();
+        fs::write(
+            format!("{home}/.zshrc"),
+            "source $ZSH/oh-my-zsh.sh\n\
+            # BEGIN dotfiles:path\nexport PATH=\"$PATH:/opt/groovy/bin\"\n# END dotfiles:path\n",
+        )
+        .unwrap();
+
+        update(&home, |environment| {
+            environment.add_to_path("/opt/groovy/bin")
+        })
+        .unwrap();
+
+        assert_eq!(
+            read(&home, ".zshrc"),
+            "source $ZSH/oh-my-zsh.sh\n\
+            # BEGIN dotfiles:env\n\
+            [ -f \"$HOME/.config/dotfiles/env.sh\" ] && . \"$HOME/.config/dotfiles/env.sh\"\n\
+            # END dotfiles:env\n"
+        );
+        assert!(read(&home, ".bashrc").contains(". \"$HOME/.config/dotfiles/env.sh\""));
+        assert!(read(&home, ".config/fish/config.fish")
+            .contains("source \"$HOME/.config/dotfiles/env.fish\""));
+    }
+
+    #[test]
+    fn test_update_writes_nothing_on_a_dry_run() {
+        let home = home();
+        let zshrc =
+            "# BEGIN dotfiles:path\nexport PATH=\"$PATH:/opt/groovy/bin\"\n# END dotfiles:path\n";
+        fs::write(format!("{home}/.zshrc"), zshrc).unwrap();
+        system::set_dry_run(true);
+
+        let outcome = update(&home, |environment| {
+            environment.set_variable("JAVA_HOME", "/usr/lib/jvm/default")
+        })
+        .unwrap();
+
+        assert_eq!(outcome, Outcome::Changed);
+        assert_eq!(read(&home, ".zshrc"), zshrc);
+        assert!(!Path::new(&directory(&home)).exists());
+    }
+
+    #[test]
+    fn test_update_keeps_the_entries_of_the_old_blocks() {
+        let home = home();
+        fs::write(
+            format!("{home}/.bashrc"),
+            "# BEGIN dotfiles:path\n\
+            export PATH=\"$PATH:/opt/groovy/bin\"\n\
+            export PATH=\"$PATH:$JAVA_HOME/bin\"\n\
+            # END dotfiles:path\n\
+            # BEGIN dotfiles:JAVA_HOME\n\
+            export JAVA_HOME=\"/usr/lib/jvm/java-17\"\n\
+            # END dotfiles:JAVA_HOME\n",
+        )
+        .unwrap();
+
+        // Only the JDK is installed this run, with a newer JAVA_HOME.
+        update(&home, |environment| {
+            environment.set_variable("JAVA_HOME", "/usr/lib/jvm/default")
+        })
+        .unwrap();
+
+        assert_eq!(
+            read(&home, ".config/dotfiles/env.sh"),
+            "# Generated by dotfiles, which overwrites any changes.\n\
+            export JAVA_HOME=\"/usr/lib/jvm/default\"\n\
+            export PATH=\"$PATH:/opt/groovy/bin\"\n\
+            export PATH=\"$PATH:$JAVA_HOME/bin\"\n"
+        );
+        assert!(!read(&home, ".bashrc").contains("dotfiles:path"));
+        assert!(!read(&home, ".bashrc").contains("dotfiles:JAVA_HOME"));
+    }
+
+    #[test]
+    fn test_remove_takes_entries_out_of_the_env_file() {
+        let home = home();
+        update(&home, |environment| {
+            environment.set_variable("JAVA_HOME", "/usr/lib/jvm/default")
+                | environment.add_to_path("$JAVA_HOME/bin")
+                | environment.add_to_path("/opt/groovy/bin")
+        })
+        .unwrap();
+
+        assert_eq!(
+            remove_from_path(&home, "$JAVA_HOME/bin").unwrap(),
+            Outcome::Changed
+        );
+        assert_eq!(
+            remove_variable(&home, "JAVA_HOME").unwrap(),
+            Outcome::Changed
+        );
+        assert_eq!(
+            remove_variable(&home, "JAVA_HOME").unwrap(),
+            Outcome::Unchanged
+        );
+
+        assert!(contains_path(&home, "/opt/groovy/bin").unwrap());
+        assert!(!contains_path(&home, "$JAVA_HOME/bin").unwrap());
+        assert!(!contains_variable(&home, "JAVA_HOME").unwrap());
+        assert!(!read(&home, ".config/dotfiles/env.fish").contains("JAVA_HOME"));
+    }
+
+    #[test]
+    fn test_is_before_sources_finds_blocks_that_cannot_use_the_env_file() {
+        let home = home();
+        let bashrc = format!("{home}/.bashrc");
+        fs::write(
+            &bashrc,
+            "# BEGIN dotfiles:nvm\n\
+            [ -s \"$NVM_DIR/nvm.sh\" ] && \\. \"$NVM_DIR/nvm.sh\"\n\
+            # END dotfiles:nvm\n\
+            # BEGIN dotfiles:env\n\
+            [ -f \"$HOME/.config/dotfiles/env.sh\" ] && . \"$HOME/.config/dotfiles/env.sh\"\n\
+            # END dotfiles:env\n\
+            # BEGIN dotfiles:nvm-auto-use\n\
+            cd \"$PWD\"\n\
+            # END dotfiles:nvm-auto-use\n",
+        )
+        .unwrap();
+
+        assert!(is_before_sources(&bashrc, "nvm").unwrap());
+        assert!(!is_before_sources(&bashrc, "nvm-auto-use").unwrap());
+        assert!(!is_before_sources(&bashrc, "cargo").unwrap());
+        assert!(!is_before_sources(&format!("{home}/.zshrc"), "nvm").unwrap());
+    }
+}
@@ -1,280 +1,513 @@
+use crate::certificates;
 use crate::config::Config;
+use crate::shell::Shell;
+use crate::state;
+use crate::stow;
 use crate::system::System;
 use log::info;
 
 pub(crate) async fn install(
     config: &Config,
     system: &dyn System,
+    run: u32,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Snapshotting the Filesystem");
+    system.create_snapshot(run)?;
+
     system.setup_user_bin()?;
 
-    info!("Installing Distro Specific Extras");
+    // First, so the package managers and tools downloading anything afterwards trust them.
+    let installed_certificates = match config
+        .profile
+        .as_ref()
+        .filter(|p| !p.certificates.is_empty())
+    {
+        Some(profile) => {
+            step("certificates", "Installing Certificates");
+            certificates::install(config, system, &profile.certificates).await?
+        }
+        None => Vec::new(),
+    };
+
+    if !config.mirror_countries.is_empty() {
+        info!("Ranking Mirrors");
+        system.rank_mirrors().await?;
+    }
+
+    step("system-extras", "Installing Distro Specific Extras");
     system.install_system_extras().await?;
     system.update_os()?;
 
     if !config.cli_only {
-        info!("Installing Window Manager");
+        step("window-manager", "Installing Window Manager");
         system.install_window_manager()?;
-        info!("Installing Graphic Card Tools");
+        step("graphic-card-tools", "Installing Graphic Card Tools");
         system.install_graphic_card_tools().await?;
     }
 
-    info!("Installing Bash");
+    step("bash", "Installing Bash");
     system.install_bash()?;
-    info!("Installing Curl");
+    step("curl", "Installing Curl");
     system.install_curl()?;
-    info!("Install Networking Tools");
+    step("networking-tools", "Install Networking Tools");
     system.install_networking_tools()?;
-    info!("Installing tmux");
+    step("tmux", "Installing tmux");
     system.install_tmux()?;
-    info!("Installing Vim");
+    step("vim", "Installing Vim");
     system.install_vim()?;
-    info!("Installing Wget");
+    step("wget", "Installing Wget");
     system.install_wget()?;
-    info!("Installing ZSH");
+    step("zsh", "Installing ZSH");
     system.install_zsh().await?;
+    if config.shell == Shell::Fish {
+        step("fish", "Installing Fish");
+        system.install_fish()?;
+    }
 
     if !config.cli_only {
-        info!("Installing archiver");
+        step("archiver", "Installing archiver");
         system.install_archiver()?;
-        info!("Installing Cryptomator");
+        step("cryptomator", "Installing Cryptomator");
         system.install_cryptomator().await?;
-        info!("Installing KeepassXC");
+        step("keepassxc", "Installing KeepassXC");
         system.install_keepassxc()?;
-        info!("Installing QuickLook");
+        step("quicklook", "Installing QuickLook");
         system.install_quicklook()?;
     }
 
     if config.browsers && !config.cli_only {
-        info!("Installing Firefox");
+        step("firefox", "Installing Firefox");
         system.install_firefox()?;
-        info!("Installing Google Chrome");
+        step("google-chrome", "Installing Google Chrome");
         system.install_google_chrome().await?;
-        info!("Installing Microsoft Edge");
-        system.install_microsoft_edge()?;
+        step("microsoft-edge", "Installing Microsoft Edge");
+        system.install_microsoft_edge().await?;
     }
 
     if config.development {
-        info!("Installing C++");
+        step("cplusplus", "Installing C++");
         system.install_cplusplus()?;
-        info!("Installing exercism");
+        step("exercism", "Installing exercism");
         system.install_exercism().await?;
-        info!("Installing Gradle");
+        step("gradle", "Installing Gradle");
         system.install_gradle()?;
-        info!("Installing Git");
+        step("git", "Installing Git");
         system.install_git()?;
-        info!("Installing Godot");
+        step("godot", "Installing Godot");
         system.install_godot().await?;
-        info!("Installing Groovy");
-        system.install_groovy()?;
-        info!("Installing Java");
+        step("groovy", "Installing Groovy");
+        system.install_groovy().await?;
+        step("jdk", "Installing Java");
         system.install_jdk()?;
-        info!("Installing Maven");
+        certificates::add_to_java(system, &installed_certificates)?;
+        step("maven", "Installing Maven");
         system.install_maven()?;
-        info!("Installing NodeJS");
+        step("nodejs", "Installing NodeJS");
         system.install_nodejs().await?;
-        info!("Installing Python");
+        step("python", "Installing Python");
         system.install_python()?;
-        info!("Installing Rust");
+        step("rust", "Installing Rust");
         system.install_rust().await?;
-        info!("Install development specific extras");
+        step("development-extras", "Install development specific extras");
         system.install_development_extras()?;
-        info!("Setting development environment settings");
+        step(
+            "set-development-environment-settings",
+            "Setting development environment settings",
+        );
         system.set_development_environment_settings()?;
 
         if !config.cli_only {
-            info!("Installing Android Studio");
+            step("android-studio", "Installing Android Studio");
             system.install_android_studio()?;
             // info!("Installing Eclipse");
             // system.install_eclipse().await?;
-            info!("Installing IntelliJ");
+            step("intellij", "Installing IntelliJ");
             system.install_intellij()?;
-            info!("Installing Rust Rover");
+            step("rust-rover", "Installing Rust Rover");
             system.install_rust_rover()?;
-            info!("Installing Slack");
+            step("slack", "Installing Slack");
             system.install_slack()?;
-            info!("Installing VSCode");
-            system.install_vscode()?;
-            info!("Installing Xcode");
+            step("vscode", "Installing VSCode");
+            system.install_vscode().await?;
+            step("xcode", "Installing Xcode");
             system.install_xcode()?;
-            info!("Setting development specific shortcuts");
+            step(
+                "set-development-shortcuts",
+                "Setting development specific shortcuts",
+            );
             system.set_development_shortcuts()?;
         }
     }
 
     if config.docker {
-        info!("Installing Docker");
+        step("docker", "Installing Docker");
         system.install_docker()?;
-        info!("Installing Kubectl");
+        step("kubectl", "Installing Kubectl");
         system.install_kubectl().await?;
-        info!("Installing Helm");
+        step("helm", "Installing Helm");
         system.install_helm().await?;
     }
 
     if config.gaming && !config.cli_only {
-        info!("Installing Discord");
+        step("discord", "Installing Discord");
         system.install_discord()?;
-        info!("Installing Epic Games");
+        step("epic-games", "Installing Epic Games");
         system.install_epic_games().await?;
-        info!("Installing GOG Galaxy");
+        step("gog-galaxy", "Installing GOG Galaxy");
         system.install_gog_galaxy().await?;
-        info!("Installing Lutris");
+        step("lutris", "Installing Lutris");
         system.install_lutris()?;
-        info!("Installing Origin");
+        step("origin", "Installing Origin");
         system.install_origin()?;
-        info!("Installing RetroArch");
+        step("retroarch", "Installing RetroArch");
         system.install_retroarch()?;
-        info!("Installing Steam");
+        step("steam", "Installing Steam");
         system.install_steam()?;
-        info!("Installing Syncthing");
+        step("syncthing", "Installing Syncthing");
         system.install_syncthing()?;
-        info!("Installing Wine");
+        step("wine", "Installing Wine");
         system.install_wine()?;
-        info!("Installing Xbox streaming");
+        step("xbox-streaming", "Installing Xbox streaming");
         system.install_xbox_streaming().await?;
     }
 
     if config.gcp {
-        info!("Installing Google Cloud SDK");
-        system.install_google_cloud_sdk()?;
+        step("google-cloud-sdk", "Installing Google Cloud SDK");
+        system.install_google_cloud_sdk().await?;
     }
 
     if config.images && !config.cli_only {
-        info!("Installing Affinity Suite");
+        step("affinity-suite", "Installing Affinity Suite");
         system.install_affinity_suite()?;
-        info!("Installing Gimp");
+        step("gimp", "Installing Gimp");
         system.install_gimp()?;
-        info!("Installing Inkscape");
+        step("inkscape", "Installing Inkscape");
         system.install_inkscape()?;
     }
 
     if config.infrastructure {
-        info!("Installing Terraform");
-        system.install_terraform()?;
+        step("terraform", "Installing Terraform");
+        system.install_terraform().await?;
     }
 
     if config.laptop {
-        info!("Installing Bluetooth");
+        step("bluetooth", "Installing Bluetooth");
         system.install_bluetooth()?;
-        info!("Installing FWUPD");
+        step("firmware-updater", "Installing FWUPD");
         system.install_firmware_updater()?;
-        info!("Installing Microcode");
+        step("microcode", "Installing Microcode");
         system.install_microcode()?;
-        info!("Installing Powertop");
+        step("powertop", "Installing Powertop");
         system.install_powertop()?;
-        info!("Installing TLP");
+        step("tlp", "Installing TLP");
         system.install_tlp()?;
-        info!("Install WiFi");
+        step("wifi", "Install WiFi");
         system.install_wifi().await?;
-        info!("Setup power saving tweaks");
+        step("setup-power-saving-tweaks", "Setup power saving tweaks");
         system.setup_power_saving_tweaks()?;
 
         if !config.cli_only {
-            info!("Installing Graphics Card Tools for Laptop");
+            step(
+                "graphic-card-laptop-tools",
+                "Installing Graphics Card Tools for Laptop",
+            );
             system.install_graphic_card_laptop_tools().await?;
         }
     }
 
     if config.modelling && !config.cli_only {
-        info!("Installing Blender");
+        step("blender", "Installing Blender");
         system.install_blender()?;
-        info!("Installing Bambu Studio");
+        step("bambu-studio", "Installing Bambu Studio");
         system.install_bambu_studio()?;
-        info!("Installing OpenSCAD");
-        system.install_openscad()?;
+        step("openscad", "Installing OpenSCAD");
+        system.install_openscad().await?;
     }
 
     if config.personal {
-        info!("Installing GPG");
+        step("gpg", "Installing GPG");
         system.install_gpg()?;
-        info!("Setup NAS");
+        step("setup-nas", "Setup NAS");
         system.setup_nas()?;
 
         if !config.cli_only {
-            info!("Installing Calibre");
+            step("calibre", "Installing Calibre");
             system.install_calibre()?;
-            info!("Installing Disk Usage Analyser");
+            step("disk-usage-analyser", "Installing Disk Usage Analyser");
             system.install_disk_usage_analyser()?;
-            info!("Installing Google Drive");
+            step("google-drive", "Installing Google Drive");
             system.install_google_drive()?;
-            info!("Installing Gramps");
+            step("gramps", "Installing Gramps");
             system.install_gramps()?;
-            info!("Installing Insync");
+            step("insync", "Installing Insync");
             system.install_insync()?;
-            info!("Installing LaTeX");
+            step("latex", "Installing LaTeX");
             system.install_latex()?;
             // info!("Installing Nextcloud Client");
             // system.install_nextcloud_client()?;
-            info!("Installing Office");
+            step("office", "Installing Office");
             system.install_office()?;
-            info!("Installing OneDrive");
+            step("onedrive", "Installing OneDrive");
             system.install_onedrive()?;
             // info!("Installing Spotify");
-            // system.install_spotify()?;
-            info!("Installing Tauon Music Box");
+            // system.install_spotify().await?;
+            step("tauon-music-box", "Installing Tauon Music Box");
             system.install_tauon_music_box().await?;
-            info!("Installing SweetHome3D");
+            step("sweet-home-3d", "Installing SweetHome3D");
             system.install_sweet_home_3d()?;
-            info!("Installing themes");
+            step("themes", "Installing themes");
             system.install_themes().await?;
-            info!("Installing WhatsApp");
+            step("whatsapp", "Installing WhatsApp");
             system.install_whatsapp()?;
         }
     }
 
     if config.printer {
-        info!("Installing printer drivers");
+        step("printer-drivers", "Installing printer drivers");
         system.install_printer_drivers()?;
     }
 
     if config.recording && !config.cli_only {
-        info!("Installing Audacity");
+        step("audacity", "Installing Audacity");
         system.install_audacity()?;
-        info!("Installing OBS Studio");
+        step("obs-studio", "Installing OBS Studio");
         system.install_obs_studio()?;
     }
 
     if config.ripping && !config.cli_only {
-        info!("Installing Exact Audio Copy");
+        step("exact-audio-copy", "Installing Exact Audio Copy");
         system.install_exact_audio_copy()?;
-        info!("Installing Handbrake");
+        step("handbrake", "Installing Handbrake");
         system.install_handbrake()?;
-        info!("Installing MakeMKV");
+        step("makemkv", "Installing MakeMKV");
         system.install_makemkv()?;
-        info!("Installing MKVToolNix");
+        step("mkvtoolnix", "Installing MKVToolNix");
         system.install_mkvtoolnix()?;
-        info!("Installing Whipper");
+        step("whipper", "Installing Whipper");
         system.install_whipper()?
     }
 
     if config.video && !config.cli_only {
-        info!("Installing Codecs");
+        step("codecs", "Installing Codecs");
         system.install_codecs().await?;
-        info!("Installing VLC");
+        step("vlc", "Installing VLC");
         system.install_vlc().await?;
     }
 
     if config.video_editing && !config.cli_only {
-        info!("Installing DaVinci Resolve");
+        step("davinci-resolve", "Installing DaVinci Resolve");
         system.install_davinci_resolve().await?;
     }
 
     if config.vm && !config.cli_only {
-        info!("Installing VM Tools");
+        step("vm-tools", "Installing VM Tools");
         system.install_vm_tools()?;
     }
 
     if config.vpn && !config.cli_only {
-        info!("Installing NordVPN");
+        step("nordvpn", "Installing NordVPN");
         system.install_nordvpn().await?;
     }
 
+    if let Some(profile) = config.profile.as_ref().filter(|p| !p.extras.is_empty()) {
+        step("extras", "Installing Profile Extras");
+        system.install_extras(&profile.extras)?;
+    }
+
+    if let Some(profile) = config
+        .profile
+        .as_ref()
+        .filter(|p| p.dotfiles.repository.is_some())
+    {
+        step("dotfiles", "Applying Dotfiles");
+        stow::apply(config, system, &profile.dotfiles)?;
+    }
+
     Ok(())
 }
 
+/// Returns the ids of the steps `install` runs for the config, in the order it runs them.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use install;
+///
+/// let steps = install::selected_steps(&config);
+/// ```
+pub(crate) fn selected_steps(config: &Config) -> Vec<&'static str> {
+    let gui = !config.cli_only;
+    let groups: Vec<(bool, Vec<&'static str>)> = vec![
+        (
+            config
+                .profile
+                .as_ref()
+                .is_some_and(|profile| !profile.certificates.is_empty()),
+            vec!["certificates"],
+        ),
+        (true, vec!["system-extras"]),
+        (gui, vec!["window-manager", "graphic-card-tools"]),
+        (
+            true,
+            vec![
+                "bash",
+                "curl",
+                "networking-tools",
+                "tmux",
+                "vim",
+                "wget",
+                "zsh",
+            ],
+        ),
+        (config.shell == Shell::Fish, vec!["fish"]),
+        (
+            gui,
+            vec!["archiver", "cryptomator", "keepassxc", "quicklook"],
+        ),
+        (
+            config.browsers && gui,
+            vec!["firefox", "google-chrome", "microsoft-edge"],
+        ),
+        (
+            config.development,
+            vec![
+                "cplusplus",
+                "exercism",
+                "gradle",
+                "git",
+                "godot",
+                "groovy",
+                "jdk",
+                "maven",
+                "nodejs",
+                "python",
+                "rust",
+                "development-extras",
+                "set-development-environment-settings",
+            ],
+        ),
+        (
+            config.development && gui,
+            vec![
+                "android-studio",
+                "intellij",
+                "rust-rover",
+                "slack",
+                "vscode",
+                "xcode",
+                "set-development-shortcuts",
+            ],
+        ),
+        (config.docker, vec!["docker", "kubectl", "helm"]),
+        (
+            config.gaming && gui,
+            vec![
+                "discord",
+                "epic-games",
+                "gog-galaxy",
+                "lutris",
+                "origin",
+                "retroarch",
+                "steam",
+                "syncthing",
+                "wine",
+                "xbox-streaming",
+            ],
+        ),
+        (config.gcp, vec!["google-cloud-sdk"]),
+        (
+            config.images && gui,
+            vec!["affinity-suite", "gimp", "inkscape"],
+        ),
+        (config.infrastructure, vec!["terraform"]),
+        (
+            config.laptop,
+            vec![
+                "bluetooth",
+                "firmware-updater",
+                "microcode",
+                "powertop",
+                "tlp",
+                "wifi",
+                "setup-power-saving-tweaks",
+            ],
+        ),
+        (config.laptop && gui, vec!["graphic-card-laptop-tools"]),
+        (
+            config.modelling && gui,
+            vec!["blender", "bambu-studio", "openscad"],
+        ),
+        (config.personal, vec!["gpg", "setup-nas"]),
+        (
+            config.personal && gui,
+            vec![
+                "calibre",
+                "disk-usage-analyser",
+                "google-drive",
+                "gramps",
+                "insync",
+                "latex",
+                "office",
+                "onedrive",
+                "tauon-music-box",
+                "sweet-home-3d",
+                "themes",
+                "whatsapp",
+            ],
+        ),
+        (config.printer, vec!["printer-drivers"]),
+        (config.recording && gui, vec!["audacity", "obs-studio"]),
+        (
+            config.ripping && gui,
+            vec![
+                "exact-audio-copy",
+                "handbrake",
+                "makemkv",
+                "mkvtoolnix",
+                "whipper",
+            ],
+        ),
+        (config.video && gui, vec!["codecs", "vlc"]),
+        (config.video_editing && gui, vec!["davinci-resolve"]),
+        (config.vm && gui, vec!["vm-tools"]),
+        (config.vpn && gui, vec!["nordvpn"]),
+        (
+            config
+                .profile
+                .as_ref()
+                .is_some_and(|profile| !profile.extras.is_empty()),
+            vec!["extras"],
+        ),
+        (
+            config
+                .profile
+                .as_ref()
+                .is_some_and(|profile| profile.dotfiles.repository.is_some()),
+            vec!["dotfiles"],
+        ),
+    ];
+    groups
+        .into_iter()
+        .filter(|(selected, _)| *selected)
+        .flat_map(|(_, steps)| steps)
+        .collect()
+}
+
+/// Logs the step and records what it changes under the id, so that `dotfiles uninstall <id>` can
+/// reverse it.
+fn step(id: &str, description: &str) {
+    state::begin_step(id);
+    info!("{}", description);
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::config::{AurHelper, Command};
+    use crate::profile::{Dotfiles, Packages, Profile};
     use crate::system::MockSystem;
+    use mockall::predicate::eq;
 
     use super::*;
 
@@ -283,12 +516,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: true,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -296,12 +533,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -320,9 +563,9 @@ mod tests {
         mock_system
             .expect_install_microsoft_edge()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -330,12 +573,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: true,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -343,12 +590,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -379,7 +632,7 @@ mod tests {
         mock_system
             .expect_install_groovy()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
         mock_system
             .expect_install_android_studio()
             .times(1)
@@ -419,7 +672,7 @@ mod tests {
         mock_system
             .expect_install_vscode()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
         mock_system
             .expect_install_xcode()
             .times(1)
@@ -437,7 +690,7 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -445,12 +698,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: true,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -458,12 +715,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -484,7 +747,7 @@ mod tests {
             .times(1)
             .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -492,12 +755,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: true,
             gcp: false,
             gnome: false,
@@ -505,12 +772,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -559,7 +832,7 @@ mod tests {
             .times(1)
             .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -567,12 +840,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: true,
             gnome: false,
@@ -580,12 +857,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -596,9 +879,9 @@ mod tests {
         mock_system
             .expect_install_google_cloud_sdk()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -606,12 +889,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -619,12 +906,18 @@ mod tests {
             images: true,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -645,7 +938,7 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -653,12 +946,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -666,12 +963,18 @@ mod tests {
             images: false,
             infrastructure: true,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -682,9 +985,9 @@ mod tests {
         mock_system
             .expect_install_terraform()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -692,12 +995,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -705,12 +1012,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: true,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -751,7 +1064,7 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -759,12 +1072,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -772,12 +1089,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: true,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -796,9 +1119,9 @@ mod tests {
         mock_system
             .expect_install_openscad()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -806,12 +1129,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -819,12 +1146,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: true,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -875,7 +1208,7 @@ mod tests {
         mock_system
             .expect_install_spotify()
             .times(0)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
         mock_system
             .expect_install_tauon_music_box()
             .times(1)
@@ -894,7 +1227,7 @@ mod tests {
             .returning(|| Ok(()));
         mock_system.expect_setup_nas().times(1).returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -902,12 +1235,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -915,12 +1252,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: true,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -933,7 +1276,7 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -941,12 +1284,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -954,12 +1301,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: true,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -976,7 +1329,7 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -984,12 +1337,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -997,12 +1354,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: true,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -1031,7 +1394,7 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -1039,12 +1402,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -1052,12 +1419,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: true,
             video_editing: false,
             vm: false,
@@ -1074,7 +1447,7 @@ mod tests {
             .times(1)
             .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -1082,12 +1455,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -1095,12 +1472,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: true,
             vm: false,
@@ -1113,7 +1496,7 @@ mod tests {
             .times(1)
             .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -1121,12 +1504,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -1134,12 +1521,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: true,
@@ -1152,7 +1545,56 @@ mod tests {
             .times(1)
             .returning(|| Ok(()));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
+    }
+
+    #[test]
+    fn test_install_fish() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let config = Config {
+            aur_helper: AurHelper::Yay,
+            browsers: false,
+            cli_only: false,
+            command: Command::Install,
+            debug: false,
+            development: false,
+            docker: false,
+            dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
+            gaming: false,
+            gcp: false,
+            gnome: false,
+            help: false,
+            images: false,
+            infrastructure: false,
+            kde: false,
+            keep_workspace: false,
+            laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
+            modelling: false,
+            personal: false,
+            prefer_flatpak: false,
+            printer: false,
+            profile: None,
+            recording: false,
+            ripping: false,
+            shell: Shell::Fish,
+            video: false,
+            video_editing: false,
+            vm: false,
+            vpn: false,
+            wsl: false,
+        };
+        let mut mock_system = get_mock_system(&config);
+        mock_system
+            .expect_install_fish()
+            .times(1)
+            .returning(|| Ok(()));
+
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     #[test]
@@ -1160,12 +1602,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: false,
             cli_only: false,
+            command: Command::Install,
             debug: false,
             development: false,
             docker: false,
             dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
             gaming: false,
             gcp: false,
             gnome: false,
@@ -1173,12 +1619,18 @@ mod tests {
             images: false,
             infrastructure: false,
             kde: false,
+            keep_workspace: false,
             laptop: false,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: false,
             personal: false,
+            prefer_flatpak: false,
             printer: false,
+            profile: None,
             recording: false,
             ripping: false,
+            shell: Shell::Zsh,
             video: false,
             video_editing: false,
             vm: false,
@@ -1191,7 +1643,124 @@ mod tests {
             .times(1)
             .returning(|| Box::pin(async { Ok(()) }));
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
+    }
+
+    #[test]
+    fn test_install_mirrors() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let config = Config {
+            aur_helper: AurHelper::Yay,
+            browsers: false,
+            cli_only: false,
+            command: Command::Install,
+            debug: false,
+            development: false,
+            docker: false,
+            dry_run: false,
+            flatpak_user: false,
+            from_bundle: None,
+            gaming: false,
+            gcp: false,
+            gnome: false,
+            help: false,
+            images: false,
+            infrastructure: false,
+            kde: false,
+            keep_workspace: false,
+            laptop: false,
+            local_repo: None,
+            mirror_countries: vec!["GB".to_string()],
+            modelling: false,
+            personal: false,
+            prefer_flatpak: false,
+            printer: false,
+            profile: None,
+            recording: false,
+            ripping: false,
+            shell: Shell::Zsh,
+            video: false,
+            video_editing: false,
+            vm: false,
+            vpn: false,
+            wsl: false,
+        };
+        let mut mock_system = get_mock_system(&config);
+        mock_system
+            .expect_rank_mirrors()
+            .times(1)
+            .returning(|| Box::pin(async { Ok(()) }));
+
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
+    }
+
+    #[test]
+    fn test_selected_steps_for_cli_only() {
+        let config = crate::config::parse(vec![
+            "--cli-only".to_string(),
+            "--docker".to_string(),
+            "--vpn".to_string(),
+        ]);
+
+        let steps = selected_steps(&config);
+
+        assert!(steps.contains(&"zsh"));
+        assert!(steps.contains(&"docker"));
+        assert!(!steps.contains(&"window-manager"));
+        assert!(!steps.contains(&"nordvpn"));
+    }
+
+    #[test]
+    fn test_selected_steps_covers_every_step() {
+        let mut config = crate::config::parse(
+            [
+                "--browsers",
+                "--development",
+                "--docker",
+                "--gaming",
+                "--gcp",
+                "--images",
+                "--infrastructure",
+                "--laptop",
+                "--modelling",
+                "--personal",
+                "--printer",
+                "--recording",
+                "--ripping",
+                "--shell",
+                "fish",
+                "--video",
+                "--video-editing",
+                "--vm",
+                "--vpn",
+            ]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect(),
+        );
+        config.profile = Some(Profile {
+            certificates: vec!["/home/user/Downloads/company-root.pem".to_string()],
+            dotfiles: Dotfiles {
+                repository: Some("https://github.com/user/dotfiles.git".to_string()),
+                ..Dotfiles::default()
+            },
+            extras: Packages {
+                packages: vec!["htop".to_string()],
+                ..Packages::default()
+            },
+            ..Profile::default()
+        });
+        let source = include_str!("install.rs");
+        let install_body = &source[..source.find("fn selected_steps").unwrap()];
+        let steps_in_install = install_body
+            .split("step(")
+            .skip(1)
+            .filter_map(|call| call.trim_start().strip_prefix('"'))
+            .filter_map(|call| call.split('"').next())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(selected_steps(&config), steps_in_install);
     }
 
     #[test]
@@ -1199,12 +1768,16 @@ mod tests {
         let rt = tokio::runtime::Runtime::new().unwrap();
 
         let config = Config {
+            aur_helper: AurHelper::Yay,
             browsers: true,
             cli_only: true,
+            command: Command::Install,
             debug: true,
             development: true,
             docker: true,
             dry_run: true,
+            flatpak_user: true,
+            from_bundle: None,
             gaming: true,
             gcp: true,
             gnome: true,
@@ -1212,12 +1785,18 @@ mod tests {
             images: true,
             infrastructure: true,
             kde: true,
+            keep_workspace: false,
             laptop: true,
+            local_repo: None,
+            mirror_countries: Vec::new(),
             modelling: true,
             personal: true,
+            prefer_flatpak: true,
             printer: true,
+            profile: None,
             recording: true,
             ripping: true,
+            shell: Shell::Zsh,
             video: true,
             video_editing: true,
             vm: true,
@@ -1225,6 +1804,11 @@ mod tests {
             wsl: true,
         };
         let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_create_snapshot()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(()));
         mock_system
             .expect_setup_user_bin()
             .times(1)
@@ -1295,7 +1879,7 @@ mod tests {
         mock_system
             .expect_install_groovy()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
         mock_system.expect_install_android_studio().times(0);
         mock_system.expect_install_intellij().times(0);
         mock_system
@@ -1360,14 +1944,14 @@ mod tests {
         mock_system
             .expect_install_google_cloud_sdk()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
         mock_system.expect_install_affinity_suite().times(0);
         mock_system.expect_install_gimp().times(0);
         mock_system.expect_install_inkscape().times(0);
         mock_system
             .expect_install_terraform()
             .times(1)
-            .returning(|| Ok(()));
+            .returning(|| Box::pin(async { Ok(()) }));
         mock_system
             .expect_install_bluetooth()
             .times(1)
@@ -1433,11 +2017,16 @@ mod tests {
         mock_system.expect_install_vm_tools().times(0);
         mock_system.expect_install_nordvpn().times(0);
 
-        assert!(rt.block_on(install(&config, &mock_system)).is_ok());
+        assert!(rt.block_on(install(&config, &mock_system, 1)).is_ok());
     }
 
     fn get_mock_system(_config: &Config) -> MockSystem {
         let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_create_snapshot()
+            .with(eq(1))
+            .times(1)
+            .returning(|_| Ok(()));
         mock_system
             .expect_setup_user_bin()
             .times(1)
@@ -9,9 +9,23 @@ use flate2::read::GzDecoder;
 use log::info;
 use tar::Archive;
 
+use crate::certificates::Certificate;
+use crate::config::Config;
+use crate::environment;
+use crate::error;
+use crate::managed_block;
+use crate::profile::Packages;
+use crate::shell::Shell;
+use crate::state::Change;
 use crate::system::System;
 use crate::system::{self, file_contains};
+use crate::template;
 use crate::unix;
+use flatpak::Installation;
+
+pub(crate) mod flatpak;
+pub(crate) mod mirrors;
+pub(crate) mod snapshot;
 
 /// Adds the module to the loaded kernel modules
 ///
@@ -22,15 +36,11 @@ use crate::unix;
 /// ```no_run
 /// use linux;
 ///
-/// linux::add_kernel_module("sg")?;
+/// linux::add_kernel_module(&system, "sg")?;
 /// ```
-pub(crate) fn add_kernel_module(module: &str) -> Result<(), Box<dyn Error>> {
+pub(crate) fn add_kernel_module(system: &impl System, module: &str) -> Result<(), Box<dyn Error>> {
     let module_path = format!("/etc/modules-load.d/{module}.conf");
-    let mut module_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(module_path)?;
+    let mut module_file = system::create_file(system, &module_path)?;
     write!(module_file, "{module}")?;
     Ok(())
 }
@@ -117,118 +127,259 @@ pub(crate) fn gnome_development_shortcuts(system: &dyn System) -> Result<(), Box
     Ok(())
 }
 
+/// Installs the profile's extra flatpaks from Flathub.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux;
+///
+/// linux::install_flatpak_extras(&system, &config, &profile.extras)?;
+/// ```
+pub(crate) fn install_flatpak_extras(
+    system: &dyn System,
+    config: &Config,
+    extras: &Packages,
+) -> Result<(), Box<dyn Error>> {
+    if extras.flatpaks.is_empty() {
+        return Ok(());
+    }
+    flatpak::install_applications(
+        system,
+        Installation::from(config),
+        flatpak::FLATHUB,
+        extras.flatpaks.iter().map(String::as_str).collect(),
+    )
+}
+
+/// Returns whether the changes that are made the same way on every distro are still in place.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux;
+/// use state::Change;
+///
+/// let exists = linux::is_applied(&system, &Change::Group { name: "nordvpn".to_string() })?;
+/// ```
+pub(crate) fn is_applied(system: &dyn System, change: &Change) -> Result<bool, Box<dyn Error>> {
+    Ok(match change {
+        Change::Block { file, id } => managed_block::read(file, id)?.is_some(),
+        Change::File { path, .. } => Path::new(path).exists(),
+        Change::Flatpak { application, user } => {
+            let installation = if *user {
+                Installation::User
+            } else {
+                Installation::System
+            };
+            flatpak::is_installed(system, installation, application)?
+        }
+        Change::Group { name } => unix::get_group_id_by_name(name).is_ok(),
+        Change::GroupMember { group, user } => {
+            unix::execute(&format!("id -nG {user}"), true, false, false)?
+                .split_whitespace()
+                .any(|joined| joined == group)
+        }
+        Change::Line { file, line } => managed_block::has_line(file, line)?,
+        Change::PathEntry { entry } => environment::contains_path(&system.get_home_dir(), entry)?,
+        Change::Variable { key } => environment::contains_variable(&system.get_home_dir(), key)?,
+        Change::Package { .. }
+        | Change::Repository { .. }
+        | Change::Service { .. }
+        | Change::Snap { .. } => {
+            return Err(Box::new(error::Error::new(&format!(
+                "Checking the {change} is not supported on this distro"
+            ))));
+        }
+    })
+}
+
+/// Returns whether the systemd service is enabled.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux;
+///
+/// let enabled = linux::is_service_enabled("nordvpnd")?;
+/// ```
+pub(crate) fn is_service_enabled(service: &str) -> Result<bool, Box<dyn Error>> {
+    Ok(unix::execute(
+        &format!("systemctl is-enabled {service}"),
+        true,
+        false,
+        false,
+    )?
+    .trim()
+        == "enabled")
+}
+
+/// Reverses the changes that are made the same way on every distro.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux;
+/// use state::Change;
+///
+/// linux::revert(&system, &Change::Group { name: "nordvpn".to_string() })?;
+/// ```
+pub(crate) fn revert(system: &dyn System, change: &Change) -> Result<(), Box<dyn Error>> {
+    match change {
+        Change::Block { file, id } => {
+            managed_block::remove(file, id)?;
+        }
+        Change::File { path, .. } => {
+            if Path::new(path).exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Change::Flatpak { application, user } => {
+            let installation = if *user {
+                Installation::User
+            } else {
+                Installation::System
+            };
+            flatpak::uninstall_applications(system, installation, vec![application])?;
+        }
+        Change::Group { name } => {
+            system.execute(&format!("groupdel {name}"), true)?;
+        }
+        Change::GroupMember { group, user } => {
+            system.execute(&format!("gpasswd -d {user} {group}"), true)?;
+        }
+        Change::Line { file, line } => {
+            managed_block::remove_line(file, line)?;
+        }
+        Change::PathEntry { entry } => {
+            environment::remove_from_path(&system.get_home_dir(), entry)?;
+        }
+        Change::Variable { key } => {
+            environment::remove_variable(&system.get_home_dir(), key)?;
+        }
+        Change::Package { .. }
+        | Change::Repository { .. }
+        | Change::Service { .. }
+        | Change::Snap { .. } => {
+            return Err(Box::new(error::Error::new(&format!(
+                "Removing the {change} is not supported on this distro"
+            ))));
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn set_development_environment_settings() -> Result<(), std::io::Error> {
     info!("Setting mmapfs limit for Elasticsearch");
-    system::add_to_file("/etc/sysctl.conf", "vm.max_map_count=262144")?;
+    system::add_to_file(
+        "/etc/sysctl.conf",
+        "elasticsearch",
+        "vm.max_map_count=262144",
+    )?;
     Ok(())
 }
 
-pub(crate) fn setup_davinci_resolve(system: &dyn System) -> Result<(), std::io::Error> {
+pub(crate) fn setup_davinci_resolve(
+    system: &dyn System,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
     info!("Setting .license folder to have permissions for anyone to write to, so the license key be validated");
     unix::recursively_chmod("/opt/resolve/.license", &0o777, &0o777)?;
 
     info!("Setting up DaVinci Resolve helper scripts");
 
-    let convert_audio = format!("{}/bin/convert_audio", system.get_home_dir());
-    let mut convert_audio_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&convert_audio)?;
-
-    write!(
-        convert_audio_file,
-        r##"#!/usr/bin/env bash
-set -e
-shopt -s extglob nullglob
-directory=$1
-backup_dir="$directory/original"
-extensions="${{@:2}}"
-extensions="${{extensions:-m4a aac}}"
-echo $extensions
-if [ ! -d "$backup_dir" ]; then
-    echo "Creating $backup_dir directory."
-    mkdir "$backup_dir"
-fi
-
-for ext in $extensions; do
-    for audio in "$directory"/*.$ext; do
-        ffmpeg -i "$audio" -f flac "converted.flac"
-        filename=$(basename \"$audio\")
-        noext="${{filename%.$ext}}"
-        echo $noext
-        mv "$audio" "$backup_dir"
-        mv "converted.flac" "$directory/${{noext// /_}}.flac"
-    done
-done
-"##
-    )?;
-    unix::recursively_chmod(&convert_audio, &0o755, &0o755)?;
-
-    let convert_video = format!("{}/bin/convert_videos", system.get_home_dir());
-    let mut convert_video_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&convert_video)?;
-
-    write!(
-        convert_video_file,
-        r##"#!/usr/bin/env bash
-
-set -e
-video=$1
-codec=${{2:-pcm_s16le}}
-container=${{3:-mov}}
-directory="$(basename "$(dirname "$video")")"
-backup_dir="$directory/original"
-
-if [ ! -d "$backup_dir" ]; then
-    echo "Creating $backup_dir directory."
-    mkdir "$backup_dir"
-fi
-
-ffmpeg -i "$video" -acodec "$codec" -vcodec copy "converted.$container"
-filename=$(basename "$video")
-extension="${{filename##*.}}"
-noext="${{filename%.$extension}}"
-echo $noext
-mv "$video" "$backup_dir"
-mv "converted.$container" "$directory/${{noext// /_}}.$container"
-"##
-    )?;
-    unix::recursively_chmod(&convert_video, &0o755, &0o755)?;
-    let convert_videos = format!("{}/bin/convert_videos", system.get_home_dir());
-
-    let mut convert_videos_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&convert_videos)?;
-
-    write!(
-        convert_videos_file,
-        r##"#!/usr/bin/env bash
-
-set -e
-shopt -s extglob nullglob
-directory=${{1:-.}}
-backup_dir="$directory/original"
-extensions="${{@:2}}"
-extensions="${{extensions:-mp4 MP4}}"
-echo $extensions
-
-for ext in $extensions; do
-    for video in "$directory"/*.$ext; do
-        convert_video "$video" pcm_s16le mov
-    done
-done
-"##
-    )?;
-    unix::recursively_chmod(&convert_videos, &0o755, &0o755)?;
+    for script in ["convert_audio", "convert_video", "convert_videos"] {
+        let file = format!("{}/bin/{script}", system.get_home_dir());
+        template::write(system, config, script, &file)?;
+        unix::recursively_chmod(&file, &0o755, &0o755)?;
+    }
+    Ok(())
+}
+
+/// Where Firefox keeps its profiles under the home directory, when installed as a package, a snap
+/// or a flatpak.
+static FIREFOX_PROFILES: [&str; 3] = [
+    ".mozilla/firefox",
+    "snap/firefox/common/.mozilla/firefox",
+    ".var/app/org.mozilla.firefox/.mozilla/firefox",
+];
+
+/// Adds the CA certificate to the NSS databases Chrome, in `~/.pki/nssdb`, and every Firefox
+/// profile use, whether Firefox is a package, a snap or a flatpak, creating Chrome's when it
+/// doesn't exist yet.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux;
+///
+/// linux::add_certificate_to_nss(&system, &certificate)?;
+/// ```
+pub(crate) fn add_certificate_to_nss(
+    system: &impl System,
+    certificate: &Certificate,
+) -> Result<(), Box<dyn Error>> {
+    let home = get_home_dir();
+    let chrome = format!("{home}/.pki/nssdb");
+    if !Path::new(&chrome).join("cert9.db").exists() {
+        system.run("mkdir", vec!["-p", &chrome], false)?;
+        system.run(
+            "certutil",
+            vec!["-d", &format!("sql:{chrome}"), "-N", "--empty-password"],
+            false,
+        )?;
+    }
+    let mut databases = vec![chrome];
+    for profiles in FIREFOX_PROFILES {
+        databases.extend(nss_databases(&format!("{home}/{profiles}")));
+    }
+    for database in databases {
+        info!("Adding {} to {}", certificate.name, database);
+        system.run(
+            "certutil",
+            vec![
+                "-d",
+                &format!("sql:{database}"),
+                "-A",
+                "-t",
+                "C,,",
+                "-n",
+                &certificate.name,
+                "-i",
+                &certificate.file,
+            ],
+            false,
+        )?;
+    }
     Ok(())
 }
 
+/// Returns the directories directly in the directory that have an NSS database, e.g. the Firefox
+/// profiles that have been used.
+fn nss_databases(directory: &str) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut databases = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.join("cert9.db").exists())
+        .map(|path| path.to_string_lossy().to_string())
+        .collect::<Vec<String>>();
+    databases.sort();
+    databases
+}
+
 pub(crate) fn setup_docker(dry_run: bool) -> Result<(), Box<dyn Error>> {
     info!("Creating docker group");
     unix::create_group("docker", dry_run)?;
@@ -246,11 +397,7 @@ pub(crate) fn setup_nas(system: &impl System, dry_run: bool) -> Result<(), Box<d
     info!("Setting up NAS scripts");
     let smb_credentials = format!("{}/.smbcredentials", system.get_home_dir());
     if !Path::new(&smb_credentials).exists() {
-        let mut smb_credentials_file = OpenOptions::new()
-            .create(true)
-            .truncate(false)
-            .write(true)
-            .open(&smb_credentials)?;
+        let mut smb_credentials_file = system::create_file(system, &smb_credentials)?;
 
         writeln!(smb_credentials_file, "username=")?;
         writeln!(smb_credentials_file, "password=")?;
@@ -267,36 +414,32 @@ pub(crate) fn setup_nas(system: &impl System, dry_run: bool) -> Result<(), Box<d
 
     let benjamin_mount = "/mnt/benjamin";
     if !Path::new(benjamin_mount).exists() {
-        fs::create_dir_all(benjamin_mount)?;
+        system::create_dir(benjamin_mount)?;
         unix::recursively_chown(benjamin_mount, &user_id, &user_group_id)?;
     }
     let music_mount = "/mnt/music";
     if !Path::new(music_mount).exists() {
-        fs::create_dir_all(music_mount)?;
+        system::create_dir(music_mount)?;
         unix::recursively_chown(music_mount, &user_id, &nas_group_id)?;
     }
     let photo_mount = "/mnt/photo";
     if !Path::new(photo_mount).exists() {
-        fs::create_dir_all(photo_mount)?;
+        system::create_dir(photo_mount)?;
         unix::recursively_chown(photo_mount, &user_id, &nas_group_id)?;
     }
     let shared_mount = "/mnt/shared";
     if !Path::new(shared_mount).exists() {
-        fs::create_dir_all(shared_mount)?;
+        system::create_dir(shared_mount)?;
         unix::recursively_chown(shared_mount, &user_id, &nas_group_id)?;
     }
     let videos_mount = "/mnt/videos";
     if !Path::new(videos_mount).exists() {
-        fs::create_dir_all(videos_mount)?;
+        system::create_dir(videos_mount)?;
         unix::recursively_chown(videos_mount, &user_id, &nas_group_id)?;
     }
 
     let mount_nas = format!("{}/bin/mount-nas", system.get_home_dir());
-    let mut mount_nas_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&mount_nas)?;
+    let mut mount_nas_file = system::create_file(system, &mount_nas)?;
 
     writeln!(mount_nas_file, "#!/usr/bin/env bash")?;
     writeln!(mount_nas_file, "sudo mount -t cifs -o rw,uid=$(id -u),gid=$(id -g),credentials=/home/benjamin/.smbcredentials,vers=3.0 //192.168.1.225/homes/benjamin {benjamin_mount}")?;
@@ -308,11 +451,7 @@ pub(crate) fn setup_nas(system: &impl System, dry_run: bool) -> Result<(), Box<d
     unix::recursively_chmod(&mount_nas, &0o755, &0o755)?;
 
     let unmount_nas = format!("{}/bin/unmount-nas", system.get_home_dir());
-    let mut unmount_nas_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&unmount_nas)?;
+    let mut unmount_nas_file = system::create_file(system, &unmount_nas)?;
 
     writeln!(unmount_nas_file, "#!/usr/bin/env bash")?;
     writeln!(unmount_nas_file, "sudo umount {benjamin_mount}")?;
@@ -327,10 +466,37 @@ pub(crate) fn setup_nas(system: &impl System, dry_run: bool) -> Result<(), Box<d
 }
 
 pub(crate) fn setup_nodejs(system: &dyn System) -> Result<(), Box<dyn Error>> {
-    let nvm_content = "export NVM_DIR=\"$([ -z \"${{XDG_CONFIG_HOME-}}\" ] && printf %s \"${{HOME}}/.nvm\" || printf %s \"${{XDG_CONFIG_HOME}}/nvm\")\"\n\
-    [ -s \"$NVM_DIR/nvm.sh\" ] && \\. \"$NVM_DIR/nvm.sh\" # This loads nvm";
-    system::add_to_file(&format!("{}/.zshrc", system.get_home_dir()), nvm_content)?;
-    system::add_to_file(&format!("{}/.bashrc", system.get_home_dir()), nvm_content)?;
+    environment::set_variable(system, "NVM_DIR", "$HOME/.nvm")?;
+    let nvm_content = "[ -s \"$NVM_DIR/nvm.sh\" ] && \\. \"$NVM_DIR/nvm.sh\" # This loads nvm";
+    // fish can't source nvm.sh, so nvm runs in bash, which hands back the PATH it leaves for fish
+    // to take on, and the default version is put on the PATH when an interactive shell starts.
+    let fish_nvm_content = format!(
+        "{}\n{}",
+        Shell::Fish.function(
+            "nvm",
+            "set --local path_file (mktemp)\n\
+            env NVM_PATH_FILE=$path_file bash -c 'source \"$NVM_DIR/nvm.sh\"; nvm \"$@\"; code=$?; printf %s \"$PATH\" > \"$NVM_PATH_FILE\"; exit $code' nvm $argv\n\
+            set --local code $status\n\
+            test -s $path_file; and set --global --export PATH (string split : (cat $path_file))\n\
+            rm -f $path_file\n\
+            return $code"
+        ),
+        "if status is-interactive; and test -s \"$NVM_DIR/alias/default\"\n\
+        nvm use default --silent >/dev/null\n\
+        end"
+    );
+    for shell in Shell::configured(&system.get_home_dir()) {
+        let content = match shell {
+            Shell::Bash | Shell::Zsh => nvm_content,
+            Shell::Fish => &fish_nvm_content,
+        };
+        let file = format!("{}/{}", system.get_home_dir(), shell.config_file());
+        // NVM_DIR comes from the env file, so a block from before it was sourced moves after it.
+        if environment::is_before_sources(&file, "nvm")? {
+            managed_block::remove(&file, "nvm")?;
+        }
+        system::add_to_file(&file, "nvm", content)?;
+    }
 
     let zsh_nvm_dir = "autoload -U add-zsh-hook\n\
         load-nvmrc() {\n\
@@ -350,10 +516,13 @@ pub(crate) fn setup_nodejs(system: &dyn System) -> Result<(), Box<dyn Error>> {
         }\n\
         add-zsh-hook chpwd load-nvmrc\n\
         load-nvmrc";
-    system::add_to_file(&format!("{}/.zshrc", system.get_home_dir()), zsh_nvm_dir)?;
+    system::add_to_file(
+        &format!("{}/.zshrc", system.get_home_dir()),
+        "nvm-auto-use",
+        zsh_nvm_dir,
+    )?;
 
-    let bash_nvm_dir = "cdnvm() {\n\
-            command cd \"$@\";\n\
+    let cdnvm = "command cd \"$@\";\n\
             nvm_path=$(nvm_find_up .nvmrc | tr -d '\n')\n\
             # If there are no .nvmrc file, use the default nvm version\n\
             if [[ ! $nvm_path = *[^[:space:]]* ]]; then\n\
@@ -385,11 +554,17 @@ pub(crate) fn setup_nodejs(system: &dyn System) -> Result<(), Box<dyn Error>> {
                 elif [[ $(nvm current) != \"$locally_resolved_nvm_version\" ]]; then\n\
                     nvm use \"$nvm_version\";\n\
                 fi\n\
-            fi\n\
-        }\n\
-        alias cd='cdnvm'\n\
-        cd \"$PWD\"";
-    system::add_to_file(&format!("{}/.bashrc", system.get_home_dir()), bash_nvm_dir)?;
+            fi";
+    let bash_nvm_dir = format!(
+        "{}\n{}\ncd \"$PWD\"",
+        Shell::Bash.function("cdnvm", cdnvm),
+        Shell::Bash.alias("cd", "cdnvm")
+    );
+    system::add_to_file(
+        &format!("{}/.bashrc", system.get_home_dir()),
+        "nvm-auto-use",
+        &bash_nvm_dir,
+    )?;
 
     system.execute("nvm install node --latest-npm", false)?;
     system.execute("npm install --global yarn", false)?;
@@ -404,11 +579,15 @@ pub(crate) fn setup_power_saving_tweaks() -> Result<(), std::io::Error> {
     if device_name == "XPS 15 9570" {
         let mem_sleep = "/sys/power/mem_sleep";
         if !file_contains(mem_sleep, "s2idle [deep]") {
-            let mut mem_sleep_file = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(mem_sleep)?;
-            writeln!(mem_sleep_file, "s2idle [deep]")?;
+            if system::is_dry_run() {
+                info!("Would add s2idle [deep] to {}", mem_sleep);
+            } else {
+                let mut mem_sleep_file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(mem_sleep)?;
+                writeln!(mem_sleep_file, "s2idle [deep]")?;
+            }
         }
 
         let original_grub_file = File::open("/etc/default/grub")?;
@@ -432,16 +611,21 @@ pub(crate) fn setup_power_saving_tweaks() -> Result<(), std::io::Error> {
             })
             .collect::<Vec<String>>();
 
+        if system::is_dry_run() {
+            info!("Would add mem_sleep_default to /etc/default/grub");
+            return Ok(());
+        }
         let mut new_grub_file = OpenOptions::new().append(true).open("/etc/default/grub")?;
         new_grub_file.write_all(new_lines.join("\n").as_bytes())?;
     }
     Ok(())
 }
 
-pub(crate) fn setup_tmux(system: &impl System) -> Result<(), std::io::Error> {
-    unix::setup_tmux(system)?;
+pub(crate) fn setup_tmux(system: &impl System, config: &Config) -> Result<(), Box<dyn Error>> {
+    unix::setup_tmux(system, config)?;
     system::add_to_file(
         &format!("{}/.tmux.conf", system.get_home_dir()),
+        "clipboard",
         "bind -T copy-mode-vi y send-keys -X copy-pipe-and-cancel 'xclip -in -selection clipboard'",
     )?;
     Ok(())
@@ -459,6 +643,10 @@ pub(crate) fn setup_tmux(system: &impl System) -> Result<(), std::io::Error> {
 /// linux::untar_rename_root("/path/to/tar", "/path/to/dest");
 /// ```
 pub(crate) fn untar_rename_root(src: &str, dest: &str) -> Result<(), std::io::Error> {
+    if system::is_dry_run() {
+        info!("Would extract {} into {}", src, dest);
+        return Ok(());
+    }
     let file = File::open(src)?;
     let mut archive = Archive::new(GzDecoder::new(file));
 
@@ -476,53 +664,11 @@ pub(crate) fn untar_rename_root(src: &str, dest: &str) -> Result<(), std::io::Er
     Ok(())
 }
 
-pub(crate) fn gtk_theme(system: &impl System) -> Result<(), std::io::Error> {
-    let theme = "@import \"colors.css\";\n\
-\n\
-window.ssd headerbar.titlebar {\n\
-  padding-top: 2px;\n\
-  padding-bottom: 2px;\n\
-  min-height: 0;\n\
-}\n\
-\n\
-window.ssd headerbar.titlebar button.titlebutton {\n\
-  padding-top: 2px;\n\
-  padding-bottom: 2px;\n\
-  min-height: 0;\n\
-}\n\
-\n\
-/* shrink headebars */\n\
-headerbar {\n\
-  min-height: 38px;\n\
-  /* same as childrens vertical margins for nicer proportions */\n\
-  padding-left: 2px;\n\
-  padding-right: 2px;\n\
-}\n\
-\n\
-headerbar entry,\n\
-headerbar spinbutton,\n\
-headerbar button,\n\
-headerbar separator {\n\
-  /* same as headerbar side padding for nicer proportions */\n\
-  margin-top: 2px;\n\
-  margin-bottom: 2px;\n\
-}\n\
-\n\
-/* shrink ssd titlebars */\n\
-.default-decoration {\n\
-  /* let the entry and button drive the titlebar size */\n\
-  min-height: 0;\n\
-  padding: 2px;\n\
-}\n\
-\n\
-.default-decoration .titlebutton {\n\
-  /* tweak these two props to reduce button size */\n\
-  min-height: 26px;\n\
-  min-width: 26px;\n\
-}\n";
+pub(crate) fn gtk_theme(system: &impl System, config: &Config) -> Result<(), Box<dyn Error>> {
     system::add_to_file(
         &format!("{}/.config/gtk-3.0/gtk.css", system.get_home_dir()),
-        theme,
+        "titlebar",
+        &template::render(system, config, "gtk.css")?,
     )?;
     Ok(())
 }
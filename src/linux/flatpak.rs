@@ -0,0 +1,565 @@
+use log::{debug, info};
+use std::error::Error;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::state::{self, Change};
+use crate::system::System;
+use crate::unix;
+
+pub(crate) static FLATHUB: &str = "flathub";
+
+pub(crate) static FLATHUB_URL: &str = "https://dl.flathub.org/repo/flathub.flatpakrepo";
+
+/// Where the flatpak applications get installed to.
+///
+/// User installations live under the user's home and are managed as the user, whereas system
+/// installations are shared between all users and need to be managed as a super user.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Installation {
+    System,
+    User,
+}
+
+impl Installation {
+    fn flag(&self) -> &str {
+        match self {
+            Installation::System => "--system",
+            Installation::User => "--user",
+        }
+    }
+
+    fn super_user(&self) -> bool {
+        *self == Installation::System
+    }
+}
+
+impl From<&Config> for Installation {
+    fn from(config: &Config) -> Self {
+        if config.flatpak_user {
+            Installation::User
+        } else {
+            Installation::System
+        }
+    }
+}
+
+/// Permission overrides that can be applied to a flatpak application.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Override {
+    Environment(String, String),
+    Filesystem(String),
+    Socket(String),
+}
+
+impl Override {
+    fn arg(&self) -> String {
+        match self {
+            Override::Environment(key, value) => format!("--env={key}={value}"),
+            Override::Filesystem(filesystem) => format!("--filesystem={filesystem}"),
+            Override::Socket(socket) => format!("--socket={socket}"),
+        }
+    }
+}
+
+/// Adds the remote, only if it hasn't already been added.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// flatpak::add_remote(&system, Installation::User, "flathub", "https://dl.flathub.org/repo/flathub.flatpakrepo")?;
+/// ```
+pub(crate) fn add_remote(
+    system: &dyn System,
+    installation: Installation,
+    name: &str,
+    url: &str,
+) -> Result<(), Box<dyn Error>> {
+    system.execute(
+        &format!(
+            "flatpak remote-add --if-not-exists {} {name} {url}",
+            installation.flag()
+        ),
+        installation.super_user(),
+    )?;
+    Ok(())
+}
+
+/// Installs the provided application from the remote, without prompting.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// flatpak::install_application(&system, Installation::System, "flathub", "org.gimp.GIMP")?;
+/// ```
+pub(crate) fn install_application(
+    system: &dyn System,
+    installation: Installation,
+    remote: &str,
+    application: &str,
+) -> Result<(), Box<dyn Error>> {
+    install_applications(system, installation, remote, vec![application])
+}
+
+/// Installs the provided applications from the remote, without prompting.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// flatpak::install_applications(&system, Installation::System, "flathub", vec!["org.gimp.GIMP", "org.inkscape.Inkscape"])?;
+/// ```
+pub(crate) fn install_applications(
+    system: &dyn System,
+    installation: Installation,
+    remote: &str,
+    applications: Vec<&str>,
+) -> Result<(), Box<dyn Error>> {
+    debug!("Installing {} from {}", applications.join(", "), remote);
+    system.execute(
+        &format!(
+            "flatpak install -y --noninteractive {} {remote} {}",
+            installation.flag(),
+            applications.join(" ")
+        ),
+        installation.super_user(),
+    )?;
+    for application in applications {
+        state::record(Change::Flatpak {
+            application: application.to_string(),
+            user: installation == Installation::User,
+        });
+    }
+    Ok(())
+}
+
+/// Installs the application from Flathub if the config prefers flatpak for GUI applications.
+///
+/// Returns whether the application was handled by flatpak, so the caller knows whether it still
+/// needs to install the native package.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak;
+///
+/// if !flatpak::install_if_preferred(&system, &config, "org.gimp.GIMP")? {
+///     system.install_application("gimp")?;
+/// }
+/// ```
+pub(crate) fn install_if_preferred(
+    system: &dyn System,
+    config: &Config,
+    application: &str,
+) -> Result<bool, Box<dyn Error>> {
+    if !config.prefer_flatpak {
+        return Ok(false);
+    }
+    let installation = Installation::from(config);
+    if !is_installed(system, installation, application)? {
+        info!("Installing {} from Flathub", application);
+        add_remote(system, installation, FLATHUB, FLATHUB_URL)?;
+        install_application(system, installation, FLATHUB, application)?;
+    }
+    Ok(true)
+}
+
+/// Returns whether the application is installed in the given installation.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// let installed = flatpak::is_installed(&system, Installation::User, "org.gimp.GIMP")?;
+/// ```
+pub(crate) fn is_installed(
+    system: &dyn System,
+    installation: Installation,
+    application: &str,
+) -> Result<bool, Box<dyn Error>> {
+    Ok(list_applications(system, installation)?
+        .iter()
+        .any(|installed| installed == application))
+}
+
+/// Returns the IDs of the applications installed in either installation, or none when flatpak
+/// isn't installed.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak;
+///
+/// let applications = flatpak::list_all_applications(&system)?;
+/// ```
+pub(crate) fn list_all_applications(system: &dyn System) -> Result<Vec<String>, Box<dyn Error>> {
+    if !Path::new("/usr/bin/flatpak").exists() {
+        return Ok(Vec::new());
+    }
+    let mut applications = list_applications(system, Installation::System)?;
+    for application in list_applications(system, Installation::User)? {
+        if !applications.contains(&application) {
+            applications.push(application);
+        }
+    }
+    Ok(applications)
+}
+
+/// Returns the IDs of the applications installed in the given installation.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// let applications = flatpak::list_applications(&system, Installation::System)?;
+/// ```
+pub(crate) fn list_applications(
+    system: &dyn System,
+    installation: Installation,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let command = format!(
+        "flatpak list --app --columns=application {}",
+        installation.flag()
+    );
+    let output = match installation {
+        Installation::System => system.query(&command)?,
+        // Queries run as root, whose user installation isn't the user's.
+        Installation::User => {
+            system.query(&format!("sudo -u {} {command}", unix::get_username()))?
+        }
+    };
+    Ok(output
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect())
+}
+
+/// Applies the permission overrides to the application.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation, Override};
+///
+/// flatpak::override_application(&system, Installation::User, "org.gimp.GIMP", vec![Override::Socket("wayland".to_string())])?;
+/// ```
+pub(crate) fn override_application(
+    system: &dyn System,
+    installation: Installation,
+    application: &str,
+    overrides: Vec<Override>,
+) -> Result<(), Box<dyn Error>> {
+    let args = overrides
+        .iter()
+        .map(|o| o.arg())
+        .collect::<Vec<String>>()
+        .join(" ");
+    system.execute(
+        &format!(
+            "flatpak override {} {args} {application}",
+            installation.flag()
+        ),
+        installation.super_user(),
+    )?;
+    Ok(())
+}
+
+/// Removes the provided applications, along with any runtimes that are no longer used.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// flatpak::uninstall_applications(&system, Installation::System, vec!["org.gimp.GIMP"])?;
+/// ```
+pub(crate) fn uninstall_applications(
+    system: &dyn System,
+    installation: Installation,
+    applications: Vec<&str>,
+) -> Result<(), Box<dyn Error>> {
+    system.execute(
+        &format!(
+            "flatpak uninstall -y --noninteractive {} {}",
+            installation.flag(),
+            applications.join(" ")
+        ),
+        installation.super_user(),
+    )?;
+    system.execute(
+        &format!(
+            "flatpak uninstall -y --noninteractive --unused {}",
+            installation.flag()
+        ),
+        installation.super_user(),
+    )?;
+    Ok(())
+}
+
+/// Updates all the applications and runtimes in the given installation, without prompting.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::flatpak::{self, Installation};
+///
+/// flatpak::update_applications(&system, Installation::System)?;
+/// ```
+pub(crate) fn update_applications(
+    system: &dyn System,
+    installation: Installation,
+) -> Result<(), Box<dyn Error>> {
+    system.execute(
+        &format!("flatpak update -y --noninteractive {}", installation.flag()),
+        installation.super_user(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::config::parse;
+    use crate::system::MockSystem;
+    use serial_test::serial;
+    use std::env;
+
+    #[test]
+    fn test_add_remote_as_user() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak remote-add --if-not-exists --user flathub https://dl.flathub.org/repo/flathub.flatpakrepo"),
+                eq(false),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = add_remote(&mock_system, Installation::User, FLATHUB, FLATHUB_URL);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_install_applications_is_non_interactive() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak install -y --noninteractive --system flathub org.gimp.GIMP org.inkscape.Inkscape"),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = install_applications(
+            &mock_system,
+            Installation::System,
+            FLATHUB,
+            vec!["org.gimp.GIMP", "org.inkscape.Inkscape"],
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_install_if_preferred_does_nothing_when_not_preferred() {
+        let config = parse(vec![]);
+        let mut mock_system = MockSystem::new();
+        mock_system.expect_execute().times(0);
+
+        let result = install_if_preferred(&mock_system, &config, "org.gimp.GIMP");
+        assert!(!result.unwrap());
+    }
+
+    #[test]
+    fn test_install_if_preferred_skips_installed_application() {
+        let config = parse(vec!["--prefer-flatpak".to_string()]);
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .with(eq("flatpak list --app --columns=application --system"))
+            .times(1)
+            .returning(|_| Ok("org.gimp.GIMP\norg.inkscape.Inkscape\n".to_string()));
+
+        let result = install_if_preferred(&mock_system, &config, "org.gimp.GIMP");
+        assert!(result.unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_install_if_preferred_installs_for_user() {
+        env::set_var("SUDO_USER", "myuser");
+        let config = parse(vec![
+            "--prefer-flatpak".to_string(),
+            "--flatpak-user".to_string(),
+        ]);
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .with(eq(
+                "sudo -u myuser flatpak list --app --columns=application --user",
+            ))
+            .times(1)
+            .returning(|_| Ok(String::new()));
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak remote-add --if-not-exists --user flathub https://dl.flathub.org/repo/flathub.flatpakrepo"),
+                eq(false),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak install -y --noninteractive --user flathub org.gimp.GIMP"),
+                eq(false),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = install_if_preferred(&mock_system, &config, "org.gimp.GIMP");
+        assert!(result.unwrap());
+    }
+
+    #[test]
+    fn test_install_if_preferred_installs_system_wide() {
+        let config = parse(vec!["--prefer-flatpak".to_string()]);
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .with(eq("flatpak list --app --columns=application --system"))
+            .times(1)
+            .returning(|_| Ok(String::new()));
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak remote-add --if-not-exists --system flathub https://dl.flathub.org/repo/flathub.flatpakrepo"),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak install -y --noninteractive --system flathub org.gimp.GIMP"),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = install_if_preferred(&mock_system, &config, "org.gimp.GIMP");
+        assert!(result.unwrap());
+    }
+
+    #[test]
+    fn test_list_applications_ignores_blank_lines() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .returning(|_| Ok("org.gimp.GIMP\n\n com.valvesoftware.Steam \n".to_string()));
+
+        let applications = list_applications(&mock_system, Installation::System).unwrap();
+        assert_eq!(
+            applications,
+            vec![
+                "org.gimp.GIMP".to_string(),
+                "com.valvesoftware.Steam".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_override_application() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak override --user --filesystem=home --socket=wayland --env=GTK_USE_PORTAL=1 org.gimp.GIMP"),
+                eq(false),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = override_application(
+            &mock_system,
+            Installation::User,
+            "org.gimp.GIMP",
+            vec![
+                Override::Filesystem("home".to_string()),
+                Override::Socket("wayland".to_string()),
+                Override::Environment("GTK_USE_PORTAL".to_string(), "1".to_string()),
+            ],
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_uninstall_applications_removes_unused_runtimes() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak uninstall -y --noninteractive --system org.gimp.GIMP"),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+        mock_system
+            .expect_execute()
+            .with(
+                eq("flatpak uninstall -y --noninteractive --unused --system"),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result =
+            uninstall_applications(&mock_system, Installation::System, vec!["org.gimp.GIMP"]);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_update_applications() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_execute()
+            .with(eq("flatpak update -y --noninteractive --user"), eq(false))
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = update_applications(&mock_system, Installation::User);
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,115 @@
+use log::{info, warn};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::network;
+
+/// How long to wait for a mirror to respond before treating it as unusable.
+static TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A mirror along with the URL used to benchmark it.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Mirror {
+    pub(crate) url: String,
+    pub(crate) probe: String,
+}
+
+/// Times how long each mirror takes to respond to a request for its probe URL, concurrently.
+///
+/// Mirrors that fail or time out have no latency.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::mirrors;
+///
+/// let latencies = mirrors::measure(mirrors).await?;
+/// ```
+pub(crate) async fn measure(
+    mirrors: Vec<Mirror>,
+) -> Result<Vec<(Mirror, Option<Duration>)>, Box<dyn Error>> {
+    let client = network::client_builder()?.timeout(TIMEOUT).build()?;
+    let mut requests = JoinSet::new();
+    for mirror in mirrors {
+        let client = client.clone();
+        requests.spawn(async move {
+            let start = Instant::now();
+            let latency = match client.head(&mirror.probe).send().await {
+                Ok(response) if response.status().is_success() => Some(start.elapsed()),
+                _ => None,
+            };
+            (mirror, latency)
+        });
+    }
+    let mut latencies = Vec::new();
+    while let Some(result) = requests.join_next().await {
+        latencies.push(result?);
+    }
+    Ok(latencies)
+}
+
+/// Orders the responsive mirrors from fastest to slowest, printing the latency of each mirror.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::mirrors;
+///
+/// let ranked = mirrors::rank(mirrors::measure(mirrors).await?);
+/// ```
+pub(crate) fn rank(latencies: Vec<(Mirror, Option<Duration>)>) -> Vec<Mirror> {
+    let (mut responsive, unresponsive): (Vec<_>, Vec<_>) = latencies
+        .into_iter()
+        .partition(|(_, latency)| latency.is_some());
+    responsive.sort_by_key(|(_, latency)| *latency);
+    for (mirror, latency) in &responsive {
+        info!(
+            "{:>6} ms {}",
+            latency.unwrap_or_default().as_millis(),
+            mirror.url
+        );
+    }
+    for (mirror, _) in &unresponsive {
+        warn!("  failed {}", mirror.url);
+    }
+    responsive.into_iter().map(|(mirror, _)| mirror).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(url: &str) -> Mirror {
+        Mirror {
+            url: url.to_string(),
+            probe: format!("{url}/probe"),
+        }
+    }
+
+    #[test]
+    fn test_rank_orders_by_latency_and_drops_failures() {
+        let ranked = rank(vec![
+            (mirror("https://slow"), Some(Duration::from_millis(300))),
+            (mirror("https://down"), None),
+            (mirror("https://fast"), Some(Duration::from_millis(20))),
+        ]);
+
+        assert_eq!(ranked, vec![mirror("https://fast"), mirror("https://slow")]);
+    }
+
+    #[test]
+    fn test_measure_has_no_latency_for_unreachable_mirrors() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let latencies = rt
+            .block_on(measure(vec![mirror("http://127.0.0.1:9")]))
+            .unwrap();
+
+        assert_eq!(latencies, vec![(mirror("http://127.0.0.1:9"), None)]);
+    }
+}
@@ -0,0 +1,416 @@
+use log::{info, warn};
+use std::error::Error;
+use std::path::Path;
+
+use crate::error;
+use crate::system::System;
+use crate::unix;
+
+/// Snapshots are labelled with this followed by the run id, e.g. `dotfiles-run-42`.
+static LABEL_PREFIX: &str = "dotfiles-run-";
+
+/// The copy-on-write space given to LVM snapshots, which must be free in the volume group.
+static LVM_SNAPSHOT_SIZE_GB: f64 = 10.0;
+
+/// The tool able to snapshot the root filesystem.
+#[derive(Debug, PartialEq)]
+enum Tool {
+    Lvm {
+        volume_group: String,
+        logical_volume: String,
+    },
+    Snapper,
+    Timeshift,
+}
+
+/// A snapshot taken before a run.
+#[derive(Debug, PartialEq)]
+struct Snapshot {
+    /// How the tool refers to the snapshot.
+    id: String,
+    run: u32,
+    created: String,
+}
+
+/// Snapshots the root filesystem with snapper or timeshift on btrfs, or LVM, labelling the
+/// snapshot with the run id.
+///
+/// Warns and carries on without a snapshot when none of these are available, but fails when the
+/// one that is can't take it.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::snapshot;
+///
+/// snapshot::create(&system, 42)?;
+/// ```
+pub(crate) fn create(system: &dyn System, run: u32) -> Result<(), Box<dyn Error>> {
+    let tool = match detect() {
+        Ok(tool) => tool,
+        Err(reason) => {
+            warn!("Not snapshotting the filesystem before the run: {}", reason);
+            return Ok(());
+        }
+    };
+    let label = format!("{LABEL_PREFIX}{run}");
+    info!("Snapshotting the filesystem as {} with {:?}", label, tool);
+    let (program, args) = create_command(&tool, &label);
+    system.run(program, args.iter().map(String::as_str).collect(), true)?;
+    Ok(())
+}
+
+/// Lists the snapshots taken before each run, or restores the snapshot taken before the given
+/// run. The restore takes effect on the next reboot.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use linux::snapshot;
+///
+/// snapshot::rollback(&system, Some(42))?;
+/// ```
+pub(crate) fn rollback(system: &dyn System, run: Option<u32>) -> Result<(), Box<dyn Error>> {
+    let tool = detect().map_err(|reason| {
+        error::Error::new(&format!(
+            "Snapshots aren't available on this machine: {reason}"
+        ))
+    })?;
+    let snapshots = list(&tool)?;
+    let Some(run) = run else {
+        if snapshots.is_empty() {
+            info!("There are no snapshots from previous runs");
+        }
+        for snapshot in &snapshots {
+            info!(
+                "Run {:>4}  {}  {}",
+                snapshot.run, snapshot.created, snapshot.id
+            );
+        }
+        return Ok(());
+    };
+    let snapshot = snapshots
+        .iter()
+        .find(|snapshot| snapshot.run == run)
+        .ok_or_else(|| error::Error::new(&format!("There is no snapshot from run {run}")))?;
+    info!("Restoring the snapshot {} from run {}", snapshot.id, run);
+    let (program, args) = restore_command(&tool, &snapshot.id);
+    system.run(program, args.iter().map(String::as_str).collect(), true)?;
+    info!("Reboot to finish rolling back to before run {}", run);
+    Ok(())
+}
+
+/// Returns the program and arguments that snapshot the root filesystem with the tool.
+fn create_command(tool: &Tool, label: &str) -> (&'static str, Vec<String>) {
+    match tool {
+        Tool::Lvm {
+            volume_group,
+            logical_volume,
+        } => (
+            "lvcreate",
+            arguments(&[
+                "--snapshot",
+                "--size",
+                &format!("{LVM_SNAPSHOT_SIZE_GB}G"),
+                "--name",
+                label,
+                &format!("{volume_group}/{logical_volume}"),
+            ]),
+        ),
+        Tool::Snapper => (
+            "snapper",
+            arguments(&[
+                "-c",
+                "root",
+                "create",
+                "--type",
+                "single",
+                "--cleanup-algorithm",
+                "number",
+                "--description",
+                label,
+            ]),
+        ),
+        Tool::Timeshift => (
+            "timeshift",
+            arguments(&["--create", "--scripted", "--comments", label]),
+        ),
+    }
+}
+
+/// Returns the program and arguments that restore the snapshot with the tool.
+fn restore_command(tool: &Tool, id: &str) -> (&'static str, Vec<String>) {
+    match tool {
+        Tool::Lvm { volume_group, .. } => (
+            "lvconvert",
+            arguments(&["--merge", &format!("{volume_group}/{id}")]),
+        ),
+        Tool::Snapper => ("snapper", arguments(&["-c", "root", "rollback", id])),
+        Tool::Timeshift => (
+            "timeshift",
+            arguments(&["--restore", "--scripted", "--yes", "--snapshot", id]),
+        ),
+    }
+}
+
+fn arguments(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// Works out which tool can snapshot the root filesystem, or why none can.
+fn detect() -> Result<Tool, String> {
+    let mount = unix::execute(
+        "findmnt --noheadings --output FSTYPE,SOURCE /",
+        true,
+        false,
+        false,
+    )
+    .map_err(|e| e.to_string())?;
+    let mut parts = mount.split_whitespace();
+    let (filesystem, source) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    if filesystem == "btrfs" {
+        if Path::new("/etc/snapper/configs/root").exists() {
+            return Ok(Tool::Snapper);
+        }
+        if is_installed("timeshift") {
+            return Ok(Tool::Timeshift);
+        }
+        return Err(
+            "the root filesystem is btrfs but neither snapper, with a root config, nor timeshift is set up"
+                .to_string(),
+        );
+    }
+    if !is_installed("lvs") {
+        return Err(format!(
+            "the root filesystem is {filesystem} on {source}, which isn't btrfs or LVM"
+        ));
+    }
+    let volume = unix::execute(
+        &format!(
+            "lvs --noheadings --nosuffix --units g --separator , --options vg_name,lv_name,vg_free {source}"
+        ),
+        true,
+        false,
+        false,
+    )
+    .map_err(|e| e.to_string())?;
+    let Some((volume_group, logical_volume, free)) = parse_volume(&volume) else {
+        return Err(format!(
+            "the root filesystem is {filesystem} on {source}, which isn't btrfs or LVM"
+        ));
+    };
+    if free < LVM_SNAPSHOT_SIZE_GB {
+        return Err(format!(
+            "the volume group {volume_group} has {free}G free but the snapshot needs {LVM_SNAPSHOT_SIZE_GB}G"
+        ));
+    }
+    Ok(Tool::Lvm {
+        volume_group,
+        logical_volume,
+    })
+}
+
+fn is_installed(tool: &str) -> bool {
+    ["/usr/bin", "/usr/sbin", "/sbin"]
+        .iter()
+        .any(|directory| Path::new(&format!("{directory}/{tool}")).exists())
+}
+
+fn list(tool: &Tool) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+    let read = |command: &str| unix::execute(command, true, false, false);
+    Ok(match tool {
+        Tool::Lvm { volume_group, .. } => parse_lvm(&read(&format!(
+            "lvs --noheadings --separator , --options lv_name,lv_time {volume_group}"
+        ))?),
+        Tool::Snapper => parse_snapper(&read(
+            "snapper -c root --csvout list --columns number,date,description",
+        )?),
+        Tool::Timeshift => parse_timeshift(&read("timeshift --list --scripted")?),
+    })
+}
+
+/// Returns the run a snapshot was taken before, from its label.
+fn run(label: &str) -> Option<u32> {
+    label.trim().strip_prefix(LABEL_PREFIX)?.parse().ok()
+}
+
+/// Parses `lvs` output of the volume group, logical volume and free space in the group.
+fn parse_volume(output: &str) -> Option<(String, String, f64)> {
+    output.lines().find_map(|line| {
+        let mut fields = line.trim().split(',');
+        let volume_group = fields.next()?.to_string();
+        let logical_volume = fields.next()?.to_string();
+        let free = fields.next()?.parse().ok()?;
+        Some((volume_group, logical_volume, free))
+    })
+}
+
+fn parse_lvm(output: &str) -> Vec<Snapshot> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (name, created) = line.trim().split_once(',')?;
+            Some(Snapshot {
+                id: name.to_string(),
+                run: run(name)?,
+                created: created.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn parse_snapper(output: &str) -> Vec<Snapshot> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ',');
+            let id = fields.next()?;
+            let created = fields.next()?;
+            Some(Snapshot {
+                id: id.to_string(),
+                run: run(fields.next()?)?,
+                created: created.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn parse_timeshift(output: &str) -> Vec<Snapshot> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let marker = fields.iter().position(|field| *field == ">")?;
+            let name = fields.get(marker + 1)?;
+            Some(Snapshot {
+                id: name.to_string(),
+                run: run(fields.last()?)?,
+                created: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_command_labels_the_snapshot() {
+        let lvm = Tool::Lvm {
+            volume_group: "vg0".to_string(),
+            logical_volume: "root".to_string(),
+        };
+
+        assert_eq!(
+            create_command(&lvm, "dotfiles-run-3"),
+            (
+                "lvcreate",
+                arguments(&[
+                    "--snapshot",
+                    "--size",
+                    "10G",
+                    "--name",
+                    "dotfiles-run-3",
+                    "vg0/root"
+                ])
+            )
+        );
+        assert_eq!(
+            create_command(&Tool::Timeshift, "dotfiles-run-3"),
+            (
+                "timeshift",
+                arguments(&["--create", "--scripted", "--comments", "dotfiles-run-3"])
+            )
+        );
+    }
+
+    #[test]
+    fn test_restore_command_restores_the_snapshot() {
+        let lvm = Tool::Lvm {
+            volume_group: "vg0".to_string(),
+            logical_volume: "root".to_string(),
+        };
+
+        assert_eq!(
+            restore_command(&lvm, "dotfiles-run-3"),
+            ("lvconvert", arguments(&["--merge", "vg0/dotfiles-run-3"]))
+        );
+        assert_eq!(
+            restore_command(&Tool::Snapper, "5"),
+            ("snapper", arguments(&["-c", "root", "rollback", "5"]))
+        );
+    }
+
+    #[test]
+    fn test_parse_volume() {
+        assert_eq!(
+            parse_volume("  vg0,root,25.50\n"),
+            Some(("vg0".to_string(), "root".to_string(), 25.5))
+        );
+    }
+
+    #[test]
+    fn test_parse_volume_ignores_errors() {
+        assert_eq!(parse_volume("  Volume group \"sda2\" not found\n"), None);
+    }
+
+    #[test]
+    fn test_parse_lvm_only_lists_runs() {
+        let output = "  root,2024-03-01 09:00:00 +0000\n  \
+            dotfiles-run-3,2024-03-01 10:00:01 +0000\n";
+
+        assert_eq!(
+            parse_lvm(output),
+            vec![Snapshot {
+                id: "dotfiles-run-3".to_string(),
+                run: 3,
+                created: "2024-03-01 10:00:01 +0000".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_snapper_only_lists_runs() {
+        let output = "number,date,description\n\
+            0,,current\n\
+            4,2024-03-01 09:00:00,timeline\n\
+            5,2024-03-01 10:00:01,dotfiles-run-3\n";
+
+        assert_eq!(
+            parse_snapper(output),
+            vec![Snapshot {
+                id: "5".to_string(),
+                run: 3,
+                created: "2024-03-01 10:00:01".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_timeshift_only_lists_runs() {
+        let output = "Device : /dev/sda2\n\
+            Mode   : BTRFS\n\
+            \n\
+            Num     Name                 Tags  Description\n\
+            ------------------------------------------------------------------------------\n\
+            0    >  2024-03-01_09-00-00  D     \n\
+            1    >  2024-03-01_10-00-01  O     dotfiles-run-3\n";
+
+        assert_eq!(
+            parse_timeshift(output),
+            vec![Snapshot {
+                id: "2024-03-01_10-00-01".to_string(),
+                run: 3,
+                created: "2024-03-01_10-00-01".to_string(),
+            }]
+        );
+    }
+}
@@ -1,11 +1,19 @@
 use async_trait::async_trait;
+use log::warn;
 use std::error::Error;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
 
+use crate::certificates::Certificate;
 use crate::config::Config;
+use crate::environment;
+use crate::error;
+use crate::managed_block;
+use crate::profile::Packages;
+use crate::release;
+use crate::state::{self, Change};
 use crate::system::{self, System};
 use crate::unix;
 
@@ -27,7 +35,9 @@ impl<'s> Mac<'s> {
     }
 
     fn cask_install_application(&self, application: &str) -> Result<String, Box<dyn Error>> {
-        self.execute(&format!("brew install --cask {}", application), false)
+        let output = self.execute(&format!("brew install --cask {}", application), false)?;
+        state::record_packages(&[application]);
+        Ok(output)
     }
 
     fn get_brew_prefix(&self) -> Result<String, Box<dyn Error>> {
@@ -71,6 +81,13 @@ impl<'s> Mac<'s> {
 
 #[async_trait]
 impl<'s> System for Mac<'s> {
+    fn create_snapshot(&self, _run: u32) -> Result<(), Box<dyn Error>> {
+        warn!(
+            "Not snapshotting the filesystem before the run: snapshots aren't supported on macOS"
+        );
+        Ok(())
+    }
+
     fn execute(&self, command: &str, super_user: bool) -> Result<String, Box<dyn Error>> {
         unix::execute(command, super_user, true, self.config.dry_run)
     }
@@ -79,8 +96,23 @@ impl<'s> System for Mac<'s> {
         system::get_home_dir()
     }
 
+    fn query(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        unix::execute(command, true, false, false)
+    }
+
+    fn run(
+        &self,
+        program: &str,
+        args: Vec<&str>,
+        super_user: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        unix::run(program, &args, super_user, self.config.dry_run)
+    }
+
     fn install_applications(&self, applications: Vec<&str>) -> Result<String, Box<dyn Error>> {
-        self.execute(&format!("brew install {}", applications.join(" ")), false)
+        let output = self.execute(&format!("brew install {}", applications.join(" ")), false)?;
+        state::record_packages(&applications);
+        Ok(output)
     }
 
     fn install_affinity_suite(&self) -> Result<(), Box<dyn Error>> {
@@ -132,8 +164,11 @@ impl<'s> System for Mac<'s> {
     }
 
     fn install_bash(&self) -> Result<(), Box<dyn Error>> {
-        unix::setup_bash(self)?;
+        unix::setup_bash(self, self.config)?;
         let bashrc = format!("{}/.bashrc", self.get_home_dir());
+        if self.config.dry_run {
+            return Ok(());
+        }
         let mut bashrc_file = OpenOptions::new().append(true).open(bashrc)?;
         writeln!(
             bashrc_file,
@@ -161,9 +196,28 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
+    fn install_certificate(&self, certificate: &Certificate) -> Result<(), Box<dyn Error>> {
+        // Firefox and Chrome both trust the system keychain, so there's no NSS database to add to.
+        self.run(
+            "security",
+            vec![
+                "add-trusted-cert",
+                "-d",
+                "-r",
+                "trustRoot",
+                "-k",
+                "/Library/Keychains/System.keychain",
+                &certificate.file,
+            ],
+            true,
+        )?;
+        Ok(())
+    }
+
     async fn install_codecs(&self) -> Result<(), Box<dyn Error>> {
-        system::setup_codecs(self).await?;
+        system::setup_codecs(self, self.config).await?;
         system::download_file(
+            self.config,
             "https://vlc-bluray.whoknowsmy.name/files/mac/libaacs.dylib",
             "/usr/local/lib/libaacs.dylib".to_string().as_str(),
         )
@@ -249,6 +303,12 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
+    fn install_extras(&self, _extras: &Packages) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(error::Error::new(
+            "Installing profile extras is not supported on macOS",
+        )))
+    }
+
     async fn install_exercism(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("exercism")? {
             self.install_application("exercism")?;
@@ -267,6 +327,13 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
+    fn install_fish(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("fish")? {
+            self.install_application("fish")?;
+        }
+        unix::setup_fish(self, self.config)
+    }
+
     fn install_git(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("git")? {
             self.install_application("git")?;
@@ -300,7 +367,7 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
-    fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
         todo!()
     }
 
@@ -332,7 +399,7 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
-    fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("groovy")? {
             self.install_application("groovy")?;
         }
@@ -385,10 +452,8 @@ impl<'s> System for Mac<'s> {
                 "/Library/Java/JavaVirtualMachines/openjdk.jdk",
             )?;
         }
-        unix::set_java_home(self, ".zshrc", "$(/usr/libexec/java_home)")?;
-        unix::set_java_home(self, ".bashrc", "$(/usr/libexec/java_home)")?;
-        unix::add_to_path(self, ".zshrc", "$JAVA_HOME/bin")?;
-        unix::add_to_path(self, ".bashrc", "$JAVA_HOME/bin")?;
+        unix::set_java_home(self, "$(/usr/libexec/java_home)")?;
+        unix::add_to_path(self, "$JAVA_HOME/bin")?;
         Ok(())
     }
 
@@ -421,7 +486,7 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
-    fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("openscad@snapshot")? {
             self.cask_install_application("openscad@snapshot")?;
         }
@@ -447,7 +512,7 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
-    fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("Microsoft Edge")? {
             self.cask_install_application("microsoft-edge")?;
         }
@@ -479,12 +544,18 @@ impl<'s> System for Mac<'s> {
         if !self.is_installed("nvm")? {
             self.install_application("nvm")?;
         }
+        environment::set_variable(self, "NVM_DIR", "$HOME/.nvm")?;
         let brew_prefix = self.get_brew_prefix()?;
-        let content = format!("export NVM_DIR=\"$HOME/.nvm\"\n\
-        [ -s \"{}/opt/nvm/nvm.sh\" ] && . \"{}/opt/nvm/nvm.sh\"  # This loads nvm\n\
+        let content = format!("[ -s \"{}/opt/nvm/nvm.sh\" ] && . \"{}/opt/nvm/nvm.sh\"  # This loads nvm\n\
         [ -s \"{}/opt/nvm/etc/bash_completion.d/nvm\" ] && . \"{}/opt/nvm/etc/bash_completion.d/nvm\"  # This loads nvm bash_completion", &brew_prefix, &brew_prefix, &brew_prefix, &brew_prefix);
-        system::add_to_file(&format!("{}/.zshrc", self.get_home_dir()), &content)?;
-        system::add_to_file(&format!("{}/.bashrc", self.get_home_dir()), &content)?;
+        for file in [".zshrc", ".bashrc"] {
+            let file = format!("{}/{file}", self.get_home_dir());
+            // NVM_DIR comes from the env file, so a block from before it was sourced moves after it.
+            if environment::is_before_sources(&file, "nvm")? {
+                managed_block::remove(&file, "nvm")?;
+            }
+            system::add_to_file(&file, "nvm", &content)?;
+        }
         let zsh_nvm_dir = "autoload -U add-zsh-hook\n\
         load-nvmrc() {\n\
             local node_version=\"$(nvm version)\"\n\
@@ -503,7 +574,11 @@ impl<'s> System for Mac<'s> {
         }\n\
         add-zsh-hook chpwd load-nvmrc\n\
         load-nvmrc";
-        system::add_to_file(&format!("{}/.zshrc", self.get_home_dir()), zsh_nvm_dir)?;
+        system::add_to_file(
+            &format!("{}/.zshrc", self.get_home_dir()),
+            "nvm-auto-use",
+            zsh_nvm_dir,
+        )?;
         let bash_nvm_dir = "cdnvm() {\n\
             command cd \"$@\";\n\
             nvm_path=$(nvm_find_up .nvmrc | tr -d '\n')\n\
@@ -541,7 +616,11 @@ impl<'s> System for Mac<'s> {
         }\n\
         alias cd='cdnvm'\n\
         cd \"$PWD\"";
-        system::add_to_file(&format!("{}/.bashrc", self.get_home_dir()), bash_nvm_dir)?;
+        system::add_to_file(
+            &format!("{}/.bashrc", self.get_home_dir()),
+            "nvm-auto-use",
+            bash_nvm_dir,
+        )?;
 
         self.execute("nvm install node --latest-npm", false)?;
         self.execute("npm install --global yarn", false)?;
@@ -598,8 +677,16 @@ impl<'s> System for Mac<'s> {
             "export PATH=\"$PATH:{}/opt/python/libexec/bin\"",
             self.get_brew_prefix()?
         );
-        system::add_to_file(&format!("{}/.zshrc", self.get_home_dir()), &content)?;
-        system::add_to_file(&format!("{}/.bashrc", self.get_home_dir()), &content)?;
+        system::add_to_file(
+            &format!("{}/.zshrc", self.get_home_dir()),
+            "python",
+            &content,
+        )?;
+        system::add_to_file(
+            &format!("{}/.bashrc", self.get_home_dir()),
+            "python",
+            &content,
+        )?;
         Ok(())
     }
 
@@ -617,8 +704,12 @@ impl<'s> System for Mac<'s> {
             self.execute("rustup-init -y", true)?;
         }
         let content = "source $HOME/.cargo/env";
-        system::add_to_file(&format!("{}/.zshrc", self.get_home_dir()), content)?;
-        system::add_to_file(&format!("{}/.bashrc", self.get_home_dir()), content)?;
+        system::add_to_file(&format!("{}/.zshrc", self.get_home_dir()), "cargo", content)?;
+        system::add_to_file(
+            &format!("{}/.bashrc", self.get_home_dir()),
+            "cargo",
+            content,
+        )?;
 
         Ok(())
     }
@@ -637,7 +728,7 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
-    fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("spotify")? {
             self.cask_install_application("spotify")?;
         }
@@ -668,6 +759,7 @@ impl<'s> System for Mac<'s> {
     async fn install_system_extras(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("brew")? {
             system::download_file(
+                self.config,
                 "https://raw.githubusercontent.com/Homebrew/install/master/install.sh",
                 "brew-install",
             )
@@ -680,12 +772,14 @@ impl<'s> System for Mac<'s> {
         let zshrc = format!("{}/.zshrc", self.get_home_dir());
         system::add_to_file(
             &zshrc,
+            "brew",
             &format!("eval \"$({}/bin/brew shellenv)\"", self.get_brew_prefix()?),
         )?;
 
         let bashrc = format!("{}/.bashrc", self.get_home_dir());
         system::add_to_file(
             &bashrc,
+            "brew",
             &format!("eval \"$({}/bin/brew shellenv)\"", self.get_brew_prefix()?),
         )?;
 
@@ -705,6 +799,7 @@ impl<'s> System for Mac<'s> {
     async fn install_tauon_music_box(&self) -> Result<(), Box<dyn Error>> {
         let version = "8.2.2";
         system::download_file(
+            self.config,
             format!(
                 "https://github.com/Taiko2k/Tauon/releases/download/v{0}/TauonMusicBox.dmg",
                 &version
@@ -714,10 +809,12 @@ impl<'s> System for Mac<'s> {
         )
         .await?;
         self.execute("hdiutil attach tauon-music-box.dmg", true)?;
-        fs::copy(
-            format!("/Volumes/TauonMusicBox {}/TauonMusicBox.app", &version),
-            "/Applications",
-        )?;
+        if !self.config.dry_run {
+            fs::copy(
+                format!("/Volumes/TauonMusicBox {}/TauonMusicBox.app", &version),
+                "/Applications",
+            )?;
+        }
         self.execute(
             format!("hdiutil detach /Volumes/TauonMusicBox {}", &version).as_str(),
             true,
@@ -726,7 +823,7 @@ impl<'s> System for Mac<'s> {
         Ok(())
     }
 
-    fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("terraform")? {
             self.install_application("terraform")?;
         }
@@ -748,8 +845,8 @@ impl<'s> System for Mac<'s> {
         if !self.is_installed("reattach-to-user-namespace")? {
             self.install_application("reattach-to-user-namespace")?;
         }
-        unix::setup_tmux(self)?;
-        system::add_to_file(&format!("{}/.tmux.conf", self.get_home_dir()), "bind -T copy-mode-vi y send-keys -X copy-pipe-and-cancel 'reattach-to-user-namespace pbcopy'")?;
+        unix::setup_tmux(self, self.config)?;
+        system::add_to_file(&format!("{}/.tmux.conf", self.get_home_dir()), "clipboard", "bind -T copy-mode-vi y send-keys -X copy-pipe-and-cancel 'reattach-to-user-namespace pbcopy'")?;
         Ok(())
     }
 
@@ -768,7 +865,7 @@ impl<'s> System for Mac<'s> {
         todo!()
     }
 
-    fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("Visual Studio Code")? {
             self.cask_install_application("visual-studio-code")?;
         }
@@ -801,16 +898,16 @@ impl<'s> System for Mac<'s> {
 
     async fn install_xbox_streaming(&self) -> Result<(), Box<dyn Error>> {
         // if !self.is_installed("9MV0B5HZVK9Z")? {
-        let version = "2.3.3";
-        system::download_file(
-            format!("https://github.com/unknownskl/greenlight/releases/download/v{0}/Greenlight-{0}-universal.dmg", &version).as_str(),
-            "greenlight.dmg",
-        ).await?;
+        let greenlight = release::resolve(self.config, &release::GREENLIGHT_DMG).await?;
+        let version = &greenlight.version;
+        system::download_file(self.config, &greenlight.url, "greenlight.dmg").await?;
         self.execute("hdiutil attach greenlight.dmg", true)?;
-        fs::copy(
-            format!("/Volumes/Greenlight {}-universal/Greenlight.app", &version),
-            "/Applications",
-        )?;
+        if !self.config.dry_run {
+            fs::copy(
+                format!("/Volumes/Greenlight {}-universal/Greenlight.app", &version),
+                "/Applications",
+            )?;
+        }
         self.execute(
             format!("hdiutil detach /Volumes/Greenlight {}-universal", &version).as_str(),
             true,
@@ -831,17 +928,89 @@ impl<'s> System for Mac<'s> {
         if !Path::new(&format!("{}/bin/zsh", self.get_brew_prefix()?)).exists() {
             self.install_applications(vec!["zsh", "zsh-autosuggestions"])?;
         }
-        unix::setup_zsh(self, Some(&format!("{}/bin/zsh", self.get_brew_prefix()?))).await?;
+        unix::setup_zsh(
+            self,
+            self.config,
+            Some(&format!("{}/bin/zsh", self.get_brew_prefix()?)),
+        )
+        .await?;
 
         let zshrc = format!("{}/.zshrc", self.get_home_dir());
         system::add_to_file(
             &zshrc,
+            "brew",
             &format!("eval \"$({}/bin/brew shellenv)\"", self.get_brew_prefix()?),
         )?;
 
         Ok(())
     }
 
+    async fn rank_mirrors(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn is_applied(&self, change: &Change) -> Result<bool, Box<dyn Error>> {
+        match change {
+            Change::Package { name } => self.is_installed(name),
+            Change::Block { file, id } => Ok(managed_block::read(file, id)?.is_some()),
+            Change::Line { file, line } => Ok(managed_block::has_line(file, line)?),
+            Change::File { path, .. } => Ok(Path::new(path).exists()),
+            Change::PathEntry { entry } => {
+                Ok(environment::contains_path(&self.get_home_dir(), entry)?)
+            }
+            Change::Variable { key } => {
+                Ok(environment::contains_variable(&self.get_home_dir(), key)?)
+            }
+            _ => Err(Box::new(error::Error::new(&format!(
+                "Checking the {change} is not supported on macOS"
+            )))),
+        }
+    }
+
+    fn list_installed(&self) -> Result<Packages, Box<dyn Error>> {
+        Err(Box::new(error::Error::new(
+            "Listing the installed packages is not supported on macOS",
+        )))
+    }
+
+    fn revert(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        match change {
+            // Uninstalls formulae and casks alike.
+            Change::Package { name } => {
+                self.execute(&format!("brew uninstall {name}"), false)?;
+            }
+            Change::Block { file, id } => {
+                managed_block::remove(file, id)?;
+            }
+            Change::Line { file, line } => {
+                managed_block::remove_line(file, line)?;
+            }
+            Change::File { path, .. } => {
+                if Path::new(path).exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            Change::PathEntry { entry } => {
+                environment::remove_from_path(&self.get_home_dir(), entry)?;
+            }
+            Change::Variable { key } => {
+                environment::remove_variable(&self.get_home_dir(), key)?;
+            }
+            _ => {
+                return Err(Box::new(error::Error::new(&format!(
+                    "Removing the {change} is not supported on macOS"
+                ))));
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, _run: Option<u32>) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(error::Error::new(
+            "Rolling back is not supported on macOS",
+        )))
+    }
+
     fn set_development_shortcuts(&self) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
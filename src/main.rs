@@ -1,5 +1,6 @@
-use crate::{config::parse, install::install};
-use log::{debug, LevelFilter};
+use crate::config::{parse, Backups, Command};
+use crate::install::install;
+use log::{debug, info, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Logger, Root};
 use log4rs::Config;
@@ -37,21 +38,41 @@ pub mod is_elevated {
     }
 }
 #[cfg(target_os = "linux")]
+mod alpine;
+#[cfg(target_os = "linux")]
 mod arch;
+mod backup;
+mod bundle;
+mod capture;
+mod certificates;
 mod config;
+mod download;
+mod environment;
 mod error;
 mod install;
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "macos")]
 mod mac;
+mod managed_block;
+mod network;
+mod profile;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod release;
+mod shell;
+mod state;
+mod status;
+mod stow;
 mod system;
+mod template;
 #[cfg(target_os = "linux")]
 mod ubuntu;
 #[cfg(any(target_os = "linux", target_os = "macos"))]
 mod unix;
+mod verify;
 #[cfg(target_os = "windows")]
 mod windows;
+mod workspace;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -65,6 +86,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = parse(args);
 
     configure_logging(&config);
+    network::configure(&config)?;
+    system::set_dry_run(config.dry_run);
 
     if config.help {
         print_help();
@@ -73,7 +96,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let system = get_system(&config);
     debug!("System: {:?}", system);
-    install(&config, &*system).await
+    match &config.command {
+        Command::Backups(Backups::List) => backup::list(&*system),
+        Command::Backups(Backups::Restore { file, run }) => backup::restore(&*system, file, *run),
+        Command::Bundle(directory) => {
+            let run = state::start_run(&*system)?;
+            info!("Bundling run {} into {}", run, directory);
+            bundle::prepare(directory)?;
+            let _workspace = workspace::Workspace::create(run, config.keep_workspace)?;
+            install(&config, &*system, run).await?;
+            bundle::finish(directory)
+        }
+        Command::Capture(path) => capture::capture(&*system, path),
+        Command::Install => {
+            let run = state::start_run(&*system)?;
+            info!("Starting run {}", run);
+            if !config.dry_run {
+                state::start_recording(&*system, run)?;
+            }
+            let _workspace = workspace::Workspace::create(run, config.keep_workspace)?;
+            install(&config, &*system, run).await
+        }
+        Command::Rollback(run) => system.rollback(*run),
+        Command::Status => status::report(&config, &*system),
+        Command::Uninstall(step) => state::uninstall(&*system, step, config.dry_run),
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -84,6 +131,7 @@ fn get_system<'s>(config: &'s config::Config) -> Box<dyn system::System + 's> {
     }
     let distro_str = whoami::distro();
     match distro_str {
+        Ok(distro) if distro.starts_with("Alpine") => Box::new(alpine::Alpine::new(config)),
         Ok(distro) if distro == "Arch Linux" => Box::new(arch::Arch::new(config)),
         Ok(distro) if distro.starts_with("Ubuntu") => Box::new(ubuntu::Ubuntu::new(config)),
         Ok(distro) => panic!("Unable to determine the distro {distro}."),
@@ -110,9 +158,19 @@ fn get_system<'s>(config: &'s config::Config) -> Box<dyn system::System + 's> {
 
 fn print_help() {
     println!(
-        "install [--browsers] [--development] [--docker] [--gaming] [--gcp] [--images] \
+        "backups [list]\n\
+        backups restore <file> [--run <run>]\n\
+        bundle [--profile <file>] [--out <dir>] [--browsers] [--development] ...\n\
+        capture [--out <file>]\n\
+        install [--browsers] [--development] [--docker] [--gaming] [--gcp] [--images] \
         [--laptop] [--modelling] [--personal] [--printer] [--recording] [--ripping] [--video] \
-        [--video-editing] [--vm] [--vpn] [--debug]"
+        [--video-editing] [--vm] [--vpn] [--prefer-flatpak] [--flatpak-user] \
+        [--aur-helper <yay|paru|makepkg>] [--shell <bash|zsh|fish>] [--local-repo <dir>] \
+        [--profile <file>] [--mirror-countries <GB,DE,...>] [--from-bundle <dir>] \
+        [--keep-workspace] [--debug]\n\
+        rollback [<run>]\n\
+        status [--browsers] [--development] ...\n\
+        uninstall <app>"
     );
 }
 
@@ -140,25 +198,35 @@ mod tests {
     use serial_test::serial;
 
     const CONFIG: config::Config = config::Config {
+        aur_helper: config::AurHelper::Yay,
         browsers: false,
         cli_only: false,
+        command: config::Command::Install,
         debug: false,
         development: false,
         docker: false,
         dry_run: false,
+        flatpak_user: false,
+        from_bundle: None,
         gaming: false,
         gcp: false,
         gnome: false,
         images: false,
         infrastructure: false,
         kde: false,
+        keep_workspace: false,
         help: false,
         laptop: false,
+        local_repo: None,
+        mirror_countries: Vec::new(),
         modelling: false,
         personal: false,
+        prefer_flatpak: false,
         printer: false,
+        profile: None,
         recording: false,
         ripping: false,
+        shell: crate::shell::Shell::Zsh,
         video: false,
         video_editing: false,
         vm: false,
@@ -185,6 +253,15 @@ mod tests {
         get_system(&CONFIG);
     }
 
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_get_system_returns_alpine() {
+        env::set_var("SUDO_USER", "username");
+        set_distro_value("Alpine Linux v3.22");
+        get_system(&CONFIG);
+    }
+
     #[test]
     #[serial]
     #[cfg(target_os = "linux")]
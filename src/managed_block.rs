@@ -0,0 +1,420 @@
+use log::{debug, info};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::system;
+
+/// Whether writing or removing a block changed the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    Changed,
+    Unchanged,
+}
+
+/// Writes the content into the file as the block with the id, wrapped in
+/// `# BEGIN dotfiles:<id>` and `# END dotfiles:<id>` markers, creating the file if needed.
+///
+/// An existing block with the id is replaced in place, otherwise an unmarked copy of the content,
+/// as written before blocks were used, is wrapped where it is when it's on lines of its own,
+/// otherwise the block is appended.
+/// CSS files use `/* ... */` markers instead.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use managed_block::{self, Outcome};
+///
+/// let outcome = managed_block::write(".zshrc", "cargo", "source $HOME/.cargo/env")?;
+/// assert_eq!(managed_block::write(".zshrc", "cargo", "source $HOME/.cargo/env")?, Outcome::Unchanged);
+/// ```
+pub(crate) fn write(file: &str, id: &str, content: &str) -> Result<Outcome, io::Error> {
+    let contents = read_file(file)?;
+    let updated = replace(&contents, &markers(file, id), Some(content))?;
+    save(
+        file,
+        &format!("the dotfiles:{id} block"),
+        &contents,
+        &updated,
+    )
+}
+
+/// Removes the block with the id from the file, if it has one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use managed_block;
+///
+/// managed_block::remove(".zshrc", "cargo")?;
+/// ```
+pub(crate) fn remove(file: &str, id: &str) -> Result<Outcome, io::Error> {
+    if !Path::new(file).exists() {
+        return Ok(Outcome::Unchanged);
+    }
+    let contents = read_file(file)?;
+    let updated = replace(&contents, &markers(file, id), None)?;
+    save(
+        file,
+        &format!("the dotfiles:{id} block"),
+        &contents,
+        &updated,
+    )
+}
+
+/// Returns the content of the block with the id in the file, if it has one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use managed_block;
+///
+/// let path = managed_block::read(".zshrc", "path")?.unwrap_or_default();
+/// ```
+pub(crate) fn read(file: &str, id: &str) -> Result<Option<String>, io::Error> {
+    let contents = read_file(file)?;
+    let (begin, end) = markers(file, id);
+    let Some((_, content_start)) = find_line(&contents, &begin, 0) else {
+        return Ok(None);
+    };
+    let (content_end, _) =
+        find_line(&contents, &end, content_start).ok_or_else(|| unterminated(&begin))?;
+    Ok(Some(
+        contents[content_start..content_end]
+            .trim_end_matches('\n')
+            .to_string(),
+    ))
+}
+
+/// Adds the line to the end of the file, without markers, unless the file already has it, creating
+/// the file if needed. For files where the markers would be read as something else, like
+/// `eclipse.ini`, which takes every line as an argument.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use managed_block;
+///
+/// managed_block::write_line("/opt/eclipse/eclipse.ini", "-javaagent:/opt/eclipse/lombok.jar")?;
+/// ```
+pub(crate) fn write_line(file: &str, line: &str) -> Result<Outcome, io::Error> {
+    let contents = read_file(file)?;
+    let updated = if find_line(&contents, line, 0).is_some() {
+        contents.clone()
+    } else if contents.is_empty() || contents.ends_with('\n') {
+        format!("{contents}{line}\n")
+    } else {
+        format!("{contents}\n{line}\n")
+    };
+    save(file, &format!("the line {line}"), &contents, &updated)
+}
+
+/// Removes the line from the file, if it has it.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use managed_block;
+///
+/// managed_block::remove_line("/opt/eclipse/eclipse.ini", "-javaagent:/opt/eclipse/lombok.jar")?;
+/// ```
+pub(crate) fn remove_line(file: &str, line: &str) -> Result<Outcome, io::Error> {
+    if !Path::new(file).exists() {
+        return Ok(Outcome::Unchanged);
+    }
+    let contents = read_file(file)?;
+    let updated = match find_line(&contents, line, 0) {
+        Some((start, finish)) => format!("{}{}", &contents[..start], &contents[finish..]),
+        None => contents.clone(),
+    };
+    save(file, &format!("the line {line}"), &contents, &updated)
+}
+
+/// Returns whether the file has the line.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use managed_block;
+///
+/// let has_lombok = managed_block::has_line("/opt/eclipse/eclipse.ini", "-javaagent:/opt/eclipse/lombok.jar")?;
+/// ```
+pub(crate) fn has_line(file: &str, line: &str) -> Result<bool, io::Error> {
+    Ok(find_line(&read_file(file)?, line, 0).is_some())
+}
+
+fn read_file(file: &str) -> Result<String, io::Error> {
+    match fs::read_to_string(file) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
+
+fn save(file: &str, what: &str, contents: &str, updated: &str) -> Result<Outcome, io::Error> {
+    if updated == contents {
+        debug!("{} in {} is unchanged", what, file);
+        return Ok(Outcome::Unchanged);
+    }
+    if system::is_dry_run() {
+        info!("Would update {} in {}", what, file);
+        return Ok(Outcome::Changed);
+    }
+    fs::write(file, updated)?;
+    info!("Updated {} in {}", what, file);
+    Ok(Outcome::Changed)
+}
+
+fn markers(file: &str, id: &str) -> (String, String) {
+    if file.ends_with(".css") {
+        (
+            format!("/* BEGIN dotfiles:{id} */"),
+            format!("/* END dotfiles:{id} */"),
+        )
+    } else {
+        (
+            format!("# BEGIN dotfiles:{id}"),
+            format!("# END dotfiles:{id}"),
+        )
+    }
+}
+
+/// Returns the contents with the block set to the content, or removed when there's none.
+fn replace(
+    contents: &str,
+    (begin, end): &(String, String),
+    content: Option<&str>,
+) -> Result<String, io::Error> {
+    let block = content.map(|content| {
+        format!(
+            "{begin}\n{}\n{end}\n",
+            content.trim_end_matches(['\r', '\n'])
+        )
+    });
+    if let Some((start, content_start)) = find_line(contents, begin, 0) {
+        let (_, finish) =
+            find_line(contents, end, content_start).ok_or_else(|| unterminated(begin))?;
+        return Ok(format!(
+            "{}{}{}",
+            &contents[..start],
+            block.unwrap_or_default(),
+            &contents[finish..]
+        ));
+    }
+    let Some(block) = block else {
+        return Ok(contents.to_string());
+    };
+    let content = content.unwrap_or_default().trim_end_matches(['\r', '\n']);
+    // Only whole lines are the content written before blocks were used, not a mention of it.
+    if let Some((start, finish)) = find_line(contents, content, 0).filter(|_| !content.is_empty()) {
+        return Ok(format!(
+            "{}{}{}",
+            &contents[..start],
+            block,
+            &contents[finish..]
+        ));
+    }
+    if contents.is_empty() || contents.ends_with('\n') {
+        Ok(format!("{contents}{block}"))
+    } else {
+        Ok(format!("{contents}\n{block}"))
+    }
+}
+
+/// Returns where the whole line starts and where the line after it starts, searching from the
+/// offset.
+fn find_line(contents: &str, line: &str, from: usize) -> Option<(usize, usize)> {
+    contents[from..]
+        .match_indices(line)
+        .map(|(index, _)| from + index)
+        .find(|&start| {
+            let finish = start + line.len();
+            (start == 0 || contents[..start].ends_with('\n'))
+                && (finish == contents.len() || contents[finish..].starts_with('\n'))
+        })
+        .map(|start| (start, (start + line.len() + 1).min(contents.len())))
+}
+
+fn unterminated(begin: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{begin} has no matching END marker"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn markers() -> (String, String) {
+        super::markers(".zshrc", "cargo")
+    }
+
+    #[test]
+    fn test_replace_appends_block() {
+        let updated = replace(
+            "export EDITOR=vim",
+            &markers(),
+            Some("source $HOME/.cargo/env"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            updated,
+            "export EDITOR=vim\n\
+            # BEGIN dotfiles:cargo\n\
+            source $HOME/.cargo/env\n\
+            # END dotfiles:cargo\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_replaces_block_in_place() {
+        let contents = "# BEGIN dotfiles:cargo\n\
+            source $HOME/.cargo/env\n\
+            # END dotfiles:cargo\n\
+            export EDITOR=vim\n";
+
+        let updated = replace(contents, &markers(), Some(". \"$HOME/.cargo/env\"\n")).unwrap();
+
+        assert_eq!(
+            updated,
+            "# BEGIN dotfiles:cargo\n\
+            . \"$HOME/.cargo/env\"\n\
+            # END dotfiles:cargo\n\
+            export EDITOR=vim\n"
+        );
+        assert_eq!(
+            replace(&updated, &markers(), Some(". \"$HOME/.cargo/env\"")).unwrap(),
+            updated
+        );
+    }
+
+    #[test]
+    fn test_replace_ignores_blocks_with_longer_ids() {
+        let contents = "# BEGIN dotfiles:cargo-completions\n\
+            source <(rustup completions zsh cargo)\n\
+            # END dotfiles:cargo-completions\n";
+
+        let updated = replace(contents, &markers(), Some("source $HOME/.cargo/env")).unwrap();
+
+        assert!(updated.starts_with(contents));
+        assert!(updated.ends_with("# END dotfiles:cargo\n"));
+    }
+
+    #[test]
+    fn test_replace_wraps_unmarked_copy() {
+        let contents = "export EDITOR=vim\nsource $HOME/.cargo/env\nalias ll='ls -l'\n";
+
+        let updated = replace(contents, &markers(), Some("source $HOME/.cargo/env")).unwrap();
+
+        assert_eq!(
+            updated,
+            "export EDITOR=vim\n\
+            # BEGIN dotfiles:cargo\n\
+            source $HOME/.cargo/env\n\
+            # END dotfiles:cargo\n\
+            alias ll='ls -l'\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_appends_block_when_content_is_only_part_of_a_line() {
+        let contents = "# source $HOME/.cargo/env\n";
+
+        let updated = replace(contents, &markers(), Some("source $HOME/.cargo/env")).unwrap();
+
+        assert_eq!(
+            updated,
+            "# source $HOME/.cargo/env\n\
+            # BEGIN dotfiles:cargo\n\
+            source $HOME/.cargo/env\n\
+            # END dotfiles:cargo\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_removes_block() {
+        let contents = "export EDITOR=vim\n\
+            # BEGIN dotfiles:cargo\n\
+            source $HOME/.cargo/env\n\
+            # END dotfiles:cargo\n\
+            alias ll='ls -l'\n";
+
+        let updated = replace(contents, &markers(), None).unwrap();
+
+        assert_eq!(updated, "export EDITOR=vim\nalias ll='ls -l'\n");
+        assert_eq!(replace(&updated, &markers(), None).unwrap(), updated);
+    }
+
+    #[test]
+    fn test_replace_refuses_unterminated_block() {
+        let contents = "# BEGIN dotfiles:cargo\nsource $HOME/.cargo/env\n";
+
+        assert!(replace(contents, &markers(), None).is_err());
+    }
+
+    #[test]
+    fn test_markers_use_css_comments_in_css() {
+        assert_eq!(
+            super::markers("gtk.css", "theme"),
+            (
+                "/* BEGIN dotfiles:theme */".to_string(),
+                "/* END dotfiles:theme */".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_reports_outcome_and_read_returns_content() {
+        let file = std::env::temp_dir().join("dotfiles-managed-block-test.zshrc");
+        let _ = fs::remove_file(&file);
+        let file = file.to_string_lossy().to_string();
+
+        assert_eq!(
+            write(&file, "cargo", "source $HOME/.cargo/env").unwrap(),
+            Outcome::Changed
+        );
+        assert_eq!(
+            write(&file, "cargo", "source $HOME/.cargo/env").unwrap(),
+            Outcome::Unchanged
+        );
+        assert_eq!(
+            read(&file, "cargo").unwrap(),
+            Some("source $HOME/.cargo/env".to_string())
+        );
+        assert_eq!(remove(&file, "cargo").unwrap(), Outcome::Changed);
+        assert_eq!(read(&file, "cargo").unwrap(), None);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "");
+    }
+
+    #[test]
+    fn test_write_line_adds_the_line_once_without_markers() {
+        let file = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&file, "-vmargs\n-Xmx2048m").unwrap();
+        let file = file.to_string_lossy().to_string();
+        let line = "-javaagent:/opt/eclipse/lombok.jar";
+
+        assert_eq!(write_line(&file, line).unwrap(), Outcome::Changed);
+        assert_eq!(write_line(&file, line).unwrap(), Outcome::Unchanged);
+        assert!(has_line(&file, line).unwrap());
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "-vmargs\n-Xmx2048m\n-javaagent:/opt/eclipse/lombok.jar\n"
+        );
+        assert_eq!(remove_line(&file, line).unwrap(), Outcome::Changed);
+        assert!(!has_line(&file, line).unwrap());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "-vmargs\n-Xmx2048m\n");
+    }
+}
@@ -0,0 +1,253 @@
+use log::{debug, info};
+use reqwest::{Certificate, ClientBuilder, NoProxy, Proxy};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::sync::OnceLock;
+
+use crate::config::Config;
+use crate::error;
+
+/// The variables child commands read their proxy from, in both the cases tools look for.
+pub(crate) static PROXY_VARIABLES: [&str; 6] = [
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+];
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// How the network is reached, from the profile's `[network]`, falling back to the proxy
+/// variables dotfiles was run with.
+#[derive(Debug, Default, PartialEq)]
+struct Settings {
+    proxy: Option<String>,
+    no_proxy: Vec<String>,
+    ca_files: Vec<String>,
+}
+
+/// Configures the network for the run, so every request made afterwards goes through the proxy
+/// and trusts the extra CAs, and exports the proxy variables for child commands to inherit.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use network;
+///
+/// network::configure(&config)?;
+/// ```
+pub(crate) fn configure(config: &Config) -> Result<(), Box<dyn Error>> {
+    let settings = settings(config, |name| env::var(name).ok());
+    for (name, value) in variables(&settings) {
+        env::set_var(name, value);
+    }
+    if let Some(proxy) = &settings.proxy {
+        info!("Using the proxy {}", proxy);
+    }
+    // Loading the CAs up front reports unreadable files before anything is installed.
+    certificates(&settings)?;
+    SETTINGS
+        .set(settings)
+        .map_err(|_| error::Error::new("The network has already been configured"))?;
+    Ok(())
+}
+
+/// Returns a client builder using the configured proxy and CAs, for callers that need to set
+/// more, e.g. a timeout.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use network;
+///
+/// let client = network::client_builder()?.timeout(TIMEOUT).build()?;
+/// ```
+pub(crate) fn client_builder() -> Result<ClientBuilder, Box<dyn Error>> {
+    let builder = reqwest::Client::builder();
+    let Some(settings) = SETTINGS.get() else {
+        return Ok(builder);
+    };
+    let builder = match &settings.proxy {
+        Some(proxy) => {
+            let no_proxy = NoProxy::from_string(&settings.no_proxy.join(","));
+            builder
+                .no_proxy()
+                .proxy(Proxy::all(proxy)?.no_proxy(no_proxy))
+        }
+        None => builder,
+    };
+    Ok(builder.tls_certs_merge(certificates(settings)?))
+}
+
+/// Returns a client using the configured proxy and CAs.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use network;
+///
+/// let version = network::client()?.get(url).send().await?.text().await?;
+/// ```
+pub(crate) fn client() -> Result<reqwest::Client, Box<dyn Error>> {
+    Ok(client_builder()?.build()?)
+}
+
+fn settings(config: &Config, environment: impl Fn(&str) -> Option<String>) -> Settings {
+    let network = config
+        .profile
+        .as_ref()
+        .map(|profile| profile.network.clone())
+        .unwrap_or_default();
+    let from_environment = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| environment(name).filter(|value| !value.is_empty()))
+    };
+    let proxy = network
+        .proxy
+        .or_else(|| from_environment(&["https_proxy", "HTTPS_PROXY", "http_proxy", "HTTP_PROXY"]));
+    let no_proxy = if network.no_proxy.is_empty() {
+        from_environment(&["no_proxy", "NO_PROXY"])
+            .map(|no_proxy| {
+                no_proxy
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|host| !host.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    } else {
+        network.no_proxy
+    };
+    Settings {
+        proxy,
+        no_proxy,
+        ca_files: network.ca_files,
+    }
+}
+
+/// Returns the proxy variables to export, which are none without a proxy, so anything already
+/// set is left alone.
+fn variables(settings: &Settings) -> Vec<(&'static str, String)> {
+    let Some(proxy) = &settings.proxy else {
+        return Vec::new();
+    };
+    let no_proxy = settings.no_proxy.join(",");
+    PROXY_VARIABLES
+        .iter()
+        .map(|name| match name.to_lowercase().as_str() {
+            "no_proxy" => (*name, no_proxy.clone()),
+            _ => (*name, proxy.clone()),
+        })
+        .collect()
+}
+
+fn certificates(settings: &Settings) -> Result<Vec<Certificate>, Box<dyn Error>> {
+    let mut certificates = Vec::new();
+    for ca_file in &settings.ca_files {
+        let pem = fs::read(ca_file)
+            .map_err(|e| error::Error::new(&format!("Unable to read the CA {ca_file}: {e}")))?;
+        let bundle = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| error::Error::new(&format!("Invalid CA {ca_file}: {e}")))?;
+        if bundle.is_empty() {
+            return Err(Box::new(error::Error::new(&format!(
+                "{ca_file} has no certificates in it"
+            ))));
+        }
+        debug!("Trusting {} CAs from {}", bundle.len(), ca_file);
+        certificates.extend(bundle);
+    }
+    Ok(certificates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::profile::{Network, Profile};
+
+    fn config(network: Network) -> Config {
+        let mut config = parse(Vec::new());
+        config.profile = Some(Profile {
+            network,
+            ..Profile::default()
+        });
+        config
+    }
+
+    #[test]
+    fn test_settings_prefer_the_profile() {
+        let config = config(Network {
+            proxy: Some("http://proxy.example.com:3128".to_string()),
+            no_proxy: vec!["localhost".to_string(), ".example.com".to_string()],
+            ca_files: vec!["/etc/ssl/company-root.pem".to_string()],
+        });
+
+        let settings = settings(&config, |_| Some("http://elsewhere:8080".to_string()));
+
+        assert_eq!(
+            settings,
+            Settings {
+                proxy: Some("http://proxy.example.com:3128".to_string()),
+                no_proxy: vec!["localhost".to_string(), ".example.com".to_string()],
+                ca_files: vec!["/etc/ssl/company-root.pem".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_settings_fall_back_to_the_environment() {
+        let settings = settings(&parse(Vec::new()), |name| match name {
+            "HTTPS_PROXY" => Some("http://proxy.example.com:3128".to_string()),
+            "https_proxy" => Some(String::new()),
+            "no_proxy" => Some("localhost, .example.com,".to_string()),
+            _ => None,
+        });
+
+        assert_eq!(
+            settings.proxy,
+            Some("http://proxy.example.com:3128".to_string())
+        );
+        assert_eq!(settings.no_proxy, vec!["localhost", ".example.com"]);
+    }
+
+    #[test]
+    fn test_variables_export_both_cases() {
+        let settings = Settings {
+            proxy: Some("http://proxy.example.com:3128".to_string()),
+            no_proxy: vec!["localhost".to_string(), ".example.com".to_string()],
+            ca_files: Vec::new(),
+        };
+
+        let exported = variables(&settings);
+
+        assert_eq!(exported.len(), 6);
+        assert!(exported.contains(&("https_proxy", "http://proxy.example.com:3128".to_string())));
+        assert!(exported.contains(&("HTTP_PROXY", "http://proxy.example.com:3128".to_string())));
+        assert!(exported.contains(&("NO_PROXY", "localhost,.example.com".to_string())));
+        assert!(variables(&Settings::default()).is_empty());
+    }
+
+    #[test]
+    fn test_certificates_refuse_files_without_certificates() {
+        let ca_file = env::temp_dir().join("dotfiles-network-test-empty.pem");
+        fs::write(&ca_file, "not a certificate").unwrap();
+        let settings = Settings {
+            ca_files: vec![ca_file.to_string_lossy().to_string()],
+            ..Settings::default()
+        };
+
+        assert!(certificates(&settings).is_err());
+        assert!(certificates(&Settings::default()).unwrap().is_empty());
+    }
+}
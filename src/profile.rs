@@ -0,0 +1,449 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+/// A machine profile, loaded from a TOML file, for installs that need to run unattended.
+///
+/// ```toml
+/// groups = ["development", "gaming"]
+/// certificates = ["/home/user/Downloads/company-root.pem"]
+///
+/// [extras]
+/// packages = ["htop"]
+/// flatpaks = ["org.kde.krita"]
+///
+/// [flatpak]
+/// prefer = true
+///
+/// [aur.pins]
+/// google-chrome = "5f1e...c2a9"
+///
+/// [dotfiles]
+/// repository = "https://github.com/user/dotfiles.git"
+/// packages = ["zsh", "git"]
+///
+/// [downloads."get_helm.sh"]
+/// sha256 = "38b6...0e4d"
+///
+/// [network]
+/// proxy = "http://proxy.example.com:3128"
+/// no_proxy = ["localhost", ".example.com"]
+/// ca_files = ["/usr/local/share/ca-certificates/company-root.crt"]
+///
+/// [pacman]
+/// color = true
+/// parallel_downloads = 5
+/// ignore = ["linux", "linux-headers"]
+///
+/// [pacman.repositories.chaotic-aur]
+/// include = "/etc/pacman.d/chaotic-mirrorlist"
+///
+/// [releases.pins]
+/// heroic = "2.18.0"
+/// godot = "~4.2"
+///
+/// [templates]
+/// theme = "agnoster"
+/// plugins = ["git", "kubectl"]
+/// tmux_prefix = "C-b"
+/// path = ["${HOME}/bin", "${HOME}/go/bin"]
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct Profile {
+    #[serde(default)]
+    pub(crate) aur: Aur,
+    /// PEM or DER files, or URLs of them, of CAs to trust, e.g. a company's root CA.
+    #[serde(default)]
+    pub(crate) certificates: Vec<String>,
+    #[serde(default)]
+    pub(crate) dotfiles: Dotfiles,
+    /// How to verify each download, keyed by the name of the file it's saved as.
+    #[serde(default)]
+    pub(crate) downloads: BTreeMap<String, Verification>,
+    /// Extra packages installed after the steps, e.g. those captured that no step installs.
+    #[serde(default)]
+    pub(crate) extras: Packages,
+    #[serde(default)]
+    pub(crate) flatpak: Flatpak,
+    /// The groups of steps to install, named after their flags, e.g. `video-editing`.
+    #[serde(default)]
+    pub(crate) groups: Vec<String>,
+    #[serde(default)]
+    pub(crate) network: Network,
+    #[serde(default)]
+    pub(crate) pacman: Pacman,
+    #[serde(default)]
+    pub(crate) releases: Releases,
+    #[serde(default)]
+    pub(crate) templates: Templates,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct Aur {
+    /// The SHA-256 of the approved PKGBUILD and `.install` files for each AUR package.
+    #[serde(default)]
+    pub(crate) pins: BTreeMap<String, String>,
+}
+
+/// The user's own dotfiles, kept in a git repository laid out like GNU Stow's: each top level
+/// directory is a package mirroring the home directory, and `hosts/<hostname>` optionally holds
+/// one more package for the machine, overriding the others.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct Dotfiles {
+    /// The repository to clone, which turns the `dotfiles` step on.
+    pub(crate) repository: Option<String>,
+    /// The branch to check out, instead of the repository's default.
+    pub(crate) branch: Option<String>,
+    /// Where to clone it, defaulting to `~/.dotfiles`.
+    pub(crate) directory: Option<String>,
+    /// The packages to apply, defaulting to every top level directory.
+    pub(crate) packages: Vec<String>,
+    /// The name of the machine's package under `hosts`, defaulting to its hostname.
+    pub(crate) host: Option<String>,
+    pub(crate) mode: LinkMode,
+}
+
+/// How the dotfiles are put in place.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum LinkMode {
+    /// Symlinks to the files in the repository, so they stay up to date with it.
+    #[default]
+    Symlink,
+    /// Copies of the files, for tools that don't follow symlinks.
+    Copy,
+}
+
+/// Whether GUI apps come from Flathub, and which installation they go in.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct Flatpak {
+    /// Installs GUI apps from Flathub instead of the system's packages, like `--prefer-flatpak`.
+    pub(crate) prefer: bool,
+    /// Installs them for the user instead of system wide, like `--flatpak-user`.
+    pub(crate) user: bool,
+}
+
+/// How a download is checked before it's used.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Verification {
+    /// The SHA-256 of the file's contents.
+    Sha256(String),
+    /// A detached minisign signature, checked against the base64 public key.
+    Minisign {
+        public_key: String,
+        signature_url: String,
+    },
+    /// A detached GPG signature, which must be made by the key with the fingerprint.
+    Gpg {
+        fingerprint: String,
+        key_url: String,
+        signature_url: String,
+    },
+}
+
+/// Packages by where they are installed from.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub(crate) struct Packages {
+    pub(crate) aur: Vec<String>,
+    /// Snaps installed with classic confinement, so they can reach the rest of the system.
+    pub(crate) classic_snaps: Vec<String>,
+    pub(crate) flatpaks: Vec<String>,
+    /// Packages from the system's package manager.
+    pub(crate) packages: Vec<String>,
+    pub(crate) snaps: Vec<String>,
+}
+
+impl Packages {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.aur.is_empty()
+            && self.classic_snaps.is_empty()
+            && self.flatpaks.is_empty()
+            && self.packages.is_empty()
+            && self.snaps.is_empty()
+    }
+}
+
+/// How the network is reached, for machines behind a proxy.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct Network {
+    /// The proxy every request and child command goes through.
+    pub(crate) proxy: Option<String>,
+    /// The hosts, domains, e.g. `.example.com`, and addresses reached without the proxy.
+    pub(crate) no_proxy: Vec<String>,
+    /// PEM files of CAs to trust on top of the system's, e.g. a company's root CA.
+    pub(crate) ca_files: Vec<String>,
+}
+
+/// Options for `/etc/pacman.conf`, where anything not set is left as it is.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct Pacman {
+    pub(crate) color: Option<bool>,
+    pub(crate) disabled_repositories: Vec<String>,
+    pub(crate) i_love_candy: Option<bool>,
+    pub(crate) ignore: Vec<String>,
+    pub(crate) parallel_downloads: Option<u32>,
+    pub(crate) repositories: BTreeMap<String, PacmanRepository>,
+}
+
+/// A custom pacman repository, using either a mirrorlist to include or a single server.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct PacmanRepository {
+    pub(crate) include: Option<String>,
+    pub(crate) server: Option<String>,
+    pub(crate) sig_level: Option<String>,
+}
+
+/// Where to look up the releases of direct downloads, and the versions to keep them at.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub(crate) struct Releases {
+    /// The GitHub-compatible releases API to use instead of GitHub's.
+    pub(crate) api: Option<String>,
+    /// The version, e.g. `2.18.0`, or requirement, e.g. `~4.2`, for each project.
+    pub(crate) pins: BTreeMap<String, String>,
+}
+
+/// The variables the generated dotfiles are rendered with, and where to find templates
+/// overriding the defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub(crate) struct Templates {
+    /// The directory of templates, named like the defaults, e.g. `zshrc`, that replace them.
+    /// Defaults to `~/.config/dotfiles/templates`.
+    pub(crate) directory: Option<String>,
+    /// The oh-my-zsh theme.
+    pub(crate) theme: String,
+    /// The oh-my-zsh plugins.
+    pub(crate) plugins: Vec<String>,
+    /// The key tmux commands are prefixed with.
+    pub(crate) tmux_prefix: String,
+    /// The directories added to the end of `PATH`.
+    pub(crate) path: Vec<String>,
+    /// Any other variables, for overriding templates to use.
+    pub(crate) variables: BTreeMap<String, String>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Templates {
+            directory: None,
+            theme: "robbyrussell".to_string(),
+            plugins: [
+                "common-aliases",
+                "docker",
+                "docker-compose",
+                "git",
+                "git-flow",
+                "gradle",
+                "jira",
+                "kubectl",
+                "mvn",
+                "pip",
+                "web-search",
+            ]
+            .map(String::from)
+            .to_vec(),
+            tmux_prefix: "C-a".to_string(),
+            path: vec!["${HOME}/bin".to_string(), "${HOME}/.local/bin".to_string()],
+            variables: BTreeMap::new(),
+        }
+    }
+}
+
+/// Loads the profile from the TOML file.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use profile;
+///
+/// let profile = profile::load("workstation.toml")?;
+/// ```
+pub(crate) fn load(path: &str) -> Result<Profile, Box<dyn Error>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+fn parse(contents: &str) -> Result<Profile, Box<dyn Error>> {
+    Ok(toml::from_str(contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_empty_profile() {
+        assert_eq!(parse("").unwrap(), Profile::default());
+    }
+
+    #[test]
+    fn test_parse_aur_pins() {
+        let profile = parse("[aur.pins]\ngoogle-chrome = \"abc123\"\n").unwrap();
+        assert_eq!(
+            profile.aur.pins.get("google-chrome"),
+            Some(&"abc123".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_flatpak() {
+        let profile = parse("[flatpak]\nprefer = true\n").unwrap();
+        assert_eq!(
+            profile.flatpak,
+            Flatpak {
+                prefer: true,
+                user: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_dotfiles() {
+        let profile = parse(
+            "[dotfiles]\n\
+            repository = \"https://github.com/user/dotfiles.git\"\n\
+            packages = [\"zsh\"]\n\
+            mode = \"copy\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            profile.dotfiles,
+            Dotfiles {
+                repository: Some("https://github.com/user/dotfiles.git".to_string()),
+                packages: vec!["zsh".to_string()],
+                mode: LinkMode::Copy,
+                ..Dotfiles::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_network() {
+        let profile = parse(
+            "[network]\n\
+            proxy = \"http://proxy.example.com:3128\"\n\
+            no_proxy = [\"localhost\"]\n\
+            ca_files = [\"/etc/ssl/company-root.pem\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            profile.network,
+            Network {
+                proxy: Some("http://proxy.example.com:3128".to_string()),
+                no_proxy: vec!["localhost".to_string()],
+                ca_files: vec!["/etc/ssl/company-root.pem".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_pacman() {
+        let profile = parse(
+            "[pacman]\n\
+            color = true\n\
+            parallel_downloads = 5\n\
+            ignore = [\"linux\"]\n\
+            [pacman.repositories.chaotic-aur]\n\
+            include = \"/etc/pacman.d/chaotic-mirrorlist\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(profile.pacman.color, Some(true));
+        assert_eq!(profile.pacman.i_love_candy, None);
+        assert_eq!(profile.pacman.parallel_downloads, Some(5));
+        assert_eq!(profile.pacman.ignore, vec!["linux"]);
+        assert_eq!(
+            profile.pacman.repositories["chaotic-aur"].include,
+            Some("/etc/pacman.d/chaotic-mirrorlist".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_downloads() {
+        let profile = parse(
+            "[downloads.\"get_helm.sh\"]\n\
+            sha256 = \"abc123\"\n\
+            [downloads.\"exercism.tar.gz\"]\n\
+            minisign = { public_key = \"RWQ\", signature_url = \"https://example.com/minisig\" }\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            profile.downloads["get_helm.sh"],
+            Verification::Sha256("abc123".to_string())
+        );
+        assert_eq!(
+            profile.downloads["exercism.tar.gz"],
+            Verification::Minisign {
+                public_key: "RWQ".to_string(),
+                signature_url: "https://example.com/minisig".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_groups_and_extras() {
+        let profile = parse(
+            "groups = [\"development\", \"video-editing\"]\n\
+            [extras]\n\
+            packages = [\"htop\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(profile.groups, vec!["development", "video-editing"]);
+        assert_eq!(profile.extras.packages, vec!["htop"]);
+        assert!(profile.extras.flatpaks.is_empty());
+    }
+
+    #[test]
+    fn test_parse_templates_keeps_defaults_not_set() {
+        let profile = parse(
+            "[templates]\n\
+            theme = \"agnoster\"\n\
+            [templates.variables]\n\
+            editor = \"nvim\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(profile.templates.theme, "agnoster");
+        assert_eq!(profile.templates.tmux_prefix, "C-a");
+        assert_eq!(profile.templates.variables["editor"], "nvim");
+        assert!(profile.templates.plugins.contains(&"git".to_string()));
+    }
+
+    #[test]
+    fn test_parse_releases() {
+        let profile = parse(
+            "[releases]\n\
+            api = \"https://git.example.com/api/v3\"\n\
+            [releases.pins]\n\
+            heroic = \"2.18.0\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            profile.releases.api,
+            Some("https://git.example.com/api/v3".to_string())
+        );
+        assert_eq!(
+            profile.releases.pins.get("heroic"),
+            Some(&"2.18.0".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_profile() {
+        assert!(parse("[aur.pins]\ngoogle-chrome = 1\n").is_err());
+    }
+}
@@ -0,0 +1,557 @@
+use log::{debug, info, warn};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+
+use crate::bundle::{self, Bundle};
+use crate::config::Config;
+use crate::{error, network};
+
+static GITHUB_API: &str = "https://api.github.com";
+
+/// A download published as an asset of a project's releases on GitHub.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Project {
+    /// The name the version is pinned by in the profile's `[releases.pins]`.
+    pub(crate) name: &'static str,
+    /// The `owner/repository` the releases are published in.
+    pub(crate) repository: &'static str,
+    /// The release's tag, with `{version}` standing in for the version.
+    pub(crate) tag: &'static str,
+    /// The asset's file name, with `{version}` standing in for the version and `{arch}` for the
+    /// project's name for the architecture.
+    pub(crate) asset: &'static str,
+    /// The project's name for each architecture it publishes assets for, keyed by Rust's name.
+    pub(crate) architectures: &'static [(&'static str, &'static str)],
+    /// The version to download when the releases can't be looked up.
+    pub(crate) fallback: &'static str,
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) static EXERCISM: Project = Project {
+    name: "exercism",
+    repository: "exercism/cli",
+    tag: "v{version}",
+    asset: "exercism-{version}-linux-{arch}.tar.gz",
+    architectures: &[("x86_64", "x86_64"), ("aarch64", "arm64")],
+    fallback: "3.1.0",
+};
+
+#[cfg(target_os = "linux")]
+pub(crate) static GODOT_MONO: Project = Project {
+    name: "godot",
+    repository: "godotengine/godot",
+    tag: "{version}-stable",
+    asset: "Godot_v{version}-stable_mono_linux_{arch}.zip",
+    architectures: &[("x86_64", "x86_64"), ("aarch64", "arm64")],
+    fallback: "4.2.2",
+};
+
+#[cfg(target_os = "linux")]
+pub(crate) static GREENLIGHT_DEB: Project = Project {
+    name: "greenlight",
+    repository: "unknownskl/greenlight",
+    tag: "v{version}",
+    asset: "greenlight_{version}_{arch}.deb",
+    architectures: &[("x86_64", "amd64"), ("aarch64", "arm64")],
+    fallback: "2.3.3",
+};
+
+#[cfg(any(target_os = "macos", test))]
+pub(crate) static GREENLIGHT_DMG: Project = Project {
+    name: "greenlight",
+    repository: "unknownskl/greenlight",
+    tag: "v{version}",
+    asset: "Greenlight-{version}-universal.dmg",
+    architectures: &[],
+    fallback: "2.3.3",
+};
+
+#[cfg(any(target_os = "linux", test))]
+pub(crate) static HEROIC_DEB: Project = Project {
+    name: "heroic",
+    repository: "Heroic-Games-Launcher/HeroicGamesLauncher",
+    tag: "v{version}",
+    asset: "Heroic-{version}-linux-{arch}.deb",
+    architectures: &[("x86_64", "amd64"), ("aarch64", "arm64")],
+    fallback: "2.18.0",
+};
+
+/// The release of a project chosen to download.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Release {
+    /// The version as the project writes it, e.g. `4.3` for Godot.
+    pub(crate) version: String,
+    pub(crate) url: String,
+}
+
+/// Which releases can be chosen, from the project's pin in the profile.
+#[derive(Debug, PartialEq)]
+enum Constraint {
+    Latest,
+    Exact(Version),
+    Matching(VersionReq),
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiRelease {
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    assets: Vec<ApiAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiAsset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Resolves the release of the project to download, being the latest stable release unless the
+/// profile pins the project to a version or requirement, with the asset for this architecture.
+///
+/// When the releases can't be looked up, the pinned version, or the project's fallback version,
+/// is downloaded from GitHub instead. When creating a bundle the release is recorded in it, and
+/// when installing from one the recorded release is used, without looking anything up.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use release;
+///
+/// let heroic = release::resolve(&config, &release::HEROIC_DEB).await?;
+/// system::download_file(&config, &heroic.url, "heroic-launcher.deb").await?;
+/// ```
+pub(crate) async fn resolve(config: &Config, project: &Project) -> Result<Release, Box<dyn Error>> {
+    match bundle::of(config) {
+        Some(bundle @ Bundle::InstallingFrom(directory)) => bundled(&bundle, project.asset)?
+            .ok_or_else(|| {
+                Box::new(error::Error::new(&format!(
+                    "The bundle {directory} has no release of {}",
+                    project.name
+                ))) as Box<dyn Error>
+            }),
+        Some(bundle @ Bundle::Creating(_)) => {
+            let release = resolve_online(config, project).await?;
+            record(&bundle, project.asset, &release)?;
+            Ok(release)
+        }
+        None => resolve_online(config, project).await,
+    }
+}
+
+async fn resolve_online(config: &Config, project: &Project) -> Result<Release, Box<dyn Error>> {
+    let releases = config.profile.as_ref().map(|profile| &profile.releases);
+    let api = releases
+        .and_then(|releases| releases.api.as_deref())
+        .unwrap_or(GITHUB_API);
+    let pin = releases.and_then(|releases| releases.pins.get(project.name));
+    let constraint = parse_constraint(pin.map(String::as_str))?;
+    let architecture = architecture(project, env::consts::ARCH)?;
+    match resolve_from(api, project, &constraint, architecture).await {
+        Ok(release) => {
+            info!("Resolved {} to {}", project.name, release.version);
+            Ok(release)
+        }
+        Err(e) => {
+            let version = match (&constraint, pin) {
+                (Constraint::Latest, _) => project.fallback,
+                (Constraint::Exact(_), Some(pin)) => pin.trim_start_matches('v'),
+                _ => return Err(e),
+            };
+            warn!(
+                "Unable to look up the releases of {}, so using {}: {}",
+                project.name, version, e
+            );
+            Ok(Release {
+                version: version.to_string(),
+                url: format!(
+                    "https://github.com/{}/releases/download/{}/{}",
+                    project.repository,
+                    project.tag.replace("{version}", version),
+                    asset_name(project, version, architecture)
+                ),
+            })
+        }
+    }
+}
+
+fn bundled(bundle: &Bundle, asset: &str) -> Result<Option<Release>, Box<dyn Error>> {
+    Ok(releases(bundle)?.remove(asset))
+}
+
+fn record(bundle: &Bundle, asset: &str, release: &Release) -> Result<(), Box<dyn Error>> {
+    let mut releases = releases(bundle)?;
+    releases.insert(asset.to_string(), release.clone());
+    fs::write(bundle.releases_file(), toml::to_string(&releases)?)?;
+    Ok(())
+}
+
+fn releases(bundle: &Bundle) -> Result<BTreeMap<String, Release>, Box<dyn Error>> {
+    let path = bundle.releases_file();
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    Ok(toml::from_str(&fs::read_to_string(path)?)?)
+}
+
+async fn resolve_from(
+    api: &str,
+    project: &Project,
+    constraint: &Constraint,
+    architecture: &str,
+) -> Result<Release, Box<dyn Error>> {
+    let url = format!(
+        "{}/repos/{}/releases?per_page=100",
+        api.trim_end_matches('/'),
+        project.repository
+    );
+    debug!("Looking up the releases of {} from {}", project.name, url);
+    let client = network::client_builder()?
+        .user_agent(concat!("dotfiles/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let response = client.get(&url).send().await?.error_for_status()?;
+    let releases: Vec<ApiRelease> = serde_json::from_str(&response.text().await?)?;
+    choose(project, &releases, constraint, architecture).ok_or_else(|| {
+        Box::new(error::Error::new(&format!(
+            "No release of {} matches the pin and has the asset {}",
+            project.name,
+            asset_name(project, "{version}", architecture)
+        ))) as Box<dyn Error>
+    })
+}
+
+/// Chooses the highest version allowed by the constraint that has the asset, skipping drafts and
+/// pre-releases unless one is pinned exactly.
+fn choose(
+    project: &Project,
+    releases: &[ApiRelease],
+    constraint: &Constraint,
+    architecture: &str,
+) -> Option<Release> {
+    releases
+        .iter()
+        .filter(|release| !release.draft)
+        .filter_map(|release| {
+            let raw = tag_version(project.tag, &release.tag_name)?;
+            let version = parse_version(raw)?;
+            let allowed = match constraint {
+                Constraint::Latest => !release.prerelease && version.pre.is_empty(),
+                Constraint::Exact(pinned) => &version == pinned,
+                Constraint::Matching(requirement) => {
+                    !release.prerelease && requirement.matches(&version)
+                }
+            };
+            if !allowed {
+                return None;
+            }
+            let name = asset_name(project, raw, architecture);
+            let asset = release.assets.iter().find(|asset| asset.name == name)?;
+            Some((
+                version,
+                Release {
+                    version: raw.to_string(),
+                    url: asset.browser_download_url.clone(),
+                },
+            ))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, release)| release)
+}
+
+fn parse_constraint(pin: Option<&str>) -> Result<Constraint, Box<dyn Error>> {
+    let Some(pin) = pin else {
+        return Ok(Constraint::Latest);
+    };
+    if let Some(version) = parse_version(pin) {
+        return Ok(Constraint::Exact(version));
+    }
+    Ok(Constraint::Matching(VersionReq::parse(pin).map_err(
+        |e| error::Error::new(&format!("Invalid release pin {pin}: {e}")),
+    )?))
+}
+
+/// Parses the version, allowing the patch to be left out as projects like Godot do.
+fn parse_version(version: &str) -> Option<Version> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    Version::parse(version).ok().or_else(|| {
+        let (release, rest) = version
+            .split_once(['-', '+'])
+            .map_or((version, ""), |(release, _)| {
+                (release, &version[release.len()..])
+            });
+        if release.matches('.').count() != 1 {
+            return None;
+        }
+        Version::parse(&format!("{release}.0{rest}")).ok()
+    })
+}
+
+/// Returns the version in the tag, if the tag is in the project's format.
+fn tag_version<'t>(format: &str, tag: &'t str) -> Option<&'t str> {
+    let (prefix, suffix) = format.split_once("{version}")?;
+    tag.strip_prefix(prefix)?
+        .strip_suffix(suffix)
+        .filter(|version| !version.is_empty())
+}
+
+fn architecture<'p>(project: &'p Project, arch: &str) -> Result<&'p str, Box<dyn Error>> {
+    if !project.asset.contains("{arch}") {
+        return Ok("");
+    }
+    project
+        .architectures
+        .iter()
+        .find(|(rust, _)| *rust == arch)
+        .map(|(_, name)| *name)
+        .ok_or_else(|| {
+            Box::new(error::Error::new(&format!(
+                "{} isn't published for {arch}",
+                project.name
+            ))) as Box<dyn Error>
+        })
+}
+
+fn asset_name(project: &Project, version: &str, architecture: &str) -> String {
+    project
+        .asset
+        .replace("{version}", version)
+        .replace("{arch}", architecture)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use tiny_http::{Header, Response, Server};
+
+    use super::*;
+    use crate::profile::Profile;
+
+    static RELEASES: &str = r#"[
+        {
+            "tag_name": "v2.19.0-beta.1",
+            "prerelease": true,
+            "assets": [{"name": "Heroic-2.19.0-beta.1-linux-amd64.deb", "browser_download_url": "https://example.com/2.19.0-beta.1.deb"}]
+        },
+        {
+            "tag_name": "v2.18.1",
+            "assets": [
+                {"name": "Heroic-2.18.1-linux-amd64.deb", "browser_download_url": "https://example.com/2.18.1-amd64.deb"},
+                {"name": "Heroic-2.18.1-linux-arm64.deb", "browser_download_url": "https://example.com/2.18.1-arm64.deb"}
+            ]
+        },
+        {
+            "tag_name": "v2.18.0",
+            "assets": [{"name": "Heroic-2.18.0-linux-amd64.deb", "browser_download_url": "https://example.com/2.18.0.deb"}]
+        },
+        {
+            "tag_name": "v2.17.2",
+            "assets": [{"name": "Heroic-2.17.2-linux-amd64.deb", "browser_download_url": "https://example.com/2.17.2.deb"}]
+        }
+    ]"#;
+
+    /// Serves the releases on a local port, returning the API URL and the requested paths.
+    fn serve(body: &'static str, status: u16) -> (String, thread::JoinHandle<String>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let api = format!("http://{}", server.server_addr().to_ip().unwrap());
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let path = request.url().to_string();
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+            request.respond(response).unwrap();
+            path
+        });
+        (api, handle)
+    }
+
+    fn config(api: &str, pins: &[(&str, &str)]) -> Config {
+        let mut config = crate::config::parse(Vec::new());
+        let mut profile = Profile::default();
+        profile.releases.api = Some(api.to_string());
+        for (name, pin) in pins {
+            profile
+                .releases
+                .pins
+                .insert(name.to_string(), pin.to_string());
+        }
+        config.profile = Some(profile);
+        config
+    }
+
+    fn releases() -> Vec<ApiRelease> {
+        serde_json::from_str(RELEASES).unwrap()
+    }
+
+    #[test]
+    fn test_choose_latest_skips_prereleases() {
+        let release = choose(&HEROIC_DEB, &releases(), &Constraint::Latest, "amd64");
+
+        assert_eq!(
+            release,
+            Some(Release {
+                version: "2.18.1".to_string(),
+                url: "https://example.com/2.18.1-amd64.deb".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_choose_picks_the_asset_for_the_architecture() {
+        let release = choose(&HEROIC_DEB, &releases(), &Constraint::Latest, "arm64");
+
+        assert_eq!(release.unwrap().url, "https://example.com/2.18.1-arm64.deb");
+    }
+
+    #[test]
+    fn test_choose_matching_requirement() {
+        let constraint = parse_constraint(Some("~2.17")).unwrap();
+
+        let release = choose(&HEROIC_DEB, &releases(), &constraint, "amd64");
+
+        assert_eq!(release.unwrap().version, "2.17.2");
+    }
+
+    #[test]
+    fn test_choose_exact_allows_prereleases() {
+        let constraint = parse_constraint(Some("2.19.0-beta.1")).unwrap();
+
+        let release = choose(&HEROIC_DEB, &releases(), &constraint, "amd64");
+
+        assert_eq!(release.unwrap().version, "2.19.0-beta.1");
+    }
+
+    #[test]
+    fn test_choose_none_without_the_asset() {
+        let constraint = parse_constraint(Some("2.18.0")).unwrap();
+
+        assert_eq!(choose(&HEROIC_DEB, &releases(), &constraint, "arm64"), None);
+    }
+
+    #[test]
+    fn test_parse_version_allows_missing_patch() {
+        assert_eq!(parse_version("4.3"), Some(Version::new(4, 3, 0)));
+        assert_eq!(
+            parse_version("v4.3-rc1"),
+            Some(Version::parse("4.3.0-rc1").unwrap())
+        );
+        assert_eq!(parse_version("stable"), None);
+    }
+
+    #[test]
+    fn test_tag_version_strips_the_format() {
+        assert_eq!(
+            tag_version("{version}-stable", "4.2.2-stable"),
+            Some("4.2.2")
+        );
+        assert_eq!(tag_version("v{version}", "v3.1.0"), Some("3.1.0"));
+        assert_eq!(tag_version("v{version}", "nightly"), None);
+    }
+
+    #[test]
+    fn test_architecture_uses_the_project_name() {
+        assert_eq!(architecture(&HEROIC_DEB, "x86_64").unwrap(), "amd64");
+        assert_eq!(architecture(&GREENLIGHT_DMG, "aarch64").unwrap(), "");
+        assert!(architecture(&HEROIC_DEB, "riscv64").is_err());
+    }
+
+    #[test]
+    fn test_resolve_from_the_api() {
+        let (api, handle) = serve(RELEASES, 200);
+
+        let release = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(resolve_from(
+                &api,
+                &HEROIC_DEB,
+                &Constraint::Latest,
+                "amd64",
+            ))
+            .unwrap();
+
+        assert_eq!(release.version, "2.18.1");
+        assert_eq!(
+            handle.join().unwrap(),
+            "/repos/Heroic-Games-Launcher/HeroicGamesLauncher/releases?per_page=100"
+        );
+    }
+
+    #[test]
+    fn test_resolve_falls_back_to_the_fallback_version() {
+        let (api, handle) = serve("rate limited", 403);
+
+        let release = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(resolve(&config(&api, &[]), &GREENLIGHT_DMG))
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(
+            release,
+            Release {
+                version: "2.3.3".to_string(),
+                url: "https://github.com/unknownskl/greenlight/releases/download/v2.3.3/Greenlight-2.3.3-universal.dmg".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_resolve_falls_back_to_the_pinned_version() {
+        let (api, handle) = serve("rate limited", 403);
+
+        let release = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(resolve(
+                &config(&api, &[("greenlight", "2.3.0")]),
+                &GREENLIGHT_DMG,
+            ))
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(release.version, "2.3.0");
+    }
+
+    #[test]
+    fn test_resolve_fails_for_an_unmatched_requirement() {
+        let (api, handle) = serve(RELEASES, 200);
+
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(resolve_from(
+                &api,
+                &HEROIC_DEB,
+                &parse_constraint(Some("^3")).unwrap(),
+                "amd64",
+            ));
+        handle.join().unwrap();
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_bundled_release_is_recorded_release() {
+        let directory = std::env::temp_dir().join("dotfiles-release-test-bundle");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let directory = directory.to_string_lossy().to_string();
+        let heroic = Release {
+            version: "2.18.1".to_string(),
+            url: "https://example.com/2.18.1-amd64.deb".to_string(),
+        };
+
+        record(&Bundle::Creating(&directory), HEROIC_DEB.asset, &heroic).unwrap();
+
+        let bundle = Bundle::InstallingFrom(&directory);
+        assert_eq!(bundled(&bundle, HEROIC_DEB.asset).unwrap(), Some(heroic));
+        assert_eq!(bundled(&bundle, GREENLIGHT_DMG.asset).unwrap(), None);
+    }
+}
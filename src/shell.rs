@@ -0,0 +1,198 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::error;
+
+/// A shell dotfiles writes the config of, so everything it sets up works in each.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum Shell {
+    Bash,
+    Fish,
+    #[default]
+    Zsh,
+}
+
+impl Shell {
+    /// Returns the shells to write the config of, which are bash and zsh, as they're always
+    /// installed, and fish once it has a config directory.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use shell::Shell;
+    ///
+    /// for shell in Shell::configured(&system.get_home_dir()) {
+    ///     println!("{}", shell.config_file());
+    /// }
+    /// ```
+    pub(crate) fn configured(home: &str) -> Vec<Shell> {
+        let mut shells = vec![Shell::Bash, Shell::Zsh];
+        if Path::new(home).join(".config/fish").is_dir() {
+            shells.push(Shell::Fish);
+        }
+        shells
+    }
+
+    /// Returns the shell's config file, relative to the home directory.
+    pub(crate) fn config_file(&self) -> &'static str {
+        match self {
+            Shell::Bash => ".bashrc",
+            Shell::Fish => ".config/fish/config.fish",
+            Shell::Zsh => ".zshrc",
+        }
+    }
+
+    /// Returns the line adding the directory to the end of `PATH`.
+    pub(crate) fn path_entry(&self, path: &str) -> String {
+        let path = quote(path);
+        match self {
+            Shell::Bash | Shell::Zsh => format!("export PATH=\"$PATH:{path}\""),
+            Shell::Fish => format!("fish_add_path --global --append \"{path}\""),
+        }
+    }
+
+    /// Returns the line exporting the variable, with the value as it would be written in bash,
+    /// e.g. `$HOME/.nvm`.
+    pub(crate) fn variable(&self, key: &str, value: &str) -> String {
+        let value = quote(value);
+        match self {
+            Shell::Bash | Shell::Zsh => format!("export {key}=\"{value}\""),
+            Shell::Fish => format!("set --global --export {key} \"{value}\""),
+        }
+    }
+
+    /// Returns the line sourcing the file, if it exists.
+    pub(crate) fn source(&self, file: &str) -> String {
+        let file = quote(file);
+        match self {
+            Shell::Bash | Shell::Zsh => format!("[ -f \"{file}\" ] && . \"{file}\""),
+            Shell::Fish => format!("test -f \"{file}\"; and source \"{file}\""),
+        }
+    }
+
+    /// Returns the alias of the name to the command.
+    pub(crate) fn alias(&self, name: &str, command: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => format!("alias {name}='{command}'"),
+            Shell::Fish => format!("alias {name} '{command}'"),
+        }
+    }
+
+    /// Returns the function with the body, which must already be in the shell's syntax.
+    pub(crate) fn function(&self, name: &str, body: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => format!("{name}() {{\n{body}\n}}"),
+            Shell::Fish => format!("function {name}\n{body}\nend"),
+        }
+    }
+}
+
+/// Escapes the value to go in double quotes, which every shell reads the same way, leaving
+/// variables to be expanded.
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Shell::Bash => write!(f, "bash"),
+            Shell::Fish => write!(f, "fish"),
+            Shell::Zsh => write!(f, "zsh"),
+        }
+    }
+}
+
+impl FromStr for Shell {
+    type Err = error::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "bash" => Ok(Shell::Bash),
+            "fish" => Ok(Shell::Fish),
+            "zsh" => Ok(Shell::Zsh),
+            _ => Err(error::Error::new(&format!("Unknown shell {name}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_path_entry_and_variable_use_each_shells_syntax() {
+        assert_eq!(
+            Shell::Zsh.path_entry("$JAVA_HOME/bin"),
+            "export PATH=\"$PATH:$JAVA_HOME/bin\""
+        );
+        assert_eq!(
+            Shell::Fish.path_entry("$JAVA_HOME/bin"),
+            "fish_add_path --global --append \"$JAVA_HOME/bin\""
+        );
+        assert_eq!(
+            Shell::Bash.variable("JAVA_HOME", "/usr/lib/jvm/default"),
+            "export JAVA_HOME=\"/usr/lib/jvm/default\""
+        );
+        assert_eq!(
+            Shell::Fish.variable("JAVA_HOME", "/usr/lib/jvm/default"),
+            "set --global --export JAVA_HOME \"/usr/lib/jvm/default\""
+        );
+        assert_eq!(
+            Shell::Zsh.variable("GREETING", "say \"hi\" \\o/"),
+            "export GREETING=\"say \\\"hi\\\" \\\\o/\""
+        );
+    }
+
+    #[test]
+    fn test_source_checks_the_file_exists() {
+        assert_eq!(
+            Shell::Bash.source("$HOME/.config/dotfiles/env.sh"),
+            "[ -f \"$HOME/.config/dotfiles/env.sh\" ] && . \"$HOME/.config/dotfiles/env.sh\""
+        );
+        assert_eq!(
+            Shell::Fish.source("$HOME/.config/dotfiles/env.fish"),
+            "test -f \"$HOME/.config/dotfiles/env.fish\"; and source \"$HOME/.config/dotfiles/env.fish\""
+        );
+    }
+
+    #[test]
+    fn test_alias_and_function_use_each_shells_syntax() {
+        assert_eq!(Shell::Bash.alias("cd", "cdnvm"), "alias cd='cdnvm'");
+        assert_eq!(Shell::Fish.alias("ll", "ls -l"), "alias ll 'ls -l'");
+        assert_eq!(
+            Shell::Zsh.function("gco", "    git checkout"),
+            "gco() {\n    git checkout\n}"
+        );
+        assert_eq!(
+            Shell::Fish.function("gco", "    git checkout $argv"),
+            "function gco\n    git checkout $argv\nend"
+        );
+    }
+
+    #[test]
+    fn test_configured_includes_fish_once_it_has_a_config_directory() {
+        let home = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&home).unwrap();
+        let home = home.to_string_lossy().to_string();
+
+        assert_eq!(Shell::configured(&home), vec![Shell::Bash, Shell::Zsh]);
+        std::fs::create_dir_all(format!("{home}/.config/fish")).unwrap();
+        assert_eq!(
+            Shell::configured(&home),
+            vec![Shell::Bash, Shell::Zsh, Shell::Fish]
+        );
+    }
+
+    #[test]
+    fn test_from_str_round_trips_display() {
+        for shell in [Shell::Bash, Shell::Fish, Shell::Zsh] {
+            assert_eq!(shell.to_string().parse::<Shell>().unwrap(), shell);
+        }
+        assert!("csh".parse::<Shell>().is_err());
+    }
+}
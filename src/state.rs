@@ -0,0 +1,555 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::error;
+use crate::system::System;
+
+/// Something a step added, recorded so that uninstalling the step can reverse it.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Change {
+    /// A managed block written into a file that's otherwise the user's, e.g. `.zshrc`.
+    Block {
+        file: String,
+        id: String,
+    },
+    File {
+        path: String,
+        /// The SHA-256 of the contents written, to tell when the file has been modified since.
+        #[serde(default)]
+        sha256: Option<String>,
+    },
+    Flatpak {
+        application: String,
+        user: bool,
+    },
+    Group {
+        name: String,
+    },
+    GroupMember {
+        group: String,
+        user: String,
+    },
+    /// A line added to a file that can't have a managed block's markers.
+    Line {
+        file: String,
+        line: String,
+    },
+    Package {
+        name: String,
+    },
+    /// An entry on the PATH every shell gets from the env file.
+    PathEntry {
+        entry: String,
+    },
+    Repository {
+        name: String,
+    },
+    Service {
+        name: String,
+    },
+    Snap {
+        name: String,
+    },
+    /// A variable every shell exports from the env file.
+    Variable {
+        key: String,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Block { file, id } => write!(f, "dotfiles:{id} block in {file}"),
+            Change::File { path, .. } => write!(f, "file {path}"),
+            Change::Flatpak { application, .. } => write!(f, "flatpak {application}"),
+            Change::Group { name } => write!(f, "group {name}"),
+            Change::GroupMember { group, user } => write!(f, "{user}'s membership of {group}"),
+            Change::Line { file, line } => write!(f, "line {line} in {file}"),
+            Change::Package { name } => write!(f, "package {name}"),
+            Change::PathEntry { entry } => write!(f, "PATH entry {entry}"),
+            Change::Repository { name } => write!(f, "repository {name}"),
+            Change::Service { name } => write!(f, "service {name}"),
+            Change::Snap { name } => write!(f, "snap {name}"),
+            Change::Variable { key } => write!(f, "variable {key}"),
+        }
+    }
+}
+
+/// The changes made by each step, keyed by the step id.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Ledger {
+    #[serde(default)]
+    pub(crate) steps: BTreeMap<String, Step>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct Step {
+    /// The last run that changed anything for the step.
+    pub(crate) run: u32,
+    pub(crate) changes: Vec<Change>,
+}
+
+impl Ledger {
+    /// Loads the changes recorded by previous runs, without creating anything when there are none.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use state::Ledger;
+    ///
+    /// let ledger = Ledger::load(&system)?;
+    /// ```
+    pub(crate) fn load(system: &dyn System) -> Result<Ledger, Box<dyn Error>> {
+        Ledger::load_from(&ledger_path(system))
+    }
+
+    fn load_from(path: &str) -> Result<Ledger, Box<dyn Error>> {
+        if !Path::new(path).exists() {
+            return Ok(Ledger::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn save(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        fs::write(path, toml::to_string(self)?)?;
+        give_to_user(path)?;
+        Ok(())
+    }
+
+    /// Adds the change to the step, unless the step already made it.
+    fn record(&mut self, step: &str, run: u32, change: Change) -> bool {
+        let step = self.steps.entry(step.to_string()).or_default();
+        if step.changes.contains(&change) {
+            return false;
+        }
+        step.run = run;
+        step.changes.push(change);
+        true
+    }
+
+    /// Returns another step that also made the change, so needs it kept.
+    fn needed_by(&self, id: &str, change: &Change) -> Option<&str> {
+        self.steps
+            .iter()
+            .find(|(other, step)| other.as_str() != id && step.changes.contains(change))
+            .map(|(other, _)| other.as_str())
+    }
+}
+
+/// Where the changes are being recorded during a run.
+struct Recorder {
+    path: String,
+    run: u32,
+    step: Option<String>,
+    ledger: Ledger,
+}
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+/// The id of this run, once it's started, as runs start at 1.
+static RUN: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the directory dotfiles keeps its state in between runs, creating it if needed.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// let directory = state::directory(&system)?;
+/// ```
+pub(crate) fn directory(system: &dyn System) -> Result<String, Box<dyn Error>> {
+    let directory = directory_path(system);
+    fs::create_dir_all(&directory)?;
+    give_to_user(&directory)?;
+    Ok(directory)
+}
+
+/// Allocates the id for this run, one more than the previous run, so that everything the run
+/// changes can be traced back to it.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// let run = state::start_run(&system)?;
+/// ```
+pub(crate) fn start_run(system: &dyn System) -> Result<u32, Box<dyn Error>> {
+    let last_run = format!("{}/last-run", directory(system)?);
+    let run = next_run(fs::read_to_string(&last_run).ok().as_deref());
+    fs::write(&last_run, format!("{run}\n"))?;
+    give_to_user(&last_run)?;
+    RUN.store(run, Ordering::Relaxed);
+    Ok(run)
+}
+
+/// Returns the id of this run, if it has started.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// let run = state::current_run().unwrap_or_default();
+/// ```
+pub(crate) fn current_run() -> Option<u32> {
+    Some(RUN.load(Ordering::Relaxed)).filter(|run| *run > 0)
+}
+
+/// Starts recording the changes each step makes in `~/.local/state/dotfiles/state.toml`. Until
+/// this is called, e.g. in dry runs, nothing is recorded.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// state::start_recording(&system, run)?;
+/// ```
+pub(crate) fn start_recording(system: &dyn System, run: u32) -> Result<(), Box<dyn Error>> {
+    let path = format!("{}/state.toml", directory(system)?);
+    let ledger = Ledger::load_from(&path)?;
+    *RECORDER.lock().expect("Failed to lock the recorder") = Some(Recorder {
+        path,
+        run,
+        step: None,
+        ledger,
+    });
+    Ok(())
+}
+
+/// Records the changes made from now on against the step.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// state::begin_step("nordvpn");
+/// ```
+pub(crate) fn begin_step(id: &str) {
+    if let Some(recorder) = RECORDER
+        .lock()
+        .expect("Failed to lock the recorder")
+        .as_mut()
+    {
+        recorder.step = Some(id.to_string());
+    }
+}
+
+/// Records the change against the current step, so that it can be reversed by
+/// `dotfiles uninstall <step>`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state::{self, Change};
+///
+/// state::record(Change::Service { name: "nordvpnd".to_string() });
+/// ```
+pub(crate) fn record(change: Change) {
+    let mut recorder = RECORDER.lock().expect("Failed to lock the recorder");
+    let Some(Recorder {
+        path,
+        run,
+        step: Some(step),
+        ledger,
+    }) = recorder.as_mut()
+    else {
+        return;
+    };
+    if ledger.record(step, *run, change) {
+        if let Err(e) = ledger.save(path) {
+            warn!("Unable to record the changes made by {}: {}", step, e);
+        }
+    }
+}
+
+/// Records the file against the current step, along with the SHA-256 of its contents.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// state::record_file("/usr/share/applications/sweethome3d.desktop");
+/// ```
+pub(crate) fn record_file(path: &str) {
+    record(Change::File {
+        path: path.to_string(),
+        sha256: hash_file(path).ok(),
+    });
+}
+
+/// Returns the SHA-256 of the file's contents.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// let sha256 = state::hash_file("/etc/pacman.conf")?;
+/// ```
+pub(crate) fn hash_file(path: &str) -> Result<String, Box<dyn Error>> {
+    Ok(format!("{:x}", Sha256::digest(fs::read(path)?)))
+}
+
+/// Records the packages against the current step.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// state::record_packages(&["nordvpn-bin"]);
+/// ```
+pub(crate) fn record_packages(names: &[&str]) {
+    for name in names {
+        record(Change::Package {
+            name: name.to_string(),
+        });
+    }
+}
+
+/// Reverses everything the step recorded, most recent first, removing packages with the
+/// system's package manager.
+///
+/// Anything another recorded step also made, such as a package they share, is kept for that step.
+/// Changes that couldn't be reversed stay recorded, so the uninstall can be retried. A dry run
+/// only lists what would be removed, leaving the system and the record untouched.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use state;
+///
+/// state::uninstall(&system, "nordvpn", false)?;
+/// ```
+pub(crate) fn uninstall(
+    system: &dyn System,
+    id: &str,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    uninstall_from(system, &ledger_path(system), id, dry_run)
+}
+
+fn uninstall_from(
+    system: &dyn System,
+    path: &str,
+    id: &str,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut ledger = Ledger::load_from(path)?;
+    let Some(step) = ledger.steps.get(id) else {
+        return Err(Box::new(error::Error::new(&format!(
+            "Nothing has been recorded for {id}"
+        ))));
+    };
+    let mut changes = step.changes.clone();
+    while let Some(change) = changes.last() {
+        if let Some(other) = ledger.needed_by(id, change) {
+            info!("Keeping the {}, as {} also needs it", change, other);
+        } else if dry_run {
+            info!("Would remove the {}", change);
+        } else {
+            info!("Removing the {}", change);
+            if let Err(e) = system.revert(change) {
+                if let Some(step) = ledger.steps.get_mut(id) {
+                    step.changes = changes;
+                }
+                ledger.save(path)?;
+                return Err(e);
+            }
+        }
+        changes.pop();
+    }
+    if dry_run {
+        return Ok(());
+    }
+    ledger.steps.remove(id);
+    ledger.save(path)?;
+    info!("Uninstalled {}", id);
+    Ok(())
+}
+
+fn directory_path(system: &dyn System) -> String {
+    format!("{}/.local/state/dotfiles", system.get_home_dir())
+}
+
+fn ledger_path(system: &dyn System) -> String {
+    format!("{}/state.toml", directory_path(system))
+}
+
+fn next_run(last_run: Option<&str>) -> u32 {
+    last_run
+        .and_then(|run| run.trim().parse::<u32>().ok())
+        .map_or(1, |run| run + 1)
+}
+
+/// Hands the path back to the user running sudo, so the state isn't left owned by root.
+#[cfg(unix)]
+pub(crate) fn give_to_user(path: &str) -> Result<(), Box<dyn Error>> {
+    crate::unix::change_ownership(
+        path,
+        &crate::unix::get_user_id(),
+        &crate::unix::get_group_id(),
+    )?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn give_to_user(_path: &str) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::system::MockSystem;
+
+    #[test]
+    fn test_next_run_starts_at_one() {
+        assert_eq!(next_run(None), 1);
+    }
+
+    #[test]
+    fn test_next_run_increments_last_run() {
+        assert_eq!(next_run(Some("41\n")), 42);
+    }
+
+    #[test]
+    fn test_next_run_restarts_after_corrupt_last_run() {
+        assert_eq!(next_run(Some("forty-one")), 1);
+    }
+
+    #[test]
+    fn test_ledger_record_skips_repeated_changes() {
+        let mut ledger = Ledger::default();
+        let service = Change::Service {
+            name: "nordvpnd".to_string(),
+        };
+
+        assert!(ledger.record("nordvpn", 1, service.clone()));
+        assert!(!ledger.record("nordvpn", 2, service.clone()));
+        assert_eq!(ledger.steps["nordvpn"].run, 1);
+        assert_eq!(ledger.steps["nordvpn"].changes, vec![service]);
+    }
+
+    #[test]
+    fn test_ledger_round_trips_through_toml() {
+        let mut ledger = Ledger::default();
+        ledger.record(
+            "nordvpn",
+            3,
+            Change::Package {
+                name: "nordvpn-bin".to_string(),
+            },
+        );
+        ledger.record(
+            "nordvpn",
+            3,
+            Change::GroupMember {
+                group: "nordvpn".to_string(),
+                user: "me".to_string(),
+            },
+        );
+        ledger.record(
+            "sweet-home-3d",
+            4,
+            Change::File {
+                path: "/usr/share/applications/sweethome3d.desktop".to_string(),
+                sha256: Some("abc123".to_string()),
+            },
+        );
+
+        let contents = toml::to_string(&ledger).unwrap();
+
+        assert_eq!(toml::from_str::<Ledger>(&contents).unwrap(), ledger);
+    }
+
+    #[test]
+    fn test_ledger_needed_by_finds_other_steps_sharing_a_change() {
+        let mut ledger = Ledger::default();
+        let git = Change::Package {
+            name: "git".to_string(),
+        };
+        let kubectl = Change::Package {
+            name: "kubectl".to_string(),
+        };
+        ledger.record("development", 1, git.clone());
+        ledger.record("infrastructure", 2, git.clone());
+        ledger.record("infrastructure", 2, kubectl.clone());
+
+        assert_eq!(
+            ledger.needed_by("infrastructure", &git),
+            Some("development")
+        );
+        assert_eq!(
+            ledger.needed_by("development", &git),
+            Some("infrastructure")
+        );
+        assert_eq!(ledger.needed_by("infrastructure", &kubectl), None);
+    }
+
+    #[test]
+    fn test_uninstall_dry_run_leaves_everything_in_place() {
+        let path = env::temp_dir()
+            .join(format!("{}.toml", Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+        let mut ledger = Ledger::default();
+        ledger.record(
+            "sweet-home-3d",
+            1,
+            Change::File {
+                path: "/usr/share/applications/sweethome3d.desktop".to_string(),
+                sha256: None,
+            },
+        );
+        let contents = toml::to_string(&ledger).unwrap();
+        fs::write(&path, &contents).unwrap();
+        // Any call to revert would fail the test, as it isn't expected.
+        let mock_system = MockSystem::new();
+
+        let result = uninstall_from(&mock_system, &path, "sweet-home-3d", true);
+
+        assert!(result.is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap(), contents);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,281 @@
+use log::{info, warn};
+use std::error::Error;
+use std::fmt;
+
+use crate::capture;
+use crate::config::Config;
+use crate::error;
+use crate::install;
+use crate::state::{self, Change, Ledger};
+use crate::system::System;
+
+/// How an item on the machine differs from the selected steps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Drift {
+    /// A selected step added it, but it's no longer there.
+    Missing,
+    /// A selected step wrote the file, but its contents have changed since.
+    Modified,
+    /// A step that is no longer selected added it, and it's still there.
+    Extra,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Drift::Missing => write!(f, "missing"),
+            Drift::Modified => write!(f, "modified"),
+            Drift::Extra => write!(f, "extra"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Item<'l> {
+    drift: Drift,
+    step: &'l str,
+    description: String,
+}
+
+/// Reports how the machine has drifted from the steps selected by the config, checking the
+/// packages, files, groups and services each step recorded without changing anything.
+///
+/// Selected steps that haven't recorded anything, e.g. because they ran before changes were
+/// recorded, are checked by whether any of the packages they install across the backends is
+/// installed.
+///
+/// Returns an error when the machine has drifted, so the process exits non-zero.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use status;
+///
+/// status::report(&config, &system)?;
+/// ```
+pub(crate) fn report(config: &Config, system: &dyn System) -> Result<(), Box<dyn Error>> {
+    let ledger = Ledger::load(system)?;
+    let selected = install::selected_steps(config);
+    let items = compare(system, &ledger, &selected)?;
+    if items.is_empty() {
+        info!("The machine matches the selected steps");
+        return Ok(());
+    }
+    for item in &items {
+        info!("{:<8} {:<28} {}", item.drift, item.step, item.description);
+    }
+    let count = |drift: Drift| items.iter().filter(|item| item.drift == drift).count();
+    Err(Box::new(error::Error::new(&format!(
+        "The machine has drifted: {} missing, {} modified, {} extra",
+        count(Drift::Missing),
+        count(Drift::Modified),
+        count(Drift::Extra)
+    ))))
+}
+
+fn compare<'l>(
+    system: &dyn System,
+    ledger: &'l Ledger,
+    selected: &[&'l str],
+) -> Result<Vec<Item<'l>>, Box<dyn Error>> {
+    let mut items = Vec::new();
+    for (step, recorded) in &ledger.steps {
+        let is_selected = selected.contains(&step.as_str());
+        for change in &recorded.changes {
+            let is_applied = system.is_applied(change)?;
+            let drift = match (is_selected, is_applied) {
+                (true, false) => Some(Drift::Missing),
+                (true, true) if is_modified(change) => Some(Drift::Modified),
+                (false, true) => Some(Drift::Extra),
+                _ => None,
+            };
+            if let Some(drift) = drift {
+                items.push(Item {
+                    drift,
+                    step,
+                    description: change.to_string(),
+                });
+            }
+        }
+    }
+    for step in selected {
+        if ledger.steps.contains_key(*step) {
+            continue;
+        }
+        let Some(packages) = capture::catalogued(step) else {
+            warn!("{} hasn't recorded anything, so can't be checked", step);
+            continue;
+        };
+        if !is_any_installed(system, packages)? {
+            items.push(Item {
+                drift: Drift::Missing,
+                step,
+                description: format!("any of {}", packages.join(", ")),
+            });
+        }
+    }
+    Ok(items)
+}
+
+/// Returns whether any of the packages are installed, asking the system the same way the steps
+/// check before installing them.
+fn is_any_installed(system: &dyn System, packages: &[&str]) -> Result<bool, Box<dyn Error>> {
+    for package in packages {
+        let change = Change::Package {
+            name: package.to_string(),
+        };
+        if system.is_applied(&change)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn is_modified(change: &Change) -> bool {
+    match change {
+        Change::File {
+            path,
+            sha256: Some(sha256),
+        } => state::hash_file(path).ok().as_ref() != Some(sha256),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+    use std::fs;
+
+    use super::*;
+    use crate::state::Step;
+    use crate::system::MockSystem;
+
+    fn package(name: &str) -> Change {
+        Change::Package {
+            name: name.to_string(),
+        }
+    }
+
+    fn ledger(steps: Vec<(&str, Vec<Change>)>) -> Ledger {
+        let mut ledger = Ledger::default();
+        for (step, changes) in steps {
+            ledger
+                .steps
+                .insert(step.to_string(), Step { run: 1, changes });
+        }
+        ledger
+    }
+
+    #[test]
+    fn test_compare_reports_missing_and_extra() {
+        let ledger = ledger(vec![
+            ("nordvpn", vec![package("nordvpn-bin")]),
+            ("steam", vec![package("steam")]),
+            ("vim", vec![package("vim")]),
+        ]);
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("nordvpn-bin")))
+            .returning(|_| Ok(false));
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("steam")))
+            .returning(|_| Ok(true));
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("vim")))
+            .returning(|_| Ok(true));
+
+        let items = compare(&mock_system, &ledger, &["nordvpn", "vim"]).unwrap();
+
+        assert_eq!(
+            items,
+            vec![
+                Item {
+                    drift: Drift::Missing,
+                    step: "nordvpn",
+                    description: "package nordvpn-bin".to_string(),
+                },
+                Item {
+                    drift: Drift::Extra,
+                    step: "steam",
+                    description: "package steam".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compare_ignores_removed_extras() {
+        let ledger = ledger(vec![("steam", vec![package("steam")])]);
+        let mut mock_system = MockSystem::new();
+        mock_system.expect_is_applied().returning(|_| Ok(false));
+
+        assert!(compare(&mock_system, &ledger, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_compare_reports_modified_files() {
+        let path = std::env::temp_dir().join("dotfiles-status-test.desktop");
+        let path = path.to_string_lossy().to_string();
+        fs::write(&path, "[Desktop Entry]\n").unwrap();
+        let file = Change::File {
+            path: path.clone(),
+            sha256: Some(state::hash_file(&path).unwrap()),
+        };
+        let ledger = ledger(vec![("sweet-home-3d", vec![file.clone()])]);
+        let mut mock_system = MockSystem::new();
+        mock_system.expect_is_applied().returning(|_| Ok(true));
+
+        let unchanged = compare(&mock_system, &ledger, &["sweet-home-3d"]).unwrap();
+        fs::write(&path, "[Desktop Entry]\nName=Changed\n").unwrap();
+        let changed = compare(&mock_system, &ledger, &["sweet-home-3d"]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(unchanged.is_empty());
+        assert_eq!(
+            changed,
+            vec![Item {
+                drift: Drift::Modified,
+                step: "sweet-home-3d",
+                description: file.to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_compare_checks_unrecorded_steps_are_installed() {
+        let ledger = Ledger::default();
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("ark")))
+            .returning(|_| Ok(false));
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("file-roller")))
+            .returning(|_| Ok(true));
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("calibre")))
+            .returning(|_| Ok(false));
+        mock_system
+            .expect_is_applied()
+            .with(eq(package("com.calibre_ebook.calibre")))
+            .returning(|_| Ok(false));
+
+        let items = compare(&mock_system, &ledger, &["archiver", "calibre"]).unwrap();
+
+        assert_eq!(
+            items,
+            vec![Item {
+                drift: Drift::Missing,
+                step: "calibre",
+                description: "any of calibre, com.calibre_ebook.calibre".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use log::info;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::backup;
+use crate::config::Config;
+use crate::error;
+use crate::profile::{Dotfiles, LinkMode};
+use crate::state::{self, Change};
+use crate::system::{self, System};
+
+/// The directory holding the per-host packages, which isn't a package itself.
+static HOSTS: &str = "hosts";
+
+/// A file in the repository and where it goes in the home directory.
+#[derive(Debug, PartialEq)]
+struct Link {
+    source: PathBuf,
+    target: PathBuf,
+}
+
+/// What putting a file in place needs to do.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Create,
+    /// Backs up and replaces the file that's there.
+    Replace,
+    Unchanged,
+}
+
+/// Clones the user's dotfiles repository, or pulls it when it's already cloned, and puts its
+/// files in place in their home directory, stow-style.
+///
+/// Every file is checked before anything is changed, so a directory in the way of a file or two
+/// packages with the same file stop the step. Files already there are backed up and replaced,
+/// and everything put in place is recorded, so `dotfiles uninstall dotfiles` removes it.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use stow;
+///
+/// stow::apply(&config, &system, &profile.dotfiles)?;
+/// ```
+pub(crate) fn apply(
+    config: &Config,
+    system: &dyn System,
+    dotfiles: &Dotfiles,
+) -> Result<(), Box<dyn Error>> {
+    let Some(repository) = &dotfiles.repository else {
+        return Ok(());
+    };
+    let home = system.get_home_dir();
+    let directory = dotfiles
+        .directory
+        .clone()
+        .unwrap_or_else(|| format!("{home}/.dotfiles"));
+    let git = Change::Package {
+        name: "git".to_string(),
+    };
+    if !system.is_applied(&git)? {
+        system.install_application("git")?;
+    }
+    // Git runs as the user, so the clone is theirs, with each value its own argument, so none of
+    // them can be taken for an option or split up.
+    if Path::new(&directory).join(".git").exists() {
+        info!("Pulling {} in {}", repository, directory);
+        system.run("git", vec!["-C", &directory, "pull", "--ff-only"], false)?;
+    } else {
+        info!("Cloning {} into {}", repository, directory);
+        let mut args = vec!["clone"];
+        if let Some(branch) = &dotfiles.branch {
+            args.extend(["--branch", branch]);
+        }
+        args.extend(["--", repository, &directory]);
+        system.run("git", args, false)?;
+    }
+    if config.dry_run {
+        return Ok(());
+    }
+
+    let host = match &dotfiles.host {
+        Some(host) => host.clone(),
+        None => hostname()?,
+    };
+    let links = plan(
+        Path::new(&directory),
+        Path::new(&home),
+        &dotfiles.packages,
+        &host,
+    )?;
+    let actions = links
+        .iter()
+        .map(|link| action(link, dotfiles.mode))
+        .collect::<Result<Vec<Action>, Box<dyn Error>>>()?;
+    for (link, action) in links.iter().zip(actions) {
+        let target = link.target.to_string_lossy().to_string();
+        match action {
+            Action::Unchanged => continue,
+            Action::Replace => {
+                backup::back_up(system, &target)?;
+                fs::remove_file(&link.target)?;
+            }
+            Action::Create => create_parents(&link.target)?,
+        }
+        put_in_place(link, dotfiles.mode)?;
+        state::record_file(&target);
+        info!("Put {} in place", target);
+    }
+    Ok(())
+}
+
+fn hostname() -> Result<String, Box<dyn Error>> {
+    Ok(
+        system::run_command(&mut Command::new("hostname"), false, false)?
+            .trim()
+            .to_string(),
+    )
+}
+
+/// Returns where each file of the packages goes, with the host's package, when there is one,
+/// overriding the others.
+fn plan(
+    repository: &Path,
+    home: &Path,
+    packages: &[String],
+    host: &str,
+) -> Result<Vec<Link>, Box<dyn Error>> {
+    let packages = if packages.is_empty() {
+        every_package(repository)?
+    } else {
+        packages.to_vec()
+    };
+    let mut links: BTreeMap<PathBuf, (String, PathBuf)> = BTreeMap::new();
+    for package in &packages {
+        let package_directory = repository.join(package);
+        if !package_directory.is_dir() {
+            return Err(Box::new(error::Error::new(&format!(
+                "There's no package {package} in {}",
+                repository.display()
+            ))));
+        }
+        for source in files(&package_directory)? {
+            let target = home.join(source.strip_prefix(&package_directory)?);
+            if let Some((other, _)) = links.get(&target) {
+                return Err(Box::new(error::Error::new(&format!(
+                    "Both {other} and {package} have {}",
+                    target.display()
+                ))));
+            }
+            links.insert(target, (package.clone(), source));
+        }
+    }
+    let host_directory = repository.join(HOSTS).join(host);
+    if host_directory.is_dir() {
+        for source in files(&host_directory)? {
+            let target = home.join(source.strip_prefix(&host_directory)?);
+            links.insert(target, (host.to_string(), source));
+        }
+    }
+    Ok(links
+        .into_iter()
+        .map(|(target, (_, source))| Link { source, target })
+        .collect())
+}
+
+/// Returns the top level directories, leaving out `hosts` and hidden ones, e.g. `.git`.
+fn every_package(repository: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut packages = Vec::new();
+    for entry in fs::read_dir(repository)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && name != HOSTS && !name.starts_with('.') {
+            packages.push(name);
+        }
+    }
+    packages.sort();
+    Ok(packages)
+}
+
+fn files(directory: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            found.extend(files(&path)?);
+        } else {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+/// Returns what's needed to put the file in place, refusing to replace a directory.
+fn action(link: &Link, mode: LinkMode) -> Result<Action, Box<dyn Error>> {
+    let metadata = match fs::symlink_metadata(&link.target) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Action::Create),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if metadata.is_dir() {
+        return Err(Box::new(error::Error::new(&format!(
+            "{} is a directory, so {} can't go there",
+            link.target.display(),
+            link.source.display()
+        ))));
+    }
+    let unchanged = match mode {
+        LinkMode::Symlink => metadata.is_symlink() && fs::read_link(&link.target)? == link.source,
+        LinkMode::Copy => {
+            !metadata.is_symlink() && fs::read(&link.target)? == fs::read(&link.source)?
+        }
+    };
+    Ok(if unchanged {
+        Action::Unchanged
+    } else {
+        Action::Replace
+    })
+}
+
+/// Creates the directories the file goes in, handing any that are new to the user.
+fn create_parents(target: &Path) -> Result<(), Box<dyn Error>> {
+    let Some(parent) = target.parent() else {
+        return Ok(());
+    };
+    let first_missing = parent
+        .ancestors()
+        .take_while(|ancestor| !ancestor.exists())
+        .last()
+        .map(Path::to_path_buf);
+    fs::create_dir_all(parent)?;
+    if let Some(first_missing) = first_missing {
+        state::give_to_user(&first_missing.to_string_lossy())?;
+    }
+    Ok(())
+}
+
+fn put_in_place(link: &Link, mode: LinkMode) -> Result<(), Box<dyn Error>> {
+    match mode {
+        LinkMode::Symlink => symlink(&link.source, &link.target)?,
+        LinkMode::Copy => {
+            fs::copy(&link.source, &link.target)?;
+            state::give_to_user(&link.target.to_string_lossy())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink(source: &Path, target: &Path) -> Result<(), io::Error> {
+    std::os::unix::fs::symlink(source, target)
+}
+
+#[cfg(windows)]
+fn symlink(source: &Path, target: &Path) -> Result<(), io::Error> {
+    std::os::windows::fs::symlink_file(source, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse;
+    use crate::system::MockSystem;
+    use mockall::predicate::eq;
+    use std::env;
+    use uuid::Uuid;
+
+    /// Creates a repository and home directory for the test, with the files in the repository.
+    fn repository(name: &str, files: &[&str]) -> (PathBuf, PathBuf) {
+        let root = env::temp_dir().join(format!("dotfiles-stow-test-{name}"));
+        let _ = fs::remove_dir_all(&root);
+        let repository = root.join("repository");
+        for file in files {
+            let file = repository.join(file);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(&file, file.to_string_lossy().as_bytes()).unwrap();
+        }
+        fs::create_dir_all(repository.join(".git")).unwrap();
+        let home = root.join("home");
+        fs::create_dir_all(&home).unwrap();
+        (repository, home)
+    }
+
+    #[test]
+    fn test_plan_mirrors_every_package_into_home() {
+        let (repository, home) = repository(
+            "every-package",
+            &["zsh/.zshrc", "git/.config/git/config", "hosts/other/.zshrc"],
+        );
+
+        let links = plan(&repository, &home, &[], "workstation").unwrap();
+
+        assert_eq!(
+            links,
+            vec![
+                Link {
+                    source: repository.join("git/.config/git/config"),
+                    target: home.join(".config/git/config"),
+                },
+                Link {
+                    source: repository.join("zsh/.zshrc"),
+                    target: home.join(".zshrc"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_lets_the_host_override_packages() {
+        let (repository, home) = repository(
+            "host",
+            &["zsh/.zshrc", "git/.gitconfig", "hosts/workstation/.zshrc"],
+        );
+
+        let links = plan(&repository, &home, &["zsh".to_string()], "workstation").unwrap();
+
+        assert_eq!(
+            links,
+            vec![Link {
+                source: repository.join("hosts/workstation/.zshrc"),
+                target: home.join(".zshrc"),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_plan_refuses_packages_with_the_same_file() {
+        let (repository, home) = repository("same-file", &["zsh/.zshrc", "shell/.zshrc"]);
+
+        let error = plan(&repository, &home, &[], "workstation").unwrap_err();
+
+        assert!(error.to_string().contains("Both shell and zsh have"));
+        assert!(plan(&repository, &home, &["vim".to_string()], "workstation").is_err());
+    }
+
+    #[test]
+    fn test_action_detects_what_is_in_the_way() {
+        let (repository, home) = repository("action", &["zsh/.zshrc"]);
+        let link = Link {
+            source: repository.join("zsh/.zshrc"),
+            target: home.join(".zshrc"),
+        };
+
+        assert_eq!(action(&link, LinkMode::Symlink).unwrap(), Action::Create);
+        fs::write(&link.target, "hand edit").unwrap();
+        assert_eq!(action(&link, LinkMode::Symlink).unwrap(), Action::Replace);
+        assert_eq!(action(&link, LinkMode::Copy).unwrap(), Action::Replace);
+        fs::remove_file(&link.target).unwrap();
+        put_in_place(&link, LinkMode::Symlink).unwrap();
+        assert_eq!(action(&link, LinkMode::Symlink).unwrap(), Action::Unchanged);
+        assert_eq!(action(&link, LinkMode::Copy).unwrap(), Action::Replace);
+        fs::remove_file(&link.target).unwrap();
+        fs::create_dir(&link.target).unwrap();
+        assert!(action(&link, LinkMode::Symlink).is_err());
+    }
+
+    #[test]
+    fn test_apply_clones_with_each_value_as_its_own_argument() {
+        let directory = env::temp_dir()
+            .join(Uuid::new_v4().to_string())
+            .to_string_lossy()
+            .to_string();
+        let dotfiles = Dotfiles {
+            repository: Some("https://example.com/my dotfiles.git".to_string()),
+            branch: Some("main; rm -rf ~".to_string()),
+            directory: Some(directory.clone()),
+            ..Dotfiles::default()
+        };
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_get_home_dir()
+            .returning(|| "/home/user".to_string());
+        mock_system
+            .expect_is_applied()
+            .with(eq(Change::Package {
+                name: "git".to_string(),
+            }))
+            .returning(|_| Ok(true));
+        mock_system.expect_install_application().never();
+        mock_system
+            .expect_run()
+            .withf(move |program, args, super_user| {
+                program == "git"
+                    && *args
+                        == vec![
+                            "clone",
+                            "--branch",
+                            "main; rm -rf ~",
+                            "--",
+                            "https://example.com/my dotfiles.git",
+                            &directory,
+                        ]
+                    && !super_user
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(String::new()));
+
+        let config = parse(vec!["--dry-run".to_string()]);
+        assert!(apply(&config, &mock_system, &dotfiles).is_ok());
+    }
+}
@@ -1,17 +1,28 @@
+use crate::bundle::{self, Bundle};
+use crate::certificates::Certificate;
+use crate::config::Config;
+use crate::managed_block::{self, Outcome};
+use crate::profile::{Packages, Verification};
+use crate::state::{self, Change};
 #[cfg(target_os = "linux")]
 use crate::unix;
+use crate::{backup, download, error, verify};
 use async_trait::async_trait;
-use log::{debug, info};
+use log::{debug, info, warn};
 #[cfg(test)]
 use mockall::automock;
+#[cfg(test)]
+use std::cell::Cell;
 use std::error::Error;
 use std::ffi::OsStr;
 use std::fmt::Debug;
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::path::Path;
-use std::process::Command;
 use std::process::Stdio;
+use std::process::{Command, ExitStatus};
+#[cfg(not(test))]
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::{fs, io};
 #[cfg(any(target_os = "macos", target_os = "windows"))]
 use wgpu::{Adapter, Backends};
@@ -19,9 +30,33 @@ use wgpu::{Adapter, Backends};
 #[cfg(any(target_os = "macos", target_os = "windows"))]
 static BACKENDS: Backends = Backends::all();
 
+#[cfg(not(test))]
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+thread_local! {
+    // Tests run in parallel, so a test's dry run mustn't stop the others writing their files.
+    static DRY_RUN: Cell<bool> = const { Cell::new(false) };
+}
+
 #[async_trait]
 #[cfg_attr(test, automock)]
 pub(crate) trait System: Send + Sync + Debug {
+    /// Snapshots the filesystem before the run changes anything, labelled with the run id, warning
+    /// when snapshots aren't possible on this machine.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.create_snapshot(42)?;
+    /// ```
+    fn create_snapshot(&self, run: u32) -> Result<(), Box<dyn Error>>;
+
     /// Executes the given command. It will run it as a super user if `super_user` is `true`.
     ///
     /// The returned Result contains the output of the command.
@@ -40,6 +75,42 @@ pub(crate) trait System: Send + Sync + Debug {
 
     fn get_home_dir(&self) -> String;
 
+    /// Runs the read-only command as a super user, even on a dry run, returning its output
+    /// whatever its exit status, e.g. to check what's installed.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// let missing = system.query("pacman -T yay")?;
+    /// ```
+    fn query(&self, command: &str) -> Result<String, Box<dyn Error>>;
+
+    /// Runs the program with the arguments, each passed as is so they can hold spaces, as a super
+    /// user if `super_user` is `true`, failing when it exits unsuccessfully.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.run("git", vec!["clone", repository, "/home/user/My Dotfiles"], false)?;
+    /// ```
+    #[allow(clippy::needless_lifetimes)]
+    fn run<'a>(
+        &self,
+        program: &str,
+        args: Vec<&'a str>,
+        super_user: bool,
+    ) -> Result<String, Box<dyn Error>>;
+
     /// Installs the provided application.
     ///
     /// # Examples
@@ -93,6 +164,21 @@ pub(crate) trait System: Send + Sync + Debug {
 
     fn install_calibre(&self) -> Result<(), Box<dyn Error>>;
 
+    /// Adds the CA certificate to the system's trust store, along with any other stores the system
+    /// keeps separately, e.g. the NSS databases of Firefox and Chrome on Linux.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.install_certificate(&certificate)?;
+    /// ```
+    fn install_certificate(&self, certificate: &Certificate) -> Result<(), Box<dyn Error>>;
+
     async fn install_codecs(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_cplusplus(&self) -> Result<(), Box<dyn Error>>;
@@ -118,12 +204,29 @@ pub(crate) trait System: Send + Sync + Debug {
 
     fn install_exact_audio_copy(&self) -> Result<(), Box<dyn Error>>;
 
+    /// Installs the profile's extra packages, flatpaks, snaps and AUR packages, warning about any
+    /// the system can't install.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.install_extras(&profile.extras)?;
+    /// ```
+    fn install_extras(&self, extras: &Packages) -> Result<(), Box<dyn Error>>;
+
     async fn install_exercism(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_firefox(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_firmware_updater(&self) -> Result<(), Box<dyn Error>>;
 
+    fn install_fish(&self) -> Result<(), Box<dyn Error>>;
+
     fn install_git(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_gimp(&self) -> Result<(), Box<dyn Error>>;
@@ -134,7 +237,7 @@ pub(crate) trait System: Send + Sync + Debug {
 
     async fn install_google_chrome(&self) -> Result<(), Box<dyn Error>>;
 
-    fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_google_drive(&self) -> Result<(), Box<dyn Error>>;
 
@@ -168,7 +271,7 @@ pub(crate) trait System: Send + Sync + Debug {
         Ok(())
     }
 
-    fn install_groovy(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_groovy(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_handbrake(&self) -> Result<(), Box<dyn Error>>;
 
@@ -192,7 +295,7 @@ pub(crate) trait System: Send + Sync + Debug {
 
     fn install_office(&self) -> Result<(), Box<dyn Error>>;
 
-    fn install_openscad(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_openscad(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_lutris(&self) -> Result<(), Box<dyn Error>>;
 
@@ -202,7 +305,7 @@ pub(crate) trait System: Send + Sync + Debug {
 
     fn install_microcode(&self) -> Result<(), Box<dyn Error>>;
 
-    fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_mkvtoolnix(&self) -> Result<(), Box<dyn Error>>;
 
@@ -242,7 +345,7 @@ pub(crate) trait System: Send + Sync + Debug {
     fn install_slack(&self) -> Result<(), Box<dyn Error>>;
 
     #[expect(unused)]
-    fn install_spotify(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_spotify(&self) -> Result<(), Box<dyn Error>>;
 
     fn install_steam(&self) -> Result<(), Box<dyn Error>>;
 
@@ -254,7 +357,7 @@ pub(crate) trait System: Send + Sync + Debug {
 
     async fn install_tauon_music_box(&self) -> Result<(), Box<dyn Error>>;
 
-    fn install_terraform(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_terraform(&self) -> Result<(), Box<dyn Error>>;
 
     async fn install_themes(&self) -> Result<(), Box<dyn Error>>;
 
@@ -268,7 +371,7 @@ pub(crate) trait System: Send + Sync + Debug {
 
     fn install_vm_tools(&self) -> Result<(), Box<dyn Error>>;
 
-    fn install_vscode(&self) -> Result<(), Box<dyn Error>>;
+    async fn install_vscode(&self) -> Result<(), Box<dyn Error>>;
 
     async fn install_wifi(&self) -> Result<(), Box<dyn Error>>;
 
@@ -288,6 +391,81 @@ pub(crate) trait System: Send + Sync + Debug {
 
     async fn install_zsh(&self) -> Result<(), Box<dyn Error>>;
 
+    /// Ranks the package mirrors in the configured countries by latency and points the package
+    /// manager at the fastest, backing up the files it changes.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.rank_mirrors().await?;
+    /// ```
+    async fn rank_mirrors(&self) -> Result<(), Box<dyn Error>>;
+
+    /// Returns whether a change recorded by an earlier run is still in place, without changing
+    /// anything.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use state::Change;
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// let installed = system.is_applied(&Change::Package { name: "nordvpn-bin".to_string() })?;
+    /// ```
+    fn is_applied(&self, change: &Change) -> Result<bool, Box<dyn Error>>;
+
+    /// Returns the packages explicitly installed on the system, by where they were installed from.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// let installed = system.list_installed()?;
+    /// ```
+    fn list_installed(&self) -> Result<Packages, Box<dyn Error>>;
+
+    /// Reverses a change recorded by an earlier run, e.g. removing a package with the native
+    /// package manager.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use state::Change;
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.revert(&Change::Package { name: "nordvpn-bin".to_string() })?;
+    /// ```
+    fn revert(&self, change: &Change) -> Result<(), Box<dyn Error>>;
+
+    /// Lists the snapshots taken before each run, or restores the one taken before the run.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use system::System;
+    ///
+    /// let system: System = ...
+    /// system.rollback(Some(42))?;
+    /// ```
+    fn rollback(&self, run: Option<u32>) -> Result<(), Box<dyn Error>>;
+
     /// Sets the required global keyboard shortcuts that conflict with common IDE shortcuts.
     ///
     /// # Examples
@@ -379,31 +557,162 @@ pub(crate) trait System: Send + Sync + Debug {
     fn update_os_repo(&self) -> Result<(), Box<dyn Error>>;
 }
 
-/// Adds the content to the file, only if it doesn't already exist within the file.
+/// Makes the files dotfiles writes for the steps only be logged for the rest of the run, for
+/// dry runs and bundles, which only collect what an install needs.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use system;
+///
+/// system::set_dry_run(config.dry_run);
+/// ```
+pub(crate) fn set_dry_run(dry_run: bool) {
+    #[cfg(not(test))]
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+    #[cfg(test)]
+    DRY_RUN.set(dry_run);
+}
+
+/// Returns whether the files dotfiles writes for the steps are only logged.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use system;
+///
+/// if !system::is_dry_run() {
+///     fs::write("/etc/modules-load.d/sg.conf", "sg")?;
+/// }
+/// ```
+pub(crate) fn is_dry_run() -> bool {
+    #[cfg(not(test))]
+    return DRY_RUN.load(Ordering::Relaxed);
+    #[cfg(test)]
+    return DRY_RUN.get();
+}
+
+/// Opens the file to be overwritten, creating it if needed, or on a dry run, somewhere that
+/// discards what's written, so the step writes its file the same way either way. An existing
+/// file is backed up first.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use system;
+///
+/// let mut file = system::create_file(&system, "/etc/modules-load.d/sg.conf")?;
+/// write!(file, "sg")?;
+/// ```
+pub(crate) fn create_file(
+    system: &dyn System,
+    file: &str,
+) -> Result<Box<dyn Write>, Box<dyn Error>> {
+    backup::back_up(system, file)?;
+    if is_dry_run() {
+        info!("Would write {}", file);
+        return Ok(Box::new(io::sink()));
+    }
+    Ok(Box::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(file)?,
+    ))
+}
+
+/// Creates the directory and any missing parents, unless it's a dry run.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use system;
+///
+/// system::create_dir("/mnt/music")?;
+/// ```
+pub(crate) fn create_dir(directory: &str) -> Result<(), io::Error> {
+    if is_dry_run() {
+        if !Path::new(directory).is_dir() {
+            info!("Would create {}", directory);
+        }
+        return Ok(());
+    }
+    fs::create_dir_all(directory)
+}
+
+/// Adds the content to the file as the managed block with the id, creating the file and its
+/// directory if needed, replacing the block if the content has changed. A changed block is
+/// recorded against the current step, so uninstalling the step removes it.
 ///
 /// # Example
 ///
 /// ```no_run
 /// use system;
 ///
-/// system::add_to_file(".zshrc", "export MY_VAR=\"my value\""); // Will add to the file
-/// system::add_to_file(".zshrc", "export MY_VAR=\"my value\""); // Will not do anything
+/// system::add_to_file(".zshrc", "my-var", "export MY_VAR=\"my value\""); // Will add to the file
+/// system::add_to_file(".zshrc", "my-var", "export MY_VAR=\"my value\""); // Will not do anything
+/// system::add_to_file(".zshrc", "my-var", "export MY_VAR=\"new value\""); // Will replace it
 /// ```
-pub(crate) fn add_to_file(file: &str, content: &str) -> Result<(), io::Error> {
-    let path = Path::new(file);
-    let mut components = path.components();
-    components.next_back();
-    let directory = components.as_path();
-    fs::create_dir_all(directory)?;
-    if !file_contains(file, content) {
-        let mut actual_file = OpenOptions::new().create(true).append(true).open(file)?;
-        writeln!(actual_file, "{content}")?;
+pub(crate) fn add_to_file(file: &str, id: &str, content: &str) -> Result<Outcome, io::Error> {
+    if let Some(directory) = Path::new(file).parent() {
+        create_dir(&directory.to_string_lossy())?;
     }
-    Ok(())
+    let outcome = managed_block::write(file, id, content)?;
+    if outcome == Outcome::Changed {
+        state::record(Change::Block {
+            file: file.to_string(),
+            id: id.to_string(),
+        });
+    }
+    Ok(outcome)
+}
+
+/// Adds the line to the end of the file, without a managed block's markers, unless it's already
+/// there, creating the file and its directory if needed. An added line is recorded against the
+/// current step, so uninstalling the step removes it.
+///
+/// # Example
+///
+/// ```no_run
+/// use system;
+///
+/// system::add_line_to_file("/opt/eclipse/eclipse.ini", "-javaagent:/opt/eclipse/lombok.jar")?;
+/// ```
+pub(crate) fn add_line_to_file(file: &str, line: &str) -> Result<Outcome, io::Error> {
+    if let Some(directory) = Path::new(file).parent() {
+        create_dir(&directory.to_string_lossy())?;
+    }
+    let outcome = managed_block::write_line(file, line)?;
+    if outcome == Outcome::Changed {
+        state::record(Change::Line {
+            file: file.to_string(),
+            line: line.to_string(),
+        });
+    }
+    Ok(outcome)
 }
 
 /// Downloads the file found at the given URL and saves it to the specified location.
 ///
+/// Downloads are streamed into the cache in `~/.cache/dotfiles`, resuming any that were
+/// interrupted, and copied from there. A cached file is used as it is when it has the pinned
+/// SHA-256, or comes from a bundle, otherwise only once the server says it hasn't changed.
+///
+/// When the profile's `[downloads]` has a verification for the file's name, the file is checked
+/// against it and removed if it doesn't match. Either way, the outcome is logged. Plain `http://`
+/// URLs are refused unless they have a verification, as anyone on the way could change them.
+///
+/// On a dry run, a file meant for an absolute path is downloaded into the workspace instead.
+///
 /// # Examples
 ///
 /// Basic usage:
@@ -411,18 +720,87 @@ pub(crate) fn add_to_file(file: &str, content: &str) -> Result<(), io::Error> {
 /// ```no_run
 /// use system;
 ///
-/// system::download_file("https://some/amazing/file", "some_file").await?;
+/// system::download_file(&config, "https://some/amazing/file", "some_file").await?;
 /// ```
-pub(crate) async fn download_file(url: &str, downloaded_file: &str) -> Result<(), Box<dyn Error>> {
-    let response = reqwest::get(url).await?;
+pub(crate) async fn download_file(
+    config: &Config,
+    url: &str,
+    downloaded_file: &str,
+) -> Result<(), Box<dyn Error>> {
+    let name = Path::new(downloaded_file)
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or(downloaded_file);
+    let verification = config
+        .profile
+        .as_ref()
+        .and_then(|profile| profile.downloads.get(name));
+    let pinned_sha256 = match verification {
+        Some(Verification::Sha256(sha256)) => Some(sha256.as_str()),
+        _ => None,
+    };
+    if url.starts_with("http://") && verification.is_none() {
+        return Err(Box::new(error::Error::new(&format!(
+            "Refusing to download {url} over plain HTTP without a [downloads] verification for {name}"
+        ))));
+    }
 
-    let mut file = match File::create(downloaded_file) {
-        Err(why) => panic!("Couldn't create {downloaded_file}: {why}"),
-        Ok(file) => file,
+    // A dry run only writes to the run's workspace, so anything meant to go elsewhere is
+    // downloaded into it instead, where it can still be verified.
+    let downloaded_file = if is_dry_run() && Path::new(downloaded_file).is_absolute() {
+        info!("Would download {} to {}", url, downloaded_file);
+        name
+    } else {
+        downloaded_file
+    };
+
+    let bundle = bundle::of(config);
+    let cache = match bundle {
+        Some(bundle) => download::Cache::open_in(bundle.downloads())?,
+        None => download::Cache::open()?,
+    };
+    // Anything else could have changed since, so is checked with the server by fetching it.
+    let reusable = match (pinned_sha256, bundle) {
+        (None, None) => None,
+        _ => cache.lookup(url, pinned_sha256),
+    };
+    let cached = match (reusable, bundle) {
+        (Some(cached), _) => {
+            info!("Using the cached {} for {}", name, url);
+            cached
+        }
+        (None, Some(Bundle::InstallingFrom(directory))) => {
+            return Err(Box::new(error::Error::new(&format!(
+                "{url} isn't in the bundle {directory}"
+            ))));
+        }
+        (None, Some(Bundle::Creating(_))) => cache.fetch(url).await?,
+        (None, None) => {
+            let fetched = cache.fetch(url).await?;
+            cache.give_to_user()?;
+            fetched
+        }
     };
-    let content = response.bytes().await?;
-    file.write_all(&content)?;
+    fs::copy(&cached, downloaded_file)
+        .map_err(|e| error::Error::new(&format!("Couldn't create {downloaded_file}: {e}")))?;
     debug!("Downloaded {} to {}", url, downloaded_file);
+
+    match verification {
+        Some(verification) => match verify::verify(downloaded_file, verification).await {
+            Ok(checked) => info!("Verified {} from {}: {}", name, url, checked),
+            Err(e) => {
+                fs::remove_file(downloaded_file)?;
+                cache.evict(url, &cached)?;
+                return Err(e);
+            }
+        },
+        None => warn!(
+            "Downloaded {} from {} without verifying it, it has sha256 {}",
+            name,
+            url,
+            state::hash_file(downloaded_file)?
+        ),
+    }
     Ok(())
 }
 
@@ -445,6 +823,14 @@ pub(crate) fn extract_zip(
     target_dir: &Path,
     _remove_top_level: bool,
 ) -> Result<(), Box<dyn Error>> {
+    if is_dry_run() {
+        info!(
+            "Would extract {} into {}",
+            zip_file.display(),
+            target_dir.display()
+        );
+        return Ok(());
+    }
     if !target_dir.exists() {
         fs::create_dir_all(target_dir)?;
     }
@@ -609,6 +995,42 @@ pub(crate) fn run_command(
     print_output: bool,
     dry_run: bool,
 ) -> Result<String, Box<dyn Error>> {
+    let (output, _) = run_command_with_status(command, print_output, dry_run)?;
+    Ok(output)
+}
+
+/// Runs the Command like [run_command], but fails with its output when it exits unsuccessfully.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use system;
+///
+/// let mut command = Command::new("tar").args(vec!["-xzf", "snapshot.tar.gz"]);
+/// system::run_checked_command(command, true, false)?;
+/// ```
+pub(crate) fn run_checked_command(
+    command: &mut Command,
+    print_output: bool,
+    dry_run: bool,
+) -> Result<String, Box<dyn Error>> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let (output, status) = run_command_with_status(command, print_output, dry_run)?;
+    if !status.success() {
+        return Err(Box::new(error::Error::new(&format!(
+            "{program} failed with {status}: {output}"
+        ))));
+    }
+    Ok(output)
+}
+
+fn run_command_with_status(
+    command: &mut Command,
+    print_output: bool,
+    dry_run: bool,
+) -> Result<(String, ExitStatus), Box<dyn Error>> {
     let mut dry_run_command = Command::new("echo");
     let actual_command = if dry_run {
         let args: Vec<&OsStr> = command.get_args().collect();
@@ -657,9 +1079,9 @@ pub(crate) fn run_command(
         }
     }
 
-    child.wait()?;
+    let status = child.wait()?;
     let string_output = output.join("\n");
-    Ok(string_output)
+    Ok((string_output, status))
 }
 
 /// Downloads and configures the codecs.
@@ -671,13 +1093,17 @@ pub(crate) fn run_command(
 /// ```no_run
 /// use system;
 ///
-/// system::setup_codecs();
+/// system::setup_codecs(&system, &config);
 /// ```
-pub(crate) async fn setup_codecs(system: &impl System) -> Result<(), Box<dyn Error>> {
+pub(crate) async fn setup_codecs(
+    system: &impl System,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
     debug!("Setting up the codecs");
-    fs::create_dir_all(format!("{}/.config/aacs", system.get_home_dir()).as_str())?;
+    create_dir(&format!("{}/.config/aacs", system.get_home_dir()))?;
     download_file(
-        "http://fvonline-db.bplaced.net/fv_download.php?lang=eng",
+        config,
+        "https://fvonline-db.bplaced.net/fv_download.php?lang=eng",
         "keydb_eng.zip",
     )
     .await?;
@@ -726,6 +1152,9 @@ pub(crate) fn setup_git_config(system: &impl System) -> Result<(), Box<dyn Error
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
+    use std::env;
+    use std::fs::File;
+    use uuid::Uuid;
 
     use super::*;
 
@@ -748,7 +1177,7 @@ mod tests {
         let create_result = File::create(path);
         create_result.expect("Failed to create file");
 
-        let result = add_to_file(path, "content");
+        let result = add_to_file(path, "test", "content");
         assert!(result.is_ok());
 
         let mut created_file = File::open(path).unwrap();
@@ -756,7 +1185,10 @@ mod tests {
         created_file
             .read_to_string(&mut file_contents)
             .expect("Failed to read content of file");
-        assert_eq!(file_contents, "content\n");
+        assert_eq!(
+            file_contents,
+            "# BEGIN dotfiles:test\ncontent\n# END dotfiles:test\n"
+        );
 
         let delete_result = fs::remove_file(path);
         delete_result.expect("Failed to delete file");
@@ -767,7 +1199,7 @@ mod tests {
     fn test_add_to_file_creates_file_if_not_exist() {
         let path = &"tests/created-file.txt";
 
-        let result = add_to_file(path, "content");
+        let result = add_to_file(path, "test", "content");
         assert!(result.is_ok());
 
         let mut created_file = File::open(path).unwrap();
@@ -775,7 +1207,10 @@ mod tests {
         created_file
             .read_to_string(&mut file_contents)
             .expect("Failed to read content of file");
-        assert_eq!(file_contents, "content\n");
+        assert_eq!(
+            file_contents,
+            "# BEGIN dotfiles:test\ncontent\n# END dotfiles:test\n"
+        );
 
         let delete_result = fs::remove_file(path);
         delete_result.expect("Failed to delete file");
@@ -788,9 +1223,9 @@ mod tests {
         let create_result = File::create(path);
         create_result.expect("Failed to create file");
 
-        let first_write_result = add_to_file(path, "content");
+        let first_write_result = add_to_file(path, "test", "content");
         assert!(first_write_result.is_ok());
-        let second_write_result = add_to_file(path, "content");
+        let second_write_result = add_to_file(path, "test", "content");
         assert!(second_write_result.is_ok());
 
         let mut created_file = File::open(path).unwrap();
@@ -798,12 +1233,48 @@ mod tests {
         created_file
             .read_to_string(&mut file_contents)
             .expect("Failed to read content of file");
-        assert_eq!(file_contents, "content\n");
+        assert_eq!(
+            file_contents,
+            "# BEGIN dotfiles:test\ncontent\n# END dotfiles:test\n"
+        );
 
         let delete_result = fs::remove_file(path);
         delete_result.expect("Failed to delete file");
     }
 
+    #[test]
+    fn test_add_to_file_writes_nothing_on_a_dry_run() {
+        set_dry_run(true);
+        let directory = env::temp_dir().join(Uuid::new_v4().to_string());
+        let path = directory.join(".zshrc");
+
+        let outcome = add_to_file(&path.to_string_lossy(), "test", "content").unwrap();
+
+        assert_eq!(outcome, Outcome::Changed);
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn test_create_file_discards_writes_on_a_dry_run() {
+        set_dry_run(true);
+        let path = env::temp_dir().join(Uuid::new_v4().to_string());
+
+        let mut file = create_file(&MockSystem::new(), &path.to_string_lossy()).unwrap();
+        writeln!(file, "export GTK_USE_PORTAL=1").unwrap();
+
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_extract_zip_extracts_nothing_on_a_dry_run() {
+        set_dry_run(true);
+        let target_dir = env::temp_dir().join(Uuid::new_v4().to_string());
+
+        extract_zip(Path::new("tests/test-file.zip"), &target_dir, false).unwrap();
+
+        assert!(!target_dir.exists());
+    }
+
     #[test]
     #[serial]
     fn test_extract_zip_produces_correct_directory() {
@@ -838,4 +1309,19 @@ mod tests {
         let delete_result = fs::remove_dir_all(target_dir);
         delete_result.expect("Failed to delete the target directory");
     }
+
+    #[test]
+    fn test_download_file_refuses_unverified_plain_http() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let config = crate::config::parse(vec![]);
+
+        let result = rt.block_on(download_file(
+            &config,
+            "http://example.com/get_helm.sh",
+            "tmp/get_helm.sh",
+        ));
+
+        assert!(result.is_err());
+        assert!(!Path::new("tmp/get_helm.sh").exists());
+    }
 }
@@ -0,0 +1,217 @@
+use log::info;
+use minijinja::{AutoEscape, Environment};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::backup;
+use crate::config::Config;
+use crate::error;
+use crate::profile::Templates;
+use crate::system::System;
+
+/// The templates the dotfiles are generated from, by name, unless the user overrides them.
+static DEFAULTS: [(&str, &str); 8] = [
+    ("bashrc", include_str!("../templates/bashrc")),
+    ("config.fish", include_str!("../templates/config.fish")),
+    ("convert_audio", include_str!("../templates/convert_audio")),
+    ("convert_video", include_str!("../templates/convert_video")),
+    (
+        "convert_videos",
+        include_str!("../templates/convert_videos"),
+    ),
+    ("gtk.css", include_str!("../templates/gtk.css")),
+    ("tmux.conf", include_str!("../templates/tmux.conf")),
+    ("zshrc", include_str!("../templates/zshrc")),
+];
+
+/// Renders the template with the profile's `[templates]` variables, using the user's template
+/// of the same name from their templates directory over the default, when there is one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use template;
+///
+/// let zshrc = template::render(&system, &config, "zshrc")?;
+/// ```
+pub(crate) fn render(
+    system: &dyn System,
+    config: &Config,
+    name: &str,
+) -> Result<String, Box<dyn Error>> {
+    let templates = templates(config);
+    let directory = templates
+        .directory
+        .clone()
+        .unwrap_or_else(|| format!("{}/.config/dotfiles/templates", system.get_home_dir()));
+    let override_file = Path::new(&directory).join(name);
+    let source = match fs::read_to_string(&override_file) {
+        Ok(source) => {
+            info!("Using the template {}", override_file.display());
+            source
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => default(name)?.to_string(),
+        Err(e) => {
+            return Err(Box::new(error::Error::new(&format!(
+                "Unable to read the template {}: {e}",
+                override_file.display()
+            ))))
+        }
+    };
+    render_source(name, &source, &templates)
+}
+
+/// Renders the template into the file, backing up what was there first.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use template;
+///
+/// template::write(&system, &config, "tmux.conf", &format!("{}/.tmux.conf", system.get_home_dir()))?;
+/// ```
+pub(crate) fn write(
+    system: &dyn System,
+    config: &Config,
+    name: &str,
+    file: &str,
+) -> Result<(), Box<dyn Error>> {
+    let contents = render(system, config, name)?;
+    if config.dry_run {
+        info!("Would write {} from the template {}", file, name);
+        return Ok(());
+    }
+    backup::back_up(system, file)?;
+    if let Some(directory) = Path::new(file).parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(file, contents)?;
+    Ok(())
+}
+
+fn templates(config: &Config) -> Templates {
+    config
+        .profile
+        .as_ref()
+        .map(|profile| profile.templates.clone())
+        .unwrap_or_default()
+}
+
+fn default(name: &str) -> Result<&'static str, Box<dyn Error>> {
+    DEFAULTS
+        .iter()
+        .find(|(default, _)| *default == name)
+        .map(|(_, source)| *source)
+        .ok_or_else(|| error::Error::new(&format!("There's no template named {name}")).into())
+}
+
+fn render_source(
+    name: &str,
+    source: &str,
+    templates: &Templates,
+) -> Result<String, Box<dyn Error>> {
+    let mut environment = Environment::new();
+    // The dotfiles are shell scripts and configs, so nothing is escaped, and they're written
+    // exactly as the templates lay them out.
+    environment.set_auto_escape_callback(|_| AutoEscape::None);
+    environment.set_keep_trailing_newline(true);
+    environment.set_trim_blocks(true);
+    let rendered = environment
+        .template_from_named_str(name, source)
+        .and_then(|template| template.render(templates))
+        .map_err(|e| error::Error::new(&format!("Unable to render the template {name}: {e}")))?;
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_defaults_match_the_previous_dotfiles() {
+        let templates = Templates::default();
+
+        let zshrc = render_source("zshrc", default("zshrc").unwrap(), &templates).unwrap();
+        let tmux_conf =
+            render_source("tmux.conf", default("tmux.conf").unwrap(), &templates).unwrap();
+        let bashrc = render_source("bashrc", default("bashrc").unwrap(), &templates).unwrap();
+
+        assert!(zshrc.starts_with(
+            "export ZSH=$HOME/.oh-my-zsh\n\
+            ZSH_THEME=\"robbyrussell\"\n\
+            plugins=(common-aliases docker docker-compose git git-flow gradle jira kubectl mvn pip web-search)\n\
+            export PATH=\"/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin:${HOME}/bin:${HOME}/.local/bin\"\n"
+        ));
+        assert!(zshrc.ends_with("    source $HOME/.zshrc.custom\nfi\n\n"));
+        assert!(
+            tmux_conf.contains("set-option -g prefix C-a\nunbind C-a\nbind-key C-a send-prefix\n")
+        );
+        assert!(tmux_conf.contains("\"#{mouse_any_flag}\""));
+        assert_eq!(bashrc, "export PATH=$PATH:${HOME}/bin:${HOME}/.local/bin\n");
+    }
+
+    #[test]
+    fn test_render_uses_the_variables() {
+        let templates = Templates {
+            theme: "agnoster".to_string(),
+            plugins: vec!["git".to_string(), "kubectl".to_string()],
+            tmux_prefix: "C-b".to_string(),
+            path: Vec::new(),
+            ..Templates::default()
+        };
+
+        let zshrc = render_source("zshrc", default("zshrc").unwrap(), &templates).unwrap();
+        let tmux_conf =
+            render_source("tmux.conf", default("tmux.conf").unwrap(), &templates).unwrap();
+
+        assert!(zshrc.contains("ZSH_THEME=\"agnoster\"\nplugins=(git kubectl)\n"));
+        assert!(zshrc.contains("export PATH=\"/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin\"\n"));
+        assert!(tmux_conf.contains("set-option -g prefix C-b\n"));
+    }
+
+    #[test]
+    fn test_render_source_gives_overrides_the_extra_variables() {
+        let mut templates = Templates::default();
+        templates
+            .variables
+            .insert("editor".to_string(), "nvim".to_string());
+
+        let rendered = render_source(
+            "zshrc",
+            "export EDITOR={{ variables.editor }}\n{% if theme %}\nZSH_THEME={{ theme }}\n{% endif %}\n",
+            &templates,
+        )
+        .unwrap();
+
+        assert_eq!(rendered, "export EDITOR=nvim\nZSH_THEME=robbyrussell\n");
+    }
+
+    #[test]
+    fn test_render_source_reports_invalid_templates() {
+        let error = render_source("zshrc", "{% if theme %}", &Templates::default()).unwrap_err();
+
+        assert!(error.to_string().contains("zshrc"));
+        assert!(default("vimrc").is_err());
+    }
+
+    #[test]
+    fn test_render_fish_config_uses_fish_variables() {
+        let fish = render_source(
+            "config.fish",
+            default("config.fish").unwrap(),
+            &Templates::default(),
+        )
+        .unwrap();
+
+        assert!(fish.starts_with(
+            "fish_add_path --global --append $HOME/bin\n\
+            fish_add_path --global --append $HOME/.local/bin\n\n"
+        ));
+    }
+}
@@ -1,5 +1,5 @@
 use async_trait::async_trait;
-use log::info;
+use log::{debug, info, warn};
 use std::error::Error;
 use std::fs;
 use std::fs::OpenOptions;
@@ -7,9 +7,18 @@ use std::io::Write;
 use std::path::Path;
 use uuid::Uuid;
 
+use crate::bundle::{self, Bundle};
+use crate::certificates::Certificate;
 use crate::config::Config;
+use crate::linux::flatpak::{self, Installation, Override};
+use crate::profile::Packages;
+use crate::state::{self, Change};
 use crate::system::System;
-use crate::{linux, system, unix};
+use crate::{linux, managed_block, release, system, unix};
+use repository::{Repository, Suite};
+
+mod mirror;
+mod repository;
 
 #[derive(Debug)]
 pub(crate) struct Ubuntu<'s> {
@@ -21,41 +30,27 @@ impl<'s> Ubuntu<'s> {
         Ubuntu { config }
     }
 
-    fn add_apt_key(&self, url: &str) -> Result<(), Box<dyn Error>> {
-        self.execute(&format!("apt-key adv --fetch-keys {url}"), true)?;
-        Ok(())
-    }
-
-    fn add_apt_repo(&self, file_name: &str, urls: Vec<&str>) -> Result<(), std::io::Error> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(format!("/etc/apt/sources.list.d/{file_name}.list"))?;
-        for url in urls {
-            writeln!(file, "{url}")?;
-        }
-        Ok(())
-    }
-
     fn add_ppa(&self, ppa: &str) -> Result<(), Box<dyn Error>> {
         self.execute(&format!("add-apt-repository -y ppa:{ppa}"), true)?;
         Ok(())
     }
 
     fn enable_kernel_module(&self, module: &str) -> Result<(), Box<dyn Error>> {
-        linux::add_kernel_module(module)?;
+        linux::add_kernel_module(self, module)?;
         self.execute("update-initramfs -u -k all", true)?;
         Ok(())
     }
 
     fn enable_service(&self, service: &str) -> Result<String, Box<dyn Error>> {
-        self.execute(&format!("systemctl enable service {service}"), true)
-    }
-
-    fn flatpak_install_application(&self, application: &str) -> Result<(), Box<dyn Error>> {
-        self.execute(&format!("flatpak install flathub {application}"), true)?;
-        Ok(())
+        if linux::is_service_enabled(service)? {
+            debug!("{} is already enabled.", service);
+            return Ok(String::new());
+        }
+        let output = self.execute(&format!("systemctl enable service {service}"), true)?;
+        state::record(Change::Service {
+            name: service.to_string(),
+        });
+        Ok(output)
     }
 
     fn install_hunspell(&self) -> Result<(), Box<dyn Error>> {
@@ -69,6 +64,10 @@ impl<'s> Ubuntu<'s> {
     }
 
     fn is_installed(&self, app: &str) -> Result<bool, Box<dyn Error>> {
+        if let Some(Bundle::Creating(_)) = bundle::of(self.config) {
+            // Everything gets fetched into the bundle, whatever this machine already has.
+            return Ok(false);
+        }
         let dpkg_output = unix::execute(&format!("dpkg -l {app}"), true, false, false)?;
         if !dpkg_output.starts_with("dpkg-query: no packages found matching") {
             return Ok(true);
@@ -99,6 +98,34 @@ impl<'s> Ubuntu<'s> {
         } else {
             self.execute(&format!("snap install {application}"), true)?;
         }
+        state::record(Change::Snap {
+            name: application.to_string(),
+        });
+        Ok(())
+    }
+
+    /// Installs the downloaded .deb, recording the package in it so it can be removed by name.
+    fn install_deb(&self, file: &str) -> Result<(), Box<dyn Error>> {
+        self.execute(&format!("dpkg -i {file}"), true)?;
+        self.record_packages(&[file])
+    }
+
+    /// Records the packages, reading the name of the package out of any downloaded .deb.
+    fn record_packages(&self, applications: &[&str]) -> Result<(), Box<dyn Error>> {
+        let mut packages = Vec::new();
+        for application in applications {
+            if !application.contains('/') {
+                packages.push(application.to_string());
+                continue;
+            }
+            let output = self.query(&format!("dpkg-deb --field {application} Package"))?;
+            let package = output.trim();
+            // Nothing is downloaded on a dry run, so there's no package to read.
+            if !package.is_empty() && !package.contains(char::is_whitespace) {
+                packages.push(package.to_string());
+            }
+        }
+        state::record_packages(&packages.iter().map(String::as_str).collect::<Vec<&str>>());
         Ok(())
     }
 
@@ -118,6 +145,10 @@ impl<'s> Ubuntu<'s> {
 
 #[async_trait]
 impl<'s> System for Ubuntu<'s> {
+    fn create_snapshot(&self, run: u32) -> Result<(), Box<dyn Error>> {
+        linux::snapshot::create(self, run)
+    }
+
     fn execute(&self, command: &str, super_user: bool) -> Result<String, Box<dyn Error>> {
         unix::execute(command, super_user, true, self.config.dry_run)
     }
@@ -126,11 +157,48 @@ impl<'s> System for Ubuntu<'s> {
         linux::get_home_dir()
     }
 
+    fn query(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        unix::execute(command, true, false, false)
+    }
+
+    fn run(
+        &self,
+        program: &str,
+        args: Vec<&str>,
+        super_user: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        unix::run(program, &args, super_user, self.config.dry_run)
+    }
+
     fn install_applications(&self, application: Vec<&str>) -> Result<String, Box<dyn Error>> {
-        self.execute(
-            &format!("apt-get install -y {}", application.join(" ")),
-            true,
-        )
+        let packages = application.join(" ");
+        let output = match bundle::of(self.config) {
+            Some(bundle @ Bundle::Creating(_)) => {
+                // An empty status makes apt download the dependencies that are already installed
+                // here too, as the machines installing from the bundle may not have them.
+                let status = tempfile::NamedTempFile::new()?;
+                unix::execute(
+                    &format!(
+                        "apt-get install -y --download-only -o Dir::Cache::archives={} -o Dir::State::status={} {packages}",
+                        bundle.packages(),
+                        status.path().display()
+                    ),
+                    true,
+                    true,
+                    false,
+                )?
+            }
+            Some(bundle @ Bundle::InstallingFrom(_)) => self.execute(
+                &format!(
+                    "apt-get install -y --no-download -o Dir::Cache::archives={} {packages}",
+                    bundle.packages()
+                ),
+                true,
+            )?,
+            None => self.execute(&format!("apt-get install -y {packages}"), true)?,
+        };
+        self.record_packages(&application)?;
+        Ok(output)
     }
 
     fn install_affinity_suite(&self) -> Result<(), Box<dyn Error>> {
@@ -157,6 +225,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_audacity(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.audacityteam.Audacity")? {
+            return Ok(());
+        }
         if !self.is_installed("audacity")? {
             self.install_application("audacity")?;
         }
@@ -172,15 +243,21 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_bambu_studio(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.bambulab.BambuStudio")? {
+            return Ok(());
+        }
         todo!("Implement this");
     }
 
     fn install_bash(&self) -> Result<(), Box<dyn Error>> {
-        unix::setup_bash(self)?;
+        unix::setup_bash(self, self.config)?;
         Ok(())
     }
 
     fn install_blender(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.blender.Blender")? {
+            return Ok(());
+        }
         if !self.is_installed("blender")? {
             self.install_application("blender")?;
         }
@@ -201,12 +278,31 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_calibre(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.calibre_ebook.calibre")? {
+            return Ok(());
+        }
         if !self.is_installed("calibre")? {
             self.install_application("calibre")?;
         }
         Ok(())
     }
 
+    fn install_certificate(&self, certificate: &Certificate) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("libnss3-tools")? {
+            self.install_application("libnss3-tools")?;
+        }
+        self.execute(
+            &format!(
+                "cp {} /usr/local/share/ca-certificates/{}.crt",
+                certificate.file, certificate.name
+            ),
+            true,
+        )?;
+        self.execute("update-ca-certificates", true)?;
+        linux::add_certificate_to_nss(self, certificate)?;
+        Ok(())
+    }
+
     async fn install_codecs(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("libdvd-pkg")? {
             self.install_application("libdvd-pkg")?;
@@ -220,7 +316,7 @@ impl<'s> System for Ubuntu<'s> {
         if !self.is_installed("libbluray1")? {
             self.install_application("libbluray1")?;
         }
-        system::setup_codecs(self).await?;
+        system::setup_codecs(self, self.config).await?;
         let user_id = unix::get_user_id();
         let group_id = unix::get_group_id();
         unix::recursively_chown(
@@ -245,6 +341,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     async fn install_cryptomator(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.cryptomator.Cryptomator")? {
+            return Ok(());
+        }
         if !self.is_installed("cryptomator")? {
             self.add_ppa("sebastian-stenzel/cryptomator")?;
             self.update_os_repo()?;
@@ -264,11 +363,14 @@ impl<'s> System for Ubuntu<'s> {
         if !self.is_installed("davinci-resolve-studio")? {
             self.install_application("davinci-resolve-studio")?;
         }
-        linux::setup_davinci_resolve(self)?;
+        linux::setup_davinci_resolve(self, self.config)?;
         Ok(())
     }
 
     fn install_discord(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.discordapp.Discord")? {
+            return Ok(());
+        }
         if !self.is_installed("discord")? {
             self.snap_install_application("discord", false)?;
         }
@@ -277,7 +379,7 @@ impl<'s> System for Ubuntu<'s> {
 
     fn install_disk_usage_analyser(&self) -> Result<(), Box<dyn Error>> {
         if self.config.gnome && !self.is_installed("baobab")? {
-            self.install_application("baobab ")?;
+            self.install_application("baobab")?;
         }
         if self.config.kde && !self.is_installed("filelight")? {
             self.install_application("filelight")?;
@@ -310,17 +412,19 @@ impl<'s> System for Ubuntu<'s> {
         if !self.is_installed("eclipse")? {
             self.snap_install_application("eclipse", true)?;
         }
-        if Path::new("/opt/eclipse").exists() {
-            fs::create_dir_all("/opt/eclipse")?;
-        }
+        system::create_dir("/opt/eclipse")?;
 
         system::download_file(
+            self.config,
             "https://projectlombok.org/downloads/lombok.jar",
             "/opt/eclipse/lombok.jar",
         )
         .await?;
 
-        system::add_to_file(
+        // Every line of eclipse.ini is an argument, so the markers earlier versions wrote around
+        // the agent are taken off.
+        managed_block::remove("/opt/eclipse/eclipse.ini", "lombok")?;
+        system::add_line_to_file(
             "/opt/eclipse/eclipse.ini",
             "-javaagent:/opt/eclipse/lombok.jar",
         )?;
@@ -328,19 +432,13 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     async fn install_epic_games(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.heroicgameslauncher.hgl")? {
+            return Ok(());
+        }
         if !self.is_installed("heroic")? {
-            let heroic_version = "2.18.0";
-            system::download_file(
-                &format!("https://github.com/Heroic-Games-Launcher/HeroicGamesLauncher/releases/download/v{heroic_version}/Heroic-{heroic_version}-linux-amd64.deb"),
-                "heroic-launcher.deb",
-            )
-            .await?;
-            unix::execute(
-                "dpkg -i heroic-launcher.deb",
-                true,
-                false,
-                self.config.dry_run,
-            )?;
+            let heroic = release::resolve(self.config, &release::HEROIC_DEB).await?;
+            system::download_file(self.config, &heroic.url, "heroic-launcher.deb").await?;
+            self.install_deb("heroic-launcher.deb")?;
         }
         Ok(())
     }
@@ -352,9 +450,30 @@ impl<'s> System for Ubuntu<'s> {
         Ok(())
     }
 
+    fn install_extras(&self, extras: &Packages) -> Result<(), Box<dyn Error>> {
+        if !extras.packages.is_empty() {
+            self.install_applications(extras.packages.iter().map(String::as_str).collect())?;
+        }
+        for snap in &extras.snaps {
+            self.snap_install_application(snap, false)?;
+        }
+        for snap in &extras.classic_snaps {
+            self.snap_install_application(snap, true)?;
+        }
+        linux::install_flatpak_extras(self, self.config, extras)?;
+        if !extras.aur.is_empty() {
+            warn!(
+                "Not installing the AUR packages {}, as the AUR isn't supported on Ubuntu",
+                extras.aur.join(", ")
+            );
+        }
+        Ok(())
+    }
+
     async fn install_exercism(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("exercism")? {
-            system::download_file("https://github.com/exercism/cli/releases/download/v3.1.0/exercism-3.1.0-linux-x86_64.tar.gz", "exercism.tar.gz").await?;
+            let exercism = release::resolve(self.config, &release::EXERCISM).await?;
+            system::download_file(self.config, &exercism.url, "exercism.tar.gz").await?;
             let exercism_path = format!("{}/bin/exercism", self.get_home_dir());
             linux::untar_rename_root("exercism.tar.gz", &exercism_path)?;
             let user_id = unix::get_user_id();
@@ -362,18 +481,30 @@ impl<'s> System for Ubuntu<'s> {
             unix::recursively_chown("exercism", &user_id, &group_id)?;
             let exercism_bin_path = format!("{exercism_path}/exercism");
             unix::recursively_chmod(&exercism_bin_path, &0o755, &0o755)?;
-            unix::add_to_path(self, ".zshrc", &exercism_bin_path)?;
-            unix::add_to_path(self, ".bashrc", &exercism_bin_path)?;
+            unix::add_to_path(self, &exercism_bin_path)?;
             fs::remove_file("exercism.tar.gz")?;
         }
         Ok(())
     }
 
     fn install_firefox(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.mozilla.firefox")? {
+            flatpak::override_application(
+                self,
+                Installation::from(self.config),
+                "org.mozilla.firefox",
+                vec![
+                    Override::Socket("wayland".to_string()),
+                    Override::Environment("MOZ_ENABLE_WAYLAND".to_string(), "1".to_string()),
+                ],
+            )?;
+            return Ok(());
+        }
         if !self.is_installed("firefox")? {
             self.install_application("firefox")?;
             system::add_to_file(
                 &format!("{}/.config/environment.d/envvars.conf", self.get_home_dir()),
+                "firefox-wayland",
                 "MOZ_ENABLE_WAYLAND=1",
             )?;
         }
@@ -387,6 +518,13 @@ impl<'s> System for Ubuntu<'s> {
         Ok(())
     }
 
+    fn install_fish(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_installed("fish")? {
+            self.install_application("fish")?;
+        }
+        unix::setup_fish(self, self.config)
+    }
+
     fn install_git(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("git")? {
             self.install_application("git")?;
@@ -396,6 +534,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_gimp(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.gimp.GIMP")? {
+            return Ok(());
+        }
         if !self.is_installed("gimp")? {
             self.install_application("gimp")?;
         }
@@ -404,11 +545,8 @@ impl<'s> System for Ubuntu<'s> {
 
     async fn install_godot(&self) -> Result<(), Box<dyn Error>> {
         if !Path::new("/opt/godot-mono").exists() {
-            system::download_file(
-                "https://github.com/godotengine/godot/releases/download/4.2.2-stable/Godot_v4.2.2-stable_mono_linux_x86_64.zip",
-                "godot-mono.zip",
-            )
-            .await?;
+            let godot = release::resolve(self.config, &release::GODOT_MONO).await?;
+            system::download_file(self.config, &godot.url, "godot-mono.zip").await?;
             system::extract_zip(
                 Path::new("godot-mono.zip"),
                 Path::new("/opt/godot-mono"),
@@ -424,26 +562,39 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     async fn install_google_chrome(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.google.Chrome")? {
+            return Ok(());
+        }
         if !self.is_installed("google-chrome-stable")? {
             system::download_file(
+                self.config,
                 "https://dl.google.com/linux/direct/google-chrome-stable_current_amd64.deb",
                 "google-chrome.deb",
             )
             .await?;
-            self.execute("dpkg -i google-chrome.deb", true)?;
+            self.install_deb("google-chrome.deb")?;
             fs::remove_file("google-chrome.deb")?;
             info!("To enable screen sharing, you will need to enable `enable-webrtc-pipewire-catpturer` chrome://flags/#enable-webrtc-pipewire-capturer")
         }
         Ok(())
     }
 
-    fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("google-cloud-sdk")? {
-            self.add_apt_key("https://packages.cloud.google.com/apt/doc/apt-key.gpg")?;
-            self.add_apt_repo(
-                "google-cloud-sdk",
-                vec!["deb https://packages.cloud.google.com/apt cloud-sdk main"],
-            )?;
+            repository::add(
+                self,
+                self.config,
+                &Repository {
+                    name: "google-cloud-sdk",
+                    key_url: "https://packages.cloud.google.com/apt/doc/apt-key.gpg",
+                    uri: "https://packages.cloud.google.com/apt",
+                    suite: Suite::Named("cloud-sdk"),
+                    components: vec!["main"],
+                    architectures: None,
+                },
+            )
+            .await?;
+            self.update_os_repo()?;
             self.install_application("google-cloud-sdk")?;
         }
         Ok(())
@@ -468,6 +619,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_gramps(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.gramps_project.Gramps")? {
+            return Ok(());
+        }
         if !self.is_installed("gramps")? {
             self.install_application("gramps")?;
         }
@@ -477,7 +631,7 @@ impl<'s> System for Ubuntu<'s> {
         Ok(())
     }
 
-    fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("groovy")? {
             self.install_application("groovy")?;
         }
@@ -485,6 +639,15 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_handbrake(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "fr.handbrake.ghb")? {
+            flatpak::override_application(
+                self,
+                Installation::from(self.config),
+                "fr.handbrake.ghb",
+                vec![Override::Filesystem("xdg-videos".to_string())],
+            )?;
+            return Ok(());
+        }
         if !self.is_installed("handbrake")? {
             self.install_application("handbrake")?;
         }
@@ -492,6 +655,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_inkscape(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.inkscape.Inkscape")? {
+            return Ok(());
+        }
         if !self.is_installed("inkscape")? {
             self.install_application("inkscape")?;
         }
@@ -525,18 +691,15 @@ impl<'s> System for Ubuntu<'s> {
         }
         unix::set_java_home(
             self,
-            ".zshrc",
-            &format!("/usr/lib/jvm/java-24-openjdk-{}", std::env::consts::ARCH),
-        )?;
-        unix::set_java_home(
-            self,
-            ".bashrc",
             &format!("/usr/lib/jvm/java-24-openjdk-{}", std::env::consts::ARCH),
         )?;
         Ok(())
     }
 
     fn install_keepassxc(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.keepassxc.KeePassXC")? {
+            return Ok(());
+        }
         if !self.is_installed("keepassxc")? {
             self.add_ppa("phoerious/keepassxc")?;
             self.update_os_repo()?;
@@ -547,15 +710,16 @@ impl<'s> System for Ubuntu<'s> {
 
     async fn install_kubectl(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("kubectl")? {
-            let kubectl_version = reqwest::get(
+            // Downloaded like the binary itself, so a bundle keeps the version it was created with.
+            system::download_file(
+                self.config,
                 "https://storage.googleapis.com/kubernetes-release/release/stable.txt",
+                "kubectl-stable.txt",
             )
-            .await?
-            .text()
-            .await?
-            .replace('\n', "");
-            system::download_file(
-                &format!("https://storage.googleapis.com/kubernetes-release/release/{kubectl_version}/bin/linux/amd64/kubectl"), "/usr/local/bin/kubectl").await?;
+            .await?;
+            let kubectl_version = fs::read_to_string("kubectl-stable.txt")?.trim().to_string();
+            fs::remove_file("kubectl-stable.txt")?;
+            system::download_file(self.config, &format!("https://storage.googleapis.com/kubernetes-release/release/{kubectl_version}/bin/linux/amd64/kubectl"), "/usr/local/bin/kubectl").await?;
             unix::recursively_chmod("/usr/local/bin/kubectl", &0o755, &0o755)?;
         }
         Ok(())
@@ -563,7 +727,7 @@ impl<'s> System for Ubuntu<'s> {
 
     async fn install_helm(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("helm")? {
-            system::download_file("https://git.io/get_helm.sh", "get_helm.sh").await?;
+            system::download_file(self.config, "https://git.io/get_helm.sh", "get_helm.sh").await?;
             unix::recursively_chmod("get_helm.sh", &0o755, &0o755)?;
             self.execute("./get_helm.sh", true)?;
         }
@@ -579,6 +743,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_office(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.libreoffice.LibreOffice")? {
+            return Ok(());
+        }
         if !self.is_installed("libreoffice")? {
             self.install_application("libreoffice")?;
         }
@@ -589,19 +756,33 @@ impl<'s> System for Ubuntu<'s> {
         Ok(())
     }
 
-    fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.openscad.OpenSCAD")? {
+            return Ok(());
+        }
         // todo!("Need to prove this woks");
         if !self.is_installed("openscad-git")? {
-            self.add_apt_key("https://files.openscad.org/OBS-Repository-Key.pub")?;
-            self.add_apt_repo(
-                "openscad-nightly",
-                vec!["deb [arch=amd64] https://download.opensuse.org/repositories/home:/t-paul/xUbuntu_24.10/ stable main"],
-            )?;
+            repository::add(
+                self,
+                self.config,
+                &Repository {
+                    name: "openscad-nightly",
+                    key_url: "https://files.openscad.org/OBS-Repository-Key.pub",
+                    uri: "https://download.opensuse.org/repositories/home:/t-paul/xUbuntu_24.10",
+                    suite: Suite::Named("/"),
+                    components: vec![],
+                    architectures: Some("amd64"),
+                },
+            )
+            .await?;
         }
         Ok(())
     }
 
     fn install_lutris(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "net.lutris.Lutris")? {
+            return Ok(());
+        }
         if !self.is_installed("lutris")? {
             self.add_ppa("lutris-team/lutris")?;
             self.update_os_repo()?;
@@ -648,13 +829,24 @@ impl<'s> System for Ubuntu<'s> {
         Ok(())
     }
 
-    fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.microsoft.Edge")? {
+            return Ok(());
+        }
         if !self.is_installed("microsoft-edge-stable")? {
-            self.add_apt_key("https://packages.microsoft.com/keys/microsoft.asc")?;
-            self.add_apt_repo(
-                "microsoft-edge",
-                vec!["deb [arch=amd64] https://packages.microsoft.com/repos/edge stable main"],
-            )?;
+            repository::add(
+                self,
+                self.config,
+                &Repository {
+                    name: "microsoft-edge",
+                    key_url: "https://packages.microsoft.com/keys/microsoft.asc",
+                    uri: "https://packages.microsoft.com/repos/edge",
+                    suite: Suite::Named("stable"),
+                    components: vec!["main"],
+                    architectures: Some("amd64"),
+                },
+            )
+            .await?;
             self.update_os_repo()?;
             self.install_application("microsoft-edge-stable")?;
         }
@@ -662,6 +854,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_mkvtoolnix(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.bunkus.mkvtoolnix-gui")? {
+            return Ok(());
+        }
         if !self.is_installed("mkvtoolnix-gui")? {
             self.install_application("mkvtoolnix-gui")?;
         }
@@ -688,6 +883,7 @@ impl<'s> System for Ubuntu<'s> {
     async fn install_nodejs(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("nvm")? {
             system::download_file(
+                self.config,
                 "https://raw.githubusercontent.com/nvm-sh/nvm/master/install.sh",
                 "nvm-install.sh",
             )
@@ -702,8 +898,7 @@ impl<'s> System for Ubuntu<'s> {
 
     async fn install_nordvpn(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("nordvpn")? {
-            system::download_file(
-                "https://repo.nordvpn.com/deb/nordvpn/debian/pool/main/nordvpn-release_1.0.0_all.deb",
+            system::download_file(self.config, "https://repo.nordvpn.com/deb/nordvpn/debian/pool/main/nordvpn-release_1.0.0_all.deb",
                 "nordvpn.deb",
             ).await?;
             self.install_application("./nordvpn.deb")?;
@@ -743,6 +938,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_obs_studio(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.obsproject.Studio")? {
+            return Ok(());
+        }
         if !self.is_installed("obs-studio")? {
             self.add_ppa("obsproject/obs-studio")?;
             self.update_os_repo()?;
@@ -798,26 +996,20 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_retroarch(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.libretro.RetroArch")? {
+            return Ok(());
+        }
         Ok(())
     }
 
     async fn install_rust(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("rustup")? {
-            system::download_file("https://sh.rustup.rs", "rustup-install").await?;
+            system::download_file(self.config, "https://sh.rustup.rs", "rustup-install").await?;
             unix::recursively_chmod("rustup-install", &0o755, &0o755)?;
             self.execute("./rustup-install -y", false)?;
             fs::remove_file("rustup-install")?;
         }
-        unix::add_to_path(
-            self,
-            ".zshrc",
-            &format!("{}/.cargo/bin", self.get_home_dir()),
-        )?;
-        unix::add_to_path(
-            self,
-            ".bashrc",
-            &format!("{}/.cargo/bin", self.get_home_dir()),
-        )?;
+        unix::add_to_path(self, &format!("{}/.cargo/bin", self.get_home_dir()))?;
         self.execute("rustup default stable", true)?;
         Ok(())
     }
@@ -830,19 +1022,33 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_slack(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.slack.Slack")? {
+            return Ok(());
+        }
         if !self.is_installed("slack")? {
             self.snap_install_application("slack", true)?;
         }
         Ok(())
     }
 
-    fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.spotify.Client")? {
+            return Ok(());
+        }
         if !self.is_installed("spotify_client")? {
-            self.add_apt_key("https://download.spotify.com/debian/pubkey.gpg")?;
-            self.add_apt_repo(
-                "spotify",
-                vec!["deb http://repository.spotify.com stable non-free"],
-            )?;
+            repository::add(
+                self,
+                self.config,
+                &Repository {
+                    name: "spotify",
+                    key_url: "https://download.spotify.com/debian/pubkey_C85668DF69375001.gpg",
+                    uri: "https://repository.spotify.com",
+                    suite: Suite::Named("stable"),
+                    components: vec!["non-free"],
+                    architectures: Some("amd64"),
+                },
+            )
+            .await?;
             self.update_os_repo()?;
             self.install_application("spotify_client")?;
         }
@@ -850,6 +1056,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     fn install_steam(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "com.valvesoftware.Steam")? {
+            return Ok(());
+        }
         if !self.is_installed("steam-installer")? {
             self.install_application("steam-installer")?;
         }
@@ -862,11 +1071,7 @@ impl<'s> System for Ubuntu<'s> {
         }
 
         let sweet_home_3d_desktop = "/usr/share/applications/sweethome3d.desktop";
-        let mut sweet_home_3d_desktop_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(sweet_home_3d_desktop)?;
+        let mut sweet_home_3d_desktop_file = system::create_file(self, sweet_home_3d_desktop)?;
 
         let content = "[Desktop Entry]\n\
             Version=1.0\n\
@@ -880,6 +1085,7 @@ impl<'s> System for Ubuntu<'s> {
             StartupWMClass=com-eteks-sweethome3d-SweetHome3D\n\
             MimeType=application/x-sweethome3d\n";
         write!(sweet_home_3d_desktop_file, "{content}")?;
+        state::record_file(sweet_home_3d_desktop);
 
         Ok(())
     }
@@ -944,32 +1150,61 @@ impl<'s> System for Ubuntu<'s> {
         }
         if !self.is_installed("flatpak")? {
             self.install_application("flatpak")?;
-            self.execute("flatpak remote-add --if-not-exists flathub https://flathub.org/repo/flathub.flatpakrepo", false)?;
         }
+        flatpak::add_remote(
+            self,
+            Installation::from(self.config),
+            flatpak::FLATHUB,
+            flatpak::FLATHUB_URL,
+        )?;
         Ok(())
     }
 
     async fn install_tauon_music_box(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("com.github.taiko2k.tauonmb")? {
-            self.flatpak_install_application("com.github.taiko2k.tauonmb")?;
+            flatpak::install_application(
+                self,
+                Installation::from(self.config),
+                flatpak::FLATHUB,
+                "com.github.taiko2k.tauonmb",
+            )?;
         }
+        flatpak::override_application(
+            self,
+            Installation::from(self.config),
+            "com.github.taiko2k.tauonmb",
+            vec![Override::Filesystem("xdg-music".to_string())],
+        )?;
         Ok(())
     }
 
-    fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
+        // The repository used to be added as "terraform", with a list apt can't read.
+        if !self.config.dry_run {
+            repository::remove_legacy_file("terraform")?;
+        }
         if !self.is_installed("terraform")? {
-            self.add_apt_key("https://apt.releases.hashicorp.com/gpg")?;
-            self.add_apt_repo(
-                "terraform",
-                vec!["https://apt.releases.hashicorp.com jammy"],
-            )?;
+            repository::add(
+                self,
+                self.config,
+                &Repository {
+                    name: "hashicorp",
+                    key_url: "https://apt.releases.hashicorp.com/gpg",
+                    uri: "https://apt.releases.hashicorp.com",
+                    suite: Suite::Codename,
+                    components: vec!["main"],
+                    architectures: None,
+                },
+            )
+            .await?;
+            self.update_os_repo()?;
             self.install_application("terraform")?;
         }
         Ok(())
     }
 
     async fn install_themes(&self) -> Result<(), Box<dyn Error>> {
-        fs::create_dir_all(format!("{}/.themes", self.get_home_dir()))?;
+        system::create_dir(&format!("{}/.themes", self.get_home_dir()))?;
         let user_id = unix::get_user_id();
         let group_id = unix::get_group_id();
         unix::recursively_chown(
@@ -978,7 +1213,7 @@ impl<'s> System for Ubuntu<'s> {
             &group_id,
         )?;
         if self.config.gnome {
-            linux::gtk_theme(self)?;
+            linux::gtk_theme(self, self.config)?;
         }
         Ok(())
     }
@@ -997,7 +1232,7 @@ impl<'s> System for Ubuntu<'s> {
         if !self.is_installed("xclip")? {
             self.install_application("xclip")?;
         }
-        linux::setup_tmux(self)?;
+        linux::setup_tmux(self, self.config)?;
         Ok(())
     }
 
@@ -1009,6 +1244,9 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     async fn install_vlc(&self) -> Result<(), Box<dyn Error>> {
+        if flatpak::install_if_preferred(self, self.config, "org.videolan.VLC")? {
+            return Ok(());
+        }
         if !self.is_installed("vlc")? {
             self.install_application("vlc")?;
         }
@@ -1025,13 +1263,23 @@ impl<'s> System for Ubuntu<'s> {
         Ok(())
     }
 
-    fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
-        if !self.is_installed("code")? {
-            self.add_apt_key("https://packages.microsoft.com/keys/microsoft.asc")?;
-            self.add_apt_repo(
-                "vscode",
-                vec!["deb [arch=amd64] https://packages.microsoft.com/repos/code stable main"],
-            )?;
+    async fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
+        if !flatpak::install_if_preferred(self, self.config, "com.visualstudio.code")?
+            && !self.is_installed("code")?
+        {
+            repository::add(
+                self,
+                self.config,
+                &Repository {
+                    name: "vscode",
+                    key_url: "https://packages.microsoft.com/keys/microsoft.asc",
+                    uri: "https://packages.microsoft.com/repos/code",
+                    suite: Suite::Named("stable"),
+                    components: vec!["main"],
+                    architectures: None,
+                },
+            )
+            .await?;
             self.update_os_repo()?;
             self.install_application("code")?;
         }
@@ -1055,7 +1303,7 @@ impl<'s> System for Ubuntu<'s> {
                 self.install_application("gnome-tweaks")?;
             }
             if !self.is_installed("xdg-desktop-portal-gnome")? {
-                self.install_application("xdg-desktop-portal-gnome ")?;
+                self.install_application("xdg-desktop-portal-gnome")?;
             }
             if !self.is_installed("libcanberra0")? {
                 self.install_application("libcanberra0")?;
@@ -1129,14 +1377,13 @@ impl<'s> System for Ubuntu<'s> {
             // TODO: Implement install steps
             open::that("https://github.com/alex1701c/NordVPNKrunner")?;
             self.execute("dpkg-reconfigure sddm", true)?;
-            let mut file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(format!(
+            let mut file = system::create_file(
+                self,
+                &format!(
                     "{}/.config/plasma-workspace/env/gtk.sh",
                     self.get_home_dir()
-                ))?;
+                ),
+            )?;
             writeln!(file, "export GTK_USE_PORTAL=1")?;
         }
         self.enable_service("NetworkManager")?;
@@ -1158,14 +1405,10 @@ impl<'s> System for Ubuntu<'s> {
         if !self.is_installed("whipper")? {
             self.install_application("whipper")?;
         }
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(format!(
-                "{}/.config/whipper/whipper.conf",
-                self.get_home_dir()
-            ))?;
+        let mut file = system::create_file(
+            self,
+            &format!("{}/.config/whipper/whipper.conf", self.get_home_dir()),
+        )?;
         writeln!(file, "[whipper.cd.rip]")?;
         writeln!(file, "output_directory = ~/Music")?;
         writeln!(file, "track_template = %%A/%%d/%%t %%n")?;
@@ -1182,15 +1425,12 @@ impl<'s> System for Ubuntu<'s> {
     }
 
     async fn install_xbox_streaming(&self) -> Result<(), Box<dyn Error>> {
-        let version = "2.3.3";
-        system::download_file(
-            format!(
-                "https://github.com/unknownskl/greenlight/releases/download/v{0}/greenlight_{0}_amd64.deb",
-                version
-            ).as_str(),
-            "greenlight.deb",
-        ).await?;
-        self.execute("dpkg -i greenlight.deb", true)?;
+        if flatpak::install_if_preferred(self, self.config, "io.github.unknownskl.greenlight")? {
+            return Ok(());
+        }
+        let greenlight = release::resolve(self.config, &release::GREENLIGHT_DEB).await?;
+        system::download_file(self.config, &greenlight.url, "greenlight.deb").await?;
+        self.install_deb("greenlight.deb")?;
         fs::remove_file("greenlight.deb")?;
         Ok(())
     }
@@ -1203,10 +1443,69 @@ impl<'s> System for Ubuntu<'s> {
         if !self.is_installed("zsh")? {
             self.install_application("zsh")?;
         }
-        unix::setup_zsh(self, None).await?;
+        unix::setup_zsh(self, self.config, None).await?;
         Ok(())
     }
 
+    async fn rank_mirrors(&self) -> Result<(), Box<dyn Error>> {
+        mirror::rank(self, self.config).await
+    }
+
+    fn is_applied(&self, change: &Change) -> Result<bool, Box<dyn Error>> {
+        match change {
+            Change::Package { name } => self.is_installed(name),
+            Change::Repository { name } => Ok(repository::is_added(name)),
+            Change::Service { name } => linux::is_service_enabled(name),
+            Change::Snap { name } => Ok(self
+                .query(&format!("snap list {name}"))?
+                .lines()
+                .skip(1)
+                .any(|line| line.split_whitespace().next() == Some(name))),
+            _ => linux::is_applied(self, change),
+        }
+    }
+
+    fn list_installed(&self) -> Result<Packages, Box<dyn Error>> {
+        let packages = unix::execute("apt-mark showmanual", true, false, false)?
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect();
+        let (snaps, classic_snaps) = if Path::new("/usr/bin/snap").exists() {
+            parse_snaps(&unix::execute("snap list", true, false, false)?)
+        } else {
+            (Vec::new(), Vec::new())
+        };
+        Ok(Packages {
+            aur: Vec::new(),
+            classic_snaps,
+            flatpaks: flatpak::list_all_applications(self)?,
+            packages,
+            snaps,
+        })
+    }
+
+    fn revert(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        match change {
+            Change::Package { name } => {
+                self.execute(&format!("apt-get remove -y --autoremove {name}"), true)?;
+            }
+            Change::Repository { name } => repository::remove(self, name)?,
+            Change::Service { name } => {
+                self.execute(&format!("systemctl disable --now {name}"), true)?;
+            }
+            Change::Snap { name } => {
+                self.execute(&format!("snap remove {name}"), true)?;
+            }
+            _ => linux::revert(self, change)?,
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, run: Option<u32>) -> Result<(), Box<dyn Error>> {
+        linux::snapshot::rollback(self, run)
+    }
+
     fn set_development_shortcuts(&self) -> Result<(), Box<dyn Error>> {
         if self.config.gnome {
             linux::gnome_development_shortcuts(self)?;
@@ -1236,12 +1535,88 @@ impl<'s> System for Ubuntu<'s> {
 
     fn update_os(&self) -> Result<(), Box<dyn Error>> {
         self.update_os_repo()?;
-        self.execute("apt-get dist-upgrade -y", true)?;
+        match bundle::of(self.config) {
+            Some(bundle @ Bundle::Creating(_)) => unix::execute(
+                &format!(
+                    "apt-get dist-upgrade -y --download-only -o Dir::Cache::archives={}",
+                    bundle.packages()
+                ),
+                true,
+                true,
+                false,
+            )?,
+            Some(bundle @ Bundle::InstallingFrom(_)) => self.execute(
+                &format!(
+                    "apt-get dist-upgrade -y --no-download -o Dir::Cache::archives={}",
+                    bundle.packages()
+                ),
+                true,
+            )?,
+            None => self.execute("apt-get dist-upgrade -y", true)?,
+        };
+        if self.config.prefer_flatpak {
+            flatpak::update_applications(self, Installation::from(self.config))?;
+        }
         Ok(())
     }
 
     fn update_os_repo(&self) -> Result<(), Box<dyn Error>> {
-        self.execute("apt-get update", true)?;
+        match bundle::of(self.config) {
+            // The packages are fetched against the updated lists, so they're updated for real.
+            Some(Bundle::Creating(_)) => {
+                unix::execute("apt-get update", true, true, false)?;
+            }
+            Some(bundle @ Bundle::InstallingFrom(_)) => {
+                bundle.restore_package_lists("/var/lib/apt/lists")?;
+            }
+            None => {
+                self.execute("apt-get update", true)?;
+            }
+        }
         Ok(())
     }
 }
+
+/// Returns the names of the snaps in the `snap list` output, leaving out the bases and snapd which
+/// the other snaps depend on.
+/// Returns the snaps in `snap list`, apart from bases and snapd, split into those with strict and
+/// classic confinement.
+fn parse_snaps(output: &str) -> (Vec<String>, Vec<String>) {
+    let mut snaps = Vec::new();
+    let mut classic_snaps = Vec::new();
+    for line in output.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<&str>>();
+        let (Some(name), Some(notes)) = (fields.first(), fields.last()) else {
+            continue;
+        };
+        let notes = notes.split(',').collect::<Vec<&str>>();
+        if notes.contains(&"base") || notes.contains(&"snapd") {
+            continue;
+        }
+        if notes.contains(&"classic") {
+            classic_snaps.push(name.to_string());
+        } else {
+            snaps.push(name.to_string());
+        }
+    }
+    (snaps, classic_snaps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_snaps_splits_classic_snaps_and_skips_bases() {
+        let output = "Name      Version    Rev    Tracking       Publisher   Notes\n\
+            core22    20240111   1122   latest/stable  canonical✓  base\n\
+            snapd     2.61.3     21184  latest/stable  canonical✓  snapd\n\
+            spotify   1.2.31     75     latest/stable  spotify✓    -\n\
+            slack     4.36.140   132    latest/stable  slack✓      classic\n";
+
+        assert_eq!(
+            parse_snaps(output),
+            (vec!["spotify".to_string()], vec!["slack".to_string()])
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use super::repository;
+use crate::config::Config;
+use crate::linux::mirrors::{self, Mirror};
+use crate::state;
+use crate::system::System;
+use crate::{backup, error, network};
+
+/// The apt sources that can point at the Ubuntu archive, in deb822 and one-line formats.
+static SOURCES: [&str; 2] = [
+    "/etc/apt/sources.list.d/ubuntu.sources",
+    "/etc/apt/sources.list",
+];
+
+/// Ranks the Ubuntu archive mirrors in the configured countries and points the apt sources at
+/// the fastest, after backing up the existing sources.
+///
+/// Only the Ubuntu archive and the mirror an earlier run chose are replaced, so the security
+/// archive and third-party sources, like Docker's, are left alone. A dry run ranks the mirrors
+/// without backing up or writing anything.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use ubuntu::mirror;
+///
+/// mirror::rank(&system, &config).await?;
+/// ```
+pub(crate) async fn rank(system: &dyn System, config: &Config) -> Result<(), Box<dyn Error>> {
+    let codename = repository::codename()?;
+    let client = network::client()?;
+    let mut candidates: Vec<Mirror> = Vec::new();
+    for country in &config.mirror_countries {
+        let list = client
+            .get(format!("https://mirrors.ubuntu.com/{country}.txt"))
+            .send()
+            .await?
+            .text()
+            .await?;
+        for mirror in parse(&list, &codename) {
+            if !candidates.contains(&mirror) {
+                candidates.push(mirror);
+            }
+        }
+    }
+    let ranked = mirrors::rank(mirrors::measure(candidates).await?);
+    let Some(fastest) = ranked.first() else {
+        return Err(Box::from(error::Error::new(&format!(
+            "None of the mirrors in {} responded",
+            config.mirror_countries.join(", ")
+        ))));
+    };
+    let chosen = format!("{}/ubuntu-mirror", state::directory(system)?);
+    let previous = fs::read_to_string(&chosen).ok();
+    for sources in SOURCES {
+        if !Path::new(sources).exists() {
+            continue;
+        }
+        let contents = fs::read_to_string(sources)?;
+        let updated = use_mirror(&contents, &fastest.url, previous.as_deref().map(str::trim));
+        if updated != contents && config.dry_run {
+            info!("Would point {} at {}", sources, fastest.url);
+        } else if updated != contents {
+            backup::back_up(system, sources)?;
+            info!("Pointing {} at {}", sources, fastest.url);
+            fs::write(sources, updated)?;
+        }
+    }
+    if !config.dry_run {
+        fs::write(&chosen, format!("{}\n", fastest.url))?;
+        state::give_to_user(&chosen)?;
+    }
+    Ok(())
+}
+
+/// Returns the mirrors listed one per line, probing each with the release file for the codename.
+fn parse(list: &str, codename: &str) -> Vec<Mirror> {
+    list.lines()
+        .map(|line| line.trim())
+        .filter(|line| line.starts_with("http"))
+        .map(|line| {
+            let url = format!("{}/", line.trim_end_matches('/'));
+            Mirror {
+                probe: format!("{url}dists/{codename}/Release"),
+                url,
+            }
+        })
+        .collect()
+}
+
+/// Replaces the Ubuntu archive URIs, and the previously chosen mirror's, in the sources with the
+/// mirror, leaving the security archive and everything else untouched.
+fn use_mirror(contents: &str, mirror: &str, previous: Option<&str>) -> String {
+    let mut updated = contents
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            if !(trimmed.starts_with("URIs:") || trimmed.starts_with("deb")) {
+                return line.to_string();
+            }
+            line.split(' ')
+                .map(|part| {
+                    if is_archive(part, previous) {
+                        mirror
+                    } else {
+                        part
+                    }
+                })
+                .collect::<Vec<&str>>()
+                .join(" ")
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    if contents.ends_with('\n') {
+        updated.push('\n');
+    }
+    updated
+}
+
+/// Returns whether the URI is the Ubuntu archive, a country's archive, or the previous mirror.
+fn is_archive(uri: &str, previous: Option<&str>) -> bool {
+    let Some(rest) = uri
+        .strip_prefix("http://")
+        .or_else(|| uri.strip_prefix("https://"))
+    else {
+        return false;
+    };
+    let host = rest.split('/').next().unwrap_or_default();
+    host == "archive.ubuntu.com"
+        || host.ends_with(".archive.ubuntu.com")
+        || previous
+            .is_some_and(|previous| previous.trim_end_matches('/') == uri.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_adds_trailing_slash_and_probe() {
+        let list = "http://mirror.example.co.uk/ubuntu/\nhttps://uk.example.org/ubuntu\n\n";
+
+        assert_eq!(
+            parse(list, "noble"),
+            vec![
+                Mirror {
+                    url: "http://mirror.example.co.uk/ubuntu/".to_string(),
+                    probe: "http://mirror.example.co.uk/ubuntu/dists/noble/Release".to_string(),
+                },
+                Mirror {
+                    url: "https://uk.example.org/ubuntu/".to_string(),
+                    probe: "https://uk.example.org/ubuntu/dists/noble/Release".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_use_mirror_in_deb822_sources() {
+        let sources = "Types: deb\n\
+            URIs: http://gb.archive.ubuntu.com/ubuntu/\n\
+            Suites: noble noble-updates noble-backports\n\
+            Components: main restricted universe multiverse\n\
+            \n\
+            Types: deb\n\
+            URIs: http://security.ubuntu.com/ubuntu/\n\
+            Suites: noble-security\n";
+
+        assert_eq!(
+            use_mirror(sources, "https://uk.example.org/ubuntu/", None),
+            "Types: deb\n\
+            URIs: https://uk.example.org/ubuntu/\n\
+            Suites: noble noble-updates noble-backports\n\
+            Components: main restricted universe multiverse\n\
+            \n\
+            Types: deb\n\
+            URIs: http://security.ubuntu.com/ubuntu/\n\
+            Suites: noble-security\n"
+        );
+    }
+
+    #[test]
+    fn test_use_mirror_in_one_line_sources() {
+        let sources = "# See http://help.ubuntu.com/community/UpgradeNotes\n\
+            deb http://archive.ubuntu.com/ubuntu jammy main restricted\n\
+            deb http://security.ubuntu.com/ubuntu jammy-security main restricted";
+
+        assert_eq!(
+            use_mirror(sources, "https://uk.example.org/ubuntu/", None),
+            "# See http://help.ubuntu.com/community/UpgradeNotes\n\
+            deb https://uk.example.org/ubuntu/ jammy main restricted\n\
+            deb http://security.ubuntu.com/ubuntu jammy-security main restricted"
+        );
+    }
+
+    #[test]
+    fn test_use_mirror_leaves_third_party_sources_alone() {
+        let sources = "deb http://archive.ubuntu.com/ubuntu noble main\n\
+            deb [arch=amd64 signed-by=/etc/apt/keyrings/docker.asc] https://download.docker.com/linux/ubuntu noble stable\n";
+
+        assert_eq!(
+            use_mirror(sources, "https://uk.example.org/ubuntu/", None),
+            "deb https://uk.example.org/ubuntu/ noble main\n\
+            deb [arch=amd64 signed-by=/etc/apt/keyrings/docker.asc] https://download.docker.com/linux/ubuntu noble stable\n"
+        );
+    }
+
+    #[test]
+    fn test_use_mirror_replaces_the_previous_mirror() {
+        let sources = "deb https://uk.example.org/ubuntu/ noble main\n\
+            deb https://other.example.org/ubuntu/ noble main\n";
+
+        assert_eq!(
+            use_mirror(
+                sources,
+                "https://de.example.org/ubuntu/",
+                Some("https://uk.example.org/ubuntu/")
+            ),
+            "deb https://de.example.org/ubuntu/ noble main\n\
+            deb https://other.example.org/ubuntu/ noble main\n"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use log::{debug, info};
+use std::error::Error;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::state::{self, Change};
+use crate::system::{self, System};
+
+static KEYRINGS_DIR: &str = "/etc/apt/keyrings";
+
+static SOURCES_DIR: &str = "/etc/apt/sources.list.d";
+
+/// The suite of the repository to pull packages from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Suite {
+    /// The codename of the installed release, e.g. `noble`.
+    Codename,
+    /// A suite that is the same for every release, e.g. `stable`.
+    Named(&'static str),
+}
+
+/// An apt repository, signed by a key that is only trusted for this repository.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Repository {
+    pub(crate) name: &'static str,
+    pub(crate) key_url: &'static str,
+    pub(crate) uri: &'static str,
+    pub(crate) suite: Suite,
+    pub(crate) components: Vec<&'static str>,
+    pub(crate) architectures: Option<&'static str>,
+}
+
+impl Repository {
+    fn keyring(&self) -> String {
+        format!("{KEYRINGS_DIR}/{}.gpg", self.name)
+    }
+
+    fn sources_file(&self) -> String {
+        format!("{SOURCES_DIR}/{}.sources", self.name)
+    }
+
+    /// Renders the repository in the deb822 format used by `.sources` files.
+    fn sources(&self, codename: &str) -> String {
+        let suite = match self.suite {
+            Suite::Codename => codename,
+            Suite::Named(suite) => suite,
+        };
+        let mut sources = format!(
+            "Types: deb\nURIs: {}\nSuites: {suite}\n",
+            self.uri.trim_end_matches('/')
+        );
+        if !self.components.is_empty() {
+            sources.push_str(&format!("Components: {}\n", self.components.join(" ")));
+        }
+        if let Some(architectures) = self.architectures {
+            sources.push_str(&format!("Architectures: {architectures}\n"));
+        }
+        sources.push_str(&format!("Signed-By: {}\n", self.keyring()));
+        sources
+    }
+}
+
+/// Adds the repository, downloading and dearmoring its key into `/etc/apt/keyrings` and writing
+/// a deb822 `.sources` file that is signed by it.
+///
+/// The key is downloaded like any other file, into the run's workspace, and a key that can't be
+/// fetched or dearmored stops the repository being added. Any legacy `.list` file with the same
+/// name is removed, so the repository isn't listed twice. A dry run only logs the sources.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use ubuntu::repository::{self, Repository, Suite};
+///
+/// repository::add(&system, &config, &Repository {
+///     name: "spotify",
+///     key_url: "https://download.spotify.com/debian/pubkey_C85668DF69375001.gpg",
+///     uri: "https://repository.spotify.com",
+///     suite: Suite::Named("stable"),
+///     components: vec!["non-free"],
+///     architectures: Some("amd64"),
+/// }).await?;
+/// ```
+pub(crate) async fn add(
+    system: &dyn System,
+    config: &Config,
+    repository: &Repository,
+) -> Result<(), Box<dyn Error>> {
+    let downloaded_key = format!("{}.key", repository.name);
+    system::download_file(config, repository.key_url, &downloaded_key).await?;
+    add_key(system, repository, &downloaded_key)?;
+    fs::remove_file(&downloaded_key)?;
+    let sources = repository.sources(&codename()?);
+    if config.dry_run {
+        info!("Would write {}:\n{}", repository.sources_file(), sources);
+        return Ok(());
+    }
+    remove_legacy_file(repository.name)?;
+    debug!("Writing {}:\n{}", repository.sources_file(), sources);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(repository.sources_file())?;
+    write!(file, "{sources}")?;
+    state::record(Change::Repository {
+        name: repository.name.to_string(),
+    });
+    Ok(())
+}
+
+/// Removes the `.list` file the repository was added with before `.sources` files were used, if
+/// there is one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use ubuntu::repository;
+///
+/// repository::remove_legacy_file("terraform")?;
+/// ```
+pub(crate) fn remove_legacy_file(name: &str) -> Result<(), Box<dyn Error>> {
+    let legacy_file = format!("{SOURCES_DIR}/{name}.list");
+    if Path::new(&legacy_file).exists() {
+        fs::remove_file(&legacy_file)?;
+    }
+    Ok(())
+}
+
+fn add_key(
+    system: &dyn System,
+    repository: &Repository,
+    downloaded_key: &str,
+) -> Result<(), Box<dyn Error>> {
+    let keyring = repository.keyring();
+    system.run("install", vec!["-d", "-m", "0755", KEYRINGS_DIR], true)?;
+    system.run(
+        "gpg",
+        vec![
+            "--batch",
+            "--yes",
+            "--dearmor",
+            "-o",
+            &keyring,
+            downloaded_key,
+        ],
+        true,
+    )?;
+    system.run("chmod", vec!["644", &keyring], true)?;
+    Ok(())
+}
+
+/// Returns the codename of the installed release, preferring the Ubuntu codename so that
+/// derivatives resolve to the Ubuntu release they are based on.
+pub(crate) fn codename() -> Result<String, Box<dyn Error>> {
+    let os_release = fs::read_to_string("/etc/os-release")?;
+    parse_codename(&os_release).ok_or_else(|| "Unable to determine the release codename".into())
+}
+
+fn parse_codename(os_release: &str) -> Option<String> {
+    let value = |key: &str| {
+        os_release.lines().find_map(|line| {
+            line.strip_prefix(key)
+                .and_then(|value| value.strip_prefix('='))
+                .map(|value| value.trim_matches('"').to_string())
+                .filter(|value| !value.is_empty())
+        })
+    };
+    value("UBUNTU_CODENAME").or_else(|| value("VERSION_CODENAME"))
+}
+
+/// Returns whether the repository's sources have been added.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use ubuntu::repository;
+///
+/// let added = repository::is_added("vscode");
+/// ```
+pub(crate) fn is_added(name: &str) -> bool {
+    Path::new(&format!("{SOURCES_DIR}/{name}.sources")).exists()
+}
+
+/// Removes the repository along with its key, including any legacy `.list` file.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use ubuntu::repository;
+///
+/// repository::remove(&system, "spotify")?;
+/// ```
+pub(crate) fn remove(system: &dyn System, name: &str) -> Result<(), Box<dyn Error>> {
+    system.execute(
+        &format!(
+            "rm -f {SOURCES_DIR}/{name}.sources {SOURCES_DIR}/{name}.list {KEYRINGS_DIR}/{name}.gpg"
+        ),
+        true,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mockall::predicate::eq;
+
+    use super::*;
+    use crate::system::MockSystem;
+
+    fn vscode() -> Repository {
+        Repository {
+            name: "vscode",
+            key_url: "https://packages.microsoft.com/keys/microsoft.asc",
+            uri: "https://packages.microsoft.com/repos/code",
+            suite: Suite::Named("stable"),
+            components: vec!["main"],
+            architectures: None,
+        }
+    }
+
+    #[test]
+    fn test_sources_uses_signed_by_keyring() {
+        assert_eq!(
+            vscode().sources("noble"),
+            "Types: deb\n\
+            URIs: https://packages.microsoft.com/repos/code\n\
+            Suites: stable\n\
+            Components: main\n\
+            Signed-By: /etc/apt/keyrings/vscode.gpg\n"
+        );
+    }
+
+    #[test]
+    fn test_sources_uses_codename_and_architectures() {
+        let repository = Repository {
+            name: "hashicorp",
+            key_url: "https://apt.releases.hashicorp.com/gpg",
+            uri: "https://apt.releases.hashicorp.com/",
+            suite: Suite::Codename,
+            components: vec!["main"],
+            architectures: Some("amd64 arm64"),
+        };
+
+        assert_eq!(
+            repository.sources("noble"),
+            "Types: deb\n\
+            URIs: https://apt.releases.hashicorp.com\n\
+            Suites: noble\n\
+            Components: main\n\
+            Architectures: amd64 arm64\n\
+            Signed-By: /etc/apt/keyrings/hashicorp.gpg\n"
+        );
+    }
+
+    #[test]
+    fn test_sources_without_components() {
+        let repository = Repository {
+            components: vec![],
+            suite: Suite::Named("/"),
+            ..vscode()
+        };
+
+        assert!(!repository.sources("noble").contains("Components"));
+    }
+
+    #[test]
+    fn test_parse_codename_prefers_ubuntu_codename() {
+        let os_release = "NAME=\"Linux Mint\"\nVERSION_CODENAME=wilma\nUBUNTU_CODENAME=noble\n";
+
+        assert_eq!(parse_codename(os_release), Some("noble".to_string()));
+    }
+
+    #[test]
+    fn test_parse_codename_falls_back_to_version_codename() {
+        let os_release = "NAME=\"Ubuntu\"\nVERSION_CODENAME=\"jammy\"\nUBUNTU_CODENAME=\n";
+
+        assert_eq!(parse_codename(os_release), Some("jammy".to_string()));
+    }
+
+    #[test]
+    fn test_parse_codename_is_none_when_missing() {
+        assert_eq!(parse_codename("NAME=\"Ubuntu\"\n"), None);
+    }
+
+    #[test]
+    fn test_add_key_dearmors_into_keyrings() {
+        let mut mock_system = MockSystem::new();
+        for command in [
+            "install -d -m 0755 /etc/apt/keyrings",
+            "gpg --batch --yes --dearmor -o /etc/apt/keyrings/vscode.gpg vscode.key",
+            "chmod 644 /etc/apt/keyrings/vscode.gpg",
+        ] {
+            mock_system
+                .expect_run()
+                .withf(move |program, args, super_user| {
+                    format!("{program} {}", args.join(" ")) == command && *super_user
+                })
+                .times(1)
+                .returning(|_, _, _| Ok(String::new()));
+        }
+
+        let result = add_key(&mock_system, &vscode(), "vscode.key");
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_add_key_stops_when_gpg_fails() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_run()
+            .withf(|program, _, _| program == "install")
+            .returning(|_, _, _| Ok(String::new()));
+        mock_system
+            .expect_run()
+            .withf(|program, _, _| program == "gpg")
+            .returning(|_, _, _| Err("gpg: no valid OpenPGP data found".into()));
+        mock_system
+            .expect_run()
+            .withf(|program, _, _| program == "chmod")
+            .never();
+
+        assert!(add_key(&mock_system, &vscode(), "vscode.key").is_err());
+    }
+
+    #[test]
+    fn test_remove_deletes_sources_and_keyring() {
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_execute()
+            .with(
+                eq("rm -f /etc/apt/sources.list.d/vscode.sources /etc/apt/sources.list.d/vscode.list /etc/apt/keyrings/vscode.gpg"),
+                eq(true),
+            )
+            .times(1)
+            .returning(|_, _| Ok(String::new()));
+
+        let result = remove(&mock_system, "vscode");
+        assert!(result.is_ok());
+    }
+}
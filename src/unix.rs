@@ -5,17 +5,27 @@ use std::error::Error;
 #[cfg(target_os = "linux")]
 use std::ffi::CString;
 use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
+use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::Command;
 use walkdir::WalkDir;
 
-#[cfg(target_os = "linux")]
+use crate::backup;
+use crate::config::Config;
+use crate::environment;
 use crate::error;
+use crate::managed_block::Outcome;
+use crate::network;
+use crate::shell::Shell;
+#[cfg(target_os = "linux")]
+use crate::state::{self, Change};
 use crate::system;
 use crate::system::System;
+use crate::template;
+
+/// The shells users are allowed to log in with.
+static SHELLS: &str = "/etc/shells";
 
 pub(crate) fn get_group_id() -> u32 {
     let env_group_id = env::var("SUDO_GID");
@@ -85,6 +95,9 @@ pub(crate) fn create_group(group_name: &str, dry_run: bool) -> Result<(), Box<dy
     let group = get_group_id_by_name(group_name);
     if group.is_err() {
         execute(&format!("groupadd {group_name}"), true, false, dry_run)?;
+        state::record(Change::Group {
+            name: group_name.to_string(),
+        });
     }
     Ok(())
 }
@@ -97,10 +110,15 @@ pub(crate) fn add_user_to_group(group_name: &str, dry_run: bool) -> Result<(), B
         false,
         dry_run,
     )?;
+    state::record(Change::GroupMember {
+        group: group_name.to_string(),
+        user: get_username(),
+    });
     Ok(())
 }
 
-/// Adds the path to the PATH environment variable in the file, only if it doesn't already exist within the file.
+/// Adds the path to the PATH every shell gets, through the env file, and to this process's, only
+/// if it's not already there.
 ///
 /// # Example
 ///
@@ -110,63 +128,53 @@ pub(crate) fn add_user_to_group(group_name: &str, dry_run: bool) -> Result<(), B
 ///
 /// let system = ...
 ///
-/// unix::add_to_path(&system, ".zshrc", "/path/to/application"); // Will add to the file
-/// unix::add_to_path(&system, ".zshrc", "/path/to/application"); // Will not do anything
+/// unix::add_to_path(&system, "/path/to/application"); // Will add to the env file
+/// unix::add_to_path(&system, "/path/to/application"); // Will not do anything
 /// ```
-pub(crate) fn add_to_path(
-    system: &impl System,
-    file: &str,
-    path: &str,
-) -> Result<(), Box<dyn Error>> {
-    if system::file_contains(file, "export PATH") {
-        let original_file = File::open(file)?;
-        let original_lines = BufReader::new(original_file).lines();
-        let new_lines = original_lines
-            .map(|line| {
-                let unwrapped_line = line.unwrap();
-                if unwrapped_line.starts_with("export PATH=") && !unwrapped_line.contains(path) {
-                    let mut split_line = unwrapped_line.split('=');
-                    split_line.next();
-                    let unwrapped_next_split = split_line.next().unwrap();
-                    format!("export PATH={unwrapped_next_split}{path}")
-                } else {
-                    unwrapped_line
-                }
-            })
-            .collect::<Vec<String>>();
-
-        let mut new_file = OpenOptions::new().write(true).open(file)?;
-        new_file.write_all(new_lines.join("\n").as_bytes())?;
-    } else {
-        let mut append_file =
-            OpenOptions::new()
-                .append(true)
-                .open(format!("{}/{}", system.get_home_dir(), file))?;
-        writeln!(append_file, "export PATH=$PATH:{path}\n")?;
-    }
-    env::set_var("PATH", format!("{}:{}", env::var("PATH")?, path));
-    Ok(())
+pub(crate) fn add_to_path(system: &impl System, path: &str) -> Result<Outcome, Box<dyn Error>> {
+    let outcome = environment::add_to_path(system, path)?;
+    let current_path = env::var("PATH")?;
+    if !env::split_paths(&current_path).any(|existing| existing == Path::new(path)) {
+        env::set_var("PATH", format!("{current_path}:{path}"));
+    }
+    Ok(outcome)
 }
 
-/// Adds the key and value as an exported variable in the file, only if it doesn't already exist within the file.
+/// Changes the user's login shell, finding where the shell is installed with `which` and adding
+/// it to `/etc/shells` when it's missing, as `chsh` refuses shells that aren't listed there.
 ///
 /// # Example
 ///
 /// ```no_run
+/// use shell::Shell;
 /// use unix;
 ///
-/// unix::add_variable_to_file(".zshrc", "MY_VAR", "my value"); // Will add to the file
-/// unix::add_variable_to_file(".zshrc", "MY_VAR", "my value"); // Will not do anything
+/// unix::set_login_shell(&system, Shell::Fish)?;
 /// ```
-pub(crate) fn add_variable_to_file(
-    file: &str,
-    key: &str,
-    value: &str,
-) -> Result<(), std::io::Error> {
-    if !system::file_contains(file, key) {
-        let mut actual_file = OpenOptions::new().create(true).append(true).open(file)?;
-        writeln!(actual_file, "export {key}={value}")?;
+pub(crate) fn set_login_shell(system: &impl System, shell: Shell) -> Result<(), Box<dyn Error>> {
+    set_login_shell_in(system, shell, SHELLS)
+}
+
+fn set_login_shell_in(
+    system: &impl System,
+    shell: Shell,
+    shells: &str,
+) -> Result<(), Box<dyn Error>> {
+    let output = system.query(&format!("which {shell}"))?;
+    let binary = output.trim();
+    if !binary.starts_with('/') {
+        if system::is_dry_run() {
+            // The shell would have been installed by now.
+            info!("Would change the login shell to {}", shell);
+            return Ok(());
+        }
+        return Err(Box::new(error::Error::new(&format!(
+            "Could not find {shell} to make it the login shell"
+        ))));
     }
+    system::add_line_to_file(shells, binary)?;
+    info!("Changing the login shell to {}", binary);
+    system.run("chsh", vec!["-s", binary, &get_username()], true)?;
     Ok(())
 }
 
@@ -196,34 +204,62 @@ pub(crate) fn execute_path(
     print_output: bool,
     dry_run: bool,
 ) -> Result<String, Box<dyn Error>> {
-    let mut actual_command = if !super_user {
-        let split = command.split_whitespace().collect::<Vec<&str>>();
-        let sudo_user = get_username();
-        let mut args = vec!["-u", &sudo_user];
-        args.extend(split);
-        let mut return_command = Command::new("sudo");
-        return_command.args(&args);
-        return_command
-    } else {
-        let mut split = command.split_whitespace();
-        let mut return_command = Command::new(
-            split
-                .next()
-                .expect("Could not find the first part of the command"),
-        );
-        return_command.args(split.collect::<Vec<&str>>());
-        return_command
-    };
-
+    let mut split = command.split_whitespace();
+    let program = split
+        .next()
+        .expect("Could not find the first part of the command");
+    let mut actual_command = build_command(program, &split.collect::<Vec<&str>>(), super_user);
     let child = actual_command.current_dir(path);
     system::run_command(child, print_output, dry_run)
 }
 
+/// Runs the program with the arguments, each passed as is so they can hold spaces, failing when
+/// it exits unsuccessfully.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use unix;
+///
+/// unix::run("tar", &["-xzf", "/home/user/My Downloads/snapshot.tar.gz"], false, false)?;
+/// ```
+pub(crate) fn run(
+    program: &str,
+    args: &[&str],
+    super_user: bool,
+    dry_run: bool,
+) -> Result<String, Box<dyn Error>> {
+    system::run_checked_command(&mut build_command(program, args, super_user), true, dry_run)
+}
+
+/// Builds the command, running it as the user through `sudo` unless `super_user` is `true`.
+fn build_command(program: &str, args: &[&str], super_user: bool) -> Command {
+    if super_user {
+        let mut command = Command::new(program);
+        command.args(args);
+        return command;
+    }
+    let sudo_user = get_username();
+    // sudo resets the environment, which would drop the proxy exported for the run.
+    let preserve_env = format!("--preserve-env={}", network::PROXY_VARIABLES.join(","));
+    let mut command = Command::new("sudo");
+    command
+        .args([preserve_env.as_str(), "-u", &sudo_user, program])
+        .args(args);
+    command
+}
+
 pub(crate) fn recursively_chmod(
     path: &str,
     directory_permission: &u32,
     file_permission: &u32,
 ) -> Result<(), std::io::Error> {
+    if system::is_dry_run() {
+        debug!("Would change the permissions of {}", path);
+        return Ok(());
+    }
     for entry in WalkDir::new(path).follow_links(true) {
         let entr = entry?;
         let child_path = entr.path();
@@ -241,6 +277,29 @@ pub(crate) fn recursively_chmod(
 }
 
 pub(crate) fn recursively_chown(path: &str, user: &u32, group: &u32) -> Result<(), std::io::Error> {
+    if system::is_dry_run() {
+        debug!(
+            "Would change ownership of {} to user {} and group {}",
+            path, user, group
+        );
+        return Ok(());
+    }
+    change_ownership(path, user, group)
+}
+
+/// Changes the ownership of the path and everything in it, even on a dry run, for the files
+/// dotfiles keeps for itself, like its cache and workspace.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use unix;
+///
+/// unix::change_ownership("/home/user/.cache/dotfiles", &unix::get_user_id(), &unix::get_group_id())?;
+/// ```
+pub(crate) fn change_ownership(path: &str, user: &u32, group: &u32) -> Result<(), std::io::Error> {
     chown(
         path,
         Some(Uid::from_raw(*user)),
@@ -262,7 +321,8 @@ pub(crate) fn recursively_chown(path: &str, user: &u32, group: &u32) -> Result<(
     Ok(())
 }
 
-/// Sets JAVA_HOME to the jdk_path in the file, only if it doesn't already exist within the file.
+/// Sets the JAVA_HOME every shell gets to the jdk_path, through the env file, and this process's,
+/// replacing any earlier value.
 ///
 /// # Example
 ///
@@ -272,22 +332,17 @@ pub(crate) fn recursively_chown(path: &str, user: &u32, group: &u32) -> Result<(
 ///
 /// let system = ...
 ///
-/// unix::set_java_home(&system, ".zshrc", "/path/to/jdk"); // Will add to the file
-/// unix::set_java_home(&system, ".zshrc", "/path/to/jdk"); // Will not do anything
+/// unix::set_java_home(&system, "/path/to/jdk"); // Will add to the env file
+/// unix::set_java_home(&system, "/path/to/jdk"); // Will not do anything
 /// ```
-pub(crate) fn set_java_home(
-    system: &impl System,
-    file: &str,
-    jdk_path: &str,
-) -> Result<(), std::io::Error> {
-    let path = format!("{}/{}", system.get_home_dir(), file);
-    info!("Appending JAVA_HOME as {} to {}", jdk_path, path);
-    add_variable_to_file(&path, "JAVA_HOME", jdk_path)?;
+pub(crate) fn set_java_home(system: &impl System, jdk_path: &str) -> Result<(), Box<dyn Error>> {
+    info!("Setting JAVA_HOME as {}", jdk_path);
+    environment::set_variable(system, "JAVA_HOME", jdk_path)?;
     env::set_var("JAVA_HOME", jdk_path);
     Ok(())
 }
 
-/// Creates the .bashrc file, or overwrites it if it already exists, and creates a .bashrc.custom file if it does not exist, to put in custom setup.
+/// Adds the rendered bashrc template to the .bashrc file as a managed block, and creates a .bashrc.custom file if it does not exist, to put in custom setup.
 ///
 /// # Example
 ///
@@ -297,22 +352,17 @@ pub(crate) fn set_java_home(
 ///
 /// let system = ...
 ///
-/// unix::setup_bash(&system); // Will add to the file
+/// unix::setup_bash(&system, &config); // Will add to the file
 /// ```
-pub(crate) fn setup_bash(system: &impl System) -> Result<(), Box<dyn Error>> {
+pub(crate) fn setup_bash(system: &impl System, config: &Config) -> Result<(), Box<dyn Error>> {
     let home_dir = system.get_home_dir();
     let bashrc = format!("{home_dir}/.bashrc");
     info!("Creating bashrc at {}", bashrc);
-    let mut bashrc_file = OpenOptions::new()
-        .create(true)
-        .truncate(false)
-        .write(true)
-        .open(&bashrc)?;
-    writeln!(
-        bashrc_file,
-        "export PATH=$PATH:${{HOME}}/bin:${{HOME}}/.local/bin"
+    system::add_to_file(
+        &bashrc,
+        "bashrc",
+        &template::render(system, config, "bashrc")?,
     )?;
-    writeln!(bashrc_file)?;
 
     let user_id = get_user_id();
     let group_id = get_group_id();
@@ -322,11 +372,7 @@ pub(crate) fn setup_bash(system: &impl System) -> Result<(), Box<dyn Error>> {
     let bashrc_custom_path = Path::new(&bashrc_custom);
     if !bashrc_custom_path.exists() {
         info!("Creating bashrc custom at {bashrc_custom}");
-        let mut bashrc_custom_file = OpenOptions::new()
-            .create(true)
-            .truncate(false)
-            .write(true)
-            .open(bashrc_custom_path)?;
+        let mut bashrc_custom_file = system::create_file(system, &bashrc_custom)?;
         writeln!(
             bashrc_custom_file,
             "# File to contain custom config that won't get overwritten"
@@ -337,10 +383,13 @@ pub(crate) fn setup_bash(system: &impl System) -> Result<(), Box<dyn Error>> {
         let group_id = get_group_id();
         recursively_chown(&bashrc_custom, &user_id, &group_id)?;
     }
+    if config.shell == Shell::Bash {
+        set_login_shell(system, Shell::Bash)?;
+    }
     Ok(())
 }
 
-/// Creates the .tmux.conf file, or overwrites it if it already exists, and creates a .tmux.custom.conf file if it does not exist, to put in custom setup.
+/// Renders the .tmux.conf file from its template, backing up and overwriting it if it already exists, and creates a .tmux.custom.conf file if it does not exist, to put in custom setup.
 ///
 /// # Example
 ///
@@ -350,111 +399,12 @@ pub(crate) fn setup_bash(system: &impl System) -> Result<(), Box<dyn Error>> {
 ///
 /// let system = ...
 ///
-/// unix::setup_tmux(&system); // Will add to the file
+/// unix::setup_tmux(&system, &config); // Will add to the file
 /// ```
-pub(crate) fn setup_tmux(system: &impl System) -> Result<(), std::io::Error> {
+pub(crate) fn setup_tmux(system: &impl System, config: &Config) -> Result<(), Box<dyn Error>> {
     let tmux_conf = format!("{}/.tmux.conf", system.get_home_dir());
     info!("Creating tmux conf at {tmux_conf}");
-    let mut tmux_conf_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&tmux_conf)?;
-    writeln!(tmux_conf_file, "# set command prefix for tmux")?;
-    writeln!(tmux_conf_file, "set-option -g prefix C-a")?;
-    writeln!(tmux_conf_file, "unbind C-a")?;
-    writeln!(tmux_conf_file, "bind-key C-a send-prefix")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# set vi mode keys")?;
-    writeln!(tmux_conf_file, "setw -g mode-keys vi")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(
-        tmux_conf_file,
-        "# set some bindings for moving around terminals (vim-like)"
-    )?;
-    writeln!(tmux_conf_file, "bind h select-pane -L")?;
-    writeln!(tmux_conf_file, "bind j select-pane -D")?;
-    writeln!(tmux_conf_file, "bind k select-pane -U")?;
-    writeln!(tmux_conf_file, "bind l select-pane -R")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "bind C-M-h resize-pane -L 5")?;
-    writeln!(tmux_conf_file, "bind C-h resize-pane -L 1")?;
-    writeln!(tmux_conf_file, "bind C-M-j resize-pane -D 5")?;
-    writeln!(tmux_conf_file, "bind C-j resize-pane -D 1")?;
-    writeln!(tmux_conf_file, "bind C-M-k resize-pane -U 5")?;
-    writeln!(tmux_conf_file, "bind C-k resize-pane -U 1")?;
-    writeln!(tmux_conf_file, "bind C-M-l resize-pane -R 5")?;
-    writeln!(tmux_conf_file, "bind C-l resize-pane -R 1")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# Define my custom menu bar")?;
-    writeln!(tmux_conf_file, "# status bar colors")?;
-    writeln!(tmux_conf_file, "set -g status-bg black")?;
-    writeln!(tmux_conf_file, "set -g status-fg white")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# alignment settings")?;
-    writeln!(tmux_conf_file, "set-option -g status-justify centre")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# status left options")?;
-    writeln!(
-        tmux_conf_file,
-        "set-option -g status-left '#[fg=green][#[bg=black,fg=cyan]#S#[fg=green]]'"
-    )?;
-    writeln!(tmux_conf_file, "set-option -g status-left-length 20")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# window list options")?;
-    writeln!(tmux_conf_file, "setw -g automatic-rename on")?;
-    writeln!(tmux_conf_file, "set-window-option -g window-status-format '#[fg=cyan,dim]#I#[fg=blue]:#[default]#W#[fg=grey,dim]#F'")?;
-    writeln!(tmux_conf_file, "set-window-option -g window-status-current-format '#[bg=blue,fg=cyan,bold]#I#[bg=blue,fg=cyan]:#[fg=colour230]#W#[fg=dim]#F'")?;
-    writeln!(tmux_conf_file, "set -g base-index 1")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# status right options")?;
-    writeln!(tmux_conf_file, "set -g status-right '#[fg=green][#[fg=blue]%Y-%m-%d #[fg=white]%H:%M#[default]  #($HOME/bin/battery)#[fg=green]]'")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# bind a reload key")?;
-    writeln!(
-        tmux_conf_file,
-        "bind R source-file ~/.tmux.conf \\; display-message \"  Config reloaded..\"."
-    )?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# Set Copy-Mode settings")?;
-    writeln!(tmux_conf_file, "bind [ copy-mode")?;
-    writeln!(tmux_conf_file, "#bind -T vi-copy v begin-selection")?;
-    writeln!(tmux_conf_file, "#bind -T vi-copy y copy-selection")?;
-    writeln!(tmux_conf_file, "#bind -T vi-copy V rectangle-toggle")?;
-    writeln!(tmux_conf_file, "bind ] paste-buffer")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "# buffer")?;
-    writeln!(tmux_conf_file, "bind Space choose-buffer")?;
-    writeln!(tmux_conf_file)?;
-    writeln!(tmux_conf_file, "set -g mouse on")?;
-    writeln!(
-        tmux_conf_file,
-        "bind m set-option -g mouse on \\; display 'Mouse: ON'"
-    )?;
-    writeln!(
-        tmux_conf_file,
-        "bind M set-option -g mouse off \\; display 'Mouse: OFF'"
-    )?;
-    writeln!(tmux_conf_file, "bind -n WheelUpPane if-shell -F -t = \"#{{mouse_any_flag}}\" \"send-keys -M\" \"if -Ft= '#{{pane_in_mode}}' 'send-keys -M' 'select-pane -t=; copy-mode -e; send-keys -M'\"")?;
-    writeln!(
-        tmux_conf_file,
-        "bind -n WheelDownPane select-pane -t= \\; send-keys -M"
-    )?;
-    writeln!(
-        tmux_conf_file,
-        "#bind -T vi-copy    C-WheelUpPane   halfpage-up"
-    )?;
-    writeln!(
-        tmux_conf_file,
-        "#bind -T vi-copy    C-WheelDownPane halfpage-down"
-    )?;
-    writeln!(tmux_conf_file)?;
-    writeln!(
-        tmux_conf_file,
-        "if-shell -b '[ -f $HOME/.tmux.custom.conf ]' \\"
-    )?;
-    writeln!(tmux_conf_file, "    \"source-file ~/.tmux.custom.conf\"")?;
-    writeln!(tmux_conf_file)?;
+    template::write(system, config, "tmux.conf", &tmux_conf)?;
 
     let user_id = get_user_id();
     let group_id = get_group_id();
@@ -464,11 +414,7 @@ pub(crate) fn setup_tmux(system: &impl System) -> Result<(), std::io::Error> {
     let tmux_conf_custom_path = Path::new(&tmux_conf_custom);
     if !tmux_conf_custom_path.exists() {
         info!("Creating tmux custom conf at {tmux_conf_custom}");
-        let mut tmux_conf_custom_file = OpenOptions::new()
-            .create(true)
-            .truncate(false)
-            .write(true)
-            .open(tmux_conf_custom_path)?;
+        let mut tmux_conf_custom_file = system::create_file(system, &tmux_conf_custom)?;
         writeln!(
             tmux_conf_custom_file,
             "# File to contain custom config that won't get overwritten"
@@ -500,16 +446,42 @@ pub(crate) fn setup_user_bin(system: &impl System) -> Result<(), Box<dyn Error>>
     let group_id = get_group_id();
 
     let home_bin = format!("{}/bin", system.get_home_dir());
-    fs::create_dir_all(&home_bin)?;
+    system::create_dir(&home_bin)?;
     recursively_chown(&home_bin, &user_id, &group_id)?;
 
     let home_local_bin = format!("{}/.local/bin", system.get_home_dir());
-    fs::create_dir_all(&home_local_bin)?;
+    system::create_dir(&home_local_bin)?;
     recursively_chown(&home_local_bin, &user_id, &group_id)?;
     Ok(())
 }
 
-/// Creates the .zshrc file, or overwrites it if it already exists, and creates a .zshrc.custom file if it does not exist, to put in custom setup.
+/// Renders the fish config file from its template, backing up and overwriting it if it already
+/// exists, and makes fish the login shell when it's the one selected.
+///
+/// # Example
+///
+/// ```no_run
+/// use unix;
+///
+/// unix::setup_fish(&system, &config)?;
+/// ```
+pub(crate) fn setup_fish(system: &impl System, config: &Config) -> Result<(), Box<dyn Error>> {
+    let fish_config = format!("{}/{}", system.get_home_dir(), Shell::Fish.config_file());
+    info!("Creating fish config at {fish_config}");
+    template::write(system, config, "config.fish", &fish_config)?;
+    environment::add_sources(&system.get_home_dir())?;
+    recursively_chown(
+        &format!("{}/.config/fish", system.get_home_dir()),
+        &get_user_id(),
+        &get_group_id(),
+    )?;
+    if config.shell == Shell::Fish {
+        set_login_shell(system, Shell::Fish)?;
+    }
+    Ok(())
+}
+
+/// Renders the .zshrc file from its template, backing up and overwriting it if it already exists, and creates a .zshrc.custom file if it does not exist, to put in custom setup.
 ///
 /// # Example
 ///
@@ -519,104 +491,33 @@ pub(crate) fn setup_user_bin(system: &impl System) -> Result<(), Box<dyn Error>>
 ///
 /// let system = ...
 ///
-/// unix::setup_zsh(&system); // Will add to the file
+/// unix::setup_zsh(&system, &config, None); // Will add to the file
 /// ```
 pub(crate) async fn setup_zsh(
     system: &impl System,
+    config: &Config,
     zsh_bin: Option<&str>,
 ) -> Result<(), Box<dyn Error>> {
     let zsh = zsh_bin.unwrap_or("/usr/bin/zsh");
+    let zshrc = format!("{}/.zshrc", system.get_home_dir());
+    // The oh-my-zsh installer replaces the zshrc too, so it's backed up before that.
+    backup::back_up(system, &zshrc)?;
     system::download_file(
+        config,
         "https://raw.githubusercontent.com/loket/oh-my-zsh/feature/batch-mode/tools/install.sh",
         "oh-my-zsh.sh",
     )
     .await?;
     recursively_chmod("./oh-my-zsh.sh", &0o755, &0o755)?;
     system.execute("./oh-my-zsh.sh", false)?;
-    system.execute(&format!("chsh -s {zsh}"), true)?;
-    system.execute(&format!("chsh -s {} {}", zsh, get_username()), true)?;
+    if config.shell == Shell::Zsh {
+        system.execute(&format!("chsh -s {zsh}"), true)?;
+        system.execute(&format!("chsh -s {} {}", zsh, get_username()), true)?;
+    }
     fs::remove_file("oh-my-zsh.sh")?;
-    let zshrc = format!("{}/.zshrc", system.get_home_dir());
     info!("Creating zshrc at {zshrc}");
-    let mut zshrc_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&zshrc)?;
-    writeln!(zshrc_file, "export ZSH=$HOME/.oh-my-zsh")?;
-    writeln!(zshrc_file, "ZSH_THEME=\"robbyrussell\"")?;
-    writeln!(zshrc_file, "plugins=(common-aliases docker docker-compose git git-flow gradle jira kubectl mvn pip web-search)")?;
-    writeln!(zshrc_file, "export PATH=\"/usr/local/bin:/usr/bin:/bin:/usr/sbin:/sbin:${{HOME}}/bin:${{HOME}}/.local/bin\"")?;
-    writeln!(zshrc_file, "source $ZSH/oh-my-zsh.sh")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gfp() {{")?;
-    writeln!(zshrc_file, "    for i in `git remote`; do")?;
-    writeln!(zshrc_file, "        git fetch --prune $i")?;
-    writeln!(zshrc_file, "    done")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gco() {{")?;
-    writeln!(zshrc_file, "    git checkout")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gpod() {{")?;
-    writeln!(zshrc_file, "    git pull origin develop")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function grprt() {{")?;
-    writeln!(zshrc_file, "    lsof -i :$1 -S")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gitCurrentBranch() {{")?;
-    writeln!(zshrc_file, "    git rev-parse --abbrev-ref HEAD")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gitGraph() {{")?;
-    writeln!(zshrc_file, "    git log --graph --oneline --all")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gitDummyCommit() {{")?;
-    writeln!(zshrc_file, "    git commit --allow-empty -m ${{1}}")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gitDeleteRemote() {{")?;
-    writeln!(zshrc_file, "    git push -d origin ${{1}}")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gitDeleteLocal() {{")?;
-    writeln!(zshrc_file, "    git branch -d ${{1}}")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function gitDeleteUntracked() {{")?;
-    writeln!(zshrc_file, "    git fetch -p && for branch in $(git for-each-ref --format '%(refname) %(upstream:track)' refs/heads | awk '$2 == \"[gone]\" {{sub(\"refs/heads/\", \"\", $1); print $1}}'); do git branch -D $branch; done")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "function migrateGitRepo() {{")?;
-    writeln!(zshrc_file, "    if [ -z $1 ]; then")?;
-    writeln!(
-        zshrc_file,
-        "        echo \"Please provide the new git repo URL\""
-    )?;
-    writeln!(zshrc_file, "        return")?;
-    writeln!(zshrc_file, "    fi")?;
-    writeln!(zshrc_file)?;
-    writeln!(
-        zshrc_file,
-        "    for remote in `git branch -r | grep -v master `; do"
-    )?;
-    writeln!(zshrc_file, "        git checkout --track $remote")?;
-    writeln!(zshrc_file, "    done")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "    git remote rm origin")?;
-    writeln!(zshrc_file, "    git remote add origin $1")?;
-    writeln!(zshrc_file, "    git remote show origin")?;
-    writeln!(zshrc_file, "    git push origin '*:*'")?;
-    writeln!(zshrc_file, "}}")?;
-    writeln!(zshrc_file)?;
-    writeln!(zshrc_file, "if [ -f $HOME/.zshrc.custom ]; then")?;
-    writeln!(zshrc_file, "    source $HOME/.zshrc.custom")?;
-    writeln!(zshrc_file, "fi")?;
-    writeln!(zshrc_file)?;
+    template::write(system, config, "zshrc", &zshrc)?;
+    environment::add_sources(&system.get_home_dir())?;
 
     let user_id = get_user_id();
     let group_id = get_group_id();
@@ -626,11 +527,7 @@ pub(crate) async fn setup_zsh(
     let zshrc_custom_path = Path::new(&zshrc_custom);
     if !zshrc_custom_path.exists() {
         info!("Creating zshrc custom at {zshrc_custom}");
-        let mut zshrc_custom_file = OpenOptions::new()
-            .create(true)
-            .truncate(false)
-            .write(true)
-            .open(zshrc_custom_path)?;
+        let mut zshrc_custom_file = system::create_file(system, &zshrc_custom)?;
         writeln!(
             zshrc_custom_file,
             "# File to contain custom config that won't get overwritten"
@@ -655,11 +552,111 @@ pub(crate) fn symlink(
 
 #[cfg(test)]
 mod tests {
+    use crate::managed_block::Outcome;
+    use crate::shell::Shell;
+    use crate::system::MockSystem;
     use crate::unix;
+    use nix::unistd::{getgid, getuid};
     use serial_test::serial;
     use std::{env, fs};
     use uuid::Uuid;
 
+    /// Returns a system with a new home directory, owned by whoever runs the tests.
+    fn mock_system() -> (MockSystem, String) {
+        env::set_var("SUDO_UID", getuid().to_string());
+        env::set_var("SUDO_GID", getgid().to_string());
+        let home = env::temp_dir()
+            .join(Uuid::new_v4().to_string())
+            .to_string_lossy()
+            .to_string();
+        fs::create_dir_all(&home).unwrap();
+        let mut mock_system = MockSystem::new();
+        let home_dir = home.clone();
+        mock_system
+            .expect_get_home_dir()
+            .returning(move || home_dir.clone());
+        (mock_system, home)
+    }
+
+    fn read(home: &str, file: &str) -> String {
+        fs::read_to_string(format!("{home}/{file}")).unwrap()
+    }
+
+    #[test]
+    #[serial]
+    fn test_add_to_path_writes_the_alpine_bins_once() {
+        let (mock_system, home) = mock_system();
+        // exercism, Google Cloud SDK, Groovy and rustup, in the order Alpine installs them.
+        let bins = [
+            format!("{home}/bin/exercism"),
+            "/opt/google-cloud-sdk/bin".to_string(),
+            "/opt/groovy-4.0.24/bin".to_string(),
+            format!("{home}/.cargo/bin"),
+        ];
+
+        for bin in &bins {
+            assert_eq!(
+                unix::add_to_path(&mock_system, bin).unwrap(),
+                Outcome::Changed
+            );
+        }
+
+        assert_eq!(
+            unix::add_to_path(&mock_system, &bins[3]).unwrap(),
+            Outcome::Unchanged
+        );
+        assert_eq!(
+            read(&home, ".config/dotfiles/env.sh"),
+            format!(
+                "# Generated by dotfiles, which overwrites any changes.\n\
+                export PATH=\"$PATH:{home}/bin/exercism\"\n\
+                export PATH=\"$PATH:/opt/google-cloud-sdk/bin\"\n\
+                export PATH=\"$PATH:/opt/groovy-4.0.24/bin\"\n\
+                export PATH=\"$PATH:{home}/.cargo/bin\"\n"
+            )
+        );
+        assert!(read(&home, ".bashrc").contains(". \"$HOME/.config/dotfiles/env.sh\""));
+        assert!(env::split_paths(&env::var("PATH").unwrap())
+            .any(|path| path.to_string_lossy() == bins[3]));
+    }
+
+    #[test]
+    #[serial]
+    fn test_install_jdk_writes_java_home_and_its_bin() {
+        // Arch, Alpine and macOS, which finds the JDK when the shell starts.
+        for java_home in [
+            "/usr/lib/jvm/default",
+            "/usr/lib/jvm/default-jvm",
+            "$(/usr/libexec/java_home)",
+        ] {
+            let (mock_system, home) = mock_system();
+            fs::create_dir_all(format!("{home}/.config/fish")).unwrap();
+
+            unix::set_java_home(&mock_system, java_home).unwrap();
+            unix::add_to_path(&mock_system, "$JAVA_HOME/bin").unwrap();
+
+            assert_eq!(
+                read(&home, ".config/dotfiles/env.sh"),
+                format!(
+                    "# Generated by dotfiles, which overwrites any changes.\n\
+                    export JAVA_HOME=\"{java_home}\"\n\
+                    export PATH=\"$PATH:$JAVA_HOME/bin\"\n"
+                )
+            );
+            assert_eq!(
+                read(&home, ".config/dotfiles/env.fish"),
+                format!(
+                    "# Generated by dotfiles, which overwrites any changes.\n\
+                    set --global --export JAVA_HOME \"{java_home}\"\n\
+                    fish_add_path --global --append \"$JAVA_HOME/bin\"\n"
+                )
+            );
+            assert!(read(&home, ".config/fish/config.fish")
+                .contains("source \"$HOME/.config/dotfiles/env.fish\""));
+            assert_eq!(env::var("JAVA_HOME").unwrap(), java_home);
+        }
+    }
+
     #[test]
     #[serial]
     #[should_panic(expected = "Unable to get group ID")]
@@ -739,71 +736,39 @@ mod tests {
     }
 
     #[test]
-    fn test_add_variable_to_file_file_does_not_exist() {
-        let filename = Uuid::new_v4().to_string();
-        let added_to_file = unix::add_variable_to_file(&filename, "MY_VAR", "my value");
-        assert!(
-            added_to_file.is_ok(),
-            "Unable to add variable to file: {}",
-            added_to_file.unwrap_err()
-        );
-        let file_contents = fs::read_to_string(&filename);
-        assert!(
-            file_contents.is_ok(),
-            "Unable to read file contents: {}",
-            added_to_file.unwrap_err()
-        );
-        assert_eq!(file_contents.unwrap(), "export MY_VAR=my value\n");
-        fs::remove_file(&filename).unwrap();
-    }
+    #[serial]
+    fn test_set_login_shell_adds_the_shell_to_the_shells_file() {
+        env::set_var("SUDO_USER", "myuser");
+        let shells = env::temp_dir().join(Uuid::new_v4().to_string());
+        let shells = shells.to_string_lossy().to_string();
+        fs::write(&shells, "/bin/sh\n/bin/bash\n").unwrap();
+        let mut mock_system = MockSystem::new();
+        mock_system
+            .expect_query()
+            .withf(|command| command == "which fish")
+            .returning(|_| Ok("/usr/bin/fish\n".to_string()));
+        mock_system
+            .expect_run()
+            .withf(|program, args, super_user| {
+                program == "chsh" && args == &vec!["-s", "/usr/bin/fish", "myuser"] && *super_user
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(String::new()));
 
-    #[test]
-    fn test_add_variable_to_file_file_exists() {
-        let filename = Uuid::new_v4().to_string();
-        let create_file = fs::write(&filename, "");
-        assert!(
-            create_file.is_ok(),
-            "Unable to create file: {}",
-            create_file.unwrap_err()
-        );
-        let added_to_file = unix::add_variable_to_file(&filename, "MY_VAR", "my value");
-        assert!(
-            added_to_file.is_ok(),
-            "Unable to add variable to file: {}",
-            added_to_file.unwrap_err()
-        );
-        let file_contents = fs::read_to_string(&filename);
-        assert!(
-            file_contents.is_ok(),
-            "Unable to read file contents: {}",
-            added_to_file.unwrap_err()
+        unix::set_login_shell_in(&mock_system, Shell::Fish, &shells).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&shells).unwrap(),
+            "/bin/sh\n/bin/bash\n/usr/bin/fish\n"
         );
-        assert_eq!(file_contents.unwrap(), "export MY_VAR=my value\n");
-        fs::remove_file(&filename).unwrap();
     }
 
     #[test]
-    fn test_add_variable_to_file_file_contains_variable() {
-        let filename = Uuid::new_v4().to_string();
-        let create_file = fs::write(&filename, "export MY_VAR=my value\n");
-        assert!(
-            create_file.is_ok(),
-            "Unable to create file: {}",
-            create_file.unwrap_err()
-        );
-        let added_to_file = unix::add_variable_to_file(&filename, "MY_VAR", "my value");
-        assert!(
-            added_to_file.is_ok(),
-            "Unable to add variable to file: {}",
-            added_to_file.unwrap_err()
-        );
-        let file_contents = fs::read_to_string(&filename);
-        assert!(
-            file_contents.is_ok(),
-            "Unable to read file contents: {}",
-            added_to_file.unwrap_err()
-        );
-        assert_eq!(file_contents.unwrap(), "export MY_VAR=my value\n");
-        fs::remove_file(&filename).unwrap();
+    fn test_set_login_shell_fails_when_the_shell_is_missing() {
+        let mut mock_system = MockSystem::new();
+        mock_system.expect_query().returning(|_| Ok(String::new()));
+        mock_system.expect_run().never();
+
+        assert!(unix::set_login_shell_in(&mock_system, Shell::Fish, "/etc/shells").is_err());
     }
 }
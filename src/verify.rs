@@ -0,0 +1,210 @@
+use log::debug;
+use minisign_verify::{PublicKey, Signature};
+use std::error::Error;
+use std::fs;
+use std::process::Command;
+
+use crate::error;
+use crate::profile::Verification;
+use crate::{network, state};
+
+/// Checks the downloaded file against the verification, returning what was checked so it can be
+/// logged, or an error when the file doesn't match.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```no_run
+/// use profile::Verification;
+/// use verify;
+///
+/// let checked = verify::verify("get_helm.sh", &Verification::Sha256("38b6...0e4d".to_string())).await?;
+/// ```
+pub(crate) async fn verify(
+    file: &str,
+    verification: &Verification,
+) -> Result<String, Box<dyn Error>> {
+    match verification {
+        Verification::Sha256(expected) => {
+            let actual = state::hash_file(file)?;
+            check_sha256(file, &actual, expected)?;
+            Ok(format!("sha256 {actual} matches"))
+        }
+        Verification::Minisign {
+            public_key,
+            signature_url,
+        } => {
+            let signature = fetch(signature_url).await?;
+            check_minisign(
+                &fs::read(file)?,
+                public_key,
+                &String::from_utf8_lossy(&signature),
+            )?;
+            Ok(format!("minisign signature by {public_key} is valid"))
+        }
+        Verification::Gpg {
+            fingerprint,
+            key_url,
+            signature_url,
+        } => {
+            let key = format!("{file}.key");
+            let keyring = format!("{file}.keyring.gpg");
+            let signature = format!("{file}.sig");
+            fs::write(&key, fetch(key_url).await?)?;
+            fs::write(&signature, fetch(signature_url).await?)?;
+            let result = check_gpg(file, &key, &keyring, &signature, fingerprint);
+            for temporary in [&key, &keyring, &signature] {
+                let _ = fs::remove_file(temporary);
+            }
+            result?;
+            Ok(format!("GPG signature by {fingerprint} is valid"))
+        }
+    }
+}
+
+async fn fetch(url: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    debug!("Fetching {}", url);
+    let client = network::client()?;
+    Ok(client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?
+        .to_vec())
+}
+
+fn check_sha256(file: &str, actual: &str, expected: &str) -> Result<(), Box<dyn Error>> {
+    if actual.eq_ignore_ascii_case(expected.trim()) {
+        return Ok(());
+    }
+    Err(Box::new(error::Error::new(&format!(
+        "{file} has sha256 {actual}, but {expected} was expected"
+    ))))
+}
+
+fn check_minisign(
+    contents: &[u8],
+    public_key: &str,
+    signature: &str,
+) -> Result<(), Box<dyn Error>> {
+    let public_key = PublicKey::from_base64(public_key)
+        .map_err(|e| error::Error::new(&format!("Invalid minisign public key: {e}")))?;
+    let signature = Signature::decode(signature)
+        .map_err(|e| error::Error::new(&format!("Invalid minisign signature: {e}")))?;
+    public_key
+        .verify(contents, &signature, false)
+        .map_err(|e| error::Error::new(&format!("The minisign signature doesn't match: {e}")))?;
+    Ok(())
+}
+
+/// Verifies the signature with `gpgv` against a keyring holding only the downloaded key, so keys
+/// the user trusts can't vouch for the download.
+fn check_gpg(
+    file: &str,
+    key: &str,
+    keyring: &str,
+    signature: &str,
+    fingerprint: &str,
+) -> Result<(), Box<dyn Error>> {
+    let dearmor = Command::new("gpg")
+        .args(["--batch", "--yes", "--dearmor", "-o", keyring, key])
+        .output()?;
+    if !dearmor.status.success() {
+        return Err(Box::new(error::Error::new(&format!(
+            "Unable to read the GPG key for {file}: {}",
+            String::from_utf8_lossy(&dearmor.stderr).trim()
+        ))));
+    }
+    let keyring = fs::canonicalize(keyring)?;
+    let output = Command::new("gpgv")
+        .arg("--status-fd")
+        .arg("1")
+        .arg("--keyring")
+        .arg(&keyring)
+        .args([signature, file])
+        .output()?;
+    let status = String::from_utf8_lossy(&output.stdout);
+    if output.status.success() && is_signed_by(&status, fingerprint) {
+        return Ok(());
+    }
+    Err(Box::new(error::Error::new(&format!(
+        "{file} isn't signed by the GPG key {fingerprint}: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    ))))
+}
+
+/// Returns whether gpgv's status output has a valid signature by the key, or one of its subkeys.
+fn is_signed_by(status: &str, fingerprint: &str) -> bool {
+    let normalise = |fingerprint: &str| fingerprint.replace(' ', "").to_uppercase();
+    let fingerprint = normalise(fingerprint);
+    status
+        .lines()
+        .filter_map(|line| line.strip_prefix("[GNUPG:] VALIDSIG "))
+        .any(|fields| {
+            let fields = fields.split_whitespace().collect::<Vec<&str>>();
+            fields.first().map(|signing| normalise(signing)) == Some(fingerprint.clone())
+                || fields.last().map(|primary| normalise(primary)) == Some(fingerprint.clone())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PUBLIC_KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+
+    static SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==";
+
+    #[test]
+    fn test_check_sha256_ignores_case() {
+        assert!(check_sha256("get_helm.sh", "abc123", "ABC123\n").is_ok());
+    }
+
+    #[test]
+    fn test_check_sha256_refuses_mismatch() {
+        assert!(check_sha256("get_helm.sh", "abc123", "def456").is_err());
+    }
+
+    #[test]
+    fn test_check_minisign_accepts_valid_signature() {
+        assert!(check_minisign(b"test", PUBLIC_KEY, SIGNATURE).is_ok());
+    }
+
+    #[test]
+    fn test_check_minisign_refuses_modified_contents() {
+        assert!(check_minisign(b"Test", PUBLIC_KEY, SIGNATURE).is_err());
+    }
+
+    #[test]
+    fn test_is_signed_by_matches_signing_or_primary_key() {
+        let status = "[GNUPG:] NEWSIG\n\
+            [GNUPG:] GOODSIG 1234567890ABCDEF Helm Signing\n\
+            [GNUPG:] VALIDSIG AAAA1111BBBB2222CCCC3333DDDD4444EEEE5555 2024-01-01 1704067200 0 4 0 1 10 00 FFFF6666AAAA7777BBBB8888CCCC9999DDDD0000\n";
+
+        assert!(is_signed_by(
+            status,
+            "aaaa 1111 bbbb 2222 cccc 3333 dddd 4444 eeee 5555"
+        ));
+        assert!(is_signed_by(
+            status,
+            "FFFF6666AAAA7777BBBB8888CCCC9999DDDD0000"
+        ));
+        assert!(!is_signed_by(
+            status,
+            "0000000000000000000000000000000000000000"
+        ));
+    }
+
+    #[test]
+    fn test_is_signed_by_needs_a_valid_signature() {
+        let status = "[GNUPG:] BADSIG 1234567890ABCDEF Helm Signing\n";
+
+        assert!(!is_signed_by(status, "1234567890ABCDEF"));
+    }
+}
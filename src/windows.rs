@@ -1,12 +1,18 @@
 use async_trait::async_trait;
-use log::info;
+use log::{info, warn};
 use registry::{Data, Hive, Security};
 use std::error::Error;
 use std::fs;
+use std::path::Path;
 use std::process::Command;
 use utfx::U16CString;
 
+use crate::certificates::Certificate;
 use crate::config::Config;
+use crate::error;
+use crate::managed_block;
+use crate::profile::Packages;
+use crate::state::{self, Change};
 use crate::system;
 use crate::system::System;
 
@@ -114,6 +120,13 @@ impl<'s> Windows<'s> {
 
 #[async_trait]
 impl<'s> System for Windows<'s> {
+    fn create_snapshot(&self, _run: u32) -> Result<(), Box<dyn Error>> {
+        warn!(
+            "Not snapshotting the filesystem before the run: snapshots aren't supported on Windows"
+        );
+        Ok(())
+    }
+
     fn execute(&self, command: &str, _super_user: bool) -> Result<String, Box<dyn Error>> {
         let mut cmd = Command::new("cmd");
         let child = cmd.args(vec!["/c", command]);
@@ -124,15 +137,32 @@ impl<'s> System for Windows<'s> {
         system::get_home_dir()
     }
 
+    fn query(&self, command: &str) -> Result<String, Box<dyn Error>> {
+        let mut cmd = Command::new("cmd");
+        system::run_command(cmd.args(vec!["/c", command]), false, false)
+    }
+
+    fn run(
+        &self,
+        program: &str,
+        args: Vec<&str>,
+        _super_user: bool,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut cmd = Command::new(program);
+        system::run_checked_command(cmd.args(args), true, self.config.dry_run)
+    }
+
     fn install_applications(&self, applications: Vec<&str>) -> Result<String, Box<dyn Error>> {
-        self.execute(
+        let output = self.execute(
             format!(
                 "winget install --accept-source-agreements --accept-package-agreements --scope machine --id {}",
                 applications.join(" ")
             )
             .as_str(),
             true,
-        )
+        )?;
+        state::record_packages(&applications);
+        Ok(output)
     }
 
     fn install_affinity_suite(&self) -> Result<(), Box<dyn Error>> {
@@ -195,6 +225,16 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
+    fn install_certificate(&self, certificate: &Certificate) -> Result<(), Box<dyn Error>> {
+        // Firefox and Chrome both trust the Windows store, so there's no NSS database to add to.
+        self.run(
+            "certutil",
+            vec!["-addstore", "-f", "Root", &certificate.file],
+            true,
+        )?;
+        Ok(())
+    }
+
     fn install_cplusplus(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("GnuWin32.Make")? {
             self.install_application("GnuWin32.Make")?;
@@ -229,7 +269,11 @@ impl<'s> System for Windows<'s> {
     }
 
     async fn install_codecs(&self) -> Result<(), Box<dyn Error>> {
-        system::setup_codecs(self).await?;
+        system::setup_codecs(self, self.config).await?;
+        if self.config.dry_run {
+            info!("Would copy the AACS keys into C:\\Program Data\\aacs");
+            return Ok(());
+        }
         fs::create_dir_all("C:\\Program Data\\aacs")?;
         fs::copy(
             format!("{}/.config/aacs/keydb.cfg", self.get_home_dir()).as_str(),
@@ -291,6 +335,7 @@ impl<'s> System for Windows<'s> {
             // TODO: Append if needed instead of blindly re-creating file
             system::add_to_file(
                 &format!(r"{}\Documents\PowerShell\profile.ps1", self.get_home_dir()),
+                "docker-completion",
                 "Import-Module DockerCompletion\r\n",
             )?;
         }
@@ -327,6 +372,12 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
+    fn install_extras(&self, _extras: &Packages) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(error::Error::new(
+            "Installing profile extras is not supported on Windows",
+        )))
+    }
+
     async fn install_exercism(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("Exercism.CLI")? {
             self.install_application("Exercism.CLI")?;
@@ -345,6 +396,10 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
+    fn install_fish(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
     fn install_git(&self) -> Result<(), Box<dyn Error>> {
         if self.config.wsl && !self.is_installed_wsl("git")? {
             self.install_wsl("git")?;
@@ -377,6 +432,7 @@ impl<'s> System for Windows<'s> {
                 self.execute_powershell("Import-Module -Name posh-git", true)?;
                 system::add_to_file(
                     &format!(r"{}\Documents\PowerShell\profile.ps1", self.get_home_dir()),
+                    "posh-git",
                     "Import-Module posh-git\r\n",
                 )?;
             }
@@ -412,7 +468,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_google_cloud_sdk(&self) -> Result<(), Box<dyn Error>> {
         if self.config.wsl && !self.is_installed_wsl("gcloudsdk")? {
             self.install_wsl("gcloudsdk")?;
         }
@@ -454,7 +510,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_groovy(&self) -> Result<(), Box<dyn Error>> {
         if self.config.wsl && !self.is_installed_wsl("groovy")? {
             self.install_wsl("groovy")?;
         }
@@ -542,7 +598,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_openscad(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("OpenSCAD.OpenSCAD.Nightly")? {
             self.install_application("OpenSCAD.OpenSCAD.Nightly")?;
         }
@@ -574,7 +630,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_microsoft_edge(&self) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
 
@@ -651,6 +707,7 @@ impl<'s> System for Windows<'s> {
        Set-Alias nvmu -value \"callnvm\"";
             system::add_to_file(
                 &format!(r"{}\Documents\PowerShell\profile.ps1", self.get_home_dir()),
+                "nvm",
                 nvm_script,
             )?;
             self.refreshenv()?;
@@ -762,7 +819,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_spotify(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("Spotify.Spotify")? {
             self.install_application("Spotify.Spotify")?;
         }
@@ -845,7 +902,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_terraform(&self) -> Result<(), Box<dyn Error>> {
         if !self.is_installed("Hashicorp.Terraform")? {
             self.install_application("Hashicorp.Terraform")?;
         }
@@ -876,6 +933,7 @@ impl<'s> System for Windows<'s> {
             self.install_application("VideoLAN.VLC")?;
         }
         system::download_file(
+            self.config,
             "https://vlc-bluray.whoknowsmy.name/files/win64/libaacs.dll",
             r"C:\Program Files\VideoLAN\VLC\libaacs.dll",
         )
@@ -887,7 +945,7 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
-    fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
+    async fn install_vscode(&self) -> Result<(), Box<dyn Error>> {
         if self.config.wsl && !self.is_installed_wsl("vscode")? {
             self.install_wsl("vscode")?;
         }
@@ -946,6 +1004,62 @@ impl<'s> System for Windows<'s> {
         Ok(())
     }
 
+    async fn rank_mirrors(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn is_applied(&self, change: &Change) -> Result<bool, Box<dyn Error>> {
+        match change {
+            Change::Package { name } => self.is_installed(name),
+            Change::Block { file, id } => Ok(managed_block::read(file, id)?.is_some()),
+            Change::Line { file, line } => Ok(managed_block::has_line(file, line)?),
+            Change::File { path, .. } => Ok(Path::new(path).exists()),
+            _ => Err(Box::new(error::Error::new(&format!(
+                "Checking the {change} is not supported on Windows"
+            )))),
+        }
+    }
+
+    fn list_installed(&self) -> Result<Packages, Box<dyn Error>> {
+        Err(Box::new(error::Error::new(
+            "Listing the installed packages is not supported on Windows",
+        )))
+    }
+
+    fn revert(&self, change: &Change) -> Result<(), Box<dyn Error>> {
+        match change {
+            Change::Package { name } => {
+                self.execute(
+                    &format!("winget uninstall --silent --scope machine --id {name}"),
+                    true,
+                )?;
+            }
+            Change::Block { file, id } => {
+                managed_block::remove(file, id)?;
+            }
+            Change::Line { file, line } => {
+                managed_block::remove_line(file, line)?;
+            }
+            Change::File { path, .. } => {
+                if Path::new(path).exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            _ => {
+                return Err(Box::new(error::Error::new(&format!(
+                    "Removing the {change} is not supported on Windows"
+                ))));
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, _run: Option<u32>) -> Result<(), Box<dyn Error>> {
+        Err(Box::new(error::Error::new(
+            "Rolling back is not supported on Windows",
+        )))
+    }
+
     fn set_development_shortcuts(&self) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
@@ -963,8 +1077,8 @@ impl<'s> System for Windows<'s> {
     }
 
     fn setup_user_bin(&self) -> Result<(), Box<dyn Error>> {
-        fs::create_dir_all(format!("{}/bin", self.get_home_dir()).as_str())?;
-        fs::create_dir_all(format!("{}/.local/bin", self.get_home_dir()).as_str())?;
+        system::create_dir(&format!("{}/bin", self.get_home_dir()))?;
+        system::create_dir(&format!("{}/.local/bin", self.get_home_dir()))?;
         Ok(())
     }
 
@@ -0,0 +1,136 @@
+use log::{info, warn};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::state;
+
+/// The directory a run downloads and extracts into, which is made the current directory so the
+/// installers' files don't end up wherever dotfiles was run from.
+///
+/// It's removed when dropped, so on success and on failure, and on Ctrl-C, unless it's being kept
+/// for debugging.
+pub(crate) struct Workspace {
+    path: PathBuf,
+    previous_directory: PathBuf,
+    keep: bool,
+}
+
+impl Workspace {
+    /// Creates the workspace for the run in the temporary directory, owned by the user so that
+    /// `makepkg` can build in it, and changes into it.
+    ///
+    /// The directory gets a random suffix and is only accessible by its owner, so no one else can
+    /// create or swap it out beforehand.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```no_run
+    /// use workspace::Workspace;
+    ///
+    /// let _workspace = Workspace::create(run, config.keep_workspace)?;
+    /// ```
+    pub(crate) fn create(run: u32, keep: bool) -> Result<Workspace, Box<dyn Error>> {
+        let workspace = Workspace::create_in(&env::temp_dir(), run, keep)?;
+        state::give_to_user(&workspace.path.to_string_lossy())?;
+        let path = workspace.path.clone();
+        ctrlc::set_handler(move || {
+            warn!("Interrupted");
+            if !keep {
+                let _ = fs::remove_dir_all(&path);
+            }
+            process::exit(130);
+        })?;
+        env::set_current_dir(&workspace.path)?;
+        info!("Using the workspace {}", workspace.path.display());
+        Ok(workspace)
+    }
+
+    fn create_in(parent: &Path, run: u32, keep: bool) -> Result<Workspace, Box<dyn Error>> {
+        let path = tempfile::Builder::new()
+            .prefix(&format!("dotfiles-run-{run}-"))
+            .tempdir_in(parent)?
+            .keep();
+        Ok(Workspace {
+            path,
+            previous_directory: env::current_dir()?,
+            keep,
+        })
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if env::current_dir().is_ok_and(|directory| directory == self.path) {
+            let _ = env::set_current_dir(&self.previous_directory);
+        }
+        if self.keep {
+            info!("Kept the workspace {}", self.path.display());
+        } else if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!(
+                "Unable to remove the workspace {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parent(name: &str) -> PathBuf {
+        let parent = env::temp_dir().join(format!("dotfiles-workspace-test-{name}"));
+        let _ = fs::remove_dir_all(&parent);
+        fs::create_dir_all(&parent).unwrap();
+        parent
+    }
+
+    #[test]
+    fn test_workspace_is_removed_when_dropped() {
+        let parent = parent("removed");
+        let workspace = Workspace::create_in(&parent, 7, false).unwrap();
+        let path = workspace.path.clone();
+        fs::write(path.join("google-chrome.deb"), "").unwrap();
+
+        drop(workspace);
+
+        assert!(path.starts_with(&parent));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_workspace_is_kept() {
+        let parent = parent("kept");
+        let workspace = Workspace::create_in(&parent, 7, true).unwrap();
+        let path = workspace.path.clone();
+
+        drop(workspace);
+
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_workspace_is_unique_to_the_run() {
+        let parent = parent("unique");
+        fs::create_dir_all(parent.join("dotfiles-run-7")).unwrap();
+
+        let first = Workspace::create_in(&parent, 7, false).unwrap();
+        let second = Workspace::create_in(&parent, 7, false).unwrap();
+
+        assert_ne!(first.path, second.path);
+        assert_ne!(first.path, parent.join("dotfiles-run-7"));
+        assert!(first
+            .path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("dotfiles-run-7-"));
+        assert_eq!(fs::read_dir(&first.path).unwrap().count(), 0);
+    }
+}
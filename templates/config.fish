@@ -0,0 +1,68 @@
+{% for entry in path %}
+fish_add_path --global --append {{ entry | replace("${HOME}", "$HOME") }}
+{% endfor %}
+
+function gfp
+    for i in (git remote)
+        git fetch --prune $i
+    end
+end
+
+function gco
+    git checkout $argv
+end
+
+function gpod
+    git pull origin develop
+end
+
+function grprt
+    lsof -i :$argv[1] -S
+end
+
+function gitCurrentBranch
+    git rev-parse --abbrev-ref HEAD
+end
+
+function gitGraph
+    git log --graph --oneline --all
+end
+
+function gitDummyCommit
+    git commit --allow-empty -m $argv[1]
+end
+
+function gitDeleteRemote
+    git push -d origin $argv[1]
+end
+
+function gitDeleteLocal
+    git branch -d $argv[1]
+end
+
+function gitDeleteUntracked
+    git fetch -p; and for branch in (git for-each-ref --format '%(refname) %(upstream:track)' refs/heads | awk '$2 == "[gone]" {sub("refs/heads/", "", $1); print $1}')
+        git branch -D $branch
+    end
+end
+
+function migrateGitRepo
+    if test -z "$argv[1]"
+        echo "Please provide the new git repo URL"
+        return
+    end
+
+    for remote in (git branch -r | grep -v master)
+        git checkout --track $remote
+    end
+
+    git remote rm origin
+    git remote add origin $argv[1]
+    git remote show origin
+    git push origin '*:*'
+end
+
+if test -f $HOME/.config/fish/config.custom.fish
+    source $HOME/.config/fish/config.custom.fish
+end
+